@@ -0,0 +1,282 @@
+use num_bigint::BigUint;
+use num_integer::Integer;
+use num_traits::One;
+
+/// Montgomery arithmetic mod a fixed n, so that the tests running many multiplications
+/// mod the same n replace each division by n with a few multiply-adds.
+///
+/// Values handed to `mul`, `square` and `pow` are in Montgomery form, x * R mod n with
+/// R = 2^(64 * limbs of n): `to_montgomery` enters it and `from_montgomery` leaves it.
+/// Adding, subtracting, doubling and halving mod n work on the form unchanged, and zero
+/// stays zero. Montgomery reduction needs an odd n, so for even moduli the form is the
+/// plain value and every product is reduced by division instead.
+#[derive(Clone, Debug)]
+pub struct MontgomeryCtx {
+    n: BigUint,
+    /// n in 64-bit limbs, least significant first.
+    limbs: Vec<u64>,
+    /// -n^-1 mod 2^64, or `None` for an even n.
+    n_inv: Option<u64>,
+    /// R^2 mod n, which `to_montgomery` multiplies by.
+    r2: Vec<u64>,
+}
+
+impl MontgomeryCtx {
+    /// Precomputes the constants for arithmetic mod `n`, which must be at least 1.
+    pub fn new(n: &BigUint) -> MontgomeryCtx {
+        let limbs = n.to_u64_digits();
+        let n_inv = n.is_odd().then(|| {
+            // Newton's iteration doubles the correct low bits of the inverse each step,
+            // starting from the 3 that n itself gets right for any odd n
+            let mut inverse = limbs[0];
+            for _ in 0..5 {
+                inverse = inverse.wrapping_mul(2u64.wrapping_sub(limbs[0].wrapping_mul(inverse)));
+            }
+            inverse.wrapping_neg()
+        });
+        let r2 = (BigUint::one() << (128 * limbs.len())) % n;
+        let mut ctx = MontgomeryCtx { n: n.clone(), limbs, n_inv, r2: Vec::new() };
+        ctx.r2 = ctx.padded(&r2);
+        ctx
+    }
+
+    /// The modulus n.
+    pub fn modulus(&self) -> &BigUint {
+        &self.n
+    }
+
+    /// The Montgomery form of `x`, which may be any size.
+    pub fn to_montgomery(&self, x: &BigUint) -> BigUint {
+        let x = x % &self.n;
+        match self.n_inv {
+            Some(_) => number(&self.reduce_product(&self.padded(&x), &self.r2)),
+            None => x,
+        }
+    }
+
+    /// The value whose Montgomery form is `x`.
+    pub fn from_montgomery(&self, x: &BigUint) -> BigUint {
+        match self.n_inv {
+            Some(_) => {
+                let mut one = vec![0; self.limbs.len()];
+                one[0] = 1;
+                number(&self.reduce_product(&self.padded(x), &one))
+            }
+            None => x.clone(),
+        }
+    }
+
+    /// The Montgomery form of 1.
+    pub fn one(&self) -> BigUint {
+        self.to_montgomery(&BigUint::one())
+    }
+
+    /// a * b mod n, in Montgomery form.
+    pub fn mul(&self, a: &BigUint, b: &BigUint) -> BigUint {
+        match self.n_inv {
+            Some(_) => number(&self.reduce_product(&self.padded(a), &self.padded(b))),
+            None => a * b % &self.n,
+        }
+    }
+
+    /// a^2 mod n, in Montgomery form.
+    pub fn square(&self, a: &BigUint) -> BigUint {
+        match self.n_inv {
+            Some(_) => number(&self.reduce_square(&self.padded(a))),
+            None => a * a % &self.n,
+        }
+    }
+
+    /// base^exponent mod n, in Montgomery form, from the top of the exponent in 4-bit
+    /// windows: four squarings, then one multiply by a power from a table of base^0..15.
+    pub fn pow(&self, base: &BigUint, exponent: &BigUint) -> BigUint {
+        if self.n_inv.is_none() {
+            return base.modpow(exponent, &self.n);
+        }
+        let base = self.padded(base);
+        let mut table = vec![self.padded(&self.one())];
+        for i in 1..16 {
+            table.push(self.reduce_product(&table[i - 1], &base));
+        }
+        let mut result = table[0].clone();
+        for window in (0..exponent.bits().div_ceil(4)).rev() {
+            for _ in 0..4 {
+                result = self.reduce_square(&result);
+            }
+            let digit = (0..4).fold(0, |digit, bit| digit | (exponent.bit(4 * window + bit) as usize) << bit);
+            if digit != 0 {
+                result = self.reduce_product(&result, &table[digit]);
+            }
+        }
+        number(&result)
+    }
+
+    /// `x` in exactly as many limbs as n. `x` must be below n.
+    fn padded(&self, x: &BigUint) -> Vec<u64> {
+        let mut limbs = x.to_u64_digits();
+        limbs.resize(self.limbs.len(), 0);
+        limbs
+    }
+
+    /// a * b / R mod n for a, b < n.
+    fn reduce_product(&self, a: &[u64], b: &[u64]) -> Vec<u64> {
+        let k = self.limbs.len();
+        let mut t = vec![0u64; 2 * k + 1];
+        for (i, &a_i) in a.iter().enumerate() {
+            let mut carry = 0u64;
+            for (t_j, &b_j) in t[i..i + k].iter_mut().zip(b) {
+                let sum = *t_j as u128 + a_i as u128 * b_j as u128 + carry as u128;
+                *t_j = sum as u64;
+                carry = (sum >> 64) as u64;
+            }
+            t[i + k] = carry;
+        }
+        self.redc(t)
+    }
+
+    /// a^2 / R mod n for a < n, computing each cross product a_i * a_j once and doubling.
+    fn reduce_square(&self, a: &[u64]) -> Vec<u64> {
+        let k = self.limbs.len();
+        let mut t = vec![0u64; 2 * k + 1];
+        for (i, &a_i) in a.iter().enumerate() {
+            let mut carry = 0u64;
+            for (t_j, &a_j) in t[2 * i + 1..i + k].iter_mut().zip(&a[i + 1..]) {
+                let sum = *t_j as u128 + a_i as u128 * a_j as u128 + carry as u128;
+                *t_j = sum as u64;
+                carry = (sum >> 64) as u64;
+            }
+            t[i + k] = carry;
+        }
+        let mut top = 0u64;
+        for t_j in &mut t[..2 * k] {
+            let next_top = *t_j >> 63;
+            *t_j = (*t_j << 1) | top;
+            top = next_top;
+        }
+        let mut carry = 0u64;
+        for (i, &a_i) in a.iter().enumerate() {
+            let square = a_i as u128 * a_i as u128;
+            let low = t[2 * i] as u128 + (square as u64) as u128 + carry as u128;
+            t[2 * i] = low as u64;
+            let high = t[2 * i + 1] as u128 + (square >> 64) + (low >> 64);
+            t[2 * i + 1] = high as u64;
+            carry = (high >> 64) as u64;
+        }
+        self.redc(t)
+    }
+
+    /// t / R mod n for a product t < n^2 in 2k + 1 limbs: k steps that each clear the lowest
+    /// remaining limb by adding a multiple of n (SOS), then at most one subtraction.
+    fn redc(&self, mut t: Vec<u64>) -> Vec<u64> {
+        let n = &self.limbs;
+        let n_inv = self.n_inv.expect("Montgomery reduction needs an odd modulus");
+        let k = n.len();
+        for i in 0..k {
+            let m = t[i].wrapping_mul(n_inv);
+            let mut carry = 0u64;
+            for (t_j, &n_j) in t[i..i + k].iter_mut().zip(n) {
+                let sum = *t_j as u128 + m as u128 * n_j as u128 + carry as u128;
+                *t_j = sum as u64;
+                carry = (sum >> 64) as u64;
+            }
+            for t_j in &mut t[i + k..] {
+                let (sum, overflow) = t_j.overflowing_add(carry);
+                *t_j = sum;
+                if !overflow {
+                    break;
+                }
+                carry = 1;
+            }
+        }
+
+        // What is left is below 2n, so one subtraction reduces it
+        let mut result = t.split_off(k);
+        if result[k] != 0 || !less_than(&result[..k], n) {
+            let mut borrow = false;
+            for (r_j, &n_j) in result.iter_mut().zip(n) {
+                let (difference, under) = r_j.overflowing_sub(n_j);
+                let (difference, under_again) = difference.overflowing_sub(borrow as u64);
+                *r_j = difference;
+                borrow = under || under_again;
+            }
+        }
+        result.truncate(k);
+        result
+    }
+}
+
+/// Whether the little-endian limbs `a` are below `b`, of the same length.
+fn less_than(a: &[u64], b: &[u64]) -> bool {
+    for (x, y) in a.iter().rev().zip(b.iter().rev()) {
+        if x != y {
+            return x < y;
+        }
+    }
+    false
+}
+
+/// The number held in little-endian 64-bit limbs.
+fn number(limbs: &[u64]) -> BigUint {
+    let digits: Vec<u32> = limbs.iter().flat_map(|&limb| [limb as u32, (limb >> 32) as u32]).collect();
+    BigUint::from_slice(&digits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num_traits::Zero;
+
+    /// xorshift64*, enough to spread test inputs without a dependency.
+    fn random_numbers(seed: u64) -> impl Iterator<Item = u64> {
+        let mut state = seed;
+        std::iter::repeat_with(move || {
+            state ^= state >> 12;
+            state ^= state << 25;
+            state ^= state >> 27;
+            state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+        })
+    }
+
+    fn random_biguint(random: &mut impl Iterator<Item = u64>, limbs: usize) -> BigUint {
+        let digits: Vec<u32> = random.take(limbs).flat_map(|limb| [limb as u32, (limb >> 32) as u32]).collect();
+        BigUint::from_slice(&digits)
+    }
+
+    #[test]
+    fn products_and_powers_match_modpow() {
+        let mut random = random_numbers(0x9e37_79b9_7f4a_7c15);
+        for limbs in [1, 2, 3, 8, 16] {
+            for _ in 0..20 {
+                let mut n = random_biguint(&mut random, limbs);
+                if n.is_zero() {
+                    n = BigUint::one();
+                }
+                // Even moduli take the plain path and must agree all the same
+                for n in [n.clone() | BigUint::one(), &n + (&n % 2u32)] {
+                    let ctx = MontgomeryCtx::new(&n);
+                    let a = random_biguint(&mut random, limbs + 1);
+                    let b = random_biguint(&mut random, limbs);
+                    let e = random_biguint(&mut random, 2);
+                    let (a_form, b_form) = (ctx.to_montgomery(&a), ctx.to_montgomery(&b));
+                    assert_eq!(ctx.from_montgomery(&a_form), &a % &n, "n = {}", n);
+                    assert_eq!(ctx.from_montgomery(&ctx.mul(&a_form, &b_form)), &a * &b % &n, "n = {}", n);
+                    assert_eq!(ctx.from_montgomery(&ctx.square(&a_form)), &a * &a % &n, "n = {}", n);
+                    assert_eq!(ctx.from_montgomery(&ctx.pow(&a_form, &e)), a.modpow(&e, &n), "n = {}", n);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn edge_values_survive_the_round_trip() {
+        for n in [1u64, 2, 3, 4, u64::MAX, u64::MAX - 1] {
+            let n = BigUint::from(n);
+            let ctx = MontgomeryCtx::new(&n);
+            let top = &n - 1u32;
+            assert_eq!(ctx.from_montgomery(&ctx.one()), BigUint::one() % &n);
+            assert_eq!(ctx.from_montgomery(&ctx.square(&ctx.to_montgomery(&top))), &top * &top % &n);
+            assert_eq!(ctx.from_montgomery(&ctx.pow(&ctx.to_montgomery(&top), &BigUint::zero())), BigUint::one() % &n);
+            assert!(ctx.to_montgomery(&BigUint::zero()).is_zero());
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+
+/// What a configuration key holds, which decides how it is checked when read and shown.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValueKind {
+    /// A string, such as a path.
+    Text,
+    /// A non-negative integer.
+    Integer,
+    /// A boolean, for options that are switched on.
+    Flag,
+    /// An array of non-negative integers, given on the command line comma-separated.
+    List,
+}
+
+/// A key of the configuration file and the option it supplies a default for.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ConfigKey {
+    pub section: &'static str,
+    pub key: &'static str,
+    /// The clap id of the command-line option, or `None` for a key that only the file and
+    /// the environment set.
+    pub arg: Option<&'static str>,
+    /// The environment variable that overrides the file.
+    pub env: &'static str,
+    pub kind: ValueKind,
+    /// Whether `--print-config` hides the value.
+    pub secret: bool,
+}
+
+impl ConfigKey {
+    const fn new(section: &'static str, key: &'static str, arg: &'static str, env: &'static str, kind: ValueKind) -> ConfigKey {
+        ConfigKey { section, key, arg: Some(arg), env, kind, secret: false }
+    }
+}
+
+/// Every key the configuration file takes, by section.
+pub const KEYS: &[ConfigKey] = &[
+    ConfigKey::new("device", "devices", "devices", "MP_DEVICES", ValueKind::Text),
+    ConfigKey::new("device", "backend", "backend", "MP_BACKEND", ValueKind::Text),
+    ConfigKey::new("device", "program_cache", "program_cache", "MP_PROGRAM_CACHE", ValueKind::Text),
+    ConfigKey::new("checkpoint", "enabled", "memory", "MP_CHECKPOINT", ValueKind::Flag),
+    ConfigKey::new("checkpoint", "directory", "checkpoint_dir", "MP_CHECKPOINT_DIR", ValueKind::Text),
+    ConfigKey::new("checkpoint", "interval", "checkpoint_interval", "MP_CHECKPOINT_INTERVAL", ValueKind::Integer),
+    ConfigKey::new("results", "file", "result_file", "MP_RESULT_FILE", ValueKind::Text),
+    ConfigKey::new("results", "json", "save_results_json", "MP_RESULTS_JSON", ValueKind::Text),
+    ConfigKey::new("results", "skip_done", "skip_done", "MP_SKIP_DONE", ValueKind::Flag),
+    ConfigKey::new("log", "file", "log_file", "MP_LOG_FILE", ValueKind::Text),
+    ConfigKey::new("prp", "bases", "bases", "MP_PRP_BASES", ValueKind::List),
+    ConfigKey { section: "primenet", key: "user", arg: None, env: "MP_PRIMENET_USER", kind: ValueKind::Text, secret: false },
+    ConfigKey { section: "primenet", key: "password", arg: None, env: "MP_PRIMENET_PASSWORD", kind: ValueKind::Text, secret: true },
+];
+
+/// The key `section.key` names, if the configuration file takes it.
+pub fn config_key(section: &str, key: &str) -> Option<&'static ConfigKey> {
+    KEYS.iter().find(|k| k.section == section && k.key == key)
+}
+
+/// The settings of a configuration file, each as the text its command-line option takes.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Config {
+    /// The file they were read from, if any.
+    pub path: Option<PathBuf>,
+    values: Vec<(&'static ConfigKey, String)>,
+}
+
+impl Config {
+    /// Reads the configuration in `text`, returning it with a warning for every section
+    /// or key it doesn't know, which are skipped. A value of the wrong kind is an error.
+    pub fn parse(text: &str) -> Result<(Config, Vec<String>), Box<dyn Error>> {
+        let table: Table = text.parse()?;
+        let mut config = Config::default();
+        let mut warnings = Vec::new();
+        for (section, entries) in &table {
+            let Some(entries) = entries.as_table() else {
+                warnings.push(format!("ignoring {}, which is not a section", section));
+                continue;
+            };
+            for (key, value) in entries {
+                match config_key(section, key) {
+                    Some(config_key) => config.values.push((config_key, option_text(config_key, value)?)),
+                    None => warnings.push(format!("ignoring the unknown key {}.{}", section, key)),
+                }
+            }
+        }
+        Ok((config, warnings))
+    }
+
+    /// Reads the configuration file at `path`, as `parse` does.
+    pub fn load(path: &Path) -> Result<(Config, Vec<String>), Box<dyn Error>> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("Cannot read the config file {}: {}", path.display(), e))?;
+        let (mut config, warnings) =
+            Config::parse(&text).map_err(|e| format!("Invalid config file {}: {}", path.display(), e))?;
+        config.path = Some(path.to_path_buf());
+        let warnings = warnings.into_iter().map(|warning| format!("{}: {}", path.display(), warning)).collect();
+        Ok((config, warnings))
+    }
+
+    /// The settings, by section and then key.
+    pub fn values(&self) -> impl Iterator<Item = (&'static ConfigKey, &str)> {
+        self.values.iter().map(|(key, value)| (*key, value.as_str()))
+    }
+
+    /// The setting of `key`, if the file has one.
+    pub fn get(&self, key: &ConfigKey) -> Option<&str> {
+        self.values().find(|(k, _)| *k == key).map(|(_, value)| value)
+    }
+}
+
+/// `value` as the text the option of `key` takes on the command line.
+fn option_text(key: &ConfigKey, value: &Value) -> Result<String, Box<dyn Error>> {
+    let integer = |value: &Value| value.as_integer().filter(|&n| n >= 0).map(|n| n.to_string());
+    let text = match (key.kind, value) {
+        (ValueKind::Text, Value::String(text)) => Some(text.clone()),
+        (ValueKind::Integer, value) => integer(value),
+        (ValueKind::Flag, Value::Boolean(flag)) => Some(flag.to_string()),
+        (ValueKind::List, Value::Array(values)) => {
+            values.iter().map(integer).collect::<Option<Vec<_>>>().filter(|values| !values.is_empty()).map(|values| values.join(","))
+        }
+        _ => None,
+    };
+    let expected = match key.kind {
+        ValueKind::Text => "a string",
+        ValueKind::Integer => "a non-negative integer",
+        ValueKind::Flag => "true or false",
+        ValueKind::List => "a non-empty array of non-negative integers",
+    };
+    text.ok_or_else(|| format!("{}.{} must be {}, got {}", key.section, key.key, expected, value).into())
+}
+
+/// `value`, as the option of `key` took it, written the way the configuration file gives it.
+pub fn toml_value(key: &ConfigKey, value: &str) -> String {
+    if key.secret {
+        return Value::from("********").to_string();
+    }
+    match key.kind {
+        ValueKind::Text => Value::from(value).to_string(),
+        ValueKind::Integer | ValueKind::Flag => value.to_string(),
+        ValueKind::List => format!("[{}]", value.split(',').collect::<Vec<_>>().join(", ")),
+    }
+}
+
+/// The configuration file read when `--config` doesn't name one: config.toml in mp under
+/// $XDG_CONFIG_HOME or ~/.config.
+pub fn default_config_path() -> Option<PathBuf> {
+    let non_empty = |name| std::env::var_os(name).filter(|value| !value.is_empty()).map(PathBuf::from);
+    non_empty("XDG_CONFIG_HOME")
+        .or_else(|| non_empty("HOME").map(|home| home.join(".config")))
+        .map(|dir| dir.join("mp").join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_become_option_values_and_unknown_keys_warnings() {
+        let (config, warnings) = Config::parse(
+            r#"
+            [device]
+            backend = "ntt"
+            colour = "blue"
+
+            [checkpoint]
+            enabled = true
+            interval = 5000
+
+            [prp]
+            bases = [2, 3, 5]
+
+            [primenet]
+            password = "hunter2"
+
+            [plugins]
+            name = "x"
+            "#,
+        )
+        .unwrap();
+        let values: Vec<(&str, &str)> = config.values().map(|(key, value)| (key.key, value)).collect();
+        assert_eq!(
+            values,
+            [("enabled", "true"), ("interval", "5000"), ("backend", "ntt"), ("password", "hunter2"), ("bases", "2,3,5")]
+        );
+        assert_eq!(warnings, ["ignoring the unknown key device.colour", "ignoring the unknown key plugins.name"]);
+        assert_eq!(config.get(config_key("prp", "bases").unwrap()), Some("2,3,5"));
+        assert_eq!(config.get(config_key("log", "file").unwrap()), None);
+
+        assert_eq!(toml_value(config_key("prp", "bases").unwrap(), "2,3,5"), "[2, 3, 5]");
+        let path = "C:\\run \"1\".log";
+        let line: Table = format!("file = {}", toml_value(config_key("log", "file").unwrap(), path)).parse().unwrap();
+        assert_eq!(line["file"].as_str(), Some(path));
+        assert_eq!(toml_value(config_key("primenet", "password").unwrap(), "hunter2"), r#""********""#);
+    }
+
+    #[test]
+    fn values_of_the_wrong_kind_are_errors() {
+        for (text, message) in [
+            ("[checkpoint]\ninterval = \"often\"", "checkpoint.interval must be a non-negative integer, got \"often\""),
+            ("[checkpoint]\ninterval = -1", "checkpoint.interval must be a non-negative integer, got -1"),
+            ("[checkpoint]\nenabled = 1", "checkpoint.enabled must be true or false, got 1"),
+            ("[prp]\nbases = []", "prp.bases must be a non-empty array of non-negative integers, got []"),
+            ("[log]\nfile = 3", "log.file must be a string, got 3"),
+        ] {
+            assert_eq!(Config::parse(text).unwrap_err().to_string(), message);
+        }
+        assert!(Config::parse("[device\n").is_err());
+    }
+}
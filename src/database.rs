@@ -0,0 +1,170 @@
+use rusqlite::types::Value;
+use rusqlite::{params, Connection};
+use std::error::Error;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Schema changes in order; the database is at version i once the first i have run.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE primes (value INTEGER NOT NULL);
+     CREATE TABLE tests (
+         -- Untyped, so numbers past 64 bits stay exact as text instead of becoming REAL
+         exponent NOT NULL,
+         kind TEXT NOT NULL,
+         verdict TEXT NOT NULL,
+         res64 TEXT,
+         duration_ms INTEGER NOT NULL,
+         finished_at INTEGER NOT NULL
+     );",
+];
+
+/// One finished `-l` or `-p` test, as stored in the `tests` table.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TestRecord {
+    /// The exponent for Lucas-Lehmer, the number itself for PRP.
+    pub exponent: u128,
+    /// `"ll"` or `"prp"`.
+    pub kind: &'static str,
+    pub verdict: &'static str,
+    /// The low 64 bits of the final residue in hex, when the test reports one.
+    pub res64: Option<String>,
+    pub duration: Duration,
+}
+
+/// A number with a finished test and the res64 recorded for it, if any.
+pub type FinishedTest = (u128, Option<String>);
+
+/// A SQLite database of generated primes and test outcomes that accumulates across runs.
+pub struct ResultsDb {
+    connection: Connection,
+}
+
+impl ResultsDb {
+    /// Opens or creates the database at `path`, bringing its schema up to date.
+    pub fn open(path: &str) -> Result<ResultsDb, Box<dyn Error>> {
+        let mut connection = Connection::open(path)?;
+        connection.execute("CREATE TABLE IF NOT EXISTS meta (key TEXT PRIMARY KEY, value INTEGER NOT NULL)", [])?;
+        let version: usize = connection
+            .query_row("SELECT value FROM meta WHERE key = 'schema_version'", [], |row| row.get(0))
+            .unwrap_or(0);
+        if version > MIGRATIONS.len() {
+            return Err(format!(
+                "{} has schema version {}, newer than the {} this build knows.",
+                path,
+                version,
+                MIGRATIONS.len()
+            )
+            .into());
+        }
+
+        let transaction = connection.transaction()?;
+        for migration in &MIGRATIONS[version..] {
+            transaction.execute_batch(migration)?;
+        }
+        transaction.execute(
+            "INSERT OR REPLACE INTO meta (key, value) VALUES ('schema_version', ?1)",
+            [MIGRATIONS.len()],
+        )?;
+        transaction.commit()?;
+        Ok(ResultsDb { connection })
+    }
+
+    /// Inserts a batch of primes in a single transaction.
+    pub fn insert_primes(&mut self, primes: &[u128]) -> Result<(), Box<dyn Error>> {
+        let transaction = self.connection.transaction()?;
+        {
+            let mut insert = transaction.prepare_cached("INSERT INTO primes (value) VALUES (?1)")?;
+            for &prime in primes {
+                let value = i64::try_from(prime)
+                    .map_err(|_| format!("{} does not fit SQLite's 64-bit signed integers.", prime))?;
+                insert.execute([value])?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Records one finished test, stamped with the current time.
+    pub fn record_test(&mut self, record: &TestRecord) -> Result<(), Box<dyn Error>> {
+        let finished_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        // SQLite integers are signed 64-bit, so larger numbers are kept as text
+        let exponent = match i64::try_from(record.exponent) {
+            Ok(exponent) => Value::Integer(exponent),
+            Err(_) => Value::Text(record.exponent.to_string()),
+        };
+        self.connection.execute(
+            "INSERT INTO tests (exponent, kind, verdict, res64, duration_ms, finished_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                exponent,
+                record.kind,
+                record.verdict,
+                record.res64,
+                record.duration.as_millis() as i64,
+                finished_at
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The numbers with a finished `kind` test, which is one whose verdict is not "error",
+    /// with its res64 when one was recorded.
+    pub fn finished_tests(&self, kind: &str) -> Result<Vec<FinishedTest>, Box<dyn Error>> {
+        let mut query = self.connection.prepare("SELECT exponent, res64 FROM tests WHERE kind = ?1 AND verdict != 'error'")?;
+        let rows = query.query_map([kind], |row| Ok((row.get::<_, Value>(0)?, row.get::<_, Option<String>>(1)?)))?;
+        let mut finished = Vec::new();
+        for row in rows {
+            let (exponent, res64) = row?;
+            let exponent = match exponent {
+                Value::Integer(exponent) => u128::try_from(exponent).ok(),
+                Value::Text(exponent) => exponent.parse().ok(),
+                _ => None,
+            };
+            finished.extend(exponent.map(|exponent| (exponent, res64)));
+        }
+        Ok(finished)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schema_is_created_once_and_rows_accumulate() {
+        let dir = std::env::temp_dir().join(format!("mp-sqlite-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("results.db");
+        let path = path.to_str().unwrap();
+
+        for _ in 0..2 {
+            let mut db = ResultsDb::open(path).unwrap();
+            db.insert_primes(&[2, 3, 5]).unwrap();
+            db.record_test(&TestRecord {
+                exponent: u128::MAX,
+                kind: "prp",
+                verdict: "composite",
+                res64: None,
+                duration: Duration::from_millis(12),
+            })
+            .unwrap();
+        }
+        assert!(ResultsDb::open(path).unwrap().insert_primes(&[1 << 63]).is_err());
+
+        let connection = Connection::open(path).unwrap();
+        let count = |sql: &str| connection.query_row(sql, [], |row| row.get::<_, i64>(0)).unwrap();
+        assert_eq!(count("SELECT value FROM meta WHERE key = 'schema_version'"), MIGRATIONS.len() as i64);
+        assert_eq!(count("SELECT COUNT(*) FROM primes"), 6);
+        assert_eq!(count("SELECT COUNT(*) FROM tests WHERE duration_ms = 12"), 2);
+        let exponent: String = connection.query_row("SELECT exponent FROM tests", [], |row| row.get(0)).unwrap();
+        assert_eq!(exponent, u128::MAX.to_string());
+
+        let mut db = ResultsDb::open(path).unwrap();
+        for (exponent, verdict) in [(127, "prime"), (11, "error")] {
+            let res64 = Some("0000000000000000".to_string());
+            db.record_test(&TestRecord { exponent, kind: "ll", verdict, res64, duration: Duration::ZERO }).unwrap();
+        }
+        assert_eq!(db.finished_tests("ll").unwrap(), [(127, Some("0000000000000000".to_string()))]);
+        assert_eq!(db.finished_tests("prp").unwrap(), [(u128::MAX, None), (u128::MAX, None)]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
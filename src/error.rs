@@ -0,0 +1,79 @@
+use std::error::Error;
+use std::fmt;
+
+/// Errors with enough structure that callers may want to match on them.
+///
+/// They travel inside the `Box<dyn Error>` results used throughout the crate and can be
+/// recovered with `downcast_ref::<MpError>()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MpError {
+    /// The run hit its time limit after completing `iteration` of `total` iterations.
+    Timeout { iteration: u128, total: u128 },
+    /// A range reaching `end` is past the 64-bit candidates the generators work with.
+    UnsupportedRange { end: u128 },
+    /// A range was given as [start, end) with `start` past `end`.
+    ReversedRange { start: u128, end: u128 },
+    /// The run was interrupted by Ctrl-C after completing `iteration` of `total` iterations.
+    Interrupted { iteration: u128, total: u128 },
+    /// The run reached its deadline after completing `iteration` of `total` iterations, to
+    /// be picked up again from its checkpoint.
+    Paused { iteration: u128, total: u128 },
+    /// The OpenCL compiler of `device` rejected a kernel, with its build log followed by
+    /// the numbered kernel source lines the log points at.
+    KernelBuild { device: String, log: String },
+}
+
+impl fmt::Display for MpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MpError::Timeout { iteration, total } => write!(
+                f,
+                "timed out at iteration {} of {}",
+                iteration, total
+            ),
+            MpError::Interrupted { iteration, total } => write!(
+                f,
+                "interrupted at iteration {} of {}",
+                iteration, total
+            ),
+            MpError::Paused { iteration, total } => write!(
+                f,
+                "paused at iteration {} of {}, resume with the same command",
+                iteration, total
+            ),
+            MpError::UnsupportedRange { end } => write!(
+                f,
+                "range end {} exceeds the 64-bit limit of {}",
+                end,
+                u64::MAX
+            ),
+            MpError::ReversedRange { start, end } => write!(
+                f,
+                "range start {} is past its end {}",
+                start, end
+            ),
+            MpError::KernelBuild { device, log } => write!(
+                f,
+                "the OpenCL kernel failed to build on {}:\n{}",
+                device, log
+            ),
+        }
+    }
+}
+
+impl Error for MpError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn survives_the_boxed_error_results() {
+        let boxed: Box<dyn Error> = MpError::Timeout { iteration: 5, total: 125 }.into();
+        assert_eq!(boxed.to_string(), "timed out at iteration 5 of 125");
+        assert_eq!(boxed.downcast_ref::<MpError>(), Some(&MpError::Timeout { iteration: 5, total: 125 }));
+
+        let boxed: Box<dyn Error> = "not structured".into();
+        assert_eq!(boxed.downcast_ref::<MpError>(), None);
+    }
+}
@@ -0,0 +1,202 @@
+use crate::test_prime::is_prime_u64;
+use num_integer::Integer;
+
+/// Largest multiplier k tried when trial factoring 2^p - 1 by candidates q = 2kp + 1.
+pub const DEFAULT_K_LIMIT: u64 = 10_000;
+
+/// Computes 2^p mod q by square-and-multiply. `q` must fit in 64 bits so products fit in u128.
+pub fn pow2_mod(p: u128, q: u128) -> u128 {
+    let mut result = 1 % q;
+    let mut power = 2 % q;
+    let mut exponent = p;
+
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = (result * power) % q;
+        }
+        power = (power * power) % q;
+        exponent >>= 1;
+    }
+
+    result
+}
+
+/// Searches for a small factor of the Mersenne number 2^p - 1.
+///
+/// Every factor of 2^p - 1 for prime p has the form q = 2kp + 1 with q ≡ ±1 (mod 8),
+/// so only those candidates are tried, for k = 1..=k_limit.
+///
+/// # Arguments
+///
+/// * `p` - The prime exponent.
+/// * `k_limit` - The largest multiplier k to try.
+///
+/// # Returns
+///
+/// The smallest factor found, or `None` if no candidate divides 2^p - 1.
+pub fn find_mersenne_factor(p: u128, k_limit: u64) -> Option<u128> {
+    let max_factor = (2 * k_limit as u128).saturating_mul(p).saturating_add(1);
+    sweep_mersenne_factors(p, 0, max_factor).factor
+}
+
+/// How far a trial-factoring sweep of 2^p - 1 got.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FactorSweep {
+    /// The smallest factor in the range, if there is one.
+    pub factor: Option<u128>,
+    /// The largest candidate tried, or `None` if the range held none. A later sweep of the
+    /// same exponent can start just past it.
+    pub checked_up_to: Option<u128>,
+}
+
+/// Trial factors 2^p - 1 by the candidates q = 2kp + 1 with `min_factor <= q <= max_factor`,
+/// stopping at the first factor.
+///
+/// Candidates past 64 bits are not tried, and for p below 128 neither are those past
+/// sqrt(2^p - 1), which would leave a cofactor an earlier candidate would have found.
+pub fn sweep_mersenne_factors(p: u128, min_factor: u128, max_factor: u128) -> FactorSweep {
+    let mut sweep = FactorSweep { factor: None, checked_up_to: None };
+    let step = match 2u128.checked_mul(p) {
+        Some(step) if step > 0 => step,
+        _ => return sweep,
+    };
+    let first_k = (min_factor.saturating_sub(1)).div_ceil(step).max(1);
+    let last_k = max_factor.saturating_sub(1) / step;
+    for k in first_k..=last_k {
+        let q = match step.checked_mul(k) {
+            Some(q) if q < u64::MAX as u128 => q + 1,
+            _ => break,
+        };
+
+        // A factor larger than sqrt(2^p - 1) would leave a cofactor we'd have found already
+        if p < 128 && q.checked_mul(q).is_none_or(|square| square >> p != 0) {
+            break;
+        }
+
+        sweep.checked_up_to = Some(q);
+        if (q % 8 == 1 || q % 8 == 7) && pow2_mod(p, q) == 1 {
+            sweep.factor = Some(q);
+            break;
+        }
+    }
+
+    sweep
+}
+
+/// Largest trial divisor `factorize` tries before handing the cofactor to Pollard's rho.
+const TRIAL_DIVISION_LIMIT: u64 = 1000;
+
+/// Steps of the rho walk whose differences are multiplied together before taking a gcd.
+const RHO_BATCH: u64 = 128;
+
+/// Factors `n` completely, returning its prime factors in ascending order with repeats,
+/// e.g. `[2, 2, 2, 3, 3, 5]` for 360. 0 and 1 have no prime factors.
+///
+/// Factors below 1000 are found by trial division and the rest with Brent's variant of
+/// Pollard's rho, which takes about n^(1/4) steps for any 64-bit composite.
+pub fn factorize(n: u64) -> Vec<u64> {
+    let mut factors = Vec::new();
+    if n < 2 {
+        return factors;
+    }
+    let mut n = n;
+    let mut divisor = 2;
+    while divisor <= TRIAL_DIVISION_LIMIT && divisor * divisor <= n {
+        while n.is_multiple_of(divisor) {
+            factors.push(divisor);
+            n /= divisor;
+        }
+        divisor += if divisor == 2 { 1 } else { 2 };
+    }
+
+    let mut cofactors = if n > 1 { vec![n] } else { Vec::new() };
+    while let Some(m) = cofactors.pop() {
+        if is_prime_u64(m) {
+            factors.push(m);
+        } else {
+            let d = rho_factor(m);
+            cofactors.extend([d, m / d]);
+        }
+    }
+    factors.sort_unstable();
+    factors
+}
+
+/// A nontrivial factor of the odd composite `n`, by Brent's variant of Pollard's rho.
+fn rho_factor(n: u64) -> u64 {
+    let step = |x: u64, c: u64| ((x as u128 * x as u128 + c as u128) % n as u128) as u64;
+    let mul = |a: u64, b: u64| (a as u128 * b as u128 % n as u128) as u64;
+    for c in 1..n {
+        let (mut x, mut y, mut ys) = (2, 2, 2);
+        let (mut g, mut q, mut r) = (1, 1, 1);
+        while g == 1 {
+            x = y;
+            for _ in 0..r {
+                y = step(y, c);
+            }
+            let mut k = 0;
+            while k < r && g == 1 {
+                ys = y;
+                for _ in 0..RHO_BATCH.min(r - k) {
+                    y = step(y, c);
+                    q = mul(q, x.abs_diff(y));
+                }
+                g = q.gcd(&n);
+                k += RHO_BATCH;
+            }
+            r *= 2;
+        }
+        if g == n {
+            // The batch that found the cycle overshot it, so retrace it one step at a time
+            loop {
+                ys = step(ys, c);
+                g = x.abs_diff(ys).gcd(&n);
+                if g > 1 {
+                    break;
+                }
+            }
+        }
+        if g != n {
+            return g;
+        }
+    }
+    unreachable!("{} has no factor", n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sweeps_only_find_factors_inside_their_range() {
+        // 2^29 - 1 = 233 * 1103 * 2089
+        assert_eq!(sweep_mersenne_factors(29, 0, 1000), FactorSweep { factor: Some(233), checked_up_to: Some(233) });
+        let past_233 = sweep_mersenne_factors(29, 234, 1000);
+        assert_eq!(past_233, FactorSweep { factor: None, checked_up_to: Some(987) });
+        assert_eq!(sweep_mersenne_factors(29, 988, 5000).factor, Some(1103));
+        assert_eq!(sweep_mersenne_factors(29, 234, 1102).factor, None);
+        assert_eq!(sweep_mersenne_factors(29, 1104, 2089).factor, Some(2089));
+        // A range without a single 2kp + 1 in it checks nothing
+        assert_eq!(sweep_mersenne_factors(29, 60, 110), FactorSweep { factor: None, checked_up_to: None });
+        assert_eq!(find_mersenne_factor(29, 1), None);
+        assert_eq!(find_mersenne_factor(29, 4), Some(233));
+    }
+
+    #[test]
+    fn factorizations_multiply_back_to_primes() {
+        assert_eq!(factorize(0), Vec::<u64>::new());
+        assert_eq!(factorize(1), Vec::<u64>::new());
+        assert_eq!(factorize(360), [2, 2, 2, 3, 3, 5]);
+        for n in 2..5000u64 {
+            let factors = factorize(n);
+            assert_eq!(factors.iter().product::<u64>(), n);
+            assert!(factors.iter().all(|&p| is_prime_u64(p)), "{}: {:?}", n, factors);
+        }
+        assert_eq!(factorize(u64::MAX), [3, 5, 17, 257, 641, 65537, 6_700_417]);
+        assert_eq!(factorize(18_446_744_030_759_878_681), [4_294_967_291, 4_294_967_291]);
+        assert_eq!(factorize(1_000_000_007 * 998_244_353), [998_244_353, 1_000_000_007]);
+        assert_eq!(factorize(18_446_744_073_709_551_557), [18_446_744_073_709_551_557]);
+        // 2^59 - 1 = 179951 * 3203431780337
+        assert_eq!(factorize((1 << 59) - 1), [179_951, 3_203_431_780_337]);
+    }
+}
@@ -1,163 +1,3027 @@
-use ocl::{flags, Buffer, Context, Device, Kernel, Platform, Program, Queue};
-use indicatif::{ProgressBar, ProgressStyle};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use num_bigint::BigUint;
+use num_traits::{ToPrimitive, Zero};
+use ocl::enums::{DeviceInfo, DeviceInfoResult, KernelWorkGroupInfo, KernelWorkGroupInfoResult};
+use ocl::{flags, Buffer, Device, Kernel, Platform, Queue};
+use std::collections::{BTreeMap, VecDeque};
 use std::error::Error;
-use std::fs::File;
-use std::io::Write;
+use std::fmt::Display;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-/// Generates prime numbers in the range [start_n, end_n) using OpenCL for parallel processing.
+use rayon::prelude::*;
+
+use crate::error::MpError;
+use crate::profile::{self, Phase, TIMINGS};
+use crate::progress::{progress_bar, DEFAULT_TEMPLATE, THROUGHPUT_TEMPLATE};
+use log::{debug, info, warn};
+use crate::sieve::{base_primes, mark_segment, sieve_of_eratosthenes, Preallocate};
+use crate::test_prime::{cached_program, fnv1a, is_bpsw, is_prime_u64, is_sprp_u64, MOD_ARITH_SRC, PROGRAM_CACHE};
+
+/// Number of candidates the probable-prime kernel tests per chunk, bounding host and device
+/// memory, unless the device has too little memory for it.
+const CHUNK_SIZE: usize = 1 << 24;
+
+/// Share of a device's global memory its buffers are sized to fit within, leaving the rest to
+/// the driver and whatever else runs on it.
+pub const GPU_MEMORY_FRACTION: f64 = 0.7;
+
+/// Device bytes the probable-prime kernel takes per candidate of a chunk: the number and
+/// its verdict, a u64 each.
+const BYTES_PER_CANDIDATE: u64 = 16;
+
+/// Bases the GPU probable-prime kernel tests every candidate against by default.
+pub const DEFAULT_BASES: [u64; 4] = [2, 3, 5, 7];
+
+/// Number of kernel verdicts per chunk re-checked on the CPU.
+const VERIFY_SAMPLES: usize = 64;
+
+/// Number of candidates sieved per segment, sized so a segment stays in L2 cache.
+const SEGMENT_SIZE: usize = 1 << 18;
+
+/// Number of candidates used by the tuning pass to time each local work-group size.
+const TUNE_SAMPLE_SIZE: usize = 1 << 16;
+
+/// Local work-group sizes the tuning pass times, of those the device can launch.
+const TUNE_LOCAL_SIZES: [usize; 4] = [32, 64, 128, 256];
+
+/// Name of the file in `PROGRAM_CACHE` recording the local size tuned for each device.
+const TUNING_FILE: &str = "local-sizes";
+
+/// Local work-group sizes tuned so far in this process, by device name.
+static TUNED_LOCAL_SIZES: Mutex<BTreeMap<String, usize>> = Mutex::new(BTreeMap::new());
+
+/// Ranges at least this long are sieved on the GPU by `Method::Auto` when OpenCL is present,
+/// unless `GenerateOptions::gpu_threshold` says otherwise. Shorter ranges finish on the CPU
+/// before the OpenCL context would be ready.
+pub const GPU_RANGE_THRESHOLD: u128 = 1 << 28;
+
+/// Ranges at least this long stream in bounded memory but take long enough to warn about.
+pub const LARGE_SPAN: u128 = 1 << 36;
+
+/// Progress messages for the phases of a generation run: finding primes, and handing a
+/// chunk of them to the caller, which usually writes them out.
+const SIEVING: &str = "Sieving Segments";
+const TESTING: &str = "Testing Chunks";
+const WRITING: &str = "Writing Primes";
+
+/// How `generate_primes` decides which numbers in the range are prime.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Method {
+    /// `GpuSieve` for ranges of at least `GPU_RANGE_THRESHOLD` numbers when OpenCL
+    /// is present, `Sieve` otherwise.
+    Auto,
+    /// Segmented Sieve of Eratosthenes with segments spread across CPU threads. Exact.
+    Sieve,
+    /// Segmented Sieve of Eratosthenes with composites marked by an OpenCL kernel. Exact.
+    GpuSieve,
+    /// Strong probable-prime tests per number on the GPU. Fast, but pseudoprimes to
+    /// every base can slip through.
+    Fermat,
+}
+
+/// How much memory an OpenCL device has for buffers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GpuMemory {
+    /// CL_DEVICE_GLOBAL_MEM_SIZE, all the memory of the device.
+    pub global: u64,
+    /// CL_DEVICE_MAX_MEM_ALLOC_SIZE, the largest buffer it allocates.
+    pub max_alloc: u64,
+}
+
+impl GpuMemory {
+    /// Queries `device` for its memory.
+    pub fn of(device: Device) -> Result<GpuMemory, Box<dyn Error>> {
+        let global = match device.info(DeviceInfo::GlobalMemSize)? {
+            DeviceInfoResult::GlobalMemSize(size) => size,
+            other => return Err(format!("unexpected answer for the global memory size: {}", other).into()),
+        };
+        let max_alloc = match device.info(DeviceInfo::MaxMemAllocSize)? {
+            DeviceInfoResult::MaxMemAllocSize(size) => size,
+            other => return Err(format!("unexpected answer for the largest allocation: {}", other).into()),
+        };
+        Ok(GpuMemory { global, max_alloc })
+    }
+
+    /// The bytes buffers may take in all: `GPU_MEMORY_FRACTION` of the global memory, or
+    /// `cap` if that is less.
+    pub fn budget(&self, cap: Option<u64>) -> u64 {
+        let share = (self.global as f64 * GPU_MEMORY_FRACTION) as u64;
+        cap.map_or(share, |cap| cap.min(share))
+    }
+
+    /// The candidates in a chunk of the probable-prime kernel: up to `preferred`, but few
+    /// enough for its two buffers to fit the budget and each to be a buffer the device
+    /// allocates. At least one, however small the budget.
+    pub fn chunk_candidates(&self, cap: Option<u64>, preferred: usize) -> usize {
+        let fitting = (self.budget(cap) / BYTES_PER_CANDIDATE).min(self.max_alloc / (BYTES_PER_CANDIDATE / 2));
+        usize::try_from(fitting).unwrap_or(usize::MAX).clamp(1, preferred.max(1))
+    }
+
+    /// The base primes a `GpuMarker` puts in one buffer: all `count` of them when they fit
+    /// the budget beside its `GPU_SLOTS` segment buffers and in a buffer the device
+    /// allocates, otherwise as many as a buffer per slot can take, at least one.
+    pub fn base_primes_per_buffer(&self, cap: Option<u64>, count: usize) -> usize {
+        let room = self.budget(cap).saturating_sub((GPU_SLOTS * SEGMENT_SIZE) as u64);
+        let bytes = count as u64 * BYTES_PER_BASE_PRIME;
+        if bytes <= room && bytes <= self.max_alloc {
+            return count.max(1);
+        }
+        let fitting = (room / GPU_SLOTS as u64).min(self.max_alloc) / BYTES_PER_BASE_PRIME;
+        usize::try_from(fitting).unwrap_or(usize::MAX).clamp(1, count.max(1))
+    }
+}
+
+/// Settings for `generate_primes`.
+#[derive(Clone, Debug)]
+pub struct GenerateOptions {
+    /// How candidates are classified.
+    pub method: Method,
+    /// Bases the GPU probable-prime kernel tests against.
+    pub bases: Vec<u64>,
+    /// Whether to time the local work-group sizes again rather than reuse the one tuned
+    /// earlier for the device.
+    pub tune: bool,
+    /// Local work-group size of the probable-prime kernel, or `None` to tune it.
+    pub local_size: Option<usize>,
+    /// Whether to re-test probable-prime kernel survivors with deterministic Miller-Rabin on the CPU.
+    pub verify: bool,
+    /// Residue classes to generate primes from, or `None` for every prime in the range.
+    pub progression: Option<Progression>,
+    /// Indices into `opencl_devices` that the GPU sieve spreads its segments across, or
+    /// empty for the first device alone. An index may repeat to run several queues on it.
+    pub devices: Vec<usize>,
+    /// Range length from which `Method::Auto` sieves on the GPU.
+    pub gpu_threshold: u128,
+    /// Bytes the probable-prime kernel's buffers, or the GPU sieve's, may take on the
+    /// device, on top of the `GPU_MEMORY_FRACTION` of its memory they are held to anyway.
+    pub max_gpu_mem: Option<u64>,
+    /// How `generate_primes` sizes the vector it collects the primes in.
+    pub preallocate: Preallocate,
+}
+
+impl GenerateOptions {
+    /// The method `generate_primes` actually runs for [start_n, end_n), with `Auto` settled.
+    pub fn resolved_method(&self, start_n: u128, end_n: u128) -> Method {
+        match self.method {
+            Method::Auto if end_n.saturating_sub(start_n) >= self.gpu_threshold && opencl_available() => {
+                Method::GpuSieve
+            }
+            Method::Auto => Method::Sieve,
+            method => method,
+        }
+    }
+
+    /// How the probable-prime kernel's local work-group size is picked.
+    pub fn local_size_choice(&self) -> LocalSize {
+        self.local_size.map_or(LocalSize::Tuned { retune: self.tune }, LocalSize::Fixed)
+    }
+}
+
+/// How `fermat_primes` picks the local work-group size of its kernel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LocalSize {
+    /// The fastest on a sample range, which is timed once per device and recorded in the
+    /// tuning file, unless `retune` has it timed again.
+    Tuned { retune: bool },
+    /// This size, which the kernel must be able to launch with on the device.
+    Fixed(usize),
+}
+
+impl Default for GenerateOptions {
+    fn default() -> Self {
+        GenerateOptions {
+            method: Method::Auto,
+            bases: DEFAULT_BASES.to_vec(),
+            tune: false,
+            local_size: None,
+            verify: true,
+            progression: None,
+            devices: Vec::new(),
+            gpu_threshold: GPU_RANGE_THRESHOLD,
+            max_gpu_mem: None,
+            preallocate: Preallocate::Estimate,
+        }
+    }
+}
+
+/// The numbers n with n ≡ r (mod `modulus`) for one of the `residues`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Progression {
+    modulus: u64,
+    residues: Vec<u64>,
+}
+
+impl Progression {
+    /// Builds the progression, refusing a zero modulus or a residue that isn't below it.
+    pub fn new(modulus: u64, residues: &[u64]) -> Result<Progression, Box<dyn Error>> {
+        if modulus == 0 {
+            return Err("The modulus must be at least 1.".into());
+        }
+        if let Some(&residue) = residues.iter().find(|&&residue| residue >= modulus) {
+            return Err(format!("Residue {} is not below the modulus {}.", residue, modulus).into());
+        }
+        let mut residues = residues.to_vec();
+        residues.sort_unstable();
+        residues.dedup();
+        Ok(Progression { modulus, residues })
+    }
+
+    /// Whether n belongs to the progression.
+    pub fn contains(&self, n: u128) -> bool {
+        self.residues.binary_search(&((n % self.modulus as u128) as u64)).is_ok()
+    }
+
+    /// The members of the progression in [low, high), in ascending order.
+    pub fn members(&self, low: u128, high: u128) -> Vec<u128> {
+        let modulus = self.modulus as u128;
+        let mut members: Vec<u128> = self
+            .residues
+            .iter()
+            .flat_map(|&residue| {
+                let first = low + (residue as u128 + modulus - low % modulus) % modulus;
+                (first..high).step_by(self.modulus as usize)
+            })
+            .collect();
+        members.sort_unstable();
+        members
+    }
+}
+
+/// Receives each batch of primes, in ascending order, as generation produces it.
+pub type PrimeCallback<'a> = dyn FnMut(&[u128]) -> Result<(), Box<dyn Error>> + 'a;
+
+/// Sets the verdict of each candidate in a chunk, 1 for a probable prime, as the
+/// probable-prime kernel does.
+type ChunkTest<'a> = dyn FnMut(&[u64], &mut [u64]) -> Result<(), Box<dyn Error>> + 'a;
+
+/// Generates prime numbers in the range [start_n, end_n).
 ///
 /// # Arguments
 ///
 /// * `start_n` - The starting number of the range.
 /// * `end_n` - The ending number of the range.
+/// * `options` - The method and tuning settings to generate with.
 ///
 /// # Returns
 ///
-/// A vector containing all prime numbers within the specified range.
-pub fn generate_primes(start_n: u128, end_n: u128) -> Result<Vec<u128>, Box<dyn Error>> {
-    // Step 1: Initialize OpenCL
-    let platform = Platform::default();
-    let device = Device::first(platform)?;
-    let context = Context::builder()
-        .platform(platform)
-        .devices(device.clone())
-        .build()?;
-    let queue = Queue::new(&context, device, None)?;
+/// A vector containing all prime numbers within the specified range, or an error if
+/// `start_n` is past `end_n`.
+pub fn generate_primes(
+    start_n: u128,
+    end_n: u128,
+    options: &GenerateOptions,
+) -> Result<Vec<u128>, Box<dyn Error>> {
+    // A progression keeps too few of the primes for the estimate of the range to fit it
+    let mut primes = match (&options.progression, options.preallocate) {
+        (Some(_), Preallocate::Estimate) => Vec::new(),
+        (_, preallocate) => preallocate.vector(start_n, end_n),
+    };
+    generate_primes_with(start_n, end_n, options, &mut |chunk| {
+        primes.extend_from_slice(chunk);
+        Ok(())
+    })?;
+    Ok(primes)
+}
 
-    // Step 2: Load and build the OpenCL program
-    let kernel_src = r#"
-    __kernel void is_prime_kernel(__global const ulong* numbers, __global ulong* results, ulong base) {
-        int gid = get_global_id(0);
-        ulong n = numbers[gid];
-        if (n < 2) {
-            results[gid] = 0;
-            return;
+/// Generates prime numbers in the range [start_n, end_n), handing them to `on_chunk`
+/// one segment or chunk at a time instead of holding the whole list in memory.
+///
+/// # Arguments
+///
+/// * `start_n` - The starting number of the range.
+/// * `end_n` - The ending number of the range.
+/// * `options` - The method and tuning settings to generate with.
+/// * `on_chunk` - Called with each batch of primes, in ascending order.
+///
+/// # Returns
+///
+/// The number of primes passed to `on_chunk`, `MpError::ReversedRange` if `start_n` is
+/// past `end_n`, or `MpError::UnsupportedRange` if `end_n` is past `u64::MAX`, which no
+/// method can test.
+pub fn generate_primes_with(
+    start_n: u128,
+    end_n: u128,
+    options: &GenerateOptions,
+    on_chunk: &mut PrimeCallback,
+) -> Result<u64, Box<dyn Error>> {
+    if start_n > end_n {
+        return Err(MpError::ReversedRange { start: start_n, end: end_n }.into());
+    }
+    if end_n > u64::MAX as u128 {
+        return Err(MpError::UnsupportedRange { end: end_n }.into());
+    }
+    let method = options.resolved_method(start_n, end_n);
+
+    info!("Generating primes in [{}, {}) with {:?}", start_n, end_n, method);
+    let progression = options.progression.as_ref();
+    let mut count = 0u64;
+    let mut counted = |chunk: &[u128]| {
+        count += chunk.len() as u64;
+        on_chunk(chunk)
+    };
+
+    match method {
+        Method::Auto | Method::Sieve => {
+            profile::time(Phase::Execute, || segmented_sieve(start_n, end_n, None, progression, &mut counted))?
         }
-        if (n == 2) {
-            results[gid] = 1;
-            return;
+        Method::GpuSieve if options.devices.len() > 1 => {
+            let devices = opencl_devices()?;
+            let markers = options
+                .devices
+                .iter()
+                .map(|&index| {
+                    let device = *devices.get(index).ok_or_else(|| {
+                        format!("There is no OpenCL device {}; found {}.", index, devices.len())
+                    })?;
+                    Ok(Box::new(GpuMarker::on_device(device, end_n, options.max_gpu_mem)?) as Box<dyn SegmentMarker>)
+                })
+                .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+            segmented_sieve_across(start_n, end_n, markers, progression, &mut counted)?
         }
-        if (n % 2 == 0) {
-            results[gid] = 0;
-            return;
+        Method::GpuSieve => {
+            let marker = GpuMarker::for_options(options, end_n)?;
+            segmented_sieve(start_n, end_n, Some(&marker), progression, &mut counted)?
+        }
+        Method::Fermat if !options.verify => {
+            fermat_primes(start_n, end_n, &options.bases, options.local_size_choice(), progression, options.max_gpu_mem, &mut counted)?
+        }
+        Method::Fermat => {
+            let mut removed = 0;
+            fermat_primes(start_n, end_n, &options.bases, options.local_size_choice(), progression, options.max_gpu_mem, &mut |candidates| {
+                let primes = remove_pseudoprimes(candidates);
+                removed += candidates.len() - primes.len();
+                counted(&primes)
+            })?;
+            info!("Removed {} pseudoprimes", removed);
+        }
+    }
+
+    Ok(count)
+}
+
+/// Re-tests the survivors of the probable-prime kernel with deterministic Miller-Rabin on the CPU.
+///
+/// # Arguments
+///
+/// * `candidates` - The numbers the kernel reported as probable primes.
+///
+/// # Returns
+///
+/// The candidates that are actually prime, in their original order.
+pub fn remove_pseudoprimes(candidates: &[u128]) -> Vec<u128> {
+    candidates
+        .par_iter()
+        .copied()
+        .filter(|&n| is_prime_u64(n as u64))
+        .collect()
+}
+
+/// The number of primes in [start_n, end_n), as many as `generate_primes_with` would hand
+/// on. The GPU sieve on one device adds up each segment's primes on the device, so only a
+/// count per segment is read back; the other methods count the primes they generate.
+pub fn count_primes(start_n: u128, end_n: u128, options: &GenerateOptions) -> Result<u64, Box<dyn Error>> {
+    let on_one_device = options.devices.len() <= 1 && options.progression.is_none();
+    match options.resolved_method(start_n, end_n) {
+        Method::GpuSieve if on_one_device && start_n <= end_n && end_n <= u64::MAX as u128 => {
+            info!("Counting primes in [{}, {}) on the GPU", start_n, end_n);
+            let marker = GpuMarker::for_options(options, end_n)?;
+            segmented_count(start_n, end_n, &marker)
+        }
+        _ => generate_primes_with(start_n, end_n, options, &mut |_| Ok(())),
+    }
+}
+
+/// `segmented_sieve` on `gpu` that counts the primes of each segment on the device instead
+/// of reading its marks back, double-buffered the same way.
+fn segmented_count(start_n: u128, end_n: u128, gpu: &GpuMarker) -> Result<u64, Box<dyn Error>> {
+    let low = start_n.max(2);
+    if low >= end_n {
+        return Ok(0);
+    }
+    let segments = (end_n - low).div_ceil(SEGMENT_SIZE as u128);
+    let pb = progress_bar((end_n - low) as u64, THROUGHPUT_TEMPLATE, SIEVING);
+    let segment_start = |i: u128| low + i * SEGMENT_SIZE as u128;
+    let segment_len = |i: u128| ((segment_start(i) + SEGMENT_SIZE as u128).min(end_n) - segment_start(i)) as usize;
+    let enqueue = |i: u128| -> Result<(), Box<dyn Error>> {
+        gpu.enqueue(i as usize, segment_start(i), segment_len(i))?;
+        gpu.enqueue_count(i as usize, segment_len(i))
+    };
+
+    let mut count = 0;
+    enqueue(0)?;
+    for i in 0..segments {
+        if i + 1 < segments {
+            enqueue(i + 1)?;
+        }
+        count += gpu.collect_count(i as usize)?;
+        pb.inc(segment_len(i) as u64);
+    }
+    pb.finish_with_message("Sieving Completed");
+
+    Ok(count)
+}
+
+/// Generates prime numbers in the range [start_n, end_n) with a segmented Sieve of Eratosthenes.
+///
+/// The primes up to sqrt(end_n) are sieved once on the CPU, then each segment of
+/// `SEGMENT_SIZE` candidates has their multiples crossed off, either on the CPU or
+/// by `marker` on the GPU.
+///
+/// # Arguments
+///
+/// * `start_n` - The starting number of the range.
+/// * `end_n` - The ending number of the range.
+/// * `marker` - The OpenCL marking kernel to use, or `None` to mark on the CPU.
+/// * `progression` - The residue classes to collect primes from, or `None` for all of them.
+/// * `on_chunk` - Called with the primes of each segment, in ascending order.
+pub fn segmented_sieve(
+    start_n: u128,
+    end_n: u128,
+    marker: Option<&GpuMarker>,
+    progression: Option<&Progression>,
+    on_chunk: &mut PrimeCallback,
+) -> Result<(), Box<dyn Error>> {
+    if end_n > u64::MAX as u128 {
+        return Err(MpError::UnsupportedRange { end: end_n }.into());
+    }
+    let low = start_n.max(2);
+    if low >= end_n {
+        return Ok(());
+    }
+
+    // Step 1: Sieve the base primes up to sqrt(end_n) on the CPU
+    let primes_below_root = base_primes(end_n);
+
+    // Step 2: Mark and collect each segment in turn
+    let segments = (end_n - low).div_ceil(SEGMENT_SIZE as u128);
+    // Counted in candidates, so the rate and ETA reflect the sieving itself
+    let pb = progress_bar((end_n - low) as u64, THROUGHPUT_TEMPLATE, SIEVING);
+
+    let segment_end = |segment_start: u128| (segment_start + SEGMENT_SIZE as u128).min(end_n);
+
+    match marker {
+        // CPU segments are independent, so rayon workers sieve a batch of them in
+        // parallel and the indexed collect keeps the batch in order
+        None => {
+            let batch = (rayon::current_num_threads() * 4) as u128;
+            let mut first = 0;
+            while first < segments {
+                let last = (first + batch).min(segments);
+                let batch_primes = (first..last)
+                    .into_par_iter()
+                    .map_init(
+                        || vec![false; SEGMENT_SIZE],
+                        |composite, i| {
+                            let segment_start = low + i * SEGMENT_SIZE as u128;
+                            let len = (segment_end(segment_start) - segment_start) as usize;
+                            let segment = &mut composite[..len];
+                            mark_segment(segment, segment_start, &primes_below_root);
+                            pb.inc(len as u64);
+                            collect_unmarked(segment, segment_start, progression)
+                        },
+                    )
+                    .collect::<Vec<Vec<u128>>>()
+                    .concat();
+                pb.set_message(WRITING);
+                on_chunk(&batch_primes)?;
+                pb.set_message(SIEVING);
+                first = last;
+            }
         }
+        // Segments alternate between the marker's buffers: segment i + 1 is queued on one
+        // before segment i is read back from the other, so transfers overlap the kernels
+        Some(gpu) => {
+            let segment_start = |i: u128| low + i * SEGMENT_SIZE as u128;
+            let segment_len = |i: u128| (segment_end(segment_start(i)) - segment_start(i)) as usize;
+            let mut composite = vec![false; SEGMENT_SIZE];
+            gpu.enqueue(0, segment_start(0), segment_len(0))?;
+            for i in 0..segments {
+                if i + 1 < segments {
+                    gpu.enqueue((i + 1) as usize, segment_start(i + 1), segment_len(i + 1))?;
+                }
+                let segment = &mut composite[..segment_len(i)];
+                gpu.collect(i as usize, segment)?;
+                pb.inc(segment.len() as u64);
+                pb.set_message(WRITING);
+                on_chunk(&collect_unmarked(segment, segment_start(i), progression))?;
+                pb.set_message(SIEVING);
+            }
+        }
+    }
+
+    pb.finish_with_message("Sieving Completed");
 
-        // Fermat Primality Test: a^(n-1) mod n = 1
-        ulong a = base;
-        ulong result = 1;
-        ulong exponent = n - 1;
-        ulong power = a % n;
+    Ok(())
+}
+
+/// Finds the `n`th prime greater than `after` with the segmented sieve.
+///
+/// The sieve window is sized from the prime number theorem so that it usually holds enough
+/// primes in one pass; if it falls short, the next window starts where the last one ended.
+///
+/// # Arguments
+///
+/// * `n` - Which prime to find, counting from 1.
+/// * `after` - The number the count starts above.
+///
+/// # Returns
+///
+/// The `n`th prime greater than `after`.
+pub fn nth_prime(n: u64, after: u128) -> Result<u128, Box<dyn Error>> {
+    if n == 0 {
+        return Err("Primes are counted from 1.".into());
+    }
+    if after >= u64::MAX as u128 {
+        return Err("The requested prime is beyond the 64-bit sieve limit.".into());
+    }
 
-        while (exponent > 0) {
-            if (exponent & 1) {
-                // result = (result * power) % n
-                result = (result * power) % n;
+    let mut low = after + 1;
+    let mut remaining = n;
+    loop {
+        // Primes near x are about ln(x) apart, so `remaining` of them span roughly remaining * ln(x)
+        let guess = low as f64 + remaining as f64 * (remaining as f64 + 2.0).ln();
+        let width = (remaining as f64 * guess.ln() * 1.1) as u128 + SEGMENT_SIZE as u128;
+        let high = low
+            .checked_add(width)
+            .filter(|&high| high <= u64::MAX as u128)
+            .ok_or("The requested prime is beyond the 64-bit sieve limit.")?;
+
+        let mut found = None;
+        segmented_sieve(low, high, None, None, &mut |chunk| {
+            if found.is_none() {
+                if remaining <= chunk.len() as u64 {
+                    found = Some(chunk[remaining as usize - 1]);
+                } else {
+                    remaining -= chunk.len() as u64;
+                }
             }
-            // power = (power * power) % n
-            power = (power * power) % n;
-            exponent >>= 1;
+            Ok(())
+        })?;
+
+        if let Some(prime) = found {
+            return Ok(prime);
         }
+        low = high;
+    }
+}
 
-        // If result == 1, then n is a probable prime
-        results[gid] = (result == 1) ? 1 : 0;
+/// Keeps the generated primes whose companion is prime as well.
+///
+/// The companion can lie outside the generated range, so it is tested directly with
+/// `is_bpsw`, which is exact below 2^64.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PrimeFilter {
+    /// Primes p where 2p + 1 is prime.
+    SophieGermain,
+    /// Primes p where (p - 1) / 2 is prime.
+    Safe,
+}
+
+impl PrimeFilter {
+    /// Whether the prime `p` passes the filter.
+    pub fn keeps(self, p: u128) -> bool {
+        match self {
+            PrimeFilter::SophieGermain => is_bpsw(&(BigUint::from(p) * 2u32 + 1u32)),
+            PrimeFilter::Safe => p % 2 == 1 && is_bpsw(&BigUint::from(p / 2)),
+        }
     }
-    "#;
 
-    let program = Program::builder()
-        .src(kernel_src)
-        .devices(device)
-        .build(&context)?;
+    /// Returns the primes of `chunk` that pass the filter, in their original order.
+    pub fn apply(self, chunk: &[u128]) -> Vec<u128> {
+        chunk.par_iter().copied().filter(|&p| self.keeps(p)).collect()
+    }
+}
 
-    let kernel = Kernel::builder()
-        .program(&program)
-        .name("is_prime_kernel")
-        .queue(queue.clone())
-        .arg(None::<&Buffer<u64>>) // Placeholder for numbers
-        .arg(None::<&Buffer<u64>>) // Placeholder for results
-        .arg(2u64) // Base for Fermat Test
-        .build()?;
+/// Pairs up twin primes (p, p + 2) from the ascending batches generation streams out.
+///
+/// The last prime of each batch is kept, so a pair split across two batches is still found.
+#[derive(Clone, Debug, Default)]
+pub struct TwinPairer {
+    last: Option<u128>,
+}
 
-    // Step 3: Prepare data
-    let range: Vec<u128> = (start_n..end_n).collect();
-    let range_len = range.len();
+impl TwinPairer {
+    /// Returns the twin pairs completed by `chunk`, in ascending order.
+    pub fn pairs(&mut self, chunk: &[u128]) -> Vec<(u128, u128)> {
+        let mut pairs = Vec::new();
+        for &prime in chunk {
+            if self.last.is_some_and(|last| last + 2 == prime) {
+                pairs.push((prime - 2, prime));
+            }
+            self.last = Some(prime);
+        }
+        pairs
+    }
+}
 
-    // Convert to u64, ensuring values fit
-    let numbers: Vec<u64> = range.iter().map(|&n| n as u64).collect();
+/// Finds a prime constellation, such as the twin primes (0, 2) or the prime triplets
+/// (0, 2, 6), in the ascending batches generation streams out: the primes p for which
+/// p + offset is prime for every offset.
+///
+/// A prime is settled once the primes reach its widest offset past it, so generation has to
+/// run `span` past the end of the range for the tuples that start near the end to be found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Constellation {
+    offsets: Vec<u128>,
+    /// The primes from the earliest one not yet settled on.
+    window: VecDeque<u128>,
+}
 
-    // Initialize results buffer
-    let mut results = vec![0u64; range_len];
+impl Constellation {
+    /// The constellation with `offsets`, which must start at 0 and ascend, with at least two.
+    pub fn new(offsets: Vec<u128>) -> Result<Constellation, Box<dyn Error>> {
+        if offsets.len() < 2 || offsets[0] != 0 || offsets.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(format!("constellation offsets must start at 0 and ascend, with at least two, not {:?}", offsets).into());
+        }
+        Ok(Constellation { offsets, window: VecDeque::new() })
+    }
 
-    // Step 4: Create OpenCL buffers
-    let buffer_numbers = Buffer::<u64>::builder()
-        .queue(queue.clone())
-        .flags(flags::MEM_READ_ONLY | flags::MEM_COPY_HOST_PTR)
-        .len(range_len)
-        .copy_host_slice(&numbers)
-        .build()?;
+    /// The twin primes, (p, p + 2).
+    pub fn twins() -> Constellation {
+        Constellation { offsets: vec![0, 2], window: VecDeque::new() }
+    }
 
-    let buffer_results = Buffer::<u64>::builder()
-        .queue(queue.clone())
-        .flags(flags::MEM_WRITE_ONLY)
-        .len(range_len)
-        .build()?;
+    pub fn offsets(&self) -> &[u128] {
+        &self.offsets
+    }
 
-    // Step 5: Set kernel arguments
-    kernel.set_arg(0, &buffer_numbers)?;
-    kernel.set_arg(1, &buffer_results)?;
+    /// The widest offset, which generation has to run past the end of the range.
+    pub fn span(&self) -> u128 {
+        self.offsets[self.offsets.len() - 1]
+    }
 
-    // Step 6: Execute the kernel with specified Global Work Size
-    unsafe {
-        kernel.cmd()
-            .global_work_size([range_len as usize]) // Specify global work size
-            .enq()?;
+    /// Returns the first primes below `end` of the tuples that `chunk` settles, in
+    /// ascending order.
+    pub fn matches(&mut self, chunk: &[u128], end: u128) -> Vec<u128> {
+        let mut found = Vec::new();
+        for &prime in chunk {
+            self.window.push_back(prime);
+            while self.window.front().is_some_and(|&first| first + self.span() <= prime) {
+                found.extend(self.settle_first(end));
+            }
+        }
+        found
     }
 
-    // Step 7: Read the results
-    buffer_results.read(&mut results).enq()?;
+    /// Settles the primes left once generation has reached `span` past `end`, returning
+    /// the first primes below `end` of their tuples.
+    pub fn finish(&mut self, end: u128) -> Vec<u128> {
+        let mut found = Vec::new();
+        while !self.window.is_empty() {
+            found.extend(self.settle_first(end));
+        }
+        found
+    }
 
-    // Step 8: Collect prime numbers based on results with Progress Bar
-    let pb = ProgressBar::new(range_len as u64);
-    pb.set_style(ProgressStyle::default_bar()
-        .template("{msg} [{bar:40.cyan/blue}] {pos}/{len} ({percent}%, {eta_precise})")?
-        .progress_chars("=>-"));
-    pb.set_message("Collecting Primes");
+    /// Drops the earliest prime of the window, returning it if a tuple starts there below `end`.
+    fn settle_first(&mut self, end: u128) -> Option<u128> {
+        let first = self.window.pop_front()?;
+        let complete = first < end && self.offsets[1..].iter().all(|&offset| self.window.binary_search(&(first + offset)).is_ok());
+        complete.then_some(first)
+    }
+}
 
-    let mut primes = Vec::new();
+/// Gap statistics gathered over the ascending batches generation streams out, keeping
+/// only per-size counts so memory stays bounded however long the range.
+#[derive(Clone, Debug, Default)]
+pub struct GapStats {
+    last: Option<u128>,
+    /// Each gap larger than every one before it, as (gap, starting prime).
+    pub maximal: Vec<(u128, u128)>,
+    /// How many times each gap size occurred.
+    pub histogram: BTreeMap<u128, u64>,
+    /// How many gaps were recorded.
+    pub count: u64,
+    total: u128,
+}
 
-    for (idx, &is_prime) in results.iter().enumerate() {
-        if is_prime == 1 {
-            primes.push(range[idx]);
+impl GapStats {
+    /// Records the gaps between the primes of `chunk`, including the one from the last prime
+    /// of the previous batch.
+    ///
+    /// # Returns
+    ///
+    /// The gaps of at least `min_gap`, as (gap, starting prime), in ascending order.
+    pub fn record(&mut self, chunk: &[u128], min_gap: u128) -> Vec<(u128, u128)> {
+        let mut large = Vec::new();
+        for &prime in chunk {
+            if let Some(last) = self.last {
+                let gap = prime - last;
+                if self.maximal.last().is_none_or(|&(largest, _)| gap > largest) {
+                    self.maximal.push((gap, last));
+                }
+                *self.histogram.entry(gap).or_insert(0) += 1;
+                self.count += 1;
+                self.total += gap;
+                if gap >= min_gap {
+                    large.push((gap, last));
+                }
+            }
+            self.last = Some(prime);
         }
-        pb.inc(1);
+        large
     }
 
-    pb.finish_with_message("Prime Collection Completed");
+    /// The mean gap, or `None` before two primes have been seen.
+    pub fn average(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.total as f64 / self.count as f64)
+    }
+}
 
-    Ok(primes)
+/// The `--stats` summary of a generated range, gathered in one pass over the ascending
+/// batches generation streams out.
+#[derive(Clone, Debug, Default)]
+pub struct RangeStats {
+    /// How many primes were recorded.
+    pub count: u64,
+    /// How many twin pairs (p, p + 2) they hold.
+    pub twins: u64,
+    pub gaps: GapStats,
+    pairer: TwinPairer,
 }
 
-/// Writes the provided prime numbers to a file.
+impl RangeStats {
+    /// Records the primes of `chunk`, which continue the batches recorded before.
+    pub fn record(&mut self, chunk: &[u128]) {
+        self.count += chunk.len() as u64;
+        self.twins += self.pairer.pairs(chunk).len() as u64;
+        self.gaps.record(chunk, u128::MAX);
+    }
+
+    /// The largest gap, as (gap, starting prime), the first of them if several tie.
+    pub fn largest_gap(&self) -> Option<(u128, u128)> {
+        self.gaps.maximal.last().copied()
+    }
+}
+
+/// Numbers sieved per window by `next_prime` and `prev_prime` before the survivors are tested.
+const SEARCH_WINDOW: usize = 1 << 12;
+
+/// The `next_prime` and `prev_prime` windows are sieved by the primes below this bound.
+const SEARCH_SIEVE_LIMIT: u128 = 1 << 16;
+
+/// Outcome of `next_prime` and `prev_prime`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PrimeSearch {
+    /// The prime found.
+    pub prime: BigUint,
+    /// How many numbers the window sieve looked at.
+    pub examined: u64,
+    /// How many sieve survivors were BPSW-tested.
+    pub tested: u64,
+}
+
+/// Marks the numbers of the window [low, low + len) with a factor below `SEARCH_SIEVE_LIMIT`,
+/// leaving the small primes themselves unmarked.
+fn sieve_window(low: &BigUint, len: usize, small_primes: &[u128]) -> Vec<bool> {
+    let mut composite = vec![false; len];
+    let low_small = low.to_u128();
+    for &p in small_primes {
+        let r = (low % p).to_u128().unwrap_or(0);
+        let mut offset = ((p - r) % p) as usize;
+        while offset < len {
+            if low_small.is_none_or(|l| l + offset as u128 != p) {
+                composite[offset] = true;
+            }
+            offset += p as usize;
+        }
+    }
+    composite
+}
+
+/// Finds the smallest probable prime greater than `n`.
 ///
-/// # Arguments
+/// Windows of `SEARCH_WINDOW` numbers are sieved by the primes below `SEARCH_SIEVE_LIMIT`
+/// and only the survivors get the BPSW test, which is exact below 2^64.
+pub fn next_prime(n: &BigUint) -> PrimeSearch {
+    let small_primes = sieve_of_eratosthenes(0, SEARCH_SIEVE_LIMIT).unwrap_or_default();
+    let mut low = (n + 1u32).max(BigUint::from(2u32));
+    let mut search = PrimeSearch { prime: BigUint::zero(), examined: 0, tested: 0 };
+    loop {
+        let composite = sieve_window(&low, SEARCH_WINDOW, &small_primes);
+        for (offset, _) in composite.iter().enumerate().filter(|(_, &is_composite)| !is_composite) {
+            let candidate = &low + offset;
+            search.tested += 1;
+            if is_bpsw(&candidate) {
+                search.examined += offset as u64 + 1;
+                search.prime = candidate;
+                return search;
+            }
+        }
+        search.examined += SEARCH_WINDOW as u64;
+        low += SEARCH_WINDOW;
+    }
+}
+
+/// Finds the largest probable prime less than `n`, sieving windows downwards the way
+/// `next_prime` sieves them upwards.
 ///
-/// * `primes` - An iterator over prime numbers.
-/// * `filename` - The name of the file to write the primes to.
-pub fn write_primes_to_file(primes: &[u128], filename: &str) -> Result<(), Box<dyn Error>> {
-    let file = File::create(filename)?;
-    let mut writer = std::io::BufWriter::new(file);
+/// # Returns
+///
+/// The prime found, or an error when `n` is 2 or less and there is none.
+pub fn prev_prime(n: &BigUint) -> Result<PrimeSearch, Box<dyn Error>> {
+    let two = BigUint::from(2u32);
+    if *n <= two {
+        return Err(format!("There is no prime below {}.", n).into());
+    }
+    let small_primes = sieve_of_eratosthenes(0, SEARCH_SIEVE_LIMIT).unwrap_or_default();
+    let mut high = n.clone();
+    let mut search = PrimeSearch { prime: BigUint::zero(), examined: 0, tested: 0 };
+    loop {
+        let low = if high > &two + SEARCH_WINDOW { &high - SEARCH_WINDOW } else { two.clone() };
+        let len = (&high - &low).to_usize().unwrap_or(SEARCH_WINDOW);
+        let composite = sieve_window(&low, len, &small_primes);
+        for (offset, _) in composite.iter().enumerate().rev().filter(|(_, &is_composite)| !is_composite) {
+            let candidate = &low + offset;
+            search.tested += 1;
+            if is_bpsw(&candidate) {
+                search.examined += (len - offset) as u64;
+                search.prime = candidate;
+                return Ok(search);
+            }
+        }
+        // 2 is always in the last window, so the search ends there at the latest
+        search.examined += len as u64;
+        high = low;
+    }
+}
+
+/// Collects the numbers of a segment starting at `low` that were left unmarked, looking
+/// only at the members of `progression` when there is one.
+fn collect_unmarked(segment: &[bool], low: u128, progression: Option<&Progression>) -> Vec<u128> {
+    match progression {
+        Some(progression) => progression
+            .members(low, low + segment.len() as u128)
+            .into_iter()
+            .filter(|&n| !segment[(n - low) as usize])
+            .collect(),
+        None => segment
+            .iter()
+            .enumerate()
+            .filter(|(_, &is_composite)| !is_composite)
+            .map(|(offset, _)| low + offset as u128)
+            .collect(),
+    }
+}
+
+/// Checks whether an OpenCL platform with at least one device is present.
+pub fn opencl_available() -> bool {
+    Platform::first()
+        .and_then(Device::first)
+        .is_ok()
+}
+
+/// Every OpenCL device of the first platform, in the order `--devices` numbers them.
+pub fn opencl_devices() -> Result<Vec<Device>, Box<dyn Error>> {
+    Ok(Device::list_all(Platform::first()?)?)
+}
+
+/// Name of the OpenCL device the kernels would run on, if there is one.
+pub fn device_name() -> Option<String> {
+    Platform::first()
+        .and_then(Device::first)
+        .and_then(|device| device.name())
+        .ok()
+}
 
-    let pb = ProgressBar::new(primes.len() as u64);
-    pb.set_style(ProgressStyle::default_bar()
-        .template("{msg} [{bar:40.cyan/blue}] {pos}/{len} ({percent}%, {eta_precise})")?
-        .progress_chars("=>-"));
-    pb.set_message("Writing Primes to File");
+/// Number of segment buffers `GpuMarker` cycles through, each with its own queue.
+const GPU_SLOTS: usize = 2;
 
-    for &prime in primes {
-        writeln!(writer, "{}", prime)?;
-        pb.inc(1);
+/// Work-group size of the `GpuMarker` kernel counting a segment's primes, a power of two
+/// that every device runs and that divides `SEGMENT_SIZE`.
+const COUNT_GROUP_SIZE: usize = 64;
+
+/// Device bytes a `GpuMarker` takes per base prime, a u64.
+const BYTES_PER_BASE_PRIME: u64 = 8;
+
+/// One segment buffer of a `GpuMarker`, with the queue and kernel that work on it and the
+/// base primes the kernel reads.
+struct GpuSlot {
+    queue: Queue,
+    kernel: Kernel,
+    segment: Buffer<u8>,
+    primes: Buffer<u64>,
+    /// The kernel adding up the numbers left unmarked in the segment, and its sum.
+    counter: Kernel,
+    count: Buffer<u32>,
+}
+
+/// OpenCL state for marking composites of a sieve segment on the GPU.
+///
+/// The base primes are uploaded once and shared by `GPU_SLOTS` segment buffers, each on its
+/// own queue, so one segment's marks can be read back while the next one's kernel runs.
+/// When they don't fit the device's memory budget at once, each slot gets a buffer of its
+/// own that every segment refills a batch at a time.
+pub struct GpuMarker {
+    slots: Vec<GpuSlot>,
+    /// The base primes, kept on the host when they go up in batches.
+    batched: Option<Vec<u64>>,
+}
+
+impl GpuMarker {
+    /// Builds the marking kernel on the first OpenCL device and uploads the base primes
+    /// needed to sieve up to `end_n`, within `max_gpu_mem` bytes of buffers if given.
+    pub fn new(end_n: u128, max_gpu_mem: Option<u64>) -> Result<GpuMarker, Box<dyn Error>> {
+        GpuMarker::on_device(Device::first(Platform::first()?)?, end_n, max_gpu_mem)
     }
 
-    pb.finish_with_message("Prime Writing Completed");
+    /// A marker on the first of `options.devices`, or on the first device if none is given.
+    fn for_options(options: &GenerateOptions, end_n: u128) -> Result<GpuMarker, Box<dyn Error>> {
+        let Some(&index) = options.devices.first() else {
+            return GpuMarker::new(end_n, options.max_gpu_mem);
+        };
+        let devices = opencl_devices()?;
+        let device = *devices
+            .get(index)
+            .ok_or_else(|| format!("There is no OpenCL device {}; found {}.", index, devices.len()))?;
+        GpuMarker::on_device(device, end_n, options.max_gpu_mem)
+    }
 
-    Ok(())
-}
\ No newline at end of file
+    /// Like `new`, on `device`. Each marker has its own queues and buffers, so several can
+    /// share a device, and the kernel is compiled once per device however many are built.
+    pub fn on_device(device: Device, end_n: u128, max_gpu_mem: Option<u64>) -> Result<GpuMarker, Box<dyn Error>> {
+        // One work item per base prime, crossing off its multiples within the segment
+        let kernel_src = r#"
+        __kernel void mark_composites(__global uchar* segment, __global const ulong* primes, ulong low, ulong len) {
+            ulong p = primes[get_global_id(0)];
+            if (p == 0 || p * p >= low + len) {
+                return;
+            }
+            ulong first = ((low + p - 1) / p) * p;
+            if (first < p * p) {
+                first = p * p;
+            }
+            for (ulong n = first; n < low + len; n += p) {
+                segment[n - low] = 1;
+            }
+        }
+
+        // Adds the numbers left unmarked in the segment to count, a work-group sum at a time
+        __kernel void count_unmarked(__global const uchar* segment, __global uint* count, ulong len, __local uint* partial) {
+            size_t lid = get_local_id(0);
+            size_t gid = get_global_id(0);
+            partial[lid] = gid < len && segment[gid] == 0;
+            barrier(CLK_LOCAL_MEM_FENCE);
+            for (size_t stride = get_local_size(0) / 2; stride > 0; stride /= 2) {
+                if (lid < stride) {
+                    partial[lid] += partial[lid + stride];
+                }
+                barrier(CLK_LOCAL_MEM_FENCE);
+            }
+            if (lid == 0) {
+                atomic_add(count, partial[0]);
+            }
+        }
+        "#;
+
+        let (context, program) = cached_program(device, kernel_src)?;
+
+        let queues = profile::time(Phase::Init, || {
+            (0..GPU_SLOTS).map(|_| Queue::new(&context, device, None)).collect::<Result<Vec<_>, _>>()
+        })?;
+        let queue = &queues[0];
+        let setting_up = Instant::now();
+
+        let primes: Vec<u64> = base_primes(end_n).into_iter().map(|p| p as u64).collect();
+        // Buffers can't be empty, so a range with no base primes uploads a zero the kernel skips
+        let primes = if primes.is_empty() { vec![0] } else { primes };
+        let memory = GpuMemory::of(device)?;
+        let per_buffer = memory.base_primes_per_buffer(max_gpu_mem, primes.len());
+        let shared = if per_buffer < primes.len() {
+            None
+        } else {
+            Some(
+                Buffer::<u64>::builder()
+                    .queue(queue.clone())
+                    .flags(flags::MEM_READ_ONLY | flags::MEM_COPY_HOST_PTR)
+                    .len(primes.len())
+                    .copy_host_slice(&primes)
+                    .build()?,
+            )
+        };
+
+        // The base primes must be in place before another queue's kernel reads them
+        queue.finish()?;
+        debug!(
+            "GPU sieve on {}: {} base primes in buffers of {}, {} segment buffers of {} bytes",
+            device.name()?,
+            primes.len(),
+            per_buffer,
+            GPU_SLOTS,
+            SEGMENT_SIZE
+        );
+        if shared.is_none() {
+            info!(
+                "The {} base primes don't fit a budget of {} bytes on {}, uploading them {} at a time",
+                primes.len(),
+                memory.budget(max_gpu_mem),
+                device.name()?,
+                per_buffer
+            );
+        }
+
+        let mut slots = Vec::with_capacity(GPU_SLOTS);
+        for queue in queues {
+            let segment = Buffer::<u8>::builder()
+                .queue(queue.clone())
+                .flags(flags::MEM_READ_WRITE)
+                .len(SEGMENT_SIZE)
+                .build()?;
+            let primes = match &shared {
+                Some(primes) => primes.clone(),
+                None => Buffer::<u64>::builder()
+                    .queue(queue.clone())
+                    .flags(flags::MEM_READ_ONLY)
+                    .len(per_buffer)
+                    .build()?,
+            };
+
+            let kernel = Kernel::builder()
+                .program(&program)
+                .name("mark_composites")
+                .queue(queue.clone())
+                .global_work_size(per_buffer)
+                .arg(&segment)
+                .arg(&primes)
+                .arg(0u64) // Placeholder for the segment start
+                .arg(0u64) // Placeholder for the segment length
+                .build()?;
+
+            let count = Buffer::<u32>::builder().queue(queue.clone()).flags(flags::MEM_READ_WRITE).len(1).build()?;
+            let counter = Kernel::builder()
+                .program(&program)
+                .name("count_unmarked")
+                .queue(queue.clone())
+                .global_work_size(SEGMENT_SIZE)
+                .local_work_size(COUNT_GROUP_SIZE)
+                .arg(&segment)
+                .arg(&count)
+                .arg(0u64) // Placeholder for the segment length
+                .arg_local::<u32>(COUNT_GROUP_SIZE)
+                .build()?;
+
+            slots.push(GpuSlot { queue, kernel, segment, primes, counter, count });
+        }
+        TIMINGS.record(Phase::Buffers, setting_up.elapsed());
+
+        Ok(GpuMarker { slots, batched: shared.is_none().then_some(primes) })
+    }
+
+    /// Queues the marking of the `len` numbers starting at `low` in buffer `slot`, without
+    /// waiting for it; `collect` on the same slot waits and reads the marks back.
+    pub fn enqueue(&self, slot: usize, low: u128, len: usize) -> Result<(), Box<dyn Error>> {
+        let slot = &self.slots[slot % GPU_SLOTS];
+        profile::time(Phase::Execute, || {
+            slot.segment.cmd().fill(0u8, Some(len)).enq()?;
+            slot.kernel.set_arg(2, low as u64)?;
+            slot.kernel.set_arg(3, len as u64)?;
+            let Some(primes) = &self.batched else {
+                return unsafe { slot.kernel.enq() };
+            };
+            // The write blocks until the queue's last kernel is done with the batch before
+            for batch in primes.chunks(slot.primes.len()) {
+                slot.primes.write(batch).enq()?;
+                unsafe { slot.kernel.cmd().global_work_size(batch.len()).enq()? };
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    /// Reads the marks queued on buffer `slot` into `segment`, waiting for them if needed.
+    pub fn collect(&self, slot: usize, segment: &mut [bool]) -> Result<(), Box<dyn Error>> {
+        let slot = &self.slots[slot % GPU_SLOTS];
+        let mut marks = vec![0u8; segment.len()];
+        // Waiting for the kernel first keeps its time out of the readback's
+        profile::time(Phase::Execute, || slot.queue.finish())?;
+        profile::time(Phase::Readback, || slot.segment.read(&mut marks).enq())?;
+
+        for (is_composite, &mark) in segment.iter_mut().zip(&marks) {
+            *is_composite = mark != 0;
+        }
+        Ok(())
+    }
+
+    /// Queues a count of the `len` numbers of buffer `slot` left unmarked once the marking
+    /// `enqueue` queued there is done; `collect_count` on the same slot waits and reads it.
+    pub fn enqueue_count(&self, slot: usize, len: usize) -> Result<(), Box<dyn Error>> {
+        let slot = &self.slots[slot % GPU_SLOTS];
+        profile::time(Phase::Execute, || {
+            slot.count.cmd().fill(0u32, None).enq()?;
+            slot.counter.set_arg(2, len as u64)?;
+            unsafe { slot.counter.cmd().global_work_size(len.div_ceil(COUNT_GROUP_SIZE) * COUNT_GROUP_SIZE).enq() }
+        })?;
+        Ok(())
+    }
+
+    /// The count queued on buffer `slot`, waiting for it if needed.
+    pub fn collect_count(&self, slot: usize) -> Result<u64, Box<dyn Error>> {
+        let slot = &self.slots[slot % GPU_SLOTS];
+        let mut count = [0u32];
+        profile::time(Phase::Execute, || slot.queue.finish())?;
+        profile::time(Phase::Readback, || slot.count.read(&mut count[..]).enq())?;
+        Ok(u64::from(count[0]))
+    }
+
+    /// Marks the composites in `segment`, which holds the numbers starting at `low`.
+    pub fn mark(&self, segment: &mut [bool], low: u128) -> Result<(), Box<dyn Error>> {
+        self.enqueue(0, low, segment.len())?;
+        self.collect(0, segment)
+    }
+}
+
+/// Something that marks the composites of sieve segments, one of the workers
+/// `segmented_sieve_across` shares a range between.
+pub trait SegmentMarker: Send {
+    /// Sets every entry of `segment`, which holds the numbers starting at `low`, to whether
+    /// that number is composite (or, below 2, not prime).
+    fn mark_segment(&mut self, segment: &mut [bool], low: u128) -> Result<(), Box<dyn Error>>;
+}
+
+impl SegmentMarker for GpuMarker {
+    fn mark_segment(&mut self, segment: &mut [bool], low: u128) -> Result<(), Box<dyn Error>> {
+        self.mark(segment, low)
+    }
+}
+
+/// Which segments of a shared sieve are still to be marked, for the workers to claim.
+struct Schedule {
+    /// The lowest segment no worker has claimed yet.
+    next: u128,
+    /// Segments a failed worker gave back, claimed ahead of `next`.
+    returned: Vec<u128>,
+    /// Segments claimed but neither finished nor given back.
+    in_flight: usize,
+    /// Set once the primes are no longer wanted, so the workers stop.
+    cancelled: bool,
+}
+
+/// `segmented_sieve` with the segments shared out between `markers`, each on a thread of
+/// its own, e.g. one per GPU. A worker claims the next unmarked segment as soon as it is
+/// done with the last, and the primes are put back in order before reaching `on_chunk`.
+///
+/// A marker that fails gives its segment back and drops out, and the others take over its
+/// share. Only if every marker fails does the sieve fail.
+pub fn segmented_sieve_across(
+    start_n: u128,
+    end_n: u128,
+    markers: Vec<Box<dyn SegmentMarker>>,
+    progression: Option<&Progression>,
+    on_chunk: &mut PrimeCallback,
+) -> Result<(), Box<dyn Error>> {
+    if end_n > u64::MAX as u128 {
+        return Err(MpError::UnsupportedRange { end: end_n }.into());
+    }
+    let low = start_n.max(2);
+    if low >= end_n {
+        return Ok(());
+    }
+    let segments = (end_n - low).div_ceil(SEGMENT_SIZE as u128);
+    let segment_start = |i: u128| low + i * SEGMENT_SIZE as u128;
+    let segment_len = |i: u128| ((end_n - segment_start(i)).min(SEGMENT_SIZE as u128)) as usize;
+    let pb = progress_bar((end_n - low) as u64, THROUGHPUT_TEMPLATE, SIEVING);
+
+    let schedule = Mutex::new(Schedule { next: 0, returned: Vec::new(), in_flight: 0, cancelled: false });
+    let changed = Condvar::new();
+    // Waits for a segment to claim, or returns None once none is left or will come back
+    let claim = || {
+        let mut state = schedule.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            if state.cancelled {
+                return None;
+            }
+            let claimed = state.returned.pop().or_else(|| {
+                (state.next < segments).then(|| {
+                    state.next += 1;
+                    state.next - 1
+                })
+            });
+            if let Some(i) = claimed {
+                state.in_flight += 1;
+                return Some(i);
+            }
+            if state.in_flight == 0 {
+                return None;
+            }
+            state = changed.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+    };
+    let release = |returned: Option<u128>, cancel: bool| {
+        let mut state = schedule.lock().unwrap_or_else(|e| e.into_inner());
+        state.in_flight -= 1;
+        state.returned.extend(returned);
+        state.cancelled |= cancel;
+        changed.notify_all();
+    };
+
+    let (sender, receiver) = mpsc::channel::<(u128, Vec<u128>)>();
+    std::thread::scope(|scope| {
+        for (worker, mut marker) in markers.into_iter().enumerate() {
+            let sender = sender.clone();
+            let (claim, release) = (&claim, &release);
+            scope.spawn(move || {
+                let mut composite = vec![false; SEGMENT_SIZE];
+                while let Some(i) = claim() {
+                    let segment = &mut composite[..segment_len(i)];
+                    if let Err(e) = marker.mark_segment(segment, segment_start(i)) {
+                        warn!("Sieve worker {} failed ({}); the others take over its segments", worker, e);
+                        release(Some(i), false);
+                        return;
+                    }
+                    let primes = collect_unmarked(segment, segment_start(i), progression);
+                    let wanted = sender.send((i, primes)).is_ok();
+                    release(None, !wanted);
+                }
+            });
+        }
+        drop(sender);
+
+        // Segments finish out of order, so each waits here until those before it are out
+        let mut finished = BTreeMap::new();
+        let mut next = 0;
+        for (i, primes) in receiver {
+            finished.insert(i, primes);
+            while let Some(primes) = finished.remove(&next) {
+                pb.set_message(WRITING);
+                let written = on_chunk(&primes);
+                pb.set_message(SIEVING);
+                if let Err(e) = written {
+                    release_all(&schedule, &changed);
+                    return Err(e);
+                }
+                pb.inc(segment_len(next) as u64);
+                next += 1;
+            }
+        }
+        if next < segments {
+            return Err(format!("Every sieve worker failed, {} of {} segments in.", next, segments).into());
+        }
+        pb.finish_with_message("Sieving Completed");
+        Ok(())
+    })
+}
+
+/// Cancels a shared sieve, waking every worker waiting for a segment.
+fn release_all(schedule: &Mutex<Schedule>, changed: &Condvar) {
+    schedule.lock().unwrap_or_else(|e| e.into_inner()).cancelled = true;
+    changed.notify_all();
+}
+
+/// Generates probable primes in the range [start_n, end_n) using strong probable-prime tests on the GPU.
+///
+/// # Arguments
+///
+/// * `start_n` - The starting number of the range.
+/// * `end_n` - The ending number of the range.
+/// * `bases` - The bases every candidate must pass. A lone base 2 is fastest.
+/// * `local_size` - How to pick the local work-group size.
+/// * `progression` - The residue classes to restrict candidates to, or `None` for all of them.
+/// * `max_gpu_mem` - Bytes the chunk buffers may take on the device, or `None` for
+///   `GPU_MEMORY_FRACTION` of its memory.
+/// * `on_chunk` - Called with the numbers of each chunk that passed the test, in ascending order.
+pub fn fermat_primes(
+    start_n: u128,
+    end_n: u128,
+    bases: &[u64],
+    local_size: LocalSize,
+    progression: Option<&Progression>,
+    max_gpu_mem: Option<u64>,
+    on_chunk: &mut PrimeCallback,
+) -> Result<(), Box<dyn Error>> {
+    // The kernel tests 64-bit candidates, so larger ones would wrap
+    if end_n > u64::MAX as u128 {
+        return Err(MpError::UnsupportedRange { end: end_n }.into());
+    }
+
+    // Step 1: Initialize OpenCL
+    let device = profile::time(Phase::Init, || Device::first(Platform::first()?))?;
+
+    // Step 2: Load and build the OpenCL program, or reuse the one an earlier call built
+    let kernel_src = r#"
+    __kernel void is_prime_kernel(__global const ulong* numbers, __global ulong* results, __global const ulong* bases, ulong num_bases, ulong count) {
+        int gid = get_global_id(0);
+        if (gid >= count) {
+            return;
+        }
+        ulong n = numbers[gid];
+        if (n < 2) {
+            results[gid] = 0;
+            return;
+        }
+        if (n == 2) {
+            results[gid] = 1;
+            return;
+        }
+        if (n % 2 == 0) {
+            results[gid] = 0;
+            return;
+        }
+
+        ulong d = n - 1;
+        uint s = 0;
+        while ((d & 1) == 0) {
+            d >>= 1;
+            s++;
+        }
+
+        // n is a probable prime only if every base fails to witness its compositeness
+        for (ulong i = 0; i < num_bases; i++) {
+            if (!is_sprp(n, bases[i], d, s)) {
+                results[gid] = 0;
+                return;
+            }
+        }
+        results[gid] = 1;
+    }
+    "#;
+
+    let (context, program) = cached_program(device, &format!("{}{}", MOD_ARITH_SRC, kernel_src))?;
+    let queue = profile::time(Phase::Init, || Queue::new(&context, device, None))?;
+
+    if bases.is_empty() {
+        return Err("At least one base is needed for the probable-prime kernel.".into());
+    }
+    let setting_up = Instant::now();
+    let buffer_bases = Buffer::<u64>::builder()
+        .queue(queue.clone())
+        .flags(flags::MEM_READ_ONLY | flags::MEM_COPY_HOST_PTR)
+        .len(bases.len())
+        .copy_host_slice(bases)
+        .build()?;
+
+    let kernel = Kernel::builder()
+        .program(&program)
+        .name("is_prime_kernel")
+        .queue(queue.clone())
+        .arg(None::<&Buffer<u64>>) // Placeholder for numbers
+        .arg(None::<&Buffer<u64>>) // Placeholder for results
+        .arg(&buffer_bases) // Bases every candidate must pass
+        .arg(bases.len() as u64)
+        .arg(0u64) // Placeholder for candidate count
+        .build()?;
+
+    // Step 3: Size the reusable buffers to one chunk of the range, within the device's memory
+    if start_n >= end_n {
+        return Ok(());
+    }
+    let total = end_n - start_n;
+    let memory = GpuMemory::of(device)?;
+    let chunk_len = total.min(memory.chunk_candidates(max_gpu_mem, CHUNK_SIZE) as u128) as usize;
+    info!(
+        "Probable-prime chunks of {} candidates, {} bytes of device buffers within a budget of {} of {} bytes",
+        chunk_len,
+        chunk_len as u64 * BYTES_PER_CANDIDATE,
+        memory.budget(max_gpu_mem),
+        memory.global
+    );
+
+    // Step 4: Create OpenCL buffers, reused by every chunk
+    let buffer_numbers = Buffer::<u64>::builder()
+        .queue(queue.clone())
+        .flags(flags::MEM_READ_ONLY)
+        .len(chunk_len)
+        .build()?;
+
+    let buffer_results = Buffer::<u64>::builder()
+        .queue(queue.clone())
+        .flags(flags::MEM_WRITE_ONLY)
+        .len(chunk_len)
+        .build()?;
+    TIMINGS.record(Phase::Buffers, setting_up.elapsed());
+
+    // Step 5: Pick the local work-group size, tuning it on a sample range unless given
+    let local_size = match local_size {
+        LocalSize::Fixed(size) => {
+            let max = kernel_max_wg_size(&kernel, device)?;
+            if size == 0 || size > max {
+                return Err(format!("The local work-group size {} is outside 1..={}, the sizes {} launches the kernel with", size, max, device.name()?).into());
+            }
+            info!("Local work-group size {} on {}, as given", size, device.name()?);
+            size
+        }
+        LocalSize::Tuned { retune } => tuned_local_size(&kernel, &queue, device, start_n, retune)?,
+    };
+
+    debug!(
+        "Probable-prime kernel on {}: chunks of {} candidates, local size {}, bases {:?}",
+        device.name()?,
+        chunk_len,
+        local_size,
+        bases
+    );
+
+    // Step 6: Set kernel arguments
+    kernel.set_arg(0, &buffer_numbers)?;
+    kernel.set_arg(1, &buffer_results)?;
+
+    let mut sample_state = sample_seed();
+    test_in_chunks(start_n, end_n, chunk_len, progression, on_chunk, &mut |numbers, results| {
+        // Step 7: Upload this chunk's wheel candidates into the shared buffer
+        profile::time(Phase::Buffers, || buffer_numbers.write(numbers).enq())?;
+        kernel.set_arg(4, numbers.len() as u64)?;
+
+        // Step 8: Execute the kernel with specified Global Work Size
+        profile::time(Phase::Execute, || {
+            unsafe {
+                kernel.cmd()
+                    .global_work_size([numbers.len().div_ceil(local_size) * local_size]) // Pad to a multiple of the local size
+                    .local_work_size([local_size])
+                    .enq()?;
+            }
+            queue.finish()
+        })?;
+
+        // Step 9: Read the results and spot-check them on the CPU
+        profile::time(Phase::Readback, || buffer_results.read(&mut *results).enq())?;
+        verify_sample(numbers, results, bases, &mut sample_state)
+    })
+}
+
+/// Tests the wheel candidates of [start_n, end_n) the way `fermat_primes` does, in chunks
+/// of `chunk_len` numbers of the range: `test` sets the verdict of each candidate it is
+/// handed to 1 for a probable prime, and the survivors of every chunk go to `on_chunk`.
+/// The candidate and verdict vectors are reused from chunk to chunk, like the device
+/// buffers they are copied to and from.
+fn test_in_chunks(
+    start_n: u128,
+    end_n: u128,
+    chunk_len: usize,
+    progression: Option<&Progression>,
+    on_chunk: &mut PrimeCallback,
+    test: &mut ChunkTest,
+) -> Result<(), Box<dyn Error>> {
+    let pb = progress_bar(end_n.saturating_sub(start_n) as u64, THROUGHPUT_TEMPLATE, TESTING);
+    let mut numbers = vec![0u64; chunk_len];
+    let mut results = vec![0u64; chunk_len];
+    let mut chunk_start = start_n;
+
+    while chunk_start < end_n {
+        let len = (end_n - chunk_start).min(chunk_len as u128) as usize;
+        let candidates = wheel_candidates(chunk_start, chunk_start + len as u128, progression);
+        for (slot, &n) in numbers.iter_mut().zip(&candidates) {
+            *slot = n as u64;
+        }
+        let count = candidates.len();
+        if count > 0 {
+            test(&numbers[..count], &mut results[..count])?;
+            let primes: Vec<u128> = results[..count]
+                .iter()
+                .zip(&numbers)
+                .filter(|(&is_prime, _)| is_prime == 1)
+                .map(|(_, &n)| n as u128)
+                .collect();
+            pb.set_message(WRITING);
+            on_chunk(&primes)?;
+            pb.set_message(TESTING);
+        }
+
+        chunk_start += len as u128;
+        pb.inc(len as u64);
+    }
+
+    pb.finish_with_message("Prime Collection Completed");
+
+    Ok(())
+}
+
+/// Modulus of the wheel that pre-filters the probable-prime kernel's candidates.
+const WHEEL_MODULUS: u64 = 2 * 3 * 5 * 7;
+
+/// The candidates in [low, high) worth a probable-prime test: 2, 3, 5 and 7, then every
+/// number above 1 coprime to them, kept to the members of `progression` when there is one.
+///
+/// Only 48 of every 210 numbers are coprime to the wheel, so this skips about 77% of the
+/// kernel's work on a long range.
+fn wheel_candidates(low: u128, high: u128, progression: Option<&Progression>) -> Vec<u128> {
+    let spokes: Vec<u64> = (1..WHEEL_MODULUS)
+        .filter(|r| [2, 3, 5, 7].iter().all(|p| r % p != 0))
+        .collect();
+    let wheel = Progression::new(WHEEL_MODULUS, &spokes).expect("every spoke is below the modulus");
+
+    let wanted = |n: u128| progression.is_none_or(|progression| progression.contains(n));
+    // Every wheel member above 1 is at least 11, so the wheel primes come first in order
+    let mut candidates: Vec<u128> = [2, 3, 5, 7].into_iter().filter(|&n| (low..high).contains(&n) && wanted(n)).collect();
+    match progression {
+        // Walking the progression visits only its members, which the wheel then thins out
+        Some(progression) => candidates.extend(progression.members(low, high).into_iter().filter(|&n| n > 1 && wheel.contains(n))),
+        None => candidates.extend(wheel.members(low, high).into_iter().filter(|&n| n > 1)),
+    }
+    candidates
+}
+
+/// Seeds the xorshift generator that picks which kernel verdicts get re-checked.
+fn sample_seed() -> u64 {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    nanos | 1
+}
+
+/// Cross-checks a random sample of kernel verdicts against the CPU strong probable-prime test.
+///
+/// # Arguments
+///
+/// * `numbers` - The candidates uploaded for the chunk.
+/// * `results` - The kernel's verdicts for those candidates.
+/// * `bases` - The bases the kernel tested with.
+/// * `state` - The xorshift state, advanced for every sample drawn.
+///
+/// # Returns
+///
+/// An error naming the first candidate whose GPU verdict disagrees with the CPU.
+fn verify_sample(numbers: &[u64], results: &[u64], bases: &[u64], state: &mut u64) -> Result<(), Box<dyn Error>> {
+    for _ in 0..VERIFY_SAMPLES.min(numbers.len()) {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        let idx = (*state % numbers.len() as u64) as usize;
+
+        if (results[idx] == 1) != is_sprp_u64(numbers[idx], bases) {
+            return Err(format!(
+                "GPU verdict for {} disagrees with the CPU probable-prime test.",
+                numbers[idx]
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Returns the local work-group sizes worth trying for a kernel.
+///
+/// # Arguments
+///
+/// * `max_wg_size` - The largest work-group size the kernel can be launched with on the device.
+///
+/// # Returns
+///
+/// The sizes of `TUNE_LOCAL_SIZES` up to `max_wg_size` in ascending order, or `max_wg_size`
+/// alone on a device whose limit is below all of them.
+pub fn candidate_local_sizes(max_wg_size: usize) -> Vec<usize> {
+    let sizes: Vec<usize> = TUNE_LOCAL_SIZES.into_iter().filter(|&size| size <= max_wg_size).collect();
+    if sizes.is_empty() {
+        vec![max_wg_size.max(1)]
+    } else {
+        sizes
+    }
+}
+
+/// The largest work-group size `kernel` launches with on `device`.
+fn kernel_max_wg_size(kernel: &Kernel, device: Device) -> Result<usize, Box<dyn Error>> {
+    let kernel_max = match kernel.wg_info(device, KernelWorkGroupInfo::WorkGroupSize)? {
+        KernelWorkGroupInfoResult::WorkGroupSize(size) if size > 0 => size,
+        _ => device.max_wg_size()?,
+    };
+    Ok(kernel_max.min(device.max_wg_size()?))
+}
+
+/// The tuning file in `PROGRAM_CACHE`, or `None` when there is no cache to keep it in.
+fn tuning_file() -> Option<PathBuf> {
+    PROGRAM_CACHE.lock().unwrap_or_else(|e| e.into_inner()).as_ref().map(|dir| dir.join(TUNING_FILE))
+}
+
+/// The local work-group size for the probable-prime kernel on `device`: the one tuned
+/// earlier in this process or recorded in the tuning file, unless `retune`, and otherwise
+/// the fastest on a sample range from `start_n`, which is then recorded.
+fn tuned_local_size(
+    kernel: &Kernel,
+    queue: &Queue,
+    device: Device,
+    start_n: u128,
+    retune: bool,
+) -> Result<usize, Box<dyn Error>> {
+    let name = device.name()?;
+    let path = tuning_file();
+    let max = kernel_max_wg_size(kernel, device)?;
+    if !retune {
+        let known = TUNED_LOCAL_SIZES.lock().unwrap_or_else(|e| e.into_inner()).get(&name).copied();
+        let recorded = || path.as_deref().and_then(|path| read_tuned_sizes(path).remove(&name));
+        // A size recorded under another driver may no longer launch
+        if let Some(size) = known.or_else(recorded).filter(|&size| size <= max) {
+            TUNED_LOCAL_SIZES.lock().unwrap_or_else(|e| e.into_inner()).insert(name.clone(), size);
+            info!("Local work-group size {} on {}, as tuned earlier", size, name);
+            return Ok(size);
+        }
+    }
+
+    let tuning = Instant::now();
+    let size = tune_local_work_size(kernel, queue, device, start_n)?;
+    info!("Local work-group size {} on {}, tuned in {:.1?}", size, name, tuning.elapsed());
+    TUNED_LOCAL_SIZES.lock().unwrap_or_else(|e| e.into_inner()).insert(name.clone(), size);
+    if let Some(path) = path {
+        if let Err(e) = save_tuned_size(&path, &name, size) {
+            warn!("Could not record the tuned local size in {}: {}", path.display(), e);
+        }
+    }
+    Ok(size)
+}
+
+/// The local work-group sizes recorded in the tuning file at `path`, by device name. Each
+/// line holds a size and the name of the device it was tuned on; a missing file records
+/// none, and lines that don't parse are skipped.
+fn read_tuned_sizes(path: &Path) -> BTreeMap<String, usize> {
+    let Ok(text) = std::fs::read_to_string(path) else {
+        return BTreeMap::new();
+    };
+    text.lines()
+        .filter_map(|line| {
+            let (size, name) = line.split_once(' ')?;
+            Some((name.to_string(), size.parse().ok().filter(|&size| size > 0)?))
+        })
+        .collect()
+}
+
+/// Records `size` as the local work-group size tuned on the device `name` in the tuning
+/// file at `path`, keeping the other devices' sizes. Written through a temporary file, so a
+/// concurrent run never reads half of it.
+fn save_tuned_size(path: &Path, name: &str, size: usize) -> Result<(), Box<dyn Error>> {
+    let mut sizes = read_tuned_sizes(path);
+    sizes.insert(name.replace('\n', " "), size);
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let text: String = sizes.iter().map(|(name, size)| format!("{} {}\n", size, name)).collect();
+    let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+    std::fs::write(&tmp, text)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Times the kernel on a small sample range at each candidate local work-group size.
+///
+/// # Arguments
+///
+/// * `kernel` - The primality kernel, with its bases already set.
+/// * `queue` - The queue the kernel runs on.
+/// * `device` - The device the kernel was built for.
+/// * `start_n` - The start of the range being generated, used to pick representative samples.
+///
+/// # Returns
+///
+/// The fastest local work-group size, which is never above the kernel's maximum
+/// work-group size on the device.
+pub fn tune_local_work_size(
+    kernel: &Kernel,
+    queue: &Queue,
+    device: Device,
+    start_n: u128,
+) -> Result<usize, Box<dyn Error>> {
+    let max_wg_size = kernel_max_wg_size(kernel, device)?;
+
+    let sample: Vec<u64> = (0..TUNE_SAMPLE_SIZE as u64)
+        .map(|i| (start_n as u64).wrapping_add(i))
+        .collect();
+    let buffer_numbers = Buffer::<u64>::builder()
+        .queue(queue.clone())
+        .flags(flags::MEM_READ_ONLY | flags::MEM_COPY_HOST_PTR)
+        .len(TUNE_SAMPLE_SIZE)
+        .copy_host_slice(&sample)
+        .build()?;
+    let buffer_results = Buffer::<u64>::builder()
+        .queue(queue.clone())
+        .flags(flags::MEM_WRITE_ONLY)
+        .len(TUNE_SAMPLE_SIZE)
+        .build()?;
+
+    kernel.set_arg(0, &buffer_numbers)?;
+    kernel.set_arg(1, &buffer_results)?;
+    kernel.set_arg(4, TUNE_SAMPLE_SIZE as u64)?;
+
+    let mut best = (1, Duration::MAX);
+    for local in candidate_local_sizes(max_wg_size) {
+        let started = Instant::now();
+        unsafe {
+            kernel.cmd()
+                .global_work_size([TUNE_SAMPLE_SIZE.div_ceil(local) * local])
+                .local_work_size([local])
+                .enq()?;
+        }
+        queue.finish()?;
+        let elapsed = started.elapsed();
+        if elapsed < best.1 {
+            best = (local, elapsed);
+        }
+    }
+
+    Ok(best.0)
+}
+
+/// Magic bytes at the start of a binary prime file.
+pub const BINARY_MAGIC: &[u8; 4] = b"MPPR";
+
+/// Version of the binary prime file layout written by `write_primes_to_file`.
+///
+/// Version 2 follows the magic and version with the width of each value in bytes and the
+/// number of values as a little-endian u64; version 1 files, which have neither, still read.
+pub const BINARY_VERSION: u8 = 2;
+
+/// Width in bytes of each value in a binary prime file.
+const BINARY_WIDTH: u8 = 8;
+
+/// Value count written while a binary file is streamed, and left in place if the output
+/// can't be rewound to fill it in, e.g. on standard output.
+const BINARY_COUNT_UNKNOWN: u64 = u64::MAX;
+
+/// On-disk layouts for generated primes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// One decimal prime per line.
+    Lines,
+    /// A magic/version header followed by each prime as a little-endian u64.
+    Binary,
+    /// An `index,prime` header row, then one row per prime counting from 1.
+    Csv,
+    /// Like `Csv`, separated by tabs.
+    Tsv,
+    /// Rows of `columns` decimal primes, right-aligned to `width` digits and separated by
+    /// spaces, for reading as a table. The last row may be short.
+    Columns { columns: usize, width: usize },
+}
+
+impl OutputFormat {
+    /// Parses the name given to `--output-format`.
+    pub fn from_name(name: &str) -> Option<OutputFormat> {
+        match name {
+            "lines" => Some(OutputFormat::Lines),
+            "binary" | "bin" => Some(OutputFormat::Binary),
+            "csv" => Some(OutputFormat::Csv),
+            "tsv" => Some(OutputFormat::Tsv),
+            _ => None,
+        }
+    }
+
+    /// A fresh writer for this layout.
+    pub fn writer(self) -> Box<dyn PrimeWriter> {
+        match self {
+            OutputFormat::Lines => Box::new(LinesWriter),
+            OutputFormat::Binary => Box::new(BinaryWriter { count: 0 }),
+            OutputFormat::Csv => Box::new(DelimitedWriter { separator: ',', index: 0 }),
+            OutputFormat::Tsv => Box::new(DelimitedWriter { separator: '\t', index: 0 }),
+            OutputFormat::Columns { columns, width } => Box::new(ColumnsWriter { columns: columns.max(1), width, filled: 0 }),
+        }
+    }
+}
+
+/// Formats primes in one output layout, for `PrimeSink` to flush a chunk at a time.
+pub trait PrimeWriter {
+    /// Appends whatever comes before the first prime to `buffer`.
+    fn write_header(&mut self, _buffer: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    /// Appends a description of how the primes were generated to `buffer`, for formats
+    /// that have room for one.
+    fn write_metadata(&mut self, _buffer: &mut Vec<u8>, _metadata: &OutputMetadata) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    /// Appends one prime to `buffer`.
+    fn write_prime(&mut self, buffer: &mut Vec<u8>, prime: u128) -> Result<(), Box<dyn Error>>;
+
+    /// Appends whatever comes after the last prime to `buffer`.
+    fn write_footer(&mut self, _buffer: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    /// Bytes to write over the header at the given offset once every prime is written.
+    fn header_patch(&self) -> Option<(u64, Vec<u8>)> {
+        None
+    }
+}
+
+/// Writes `OutputFormat::Lines`.
+struct LinesWriter;
+
+impl PrimeWriter for LinesWriter {
+    fn write_metadata(&mut self, buffer: &mut Vec<u8>, metadata: &OutputMetadata) -> Result<(), Box<dyn Error>> {
+        // Comment lines, which `--from-list` skips
+        for line in metadata.lines(SystemTime::now()) {
+            writeln!(buffer, "# {}", line)?;
+        }
+        Ok(())
+    }
+
+    fn write_prime(&mut self, buffer: &mut Vec<u8>, prime: u128) -> Result<(), Box<dyn Error>> {
+        writeln!(buffer, "{}", prime)?;
+        Ok(())
+    }
+}
+
+/// Writes `OutputFormat::Columns`, counting the primes in the current row.
+struct ColumnsWriter {
+    columns: usize,
+    width: usize,
+    filled: usize,
+}
+
+impl PrimeWriter for ColumnsWriter {
+    fn write_metadata(&mut self, buffer: &mut Vec<u8>, metadata: &OutputMetadata) -> Result<(), Box<dyn Error>> {
+        LinesWriter.write_metadata(buffer, metadata)
+    }
+
+    fn write_prime(&mut self, buffer: &mut Vec<u8>, prime: u128) -> Result<(), Box<dyn Error>> {
+        if self.filled > 0 {
+            buffer.push(b' ');
+        }
+        write!(buffer, "{:>width$}", prime, width = self.width)?;
+        self.filled += 1;
+        if self.filled == self.columns {
+            buffer.push(b'\n');
+            self.filled = 0;
+        }
+        Ok(())
+    }
+
+    fn write_footer(&mut self, buffer: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
+        if self.filled > 0 {
+            buffer.push(b'\n');
+            self.filled = 0;
+        }
+        Ok(())
+    }
+}
+
+/// What produced a prime file, written at its top so the file can be identified later.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutputMetadata {
+    /// The range as requested, [start, end).
+    pub start: u128,
+    pub end: u128,
+    /// How the primes were found, e.g. "segmented sieve (CPU)".
+    pub method: String,
+    /// The probable-prime bases, for methods that use them.
+    pub bases: Vec<u64>,
+    /// Which primes of the range were kept, when not all of them were.
+    pub filters: Vec<String>,
+}
+
+impl OutputMetadata {
+    /// The `key: value` lines describing the run, stamped with `generated`.
+    pub fn lines(&self, generated: SystemTime) -> Vec<String> {
+        let mut lines = vec![
+            format!("generator: mersenne-prime {}", env!("CARGO_PKG_VERSION")),
+            format!("range: [{}, {})", self.start, self.end),
+            format!("method: {}", self.method),
+        ];
+        if !self.bases.is_empty() {
+            let bases: Vec<String> = self.bases.iter().map(u64::to_string).collect();
+            lines.push(format!("bases: {}", bases.join(",")));
+        }
+        for filter in &self.filters {
+            lines.push(format!("filter: {}", filter));
+        }
+        lines.push(format!("generated: {}", utc_timestamp(generated)));
+        lines
+    }
+}
+
+/// `time` as an ISO 8601 UTC timestamp to the second, e.g. "2024-03-01T12:00:00Z".
+pub(crate) fn utc_timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+    // Civil date from days since 1970-01-01, after Howard Hinnant's days_from_civil inverse
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3_600,
+        rem % 3_600 / 60,
+        rem % 60
+    )
+}
+
+/// Writes `OutputFormat::Binary`, counting the values for the header.
+struct BinaryWriter {
+    count: u64,
+}
+
+impl PrimeWriter for BinaryWriter {
+    fn write_header(&mut self, buffer: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
+        buffer.extend_from_slice(BINARY_MAGIC);
+        buffer.push(BINARY_VERSION);
+        buffer.push(BINARY_WIDTH);
+        buffer.extend_from_slice(&BINARY_COUNT_UNKNOWN.to_le_bytes());
+        Ok(())
+    }
+
+    fn write_prime(&mut self, buffer: &mut Vec<u8>, prime: u128) -> Result<(), Box<dyn Error>> {
+        let value = u64::try_from(prime)
+            .map_err(|_| format!("{} does not fit the binary format's 64-bit values.", prime))?;
+        buffer.extend_from_slice(&value.to_le_bytes());
+        self.count += 1;
+        Ok(())
+    }
+
+    fn header_patch(&self) -> Option<(u64, Vec<u8>)> {
+        Some((BINARY_MAGIC.len() as u64 + 2, self.count.to_le_bytes().to_vec()))
+    }
+}
+
+/// One row of `OutputFormat::Csv` or `OutputFormat::Tsv` output, or of any other table of
+/// numbers and plain words separated by `separator`, without its line ending. None of
+/// those fields ever needs quoting.
+pub fn delimited_row(fields: &[&dyn Display], separator: char) -> String {
+    let mut row = String::new();
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            row.push(separator);
+        }
+        row.push_str(&field.to_string());
+    }
+    row
+}
+
+/// Writes `OutputFormat::Csv` and `OutputFormat::Tsv`, numbering the primes as it goes.
+struct DelimitedWriter {
+    separator: char,
+    index: u64,
+}
+
+impl PrimeWriter for DelimitedWriter {
+    fn write_header(&mut self, buffer: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
+        writeln!(buffer, "{}", delimited_row(&[&"index", &"prime"], self.separator))?;
+        Ok(())
+    }
+
+    fn write_prime(&mut self, buffer: &mut Vec<u8>, prime: u128) -> Result<(), Box<dyn Error>> {
+        self.index += 1;
+        writeln!(buffer, "{}", delimited_row(&[&self.index, &prime], self.separator))?;
+        Ok(())
+    }
+}
+
+/// The first two bytes of every gzip stream.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Where a `PrimeSink` writes to.
+enum SinkOutput {
+    Plain(Box<dyn Write>),
+    /// A gzip stream, whose trailer `PrimeSink::finish` writes.
+    Gzip(GzEncoder<File>),
+}
+
+impl Write for SinkOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            SinkOutput::Plain(writer) => writer.write(buf),
+            SinkOutput::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            SinkOutput::Plain(writer) => writer.flush(),
+            SinkOutput::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Destination that generated primes are streamed into, one chunk at a time.
+///
+/// Each chunk is formatted in memory and written with a single flush, so a run that is
+/// interrupted leaves only whole lines (or whole values) behind.
+pub struct PrimeSink {
+    writer: SinkOutput,
+    format: Box<dyn PrimeWriter>,
+    buffer: Vec<u8>,
+    /// The output file, for `finish` to fill in the header.
+    file: Option<File>,
+    /// Bytes in the output so far, before compression.
+    written: u64,
+}
+
+impl PrimeSink {
+    /// Starts a sink on any writer, writing the format's header up front.
+    pub fn new(writer: Box<dyn Write>, format: OutputFormat) -> Result<PrimeSink, Box<dyn Error>> {
+        PrimeSink::start(SinkOutput::Plain(writer), format)
+    }
+
+    fn start(mut writer: SinkOutput, format: OutputFormat) -> Result<PrimeSink, Box<dyn Error>> {
+        let mut format = format.writer();
+        let mut buffer = Vec::new();
+        format.write_header(&mut buffer)?;
+        if !buffer.is_empty() {
+            writer.write_all(&buffer)?;
+            writer.flush()?;
+        }
+        let written = buffer.len() as u64;
+        Ok(PrimeSink { writer, format, buffer, file: None, written })
+    }
+
+    /// Starts a sink that creates (or truncates) `filename`, gzip-compressed if it ends
+    /// in `.gz`.
+    pub fn to_file(filename: &str, format: OutputFormat) -> Result<PrimeSink, Box<dyn Error>> {
+        if filename.ends_with(".gz") {
+            return PrimeSink::to_compressed_file(filename, format);
+        }
+        let file = File::create(filename)?;
+        let mut sink = PrimeSink::new(Box::new(file.try_clone()?), format)?;
+        sink.file = Some(file);
+        Ok(sink)
+    }
+
+    /// Continues a sink at the end of the existing `filename`, without writing the format's
+    /// header again.
+    pub fn append_to_file(filename: &str, format: OutputFormat) -> Result<PrimeSink, Box<dyn Error>> {
+        let file = OpenOptions::new().append(true).open(filename)?;
+        let written = file.metadata()?.len();
+        Ok(PrimeSink {
+            writer: SinkOutput::Plain(Box::new(file)),
+            format: format.writer(),
+            buffer: Vec::new(),
+            file: None,
+            written,
+        })
+    }
+
+    /// Starts a sink that gzips everything into `filename`, streaming as it goes.
+    ///
+    /// Binary headers keep an unknown count, as the compressed stream can't be rewound.
+    pub fn to_compressed_file(filename: &str, format: OutputFormat) -> Result<PrimeSink, Box<dyn Error>> {
+        let encoder = GzEncoder::new(File::create(filename)?, Compression::default());
+        PrimeSink::start(SinkOutput::Gzip(encoder), format)
+    }
+
+    /// Starts a sink on standard output.
+    pub fn stdout(format: OutputFormat) -> Result<PrimeSink, Box<dyn Error>> {
+        PrimeSink::new(Box::new(std::io::stdout()), format)
+    }
+
+    /// Writes one chunk of primes and flushes it.
+    pub fn write_chunk(&mut self, primes: &[u128]) -> Result<(), Box<dyn Error>> {
+        self.buffer.clear();
+        for &prime in primes {
+            self.format.write_prime(&mut self.buffer, prime)?;
+        }
+        self.writer.write_all(&self.buffer)?;
+        self.writer.flush()?;
+        self.written += self.buffer.len() as u64;
+        Ok(())
+    }
+
+    /// Writes `metadata` ahead of the primes, if the format has room for it. Must come
+    /// before the first chunk.
+    pub fn write_metadata(&mut self, metadata: &OutputMetadata) -> Result<(), Box<dyn Error>> {
+        self.buffer.clear();
+        self.format.write_metadata(&mut self.buffer, metadata)?;
+        self.writer.write_all(&self.buffer)?;
+        self.writer.flush()?;
+        self.written += self.buffer.len() as u64;
+        Ok(())
+    }
+
+    /// Bytes in the output so far, counted before any compression.
+    pub fn bytes_written(&self) -> u64 {
+        self.written
+    }
+
+    /// Ends the output after the last prime and fills in what the header couldn't know
+    /// until every prime was written, when the output is a file.
+    pub fn finish(mut self) -> Result<(), Box<dyn Error>> {
+        self.buffer.clear();
+        self.format.write_footer(&mut self.buffer)?;
+        self.writer.write_all(&self.buffer)?;
+        if let SinkOutput::Gzip(encoder) = self.writer {
+            encoder.finish()?;
+            return Ok(());
+        }
+        self.writer.flush()?;
+        if let (Some(file), Some((offset, bytes))) = (self.file.as_mut(), self.format.header_patch()) {
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(&bytes)?;
+        }
+        Ok(())
+    }
+}
+
+/// How far a resumable `-g` run got: every prime below `next` is in the first `bytes`
+/// bytes of the output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub next: u128,
+    pub bytes: u64,
+}
+
+/// The sidecar `<output>.progress` file of a resumable run, tied by a hash to the
+/// arguments it was started with.
+pub struct GenerationProgress {
+    path: String,
+    args_hash: u64,
+}
+
+impl GenerationProgress {
+    /// The sidecar of `output` for a run with the given arguments.
+    pub fn new(output: &str, args: &[String]) -> GenerationProgress {
+        let args_hash = fnv1a(args.iter().flat_map(|arg| arg.bytes().chain([0])));
+        GenerationProgress { path: format!("{}.progress", output), args_hash }
+    }
+
+    /// The checkpoint left by an interrupted run, if there is one.
+    ///
+    /// Fails if the sidecar is unreadable or was written by a run with other arguments.
+    pub fn load(&self) -> Result<Option<Checkpoint>, Box<dyn Error>> {
+        let text = match std::fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let field = |name: &str| {
+            text.lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+                .ok_or_else(|| format!("{} has no {} line.", self.path, name))
+        };
+        let args_hash = u64::from_str_radix(field("args")?, 16)?;
+        if args_hash != self.args_hash {
+            return Err(format!("{} was written by a run with different arguments.", self.path).into());
+        }
+        Ok(Some(Checkpoint { next: field("next")?.parse()?, bytes: field("bytes")?.parse()? }))
+    }
+
+    /// Records `checkpoint`, replacing the sidecar atomically.
+    pub fn save(&self, checkpoint: Checkpoint) -> Result<(), Box<dyn Error>> {
+        let temporary = format!("{}.tmp", self.path);
+        std::fs::write(
+            &temporary,
+            format!("args {:016x}\nnext {}\nbytes {}\n", self.args_hash, checkpoint.next, checkpoint.bytes),
+        )?;
+        std::fs::rename(&temporary, &self.path)?;
+        Ok(())
+    }
+
+    /// Cuts a lines-format `output` back to `checkpoint`, dropping anything a crash left
+    /// half-written, after checking that it ends with the prime just below `checkpoint.next`.
+    pub fn restore(&self, output: &str, checkpoint: Checkpoint) -> Result<(), Box<dyn Error>> {
+        let mut file = OpenOptions::new().read(true).write(true).open(output)?;
+        if file.metadata()?.len() < checkpoint.bytes {
+            return Err(format!("{} is shorter than {} records.", output, self.path).into());
+        }
+        file.set_len(checkpoint.bytes)?;
+
+        // The longest line is a u128 and its newline
+        let tail_len = checkpoint.bytes.min(40);
+        let mut tail = vec![0u8; tail_len as usize];
+        file.seek(SeekFrom::Start(checkpoint.bytes - tail_len))?;
+        file.read_exact(&mut tail)?;
+        let last = tail
+            .strip_suffix(b"\n")
+            .and_then(|body| body.rsplit(|&b| b == b'\n').next())
+            .and_then(|line| std::str::from_utf8(line).ok()?.parse::<u128>().ok());
+        if checkpoint.bytes > 0 && last != Some(checkpoint.next - 1) {
+            return Err(format!("{} does not end with the last prime {} records.", output, self.path).into());
+        }
+        Ok(())
+    }
+
+    /// Removes the sidecar once the run is complete.
+    pub fn clear(&self) -> Result<(), Box<dyn Error>> {
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Writes the provided prime numbers to a file.
+///
+/// # Arguments
+///
+/// * `primes` - An iterator over prime numbers.
+/// * `filename` - The name of the file to write the primes to.
+/// * `format` - The layout to write the primes in.
+/// * `metadata` - How the primes were generated, for the header of formats that have one.
+pub fn write_primes_to_file(
+    primes: &[u128],
+    filename: &str,
+    format: OutputFormat,
+    metadata: Option<&OutputMetadata>,
+) -> Result<(), Box<dyn Error>> {
+    let mut sink = PrimeSink::to_file(filename, format)?;
+    if let Some(metadata) = metadata {
+        sink.write_metadata(metadata)?;
+    }
+
+    let pb = progress_bar(primes.len() as u64, DEFAULT_TEMPLATE, "Writing Primes to File");
+
+    for chunk in primes.chunks(SEGMENT_SIZE) {
+        sink.write_chunk(chunk)?;
+        pb.inc(chunk.len() as u64);
+    }
+    sink.finish()?;
+
+    pb.finish_with_message("Prime Writing Completed");
+
+    Ok(())
+}
+
+/// Opens `filename` for reading, decompressing it on the fly if it is gzipped.
+pub fn open_prime_file(filename: &str) -> std::io::Result<Box<dyn Read>> {
+    let mut reader = BufReader::new(File::open(filename)?);
+    if reader.fill_buf()?.starts_with(&GZIP_MAGIC) {
+        Ok(Box::new(MultiGzDecoder::new(reader)))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+/// Reads primes back from a file written with `OutputFormat::Binary`, gzipped or not.
+///
+/// # Arguments
+///
+/// * `filename` - The name of the file to read the primes from.
+///
+/// # Returns
+///
+/// The primes in the order they were written.
+pub fn read_primes_from_binary(filename: &str) -> Result<Vec<u128>, Box<dyn Error>> {
+    let mut bytes = Vec::new();
+    open_prime_file(filename)?.read_to_end(&mut bytes)?;
+    let version_at = BINARY_MAGIC.len();
+
+    if bytes.len() <= version_at || &bytes[..version_at] != BINARY_MAGIC {
+        return Err(format!("{} is not a binary prime file.", filename).into());
+    }
+    let (body, count) = match bytes[version_at] {
+        1 => (&bytes[version_at + 1..], BINARY_COUNT_UNKNOWN),
+        2 => {
+            let header_len = version_at + 2 + 8;
+            if bytes.len() < header_len {
+                return Err(format!("{} ends inside its header.", filename).into());
+            }
+            if bytes[version_at + 1] != BINARY_WIDTH {
+                return Err(format!(
+                    "{} holds {}-byte values, expected {}.",
+                    filename,
+                    bytes[version_at + 1],
+                    BINARY_WIDTH
+                )
+                .into());
+            }
+            let count = u64::from_le_bytes(bytes[version_at + 2..header_len].try_into().unwrap());
+            (&bytes[header_len..], count)
+        }
+        version => {
+            return Err(format!(
+                "{} uses binary format version {}, expected {}.",
+                filename, version, BINARY_VERSION
+            )
+            .into())
+        }
+    };
+
+    if body.len() % 8 != 0 {
+        return Err(format!("{} ends with a truncated value.", filename).into());
+    }
+    let values = (body.len() / 8) as u64;
+    if count != BINARY_COUNT_UNKNOWN && count != values {
+        return Err(format!("{} holds {} values but its header says {}.", filename, values, count).into());
+    }
+
+    Ok(body
+        .chunks_exact(8)
+        .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()) as u128)
+        .collect())
+}
+
+/// Checks whether a file, once decompressed, starts with the binary prime file magic.
+pub fn is_binary_prime_file(filename: &str) -> bool {
+    let mut magic = [0u8; 4];
+    open_prime_file(filename)
+        .and_then(|mut reader| reader.read_exact(&mut magic))
+        .map(|_| &magic == BINARY_MAGIC)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Collects what `generate_primes` finds in [start_n, end_n) with `method`.
+    fn generate(start_n: u128, end_n: u128, method: Method) -> Vec<u128> {
+        generate_with(start_n, end_n, &GenerateOptions { method, ..GenerateOptions::default() })
+    }
+
+    /// Collects what `generate_primes_with` emits for [start_n, end_n) with `options`.
+    fn generate_with(start_n: u128, end_n: u128, options: &GenerateOptions) -> Vec<u128> {
+        let mut primes = Vec::new();
+        generate_primes_with(start_n, end_n, options, &mut |chunk: &[u128]| {
+            primes.extend_from_slice(chunk);
+            Ok(())
+        })
+        .unwrap();
+        primes
+    }
+
+    #[test]
+    fn candidate_sizes_stay_within_the_device_maximum() {
+        assert_eq!(candidate_local_sizes(1024), vec![32, 64, 128, 256]);
+        assert_eq!(candidate_local_sizes(192), vec![32, 64, 128]);
+        assert_eq!(candidate_local_sizes(16), vec![16]);
+        assert_eq!(candidate_local_sizes(0), vec![1]);
+    }
+
+    #[test]
+    fn tuned_sizes_round_trip_through_the_tuning_file() {
+        let dir = std::env::temp_dir().join(format!("mp-tuning-{}", std::process::id()));
+        let path = dir.join(TUNING_FILE);
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(read_tuned_sizes(&path).is_empty());
+
+        save_tuned_size(&path, "Radeon RX 7900 XTX", 128).unwrap();
+        save_tuned_size(&path, "pthread-Intel(R) Core(TM) i7", 64).unwrap();
+        save_tuned_size(&path, "Radeon RX 7900 XTX", 256).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        writeln!(file, "banana Old Device\n0 Zero Device\n").unwrap();
+
+        let sizes = read_tuned_sizes(&path);
+        assert_eq!(sizes.len(), 2);
+        assert_eq!(sizes["Radeon RX 7900 XTX"], 256);
+        assert_eq!(sizes["pthread-Intel(R) Core(TM) i7"], 64);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn default_options_find_78498_primes_below_a_million() {
+        let mut count = 0;
+        generate_primes_with(2, 1_000_000, &GenerateOptions::default(), &mut |primes: &[u128]| {
+            count += primes.len();
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(count, 78498);
+    }
+
+    #[test]
+    #[ignore = "needs an OpenCL device"]
+    fn fermat_chunks_reuse_their_buffers_across_a_range() {
+        // Three chunks through the same buffers, the last one short
+        let end = 2 * CHUNK_SIZE as u128 + 12_345;
+        assert_eq!(generate(1, end, Method::Fermat), generate(1, end, Method::Sieve));
+    }
+
+    #[test]
+    fn chunks_fit_the_memory_of_the_device() {
+        let memory = GpuMemory { global: 8 << 30, max_alloc: 2 << 30 };
+        assert_eq!(memory.budget(None), (0.7 * (8u64 << 30) as f64) as u64);
+        assert_eq!(memory.chunk_candidates(None, CHUNK_SIZE), CHUNK_SIZE);
+        assert_eq!(memory.chunk_candidates(Some(2 << 30), CHUNK_SIZE), CHUNK_SIZE);
+        assert_eq!(memory.chunk_candidates(Some(64 << 20), CHUNK_SIZE), 4 << 20);
+        // The smaller of the budget and what one buffer can hold
+        let small = GpuMemory { global: 256 << 20, max_alloc: 16 << 20 };
+        assert_eq!(small.chunk_candidates(None, CHUNK_SIZE), 2 << 20);
+        assert_eq!(small.chunk_candidates(Some(1), CHUNK_SIZE), 1);
+
+        // The 203 million base primes of a 64-bit range fit a large device in one buffer,
+        // but a cap leaves room for a batch per slot beside the segment buffers
+        let base_primes = 203_280_221;
+        assert_eq!(memory.base_primes_per_buffer(None, base_primes), base_primes);
+        assert_eq!(memory.base_primes_per_buffer(Some(1 << 30), base_primes), ((1 << 30) - (1 << 19)) / 16);
+        assert_eq!(small.base_primes_per_buffer(None, base_primes), (16 << 20) / 8);
+        assert_eq!(memory.base_primes_per_buffer(Some(1), base_primes), 1);
+        assert_eq!(memory.base_primes_per_buffer(None, 0), 1);
+    }
+
+    #[test]
+    fn fermat_chunks_cover_the_range_within_a_small_device() {
+        // 4 KiB of buffers holds 256 candidates, so [1, 100000) takes 391 chunks
+        let memory = GpuMemory { global: 1 << 30, max_alloc: 1 << 20 };
+        let chunk_len = memory.chunk_candidates(Some(4096), CHUNK_SIZE);
+        assert_eq!(chunk_len, 256);
+        let (mut chunks, mut primes) = (0, Vec::new());
+        let mut on_chunk = |chunk: &[u128]| {
+            primes.extend_from_slice(chunk);
+            Ok(())
+        };
+        test_in_chunks(1, 100_000, chunk_len, None, &mut on_chunk, &mut |numbers, results| {
+            assert!(numbers.len() <= chunk_len);
+            chunks += 1;
+            for (result, &n) in results.iter_mut().zip(numbers) {
+                *result = is_prime_u64(n) as u64;
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(chunks, 391);
+        assert_eq!(primes, generate(1, 100_000, Method::Sieve));
+    }
+
+    #[test]
+    #[ignore = "needs an OpenCL device"]
+    fn a_tiny_gpu_memory_cap_splits_the_range_into_many_chunks() {
+        // 4 KiB of buffers is 256 candidates a chunk, so about 390 chunks
+        let options = GenerateOptions { method: Method::Fermat, max_gpu_mem: Some(4096), ..GenerateOptions::default() };
+        assert_eq!(generate_with(1, 100_000, &options), generate(1, 100_000, Method::Sieve));
+    }
+
+    #[test]
+    fn wheel_candidates_keep_every_prime() {
+        let candidates = wheel_candidates(0, 100_000, None);
+        let primes = generate(0, 100_000, Method::Sieve);
+        assert!(primes.iter().all(|p| candidates.binary_search(p).is_ok()));
+        assert!(candidates.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(candidates[..6], [2, 3, 5, 7, 11, 13]);
+        // 48 of every 210 numbers make it to the kernel
+        assert_eq!(wheel_candidates(210, 210 * 101, None).len(), 48 * 100);
+
+        let progression = Progression::new(4, &[3]).unwrap();
+        assert_eq!(wheel_candidates(0, 40, Some(&progression)), [3, 7, 11, 19, 23, 31]);
+    }
+
+    #[test]
+    #[ignore = "needs an OpenCL device"]
+    fn wheel_filtered_kernel_output_matches_the_sieve() {
+        for (start, end) in [(0, 1_000), (1, 1_000_000), (211, 500_003), (4_294_967_000, 4_294_977_000)] {
+            assert_eq!(generate(start, end, Method::Fermat), generate(start, end, Method::Sieve), "{}..{}", start, end);
+        }
+    }
+
+    #[test]
+    fn sampled_verdicts_past_2_pow_32_are_checked_on_the_cpu() {
+        // 4294967311 is the first prime past 2^32, 4294967299 = 7 * 613566757
+        let numbers = [4_294_967_299u64, 4_294_967_311];
+        let mut state = 1;
+        assert!(verify_sample(&numbers, &[0, 1], &[2], &mut state).is_ok());
+        // Verdicts from a kernel whose products wrapped at 64 bits get caught
+        let error = verify_sample(&numbers, &[1, 0], &[2], &mut state).unwrap_err();
+        assert!(error.to_string().contains("disagrees with the CPU"));
+    }
+
+    #[test]
+    #[ignore = "needs an OpenCL device"]
+    fn fermat_verdicts_straddling_2_pow_32_match_the_sieve() {
+        let fermat = generate(4_294_967_295, 4_294_967_400, Method::Fermat);
+        assert_eq!(fermat, generate(4_294_967_295, 4_294_967_400, Method::Sieve));
+        assert_eq!(fermat.first(), Some(&4_294_967_311));
+    }
+
+    #[test]
+    fn binary_files_round_trip() {
+        let dir = std::env::temp_dir().join(format!("mp-binary-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("primes.bin");
+        let filename = path.to_str().unwrap();
+
+        let mut primes = generate(1, 10_000, Method::Sieve);
+        // 2^64 - 59 is the largest prime the 64-bit values can hold
+        primes.push(u64::MAX as u128 - 58);
+        write_primes_to_file(&primes, filename, OutputFormat::Binary, None).unwrap();
+        assert!(is_binary_prime_file(filename));
+        assert_eq!(read_primes_from_binary(filename).unwrap(), primes);
+
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes.truncate(bytes.len() - 3);
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(read_primes_from_binary(filename).unwrap_err().to_string().contains("truncated"));
+
+        assert!(write_primes_to_file(&[u64::MAX as u128 + 14], filename, OutputFormat::Binary, None).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn gzipped_files_read_back_the_same_primes() {
+        let dir = std::env::temp_dir().join(format!("mp-gzip-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let primes = generate(1, 100_000, Method::Sieve);
+
+        let plain = dir.join("primes.txt");
+        let compressed = dir.join("primes.txt.gz");
+        write_primes_to_file(&primes, plain.to_str().unwrap(), OutputFormat::Lines, None).unwrap();
+        write_primes_to_file(&primes, compressed.to_str().unwrap(), OutputFormat::Lines, None).unwrap();
+        let bytes = std::fs::read(&compressed).unwrap();
+        assert!(bytes.starts_with(&GZIP_MAGIC));
+        assert!(bytes.len() * 2 < std::fs::metadata(&plain).unwrap().len() as usize);
+        let mut text = String::new();
+        open_prime_file(compressed.to_str().unwrap()).unwrap().read_to_string(&mut text).unwrap();
+        assert_eq!(text, std::fs::read_to_string(&plain).unwrap());
+
+        let binary = dir.join("primes.bin");
+        let mut sink = PrimeSink::to_compressed_file(binary.to_str().unwrap(), OutputFormat::Binary).unwrap();
+        sink.write_chunk(&primes).unwrap();
+        sink.finish().unwrap();
+        assert!(is_binary_prime_file(binary.to_str().unwrap()));
+        assert_eq!(read_primes_from_binary(binary.to_str().unwrap()).unwrap(), primes);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn progress_files_restore_the_output_they_describe() {
+        let dir = std::env::temp_dir().join(format!("mp-progress-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("primes.txt");
+        let output = path.to_str().unwrap();
+        let args = |end: &str| vec!["-g".to_string(), "1".to_string(), end.to_string()];
+
+        let progress = GenerationProgress::new(output, &args("100"));
+        assert_eq!(progress.load().unwrap(), None);
+        let mut sink = PrimeSink::to_file(output, OutputFormat::Lines).unwrap();
+        sink.write_chunk(&[2, 3, 5, 7]).unwrap();
+        let checkpoint = Checkpoint { next: 8, bytes: sink.bytes_written() };
+        progress.save(checkpoint).unwrap();
+        // A crash mid-chunk leaves a partial line behind
+        sink.write_chunk(&[11, 13]).unwrap();
+        std::fs::OpenOptions::new().append(true).open(output).unwrap().write_all(b"1").unwrap();
+
+        assert_eq!(progress.load().unwrap(), Some(checkpoint));
+        assert!(GenerationProgress::new(output, &args("1000")).load().is_err());
+        progress.restore(output, checkpoint).unwrap();
+        assert_eq!(std::fs::read_to_string(output).unwrap(), "2\n3\n5\n7\n");
+        let mut sink = PrimeSink::append_to_file(output, OutputFormat::Lines).unwrap();
+        sink.write_chunk(&[11]).unwrap();
+        assert_eq!(std::fs::read_to_string(output).unwrap(), "2\n3\n5\n7\n11\n");
+
+        assert!(progress.restore(output, Checkpoint { next: 7, bytes: 6 }).is_err());
+        progress.clear().unwrap();
+        assert_eq!(progress.load().unwrap(), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupted_binary_headers_are_rejected() {
+        let dir = std::env::temp_dir().join(format!("mp-binary-header-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("primes.bin");
+        let filename = path.to_str().unwrap();
+        write_primes_to_file(&[2, 3, 5, 7], filename, OutputFormat::Binary, None).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(&bytes[..6], b"MPPR\x02\x08");
+        assert_eq!(u64::from_le_bytes(bytes[6..14].try_into().unwrap()), 4);
+
+        let corrupt = |at: usize, value: u8| {
+            let mut corrupted = bytes.clone();
+            corrupted[at] = value;
+            std::fs::write(&path, &corrupted).unwrap();
+            read_primes_from_binary(filename).unwrap_err().to_string()
+        };
+        assert!(corrupt(0, b'X').contains("not a binary prime file"));
+        assert!(corrupt(4, 9).contains("version 9"));
+        assert!(corrupt(5, 4).contains("4-byte values"));
+        assert!(corrupt(6, 5).contains("holds 4 values but its header says 5"));
+
+        std::fs::write(&path, &bytes[..10]).unwrap();
+        assert!(read_primes_from_binary(filename).unwrap_err().to_string().contains("inside its header"));
+
+        // Version 1 files have no width or count
+        let mut version_1 = b"MPPR\x01".to_vec();
+        version_1.extend_from_slice(&11u64.to_le_bytes());
+        std::fs::write(&path, &version_1).unwrap();
+        assert_eq!(read_primes_from_binary(filename).unwrap(), [11]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn metadata_goes_at_the_top_of_lines_files_only() {
+        let metadata = OutputMetadata {
+            start: 1,
+            end: 100,
+            method: "strong probable-prime test (GPU)".to_string(),
+            bases: vec![2, 3],
+            filters: vec!["safe primes".to_string()],
+        };
+        // 2000-02-29, a leap day in a century year
+        let generated = UNIX_EPOCH + Duration::from_secs(951_827_696);
+        assert_eq!(
+            metadata.lines(generated)[1..],
+            [
+                "range: [1, 100)",
+                "method: strong probable-prime test (GPU)",
+                "bases: 2,3",
+                "filter: safe primes",
+                "generated: 2000-02-29T12:34:56Z",
+            ]
+        );
+        assert_eq!(utc_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+
+        let dir = std::env::temp_dir().join(format!("mp-metadata-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("primes.out");
+        let filename = path.to_str().unwrap();
+        write_primes_to_file(&[2, 3], filename, OutputFormat::Lines, Some(&metadata)).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.starts_with("# generator: mersenne-prime "));
+        assert!(text.ends_with("Z\n2\n3\n"), "{}", text);
+        write_primes_to_file(&[2, 3], filename, OutputFormat::Csv, Some(&metadata)).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "index,prime\n1,2\n2,3\n");
+        write_primes_to_file(&[2, 3], filename, OutputFormat::Binary, Some(&metadata)).unwrap();
+        assert_eq!(read_primes_from_binary(filename).unwrap(), [2, 3]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn delimited_files_match_the_plain_lines() {
+        let dir = std::env::temp_dir().join(format!("mp-delimited-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let primes = generate(1, 10_000, Method::Sieve);
+        let lines_path = dir.join("primes.txt");
+        write_primes_to_file(&primes, lines_path.to_str().unwrap(), OutputFormat::Lines, None).unwrap();
+        let lines = std::fs::read_to_string(&lines_path).unwrap();
+
+        for (format, separator) in [(OutputFormat::Csv, ','), (OutputFormat::Tsv, '\t')] {
+            let path = dir.join("primes.table");
+            write_primes_to_file(&primes, path.to_str().unwrap(), format, None).unwrap();
+            let text = std::fs::read_to_string(&path).unwrap();
+            let mut rows = text.lines();
+            assert_eq!(rows.next(), Some(format!("index{}prime", separator).as_str()));
+            let mut count = 0;
+            for ((i, row), line) in rows.enumerate().zip(lines.lines()) {
+                let (index, prime) = row.split_once(separator).unwrap();
+                assert_eq!(index.parse::<usize>().unwrap(), i + 1);
+                assert_eq!(prime, line);
+                count += 1;
+            }
+            assert_eq!(count, primes.len(), "{:?}", format);
+            assert_eq!(text.lines().count(), primes.len() + 1, "{:?}", format);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn base_2_pseudoprimes_are_removed() {
+        // 341 and 645 are base-2 Fermat pseudoprimes; 561, 1105 and 1729 are Carmichael numbers
+        let candidates = [337, 341, 547, 561, 643, 645, 1103, 1105, 1723, 1729];
+        assert_eq!(remove_pseudoprimes(&candidates), vec![337, 547, 643, 1103, 1723]);
+        assert_eq!(remove_pseudoprimes(&[2_047, 3_215_031_751, 4_294_967_311]), vec![4_294_967_311]);
+    }
+
+    #[test]
+    fn default_bases_reject_the_strong_base_2_pseudoprimes() {
+        for n in [2_047, 3_277, 4_033, 4_681, 8_321] {
+            assert!(is_sprp_u64(n, &[2]), "{} is a strong pseudoprime to base 2", n);
+            assert!(!is_sprp_u64(n, &DEFAULT_BASES), "{} passed the default bases", n);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs an OpenCL device"]
+    fn multi_base_gpu_verdicts_match_the_cpu() {
+        for bases in [vec![2], DEFAULT_BASES.to_vec(), vec![2, 3, 5, 7, 11]] {
+            let options =
+                GenerateOptions { method: Method::Fermat, bases: bases.clone(), verify: false, ..GenerateOptions::default() };
+            let expected: Vec<u128> = (1..100_000u64).filter(|&n| is_sprp_u64(n, &bases)).map(u128::from).collect();
+            assert_eq!(generate_with(1, 100_000, &options), expected, "bases {:?}", bases);
+        }
+    }
+
+    #[test]
+    fn parallel_segments_arrive_in_order() {
+        let expected = crate::sieve::sieve_of_eratosthenes(1, 1_000_000).unwrap();
+        assert_eq!(generate(1, 1_000_000, Method::Sieve), expected);
+        // A range straddling segment boundaries at an odd offset
+        let start = 3 * SEGMENT_SIZE as u128 - 7;
+        let end = 7 * SEGMENT_SIZE as u128 + 11;
+        assert_eq!(generate(start, end, Method::Sieve), crate::sieve::sieve_of_eratosthenes(start, end).unwrap());
+    }
+
+    #[test]
+    #[ignore = "needs an OpenCL device"]
+    fn gpu_marks_match_the_cpu() {
+        assert_eq!(generate(1, 1_000_000, Method::GpuSieve), generate(1, 1_000_000, Method::Sieve));
+    }
+
+    #[test]
+    fn counts_match_the_primes_generated() {
+        let options = GenerateOptions { method: Method::Sieve, ..GenerateOptions::default() };
+        assert_eq!(count_primes(2, 1_000_000, &options).unwrap(), 78_498);
+        assert_eq!(count_primes(1000, 2000, &options).unwrap(), generate(1000, 2000, Method::Sieve).len() as u64);
+        assert!(count_primes(10, 5, &options).is_err());
+    }
+
+    #[test]
+    #[ignore = "needs an OpenCL device"]
+    fn gpu_counts_leave_the_primes_on_the_device() {
+        let options = GenerateOptions { method: Method::GpuSieve, ..GenerateOptions::default() };
+        for (start, end) in [(0, 2), (1, 1_000_000), (1_000, 5 * SEGMENT_SIZE as u128 + 123)] {
+            assert_eq!(count_primes(start, end, &options).unwrap(), generate(start, end, Method::Sieve).len() as u64);
+        }
+        let capped = GenerateOptions { max_gpu_mem: Some(1 << 20), ..options };
+        assert_eq!(count_primes(2, 1 << 30, &capped).unwrap(), 54_400_028);
+    }
+
+    #[test]
+    #[ignore = "needs an OpenCL device"]
+    fn double_buffered_segments_match_single_buffered() {
+        let (start, end) = (1_000, 5 * SEGMENT_SIZE as u128 + 123);
+        let marker = GpuMarker::new(end, None).unwrap();
+        let mut pipelined = Vec::new();
+        segmented_sieve(start, end, Some(&marker), None, &mut |chunk: &[u128]| {
+            pipelined.extend_from_slice(chunk);
+            Ok(())
+        })
+        .unwrap();
+
+        // One segment at a time through a single buffer, waiting for each
+        let mut single = Vec::new();
+        let mut composite = vec![false; SEGMENT_SIZE];
+        let mut low = start;
+        while low < end {
+            let segment = &mut composite[..(end - low).min(SEGMENT_SIZE as u128) as usize];
+            marker.mark(segment, low).unwrap();
+            single.extend(collect_unmarked(segment, low, None));
+            low += segment.len() as u128;
+        }
+        assert_eq!(pipelined, single);
+    }
+
+    /// Marks segments on the CPU, failing once it has marked `fail_after` of them.
+    struct CpuMarker {
+        primes: Vec<u32>,
+        fail_after: Option<usize>,
+        marked: usize,
+    }
+
+    impl CpuMarker {
+        fn boxed(end: u128, fail_after: Option<usize>) -> Box<dyn SegmentMarker> {
+            Box::new(CpuMarker { primes: base_primes(end), fail_after, marked: 0 })
+        }
+    }
+
+    impl SegmentMarker for CpuMarker {
+        fn mark_segment(&mut self, segment: &mut [bool], low: u128) -> Result<(), Box<dyn Error>> {
+            if self.fail_after == Some(self.marked) {
+                return Err("device lost".into());
+            }
+            self.marked += 1;
+            mark_segment(segment, low, &self.primes);
+            Ok(())
+        }
+    }
+
+    fn sieve_across(start: u128, end: u128, markers: Vec<Box<dyn SegmentMarker>>) -> Result<Vec<u128>, Box<dyn Error>> {
+        let mut primes = Vec::new();
+        segmented_sieve_across(start, end, markers, None, &mut |chunk: &[u128]| {
+            primes.extend_from_slice(chunk);
+            Ok(())
+        })?;
+        Ok(primes)
+    }
+
+    #[test]
+    fn shared_sieves_merge_in_order_and_survive_failed_workers() {
+        let (start, end) = (1_000, 12 * SEGMENT_SIZE as u128 + 123);
+        let expected = generate(start, end, Method::Sieve);
+        let markers = vec![CpuMarker::boxed(end, None), CpuMarker::boxed(end, None)];
+        assert_eq!(sieve_across(start, end, markers).unwrap(), expected);
+
+        // The failing workers' segments go to the one that keeps working
+        let markers = vec![CpuMarker::boxed(end, Some(0)), CpuMarker::boxed(end, Some(3)), CpuMarker::boxed(end, None)];
+        assert_eq!(sieve_across(start, end, markers).unwrap(), expected);
+
+        let markers = vec![CpuMarker::boxed(end, Some(2)), CpuMarker::boxed(end, Some(1))];
+        let error = sieve_across(start, end, markers).unwrap_err().to_string();
+        assert!(error.starts_with("Every sieve worker failed"), "{}", error);
+    }
+
+    #[test]
+    #[ignore = "needs an OpenCL device"]
+    fn two_queues_on_one_device_match_one_queue() {
+        let (start, end) = (1_000, 9 * SEGMENT_SIZE as u128 + 123);
+        let options = |devices: Vec<usize>| GenerateOptions { method: Method::GpuSieve, devices, ..GenerateOptions::default() };
+        assert_eq!(generate_with(start, end, &options(vec![0, 0])), generate_with(start, end, &options(vec![0])));
+    }
+
+    #[test]
+    fn interrupted_streams_leave_whole_lines() {
+        let dir = std::env::temp_dir().join(format!("mp-partial-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("primes.txt");
+
+        // Stop the run as if Ctrl-C arrived after the first of two batches of segments,
+        // without finishing the sink
+        let mut sink = PrimeSink::to_file(path.to_str().unwrap(), OutputFormat::Lines).unwrap();
+        let options = GenerateOptions { method: Method::Sieve, ..GenerateOptions::default() };
+        let end = (2 * rayon::current_num_threads() * 4 * SEGMENT_SIZE) as u128;
+        let result = generate_primes_with(1, end, &options, &mut |primes: &[u128]| {
+            sink.write_chunk(primes)?;
+            Err("interrupted".into())
+        });
+        assert!(result.is_err());
+        drop(sink);
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.ends_with('\n'));
+        let written: Vec<u128> = text.lines().map(|line| line.parse().unwrap()).collect();
+        let expected = generate(1, end, Method::Sieve);
+        assert!(!written.is_empty() && written.len() < expected.len());
+        assert_eq!(written, expected[..written.len()]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn nth_prime_pins() {
+        assert_eq!(nth_prime(1, 0).unwrap(), 2);
+        assert_eq!(nth_prime(100, 0).unwrap(), 541);
+        assert_eq!(nth_prime(10_000, 0).unwrap(), 104729);
+        assert_eq!(nth_prime(1_000_000, 0).unwrap(), 15485863);
+        // Counting starts above `after`, which may itself be prime
+        assert_eq!(nth_prime(1, 2).unwrap(), 3);
+        assert_eq!(nth_prime(1, 89).unwrap(), 97);
+        assert!(nth_prime(0, 0).is_err());
+        // Past the sieve's reach rather than wrapping around to 0
+        for after in [u64::MAX as u128, u128::MAX] {
+            let error = nth_prime(1, after).unwrap_err().to_string();
+            assert!(error.contains("beyond the 64-bit sieve limit"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn sophie_germain_and_safe_primes() {
+        let primes = generate(1, 200, Method::Sieve);
+        assert_eq!(PrimeFilter::SophieGermain.apply(&primes), [2, 3, 5, 11, 23, 29, 41, 53, 83, 89, 113, 131, 173, 179, 191]);
+        assert_eq!(PrimeFilter::Safe.apply(&primes), [5, 7, 11, 23, 47, 59, 83, 107, 167, 179]);
+        // Companions past 2^64: 2^63 + 29 is prime but 2^64 + 59 isn't
+        assert!(!PrimeFilter::SophieGermain.keeps((1 << 63) + 29));
+        assert!(PrimeFilter::SophieGermain.keeps(9_223_372_036_854_777_359));
+        // 2^64 - 59 is the largest 64-bit prime, and (2^64 - 60) / 2 is even
+        assert!(!PrimeFilter::Safe.keeps(u64::MAX as u128 - 58));
+    }
+
+    #[test]
+    fn primes_in_residue_classes_mod_4() {
+        let progression = |residues: &[u64]| GenerateOptions {
+            progression: Some(Progression::new(4, residues).unwrap()),
+            ..GenerateOptions::default()
+        };
+        assert_eq!(generate_with(1, 100_000, &progression(&[1])).len(), 4783);
+        assert_eq!(generate_with(1, 100_000, &progression(&[3])).len(), 4808);
+        assert_eq!(generate_with(1, 100, &progression(&[1, 3])), generate(3, 100, Method::Sieve));
+
+        let tens = Progression::new(10, &[7, 3, 3]).unwrap();
+        assert_eq!(tens.members(20, 45), [23, 27, 33, 37, 43]);
+        assert!(tens.contains(1_000_003) && !tens.contains(1_000_001));
+        assert!(Progression::new(0, &[0]).is_err());
+        assert!(Progression::new(4, &[4]).is_err());
+    }
+
+    #[test]
+    #[ignore = "needs an OpenCL device"]
+    fn fermat_uploads_only_the_progression() {
+        let options = GenerateOptions {
+            method: Method::Fermat,
+            progression: Some(Progression::new(4, &[1]).unwrap()),
+            ..GenerateOptions::default()
+        };
+        assert_eq!(generate_with(1, 100_000, &options).len(), 4783);
+    }
+
+    #[test]
+    fn gap_records_below_a_million() {
+        let mut stats = GapStats::default();
+        let mut large = Vec::new();
+        generate_primes_with(1, 1_000_000, &GenerateOptions::default(), &mut |chunk: &[u128]| {
+            large.extend(stats.record(chunk, 100));
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(stats.maximal[..4], [(1, 2), (2, 3), (4, 7), (6, 23)]);
+        // Gap 36 first appears after 9551, and the first gap past 100 is 112 after 370261
+        assert!(stats.maximal.contains(&(36, 9551)));
+        assert_eq!(stats.maximal.last(), Some(&(114, 492113)));
+        assert_eq!(large, [(112, 370261), (100, 396733), (114, 492113), (100, 838249)]);
+        assert_eq!(stats.count, 78497);
+        assert_eq!(stats.histogram[&2], 8169);
+        assert!((stats.average().unwrap() - 12.739).abs() < 1e-3);
+
+        // Gaps spanning two batches are counted once
+        let mut stats = GapStats::default();
+        assert!(stats.record(&[89], 8).is_empty());
+        assert_eq!(stats.record(&[97, 101], 8), [(8, 89)]);
+        assert_eq!(stats.count, 2);
+        assert_eq!(GapStats::default().average(), None);
+    }
+
+    #[test]
+    fn range_stats_below_a_hundred() {
+        let mut stats = RangeStats::default();
+        // Batches split the twins 5, 7 and the gap from 89 to 97
+        for chunk in generate_with(1, 100, &GenerateOptions::default()).chunks(3) {
+            stats.record(chunk);
+        }
+        assert_eq!(stats.count, 25);
+        assert_eq!(stats.twins, 8);
+        assert_eq!(stats.largest_gap(), Some((8, 89)));
+        assert_eq!(stats.gaps.average(), Some(95.0 / 24.0));
+        assert_eq!(RangeStats::default().largest_gap(), None);
+    }
+
+    #[test]
+    fn preallocation_fits_the_primes_below_a_hundred_thousand() {
+        // Room for every prime up front, so the vector never reallocates
+        let capacity = Preallocate::Estimate.capacity(1, 100_000);
+        let primes = generate_primes(1, 100_000, &GenerateOptions::default()).unwrap();
+        assert_eq!(primes.len(), 9592);
+        assert_eq!(primes.capacity(), capacity);
+        let options = GenerateOptions { preallocate: Preallocate::Capacity(20_000), ..GenerateOptions::default() };
+        assert_eq!(generate_primes(1, 100_000, &options).unwrap().capacity(), 20_000);
+        // Asking for more room than the range has numbers gets the range
+        let options = GenerateOptions { preallocate: Preallocate::Capacity(usize::MAX), ..GenerateOptions::default() };
+        assert_eq!(generate_primes(1, 1000, &options).unwrap().capacity(), 999);
+    }
+
+    #[test]
+    fn constellations_reach_past_the_end_of_the_range() {
+        // Generation runs the span past the end, so 29 finds 31 in [1, 30)
+        let mut twins = Constellation::twins();
+        let mut found = Vec::new();
+        for chunk in generate_with(1, 30 + twins.span(), &GenerateOptions::default()).chunks(4) {
+            found.extend(twins.matches(chunk, 30));
+        }
+        found.extend(twins.finish(30));
+        assert_eq!(found, [3, 5, 11, 17, 29]);
+
+        let mut triplets = Constellation::new(vec![0, 2, 6]).unwrap();
+        let mut found = triplets.matches(&generate_with(1, 200 + 6, &GenerateOptions::default()), 200);
+        found.extend(triplets.finish(200));
+        assert_eq!(found, [5, 11, 17, 41, 101, 107, 191]);
+
+        for offsets in [vec![0], vec![2, 6], vec![0, 6, 2], vec![0, 2, 2]] {
+            assert!(Constellation::new(offsets.clone()).is_err(), "{:?}", offsets);
+        }
+    }
+
+    #[test]
+    fn twin_pairs_below_a_million() {
+        let mut pairer = TwinPairer::default();
+        let pairs = pairer.pairs(&generate(1, 1000, Method::Sieve));
+        assert_eq!(pairs.len(), 35);
+        assert_eq!(pairs[..3], [(3, 5), (5, 7), (11, 13)]);
+        assert_eq!(pairs.last(), Some(&(881, 883)));
+
+        let mut pairer = TwinPairer::default();
+        let mut count = 0;
+        generate_primes_with(1, 1_000_000, &GenerateOptions::default(), &mut |chunk: &[u128]| {
+            count += pairer.pairs(chunk).len();
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(count, 8169);
+
+        // A pair split across two batches
+        let mut pairer = TwinPairer::default();
+        assert_eq!(pairer.pairs(&[101, 103, 107]), [(101, 103)]);
+        assert_eq!(pairer.pairs(&[109, 113]), [(107, 109)]);
+        assert!(pairer.pairs(&[]).is_empty());
+    }
+
+    #[test]
+    fn next_and_prev_prime_cross_powers_of_ten() {
+        let next = |n: BigUint| next_prime(&n).prime;
+        let prev = |n: BigUint| prev_prime(&n).unwrap().prime;
+        let ten = |k: u32| BigUint::from(10u32).pow(k);
+
+        assert_eq!(next(BigUint::zero()), BigUint::from(2u32));
+        assert_eq!(next(BigUint::from(2u32)), BigUint::from(3u32));
+        assert_eq!(next(BigUint::from(8u32)), BigUint::from(11u32));
+        assert_eq!(next(ten(9)), ten(9) + 7u32);
+        assert_eq!(prev(ten(9)), ten(9) - 63u32);
+        assert_eq!(next(ten(19)), ten(19) + 51u32);
+        assert_eq!(prev(ten(19)), ten(19) - 39u32);
+        // A 100-digit start, well past the deterministic range
+        assert_eq!(next(ten(99)), ten(99) + 289u32);
+        assert_eq!(prev(ten(99)), ten(99) - 621u32);
+
+        assert_eq!(prev(BigUint::from(3u32)), BigUint::from(2u32));
+        assert_eq!(prev(BigUint::from(100u32)), BigUint::from(97u32));
+        for n in [0u32, 1, 2] {
+            assert!(prev_prime(&BigUint::from(n)).is_err(), "{}", n);
+        }
+
+        // Sieving leaves only numbers coprime to the small primes for BPSW
+        let search = next_prime(&ten(99));
+        assert_eq!(search.examined, 289);
+        assert!(search.tested < 60, "{}", search.tested);
+    }
+
+    #[test]
+    fn ranges_past_64_bits_are_refused_by_every_method() {
+        let start = u64::MAX as u128 + 2;
+        for method in [Method::Auto, Method::Sieve, Method::GpuSieve, Method::Fermat] {
+            let options = GenerateOptions { method, ..GenerateOptions::default() };
+            let error = generate_primes(start, start + 100, &options).unwrap_err();
+            assert_eq!(error.downcast_ref::<MpError>(), Some(&MpError::UnsupportedRange { end: start + 100 }));
+        }
+    }
+
+    #[test]
+    fn reversed_ranges_are_refused_rather_than_empty() {
+        let error = generate_primes(100, 1, &GenerateOptions::default()).unwrap_err();
+        assert_eq!(error.downcast_ref::<MpError>(), Some(&MpError::ReversedRange { start: 100, end: 1 }));
+        assert_eq!(error.to_string(), "range start 100 is past its end 1");
+        assert!(generate_primes(100, 100, &GenerateOptions::default()).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,248 @@
+use crate::squarer::{squarer, Squarer};
+use log::{debug, warn};
+use num_bigint::BigUint;
+use num_traits::One;
+use std::error::Error;
+use std::sync::atomic::AtomicU64;
+
+/// Squarings between the checksum updates of a Gerbicz-checked run unless `--gerbicz-block`
+/// says otherwise. The residue is checked every block^2 squarings, at a cost of block
+/// squarings, so the check adds about 1/block to the run.
+pub const DEFAULT_GERBICZ_BLOCK: u128 = 1000;
+
+/// Times in a row a stretch of squarings may fail its check before the run gives up on the
+/// hardware.
+const MAX_RETRIES: u32 = 3;
+
+/// Failed checks the runs of this process recovered from, which the callers of
+/// `GerbiczSquarer` add up here to report.
+pub static GERBICZ_ERRORS: AtomicU64 = AtomicU64::new(0);
+
+/// Builds the squarer a `GerbiczSquarer` runs from a residue, which it does again to roll
+/// back after a failed check.
+pub type SquarerFactory = Box<dyn Fn(&BigUint) -> Box<dyn Squarer + Send> + Send>;
+
+/// A `Squarer` whose squarings are checked with Robert Gerbicz's checksum, for chains of
+/// plain squarings such as the Mersenne PRP test. The -2 of every Lucas-Lehmer iteration
+/// breaks the identity the check rests on.
+///
+/// From a verified residue v, every `block` squarings multiply the checksum d, starting at
+/// v, by the residue u reached. With the residue at every block boundary squared `block`
+/// times into the next, d after k blocks is v times d after k - 1 blocks squared `block`
+/// times, which takes `block` squarings to confirm however large k has grown. The run is
+/// checked so every block^2 squarings and by `finish`, which squares the partial block
+/// past the last boundary twice. A failed check rolls the squarer back to v and squares
+/// the whole stretch again.
+pub struct GerbiczSquarer {
+    p: u128,
+    modulus: BigUint,
+    block: u128,
+    make: SquarerFactory,
+    inner: Box<dyn Squarer + Send>,
+    /// The last residue a check confirmed, and the squarings done since.
+    verified: BigUint,
+    since: u128,
+    checksum: BigUint,
+    /// The checksum before its latest update, which a check squares.
+    previous: BigUint,
+    /// The residue at the latest block boundary, which `finish` squares into the last one.
+    boundary: BigUint,
+    errors: u64,
+}
+
+impl GerbiczSquarer {
+    /// A checked squarer for 2^p - 1 starting from `initial`, which is taken as verified,
+    /// updating its checksum every `block` squarings of the squarer `make` builds.
+    pub fn new(p: u128, initial: &BigUint, block: u128, make: SquarerFactory) -> GerbiczSquarer {
+        assert!(block > 0, "a Gerbicz block takes at least one squaring");
+        let modulus = (BigUint::one() << p) - 1u32;
+        let verified = initial % &modulus;
+        GerbiczSquarer {
+            p,
+            block,
+            inner: make(&verified),
+            make,
+            since: 0,
+            checksum: verified.clone(),
+            previous: verified.clone(),
+            boundary: verified.clone(),
+            verified,
+            modulus,
+            errors: 0,
+        }
+    }
+
+    /// How many failed checks the run has recovered from.
+    pub fn errors(&self) -> u64 {
+        self.errors
+    }
+
+    /// Checks the squarings since the last check and returns the residue they reached.
+    pub fn finish(&mut self) -> Result<BigUint, Box<dyn Error>> {
+        self.check()?;
+        Ok(self.verified.clone())
+    }
+
+    /// Squares once, updating the checksum at a block boundary.
+    fn step(&mut self) -> Result<(), Box<dyn Error>> {
+        self.inner.square_sub(0)?;
+        self.since += 1;
+        if self.since.is_multiple_of(self.block) {
+            self.boundary = self.inner.residue()?;
+            let checksum = &self.checksum * &self.boundary % &self.modulus;
+            self.previous = std::mem::replace(&mut self.checksum, checksum);
+        }
+        Ok(())
+    }
+
+    /// `x` squared `times` times mod 2^p - 1, on a squarer of its own.
+    fn squared(&self, x: &BigUint, times: u128) -> Result<BigUint, Box<dyn Error>> {
+        let mut s = squarer(self.p, x);
+        for _ in 0..times {
+            s.square_sub(0)?;
+        }
+        s.residue()
+    }
+
+    /// Whether the checksum and the squarings past the last block boundary are right.
+    fn checks_out(&self) -> Result<bool, Box<dyn Error>> {
+        let blocks_hold = self.since < self.block
+            || &self.verified * self.squared(&self.previous, self.block)? % &self.modulus == self.checksum;
+        Ok(blocks_hold && self.squared(&self.boundary, self.since % self.block)? == self.inner.residue()?)
+    }
+
+    /// Confirms the squarings since the last check, squaring them again from the last
+    /// verified residue for as long as they fail, up to `MAX_RETRIES` times.
+    fn check(&mut self) -> Result<(), Box<dyn Error>> {
+        for _ in 0..MAX_RETRIES {
+            if self.checks_out()? {
+                debug!("Gerbicz check of 2^{}-1 passed after {} squarings", self.p, self.since);
+                self.verified = self.inner.residue()?;
+                self.since = 0;
+                self.checksum = self.verified.clone();
+                self.boundary = self.verified.clone();
+                return Ok(());
+            }
+            self.errors += 1;
+            warn!("Gerbicz check of 2^{}-1 failed, squaring the last {} residues again", self.p, self.since);
+            let redo = self.since;
+            self.inner = (self.make)(&self.verified);
+            self.since = 0;
+            self.checksum = self.verified.clone();
+            self.boundary = self.verified.clone();
+            for _ in 0..redo {
+                self.step()?;
+            }
+        }
+        Err(format!("the Gerbicz check of 2^{}-1 failed {} times in a row", self.p, MAX_RETRIES).into())
+    }
+}
+
+impl Squarer for GerbiczSquarer {
+    fn square_sub(&mut self, subtract: u32) -> Result<(), Box<dyn Error>> {
+        if subtract != 0 {
+            return Err("the Gerbicz check only covers plain squarings".into());
+        }
+        self.step()?;
+        if self.since == self.block * self.block {
+            self.check()?;
+        }
+        Ok(())
+    }
+
+    /// The residue reached, which a check may not have confirmed yet.
+    fn residue(&self) -> Result<BigUint, Box<dyn Error>> {
+        self.inner.residue()
+    }
+
+    /// The last verified residue and the squarings since, after checking them all when
+    /// `stopping`. A verified residue's checksum is the residue itself, so `new` from it
+    /// picks the run up with the checksum state it had.
+    fn checkpoint(&mut self, stopping: bool) -> Result<(BigUint, u128), Box<dyn Error>> {
+        if stopping && self.since > 0 {
+            self.check()?;
+        }
+        Ok((self.verified.clone(), self.since))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::squarer::BigUintSquarer;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    /// A squarer that flips the low bit of its residue after the squaring `faults` counts
+    /// down to, once across every squarer sharing it.
+    struct Faulty {
+        inner: BigUintSquarer,
+        p: u128,
+        faults: Arc<AtomicU64>,
+    }
+
+    impl Squarer for Faulty {
+        fn square_sub(&mut self, subtract: u32) -> Result<(), Box<dyn Error>> {
+            self.inner.square_sub(subtract)?;
+            if self.faults.fetch_sub(1, Ordering::SeqCst) == 1 {
+                let corrupted = self.inner.residue()? ^ BigUint::one();
+                self.inner = BigUintSquarer::new(self.p, &corrupted);
+            }
+            Ok(())
+        }
+
+        fn residue(&self) -> Result<BigUint, Box<dyn Error>> {
+            self.inner.residue()
+        }
+    }
+
+    /// 3 squared `squarings` times mod 2^p - 1 through a `GerbiczSquarer` of `block` whose
+    /// squarer is corrupted at the squaring `fault`, if any, with the errors it caught.
+    fn checked_run(p: u128, squarings: u128, block: u128, fault: Option<u64>) -> (BigUint, u64) {
+        let faults = Arc::new(AtomicU64::new(fault.unwrap_or(u64::MAX)));
+        let make: SquarerFactory = Box::new(move |x: &BigUint| {
+            Box::new(Faulty { inner: BigUintSquarer::new(p, x), p, faults: Arc::clone(&faults) }) as Box<dyn Squarer + Send>
+        });
+        let mut s = GerbiczSquarer::new(p, &BigUint::from(3u32), block, make);
+        for _ in 0..squarings {
+            s.square_sub(0).unwrap();
+        }
+        (s.finish().unwrap(), s.errors())
+    }
+
+    #[test]
+    fn injected_corruption_is_caught_and_squared_again() {
+        let (p, squarings) = (521, 520);
+        let expected = checked_run(p, squarings, 1, None).0;
+        let modulus = (BigUint::one() << p) - 1u32;
+        assert_eq!(expected, BigUint::from(3u32).modpow(&(BigUint::one() << squarings), &modulus));
+        for block in [1, 4, 7, 30] {
+            assert_eq!(checked_run(p, squarings, block, None), (expected.clone(), 0), "block {}", block);
+            // Early in a block^2 stretch, at a boundary, and in the partial block at the end
+            for fault in [3, 2 * block as u64, squarings as u64 - 2] {
+                assert_eq!(checked_run(p, squarings, block, Some(fault)), (expected.clone(), 1), "block {} fault {}", block, fault);
+            }
+        }
+        let mut s = GerbiczSquarer::new(p, &BigUint::from(3u32), 4, Box::new(move |x: &BigUint| squarer(p, x)));
+        assert!(s.square_sub(2).is_err());
+    }
+
+    #[test]
+    fn checkpoints_hold_only_verified_residues() {
+        let (p, block) = (521, 4);
+        let faults = Arc::new(AtomicU64::new(5));
+        let make: SquarerFactory = Box::new(move |x: &BigUint| {
+            Box::new(Faulty { inner: BigUintSquarer::new(p, x), p, faults: Arc::clone(&faults) }) as Box<dyn Squarer + Send>
+        });
+        let mut s = GerbiczSquarer::new(p, &BigUint::from(3u32), block, make);
+        for _ in 0..10 {
+            s.square_sub(0).unwrap();
+        }
+        // The fault at squaring 5 is not checked yet, so a checkpoint stays at the start
+        assert_eq!(s.checkpoint(false).unwrap(), (BigUint::from(3u32), 10));
+        let modulus = (BigUint::one() << p) - 1u32;
+        let expected = BigUint::from(3u32).modpow(&(BigUint::one() << 10u32), &modulus);
+        assert_eq!(s.checkpoint(true).unwrap(), (expected, 0));
+        assert_eq!(s.errors(), 1);
+    }
+}
@@ -0,0 +1,19 @@
+//! Mersenne prime checking with the Lucas-Lehmer and PRP tests, plus prime generation,
+//! on OpenCL devices with CPU fallbacks.
+
+pub mod arith;
+pub mod config;
+pub mod database;
+pub mod error;
+pub mod factor;
+pub mod generate_primes;
+pub mod gerbicz;
+pub mod logging;
+pub mod ntt;
+pub mod profile;
+pub mod progress;
+pub mod results_log;
+pub mod sieve;
+pub mod squarer;
+pub mod test_prime;
+pub mod worktodo;
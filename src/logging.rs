@@ -0,0 +1,133 @@
+use crate::generate_primes::utc_timestamp;
+use crate::progress::{self, ansi_enabled};
+use env_logger::WriteStyle;
+use log::{LevelFilter, Log, Metadata, Record};
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{IsTerminal, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Lowest level of this crate's records that reach the log file, whatever the verbosity
+/// on stderr, so that the file of an unattended run keeps its checkpoints and checks.
+const FILE_LEVEL: LevelFilter = LevelFilter::Debug;
+
+/// Lowest level of other crates' records that reach the log file.
+const FILE_DEPENDENCY_LEVEL: LevelFilter = LevelFilter::Warn;
+
+/// Sends records to stderr at the `-v` verbosity, or as `RUST_LOG` says when it is set,
+/// clearing any progress bar while a line goes out, and with a log file also appends one
+/// timestamped line per record to it.
+pub struct RunLogger {
+    stderr: env_logger::Logger,
+    file: Option<Mutex<File>>,
+}
+
+impl RunLogger {
+    fn file_enabled(&self, metadata: &Metadata) -> bool {
+        let level = if metadata.target().starts_with(env!("CARGO_CRATE_NAME")) {
+            FILE_LEVEL
+        } else {
+            FILE_DEPENDENCY_LEVEL
+        };
+        self.file.is_some() && metadata.level() <= level
+    }
+}
+
+impl Log for RunLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.stderr.enabled(metadata) || self.file_enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.stderr.matches(record) {
+            progress::suspend(|| self.stderr.log(record));
+        }
+        if let (Some(file), true) = (&self.file, self.file_enabled(record.metadata())) {
+            let line = file_line(record, SystemTime::now());
+            let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+            // A log that can no longer be written must not stop the run it describes
+            let _ = file.write_all(line.as_bytes());
+        }
+    }
+
+    fn flush(&self) {
+        self.stderr.flush();
+        if let Some(file) = &self.file {
+            let _ = file.lock().unwrap_or_else(|e| e.into_inner()).flush();
+        }
+    }
+}
+
+/// A log file line for `record`, e.g.
+/// "2024-03-01T12:00:00Z INFO  test_prime: M127 is prime, res64 0000000000000000".
+fn file_line(record: &Record, time: SystemTime) -> String {
+    let target = record.target();
+    let module = target.strip_prefix(concat!(env!("CARGO_CRATE_NAME"), "::")).unwrap_or(target);
+    format!("{} {:<5} {}: {}\n", utc_timestamp(time), record.level(), module, record.args())
+}
+
+/// Installs the `RunLogger`: this crate's records at warnings and above on stderr, -v adding
+/// info, -vv debug and -vvv trace, and with `log_file` every record down to debug in the file,
+/// which is appended to so that a resumed run continues the log of the one it picks up.
+pub fn init(verbosity: u8, log_file: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    let stderr = env_logger::Builder::new()
+        .filter_level(LevelFilter::Warn)
+        .filter_module(
+            env!("CARGO_CRATE_NAME"),
+            match verbosity {
+                0 => LevelFilter::Warn,
+                1 => LevelFilter::Info,
+                2 => LevelFilter::Debug,
+                _ => LevelFilter::Trace,
+            },
+        )
+        .parse_default_env()
+        .write_style(match ansi_enabled(std::io::stderr().is_terminal()) {
+            true => WriteStyle::Always,
+            false => WriteStyle::Never,
+        })
+        .build();
+    let file = match log_file {
+        Some(path) => Some(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| format!("Cannot open the log file {}: {}", path.display(), e))?,
+        ),
+        None => None,
+    };
+    let max_level = match file {
+        Some(_) => stderr.filter().max(FILE_LEVEL),
+        None => stderr.filter(),
+    };
+    log::set_boxed_logger(Box::new(RunLogger { stderr, file: file.map(Mutex::new) }))?;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn file_lines_carry_a_timestamp_level_and_module() {
+        let time = UNIX_EPOCH + Duration::from_secs(951_827_696);
+        let line = file_line(
+            &Record::builder()
+                .level(Level::Info)
+                .target(concat!(env!("CARGO_CRATE_NAME"), "::test_prime"))
+                .args(format_args!("M127 is prime"))
+                .build(),
+            time,
+        );
+        assert_eq!(line, "2000-02-29T12:34:56Z INFO  test_prime: M127 is prime\n");
+
+        let line = file_line(&Record::builder().level(Level::Warn).target("ocl").args(format_args!("x")).build(), time);
+        assert_eq!(line, "2000-02-29T12:34:56Z WARN  ocl: x\n");
+    }
+}
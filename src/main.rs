@@ -1,187 +1,2785 @@
-use clap::{Arg, Command};
+use clap::builder::Resettable;
+use clap::parser::ValueSource;
+use clap::{Arg, ArgGroup, ArgMatches, Command, Id};
+use clap_complete::Shell;
+use log::{error, info, warn};
 use num_bigint::BigUint;
+use serde_json::json;
+use std::time::{Duration, Instant};
 
-mod test_prime;
-mod generate_primes;
+use mersenne_prime::config::{default_config_path, toml_value, Config, KEYS};
+use mersenne_prime::database::{ResultsDb, TestRecord};
+use mersenne_prime::error::MpError;
+use mersenne_prime::factor::{factorize, sweep_mersenne_factors, DEFAULT_K_LIMIT};
+use mersenne_prime::gerbicz::{DEFAULT_GERBICZ_BLOCK, GERBICZ_ERRORS};
+use mersenne_prime::ntt::DEFAULT_SELF_CHECK_INTERVAL;
+use mersenne_prime::test_prime::{
+    cross_check, is_prime_trial, is_prp_batch_bases, llr, lucas_lehmer_with_threshold, mersenne_prp_report, miller_rabin_report, verify_known_exponents, GpuContext,
+    average_error_bound, error_bound, remove_lucas_lehmer_checkpoints, Backend, LlResult, LucasLehmerOptions,
+    MillerRabinReport, PrpOptions, TestPlan, CHECKPOINT_EVERY, DEFAULT_BATCH_SIZE, INTERRUPTED,
+    KERNEL_DEFINES, LL_GPU_THRESHOLD, PROGRAM_CACHE,
+};
+use mersenne_prime::generate_primes::{
+    count_primes, delimited_row, device_name, generate_primes_with, opencl_devices, is_binary_prime_file, next_prime, open_prime_file, opencl_available, nth_prime, prev_prime, read_primes_from_binary,
+    Checkpoint, Constellation, GenerateOptions, GenerationProgress, Method, GapStats, LARGE_SPAN, OutputFormat, OutputMetadata, PrimeFilter, PrimeSink, Progression, RangeStats,
+    DEFAULT_BASES, GPU_RANGE_THRESHOLD,
+};
+use mersenne_prime::logging;
+use mersenne_prime::profile::{self, Phase, TIMINGS};
+use mersenne_prime::results_log::{lucas_lehmer_line, Done};
+use mersenne_prime::progress::{ansi_enabled, set_color, ColorChoice, HIDE_PROGRESS, JSON_PROGRESS, LOG_PROGRESS, SHARE_PROGRESS, STATUS_INTERVAL};
+use mersenne_prime::sieve::{prime_count_estimate, smallest_prime_factors, Preallocate, Sieve};
+use mersenne_prime::worktodo;
+use std::io::{BufRead, IsTerminal, Read, Write};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
 
-use test_prime::{is_prp, lucas_lehmer};
-use generate_primes::{generate_primes, write_primes_to_file};
+/// The exit status of a `--time-limit` run that paused, for a scheduler to requeue it on:
+/// EX_TEMPFAIL from sysexits.h.
+const EXIT_PAUSED: i32 = 75;
+
+/// Where compiled OpenCL programs are kept without `--program-cache`: the user's cache
+/// directory, if the environment names one.
+fn default_program_cache() -> Option<PathBuf> {
+    let non_empty = |name| std::env::var_os(name).filter(|value| !value.is_empty()).map(PathBuf::from);
+    non_empty("XDG_CACHE_HOME").or_else(|| non_empty("HOME").map(|home| home.join(".cache"))).map(|dir| dir.join("mp"))
+}
+
+/// The `--config` path on the command line, found before clap parses it so that the file
+/// can supply the defaults clap parses with.
+fn config_argument() -> Option<PathBuf> {
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        }
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix("--config=")) {
+            return Some(PathBuf::from(path));
+        }
+    }
+    None
+}
+
+/// The configuration from `--config`, or from `default_config_path` if that file exists.
+/// Warns about the keys it doesn't know and exits on a file it can't read.
+fn read_config() -> Config {
+    let path = match config_argument() {
+        Some(path) => path,
+        None => match default_config_path().filter(|path| path.exists()) {
+            Some(path) => path,
+            None => return Config::default(),
+        },
+    };
+    match Config::load(&path) {
+        Ok((config, warnings)) => {
+            for warning in warnings {
+                eprintln!("Warning: {}", warning);
+            }
+            config
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// `command` with the MP_* variable of every configurable option, and the settings of
+/// `config` as their defaults, so that options override the variables and both the file.
+fn with_config(mut command: Command, config: &Config) -> Command {
+    for key in KEYS {
+        if let Some(id) = key.arg {
+            command = command.mut_arg(id, |arg| arg.env(key.env));
+        }
+    }
+    for (key, value) in config.values() {
+        if let Some(id) = key.arg {
+            let value = value.to_string();
+            command = command.mut_arg(id, |arg| arg.default_value(value));
+        }
+    }
+    command
+}
+
+/// Whether `id` was set on the command line or by its MP_* variable, rather than defaulted
+/// or taken from the config file.
+fn given(matches: &ArgMatches, id: &str) -> bool {
+    matches!(matches.value_source(id), Some(ValueSource::CommandLine | ValueSource::EnvVariable))
+}
+
+/// The flags the config file may switch on, and the options that switch them off again.
+const NEGATIONS: &[(&str, &str)] = &[("memory", "no_memory"), ("skip_done", "redo")];
+
+/// Prints every configurable setting in effect as TOML, each with where it came from.
+fn print_config(matches: &ArgMatches, config: &Config) {
+    match &config.path {
+        Some(path) => println!("# config file: {}", path.display()),
+        None => println!("# no config file"),
+    }
+    let subcommand = matches.subcommand().map(|(_, subcommand)| subcommand);
+    let mut section = "";
+    for key in KEYS {
+        if key.section != section {
+            section = key.section;
+            println!("\n[{}]", section);
+        }
+        let env = std::env::var(key.env).ok().filter(|value| !value.is_empty());
+        let (value, source) = match key.arg {
+            Some(id) => {
+                // The options of a subcommand are set in its own matches
+                let matches = subcommand.filter(|subcommand| matches!(subcommand.try_get_raw(id), Ok(Some(_)))).unwrap_or(matches);
+                let value = matches
+                    .get_raw(id)
+                    .map(|values| values.map(|value| value.to_string_lossy()).collect::<Vec<_>>().join(","));
+                let source = match matches.value_source(id) {
+                    Some(ValueSource::CommandLine) => "command line".to_string(),
+                    Some(ValueSource::EnvVariable) => key.env.to_string(),
+                    Some(ValueSource::DefaultValue) if config.get(key).is_some() => "config file".to_string(),
+                    _ => "default".to_string(),
+                };
+                let negation = NEGATIONS.iter().find(|&&(flag, _)| flag == id).map(|&(_, negation)| negation);
+                match negation {
+                    Some(negation) if matches.try_get_one::<bool>(negation).ok().flatten() == Some(&true) => {
+                        (Some("false".to_string()), "command line".to_string())
+                    }
+                    _ => (value, source),
+                }
+            }
+            None => match (env, config.get(key)) {
+                (Some(value), _) => (Some(value), key.env.to_string()),
+                (None, Some(value)) => (Some(value.to_string()), "config file".to_string()),
+                (None, None) => (None, String::new()),
+            },
+        };
+        match value {
+            Some(value) => println!("{} = {}  # {}", key.key, toml_value(key, &value), source),
+            None => println!("# {} is not set", key.key),
+        }
+    }
+}
+
+/// A subcommand, which replaces the top-level mode option it stands for: `mp ll 127` does
+/// what `mp --ll 127` still does, and `mp gen 1 100` what `mp --generate 1 100` does.
+struct Mode {
+    name: &'static str,
+    about: &'static str,
+    /// The id of the mode option, which is also its long name.
+    flag: &'static str,
+    /// The ids of the mode option and the groups of mode options that the options of the
+    /// subcommand may require, which the subcommand itself stands for.
+    stands_for: &'static [&'static str],
+    positional: fn() -> Arg,
+    /// The ids of the options that take the place of the positional values.
+    inputs: &'static [&'static str],
+    /// The ids of the top-level options the subcommand takes.
+    options: &'static [&'static str],
+}
+
+const MODES: &[Mode] = &[
+    Mode {
+        name: "ll",
+        about: "Runs the Lucas-Lehmer test on 2^p-1 for each exponent p",
+        flag: "ll",
+        stands_for: &["ll", "lucas_lehmer", "mersenne_test", "primality_test"],
+        positional: || {
+            Arg::new("number")
+                .value_name("EXPONENT")
+                .num_args(1..)
+                .help("Exponents p of the Mersenne numbers 2^p-1 to test")
+        },
+        inputs: &["from_list", "worktodo", "repl"],
+        options: &[
+            "no_clear", "memory", "no_memory", "jobs", "chunked_progress", "batch_size", "backend", "self_check", "expected_residue", "time_limit",
+            "shift", "timeout", "gpu_threshold", "profile", "output", "result_file", "primes_out", "composites_out", "save_results_json", "sqlite",
+            "dry_run", "dedup", "skip_done", "redo", "quiet", "repl", "from_list", "worktodo", "read_binary", "format",
+        ],
+    },
+    Mode {
+        name: "prp",
+        about: "Runs a probable-prime test on each number",
+        flag: "prp",
+        stands_for: &["prp", "primality_test"],
+        positional: || {
+            Arg::new("number")
+                .value_name("NUMBER")
+                .num_args(1..)
+                .allow_negative_numbers(true)
+                .help("Numbers to test")
+        },
+        inputs: &["from_list", "repl"],
+        options: &[
+            "bases", "base_file", "trial", "output", "primes_out", "composites_out", "save_results_json", "sqlite", "dry_run", "dedup", "skip_done", "redo",
+            "quiet", "repl", "from_list", "read_binary", "format",
+        ],
+    },
+    Mode {
+        name: "gen",
+        about: "Generates the primes from START to END",
+        flag: "generate",
+        stands_for: &[],
+        positional: || {
+            Arg::new("generate")
+                .num_args(2)
+                .value_names(["START", "END"])
+                .value_parser(clap::value_parser!(u128))
+                .required(true)
+                .help("The range to generate primes in")
+        },
+        inputs: &[],
+        options: &[
+            "fermat", "bases", "base_file", "no_verify", "cpu", "gpu", "devices", "gpu_threshold", "max_gpu_mem", "preallocate_results", "profile", "tune", "local_size", "sieve", "mersenne_candidates",
+            "min_factor", "max_factor", "sieve_output", "twins", "constellation", "sophie_germain", "safe", "gaps", "min_gap", "mod", "residue", "count",
+            "stats", "output", "compress", "no_header", "resume", "output_format", "columns", "format", "sqlite",
+        ],
+    },
+    Mode {
+        name: "factor",
+        about: "Factors a number below 2^64 into primes",
+        flag: "factor",
+        stands_for: &[],
+        positional: || {
+            Arg::new("factor")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u64).range(2..))
+                .required(true)
+                .help("The number to factor")
+        },
+        inputs: &[],
+        options: &[],
+    },
+];
+
+/// Options of the subcommands that modes without one (--nth, --prp-mersenne, ...) take too,
+/// which stay in the top-level help.
+const SHARED_OPTIONS: &[&str] = &["memory", "no_memory", "shift", "expected_residue", "time_limit", "bases", "base_file", "skip_done", "redo", "format"];
+
+/// `command` with a subcommand for each of `MODES`, and with the top-level spellings they
+/// replace hidden from its help, though they keep working.
+fn with_modes(mut command: Command) -> Command {
+    for mode in MODES {
+        let mut positional = (mode.positional)();
+        if !mode.inputs.is_empty() {
+            positional = positional.required_unless_present_any(mode.inputs);
+        }
+        let ids: Vec<Id> = mode.options.iter().map(|&id| Id::from(id)).chain([positional.get_id().clone()]).collect();
+        let given: Vec<Id> = mode.inputs.iter().map(|&id| Id::from(id)).chain([positional.get_id().clone()]).collect();
+        let mut subcommand = Command::new(mode.name).about(mode.about).arg(positional);
+        for &id in mode.options {
+            let option = command.get_arguments().find(|arg| arg.get_id() == id).expect("modes take defined options");
+            // Conflicts with the other modes and their options have no place here
+            let conflicts: Vec<Id> = command
+                .get_arg_conflicts_with(option)
+                .into_iter()
+                .map(|arg| arg.get_id().clone())
+                .filter(|id| ids.contains(id))
+                .collect();
+            subcommand = subcommand.arg(option.clone().conflicts_with(Resettable::Reset).conflicts_with_all(conflicts));
+        }
+        // Whatever the subcommand runs on is given, so an option requiring the mode is satisfied
+        for &group in mode.stands_for {
+            subcommand = subcommand.group(ArgGroup::new(group).args(&given).multiple(true));
+        }
+        command = command.subcommand(subcommand);
+    }
+    let legacy: BTreeSet<&str> = MODES
+        .iter()
+        .flat_map(|mode| [mode.flag, "number"].into_iter().chain(mode.options.iter().copied()))
+        .filter(|id| !SHARED_OPTIONS.contains(id))
+        .collect();
+    for id in legacy {
+        command = command.mut_arg(id, |arg| arg.hide(true));
+    }
+    command.subcommand_negates_reqs(true).arg_required_else_help(true)
+}
+
+/// Exits with a usage error if `matches` of `command` run a subcommand but give it one of
+/// the top-level options before it, which the subcommand would never see. Global options
+/// can go anywhere.
+fn check_options_before_subcommand(command: &Command, matches: &ArgMatches) {
+    let Some(name) = matches.subcommand_name() else {
+        return;
+    };
+    for arg in command.get_arguments().filter(|arg| !arg.is_global_set()) {
+        if matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine) {
+            let spelling = arg.get_long().map_or_else(|| arg.get_id().to_string(), |long| format!("--{}", long));
+            clap::Error::raw(
+                clap::error::ErrorKind::ArgumentConflict,
+                format!("{} must come after the subcommand, as in `mp {} {}`\n", spelling, name, spelling),
+            )
+            .exit();
+        }
+    }
+}
+
+/// Prints the `shell` completion script for `command`, under the name the binary was run as.
+fn print_completions(shell: Shell, mut command: Command) {
+    let name = std::env::args_os()
+        .next()
+        .and_then(|arg0| Path::new(&arg0).file_name().map(|name| name.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| env!("CARGO_BIN_NAME").to_string());
+    clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+}
+
+/// What this build can run on this machine, as JSON for wrapper scripts: whether OpenCL is
+/// there, its devices, and the Lucas-Lehmer backends those allow. Without OpenCL the probe
+/// reports `"opencl": false` rather than failing.
+fn capabilities_json() -> String {
+    let devices = opencl_devices().unwrap_or_default();
+    let opencl = !devices.is_empty();
+    let devices: Vec<serde_json::Value> = devices
+        .iter()
+        .enumerate()
+        .map(|(index, device)| {
+            json!({
+                "index": index,
+                "name": device.name().unwrap_or_default(),
+                "vendor": device.vendor().unwrap_or_default(),
+            })
+        })
+        .collect();
+    // The NTT kernels are always compiled in; like the 64-bit kernel they need OpenCL to run
+    let capabilities = json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "opencl": opencl,
+        "devices": devices,
+        "backends": {"cpu": true, "kernel": opencl, "ntt": opencl},
+        "fft": ["ntt"],
+    });
+    serde_json::to_string_pretty(&capabilities).expect("JSON values always serialize")
+}
+
+/// Prints `n` as a product of prime powers.
+fn print_factorization(n: u64) {
+    println!("{} = {}", n, factorization(&factorize(n)));
+}
+
+/// `factors`, in ascending order, written as a product of prime powers, e.g. "2^3 * 3^2 * 5".
+fn factorization(factors: &[u64]) -> String {
+    factors
+        .chunk_by(|a, b| a == b)
+        .map(|run| match run.len() {
+            1 => run[0].to_string(),
+            power => format!("{}^{}", run[0], power),
+        })
+        .collect::<Vec<_>>()
+        .join(" * ")
+}
+
+/// Reads the entries of a `--from-list` file, decompressing gzipped files and decoding
+/// binary prime files written by `-g`, which `read_binary` insists the file is.
+///
+/// Text entries are trimmed, and blank lines and lines starting with `#` are skipped so
+/// lists can carry notes.
+fn read_list(filename: &str, read_binary: bool) -> Vec<String> {
+    if read_binary || is_binary_prime_file(filename) {
+        match read_primes_from_binary(filename) {
+            Ok(primes) => return primes.iter().map(|p| p.to_string()).collect(),
+            Err(e) => {
+                eprintln!("Error reading binary prime file: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    let mut contents = String::new();
+    open_prime_file(filename)
+        .and_then(|mut reader| reader.read_to_string(&mut contents))
+        .expect("Failed to read file");
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Parses the numbers to test from `--from-list`, `--worktodo` (which `mp prp` doesn't
+/// have) or the command line, reporting and skipping the ones that aren't valid, and
+/// warning about repeats (or, with `--dedup`, dropping them).
+fn read_numbers(matches: &ArgMatches) -> Vec<u128> {
+    let mut numbers = Vec::new();
+    if let Some(filename) = matches.get_one::<String>("from_list") {
+        println!("Reading numbers from file {}...", filename);
+        for number_str in read_list(filename, matches.get_flag("read_binary")) {
+            match number_str.parse::<u128>() {
+                Ok(num) => numbers.push(num),
+                Err(_) => eprintln!("Invalid number in file: {}", number_str),
+            }
+        }
+    } else if let Some(filename) = matches.try_get_one::<PathBuf>("worktodo").ok().flatten() {
+        match worktodo::load(filename) {
+            Ok((exponents, warnings)) => {
+                for warning in warnings {
+                    eprintln!("Warning: {}", warning);
+                }
+                numbers = exponents;
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(number_strs) = matches.get_many::<String>("number") {
+        for number_str in number_strs {
+            match number_str.parse::<u128>() {
+                Ok(num) => numbers.push(num),
+                Err(_) => report_invalid(number_str),
+            }
+        }
+    }
+
+    let mut seen = std::collections::HashSet::new();
+    let mut repeated = Vec::new();
+    for &number in &numbers {
+        if !seen.insert(number) && !repeated.contains(&number) {
+            repeated.push(number);
+        }
+    }
+    if !repeated.is_empty() {
+        let list = repeated.iter().map(|n| n.to_string()).collect::<Vec<_>>().join(", ");
+        if matches.get_flag("dedup") {
+            eprintln!("Skipping repeated numbers: {}", list);
+            let mut kept = std::collections::HashSet::new();
+            numbers.retain(|&number| kept.insert(number));
+        } else {
+            eprintln!("Warning: repeated numbers will be tested again: {} (--dedup tests each once)", list);
+        }
+    }
+    numbers
+}
+
+/// The bases given by `--bases` and then `--base-file`, without repeats, or `None` when
+/// neither was given. Exits on a base in the file that isn't a number of at least 2.
+fn read_bases(matches: &ArgMatches) -> Option<Vec<u64>> {
+    // Bases from the config file count only without a --base-file, which replaces them
+    // rather than adding to them
+    let from_config = matches.value_source("bases") == Some(ValueSource::DefaultValue);
+    let mut bases: Vec<u64> = match matches.get_many::<u64>("bases") {
+        Some(bases) if !(from_config && matches.contains_id("base_file")) => bases.copied().collect(),
+        _ => Vec::new(),
+    };
+    if let Some(filename) = matches.get_one::<String>("base_file") {
+        for base_str in read_list(filename, false) {
+            match base_str.parse::<u64>() {
+                Ok(base) if base >= 2 => bases.push(base),
+                _ => {
+                    eprintln!("Invalid base in {}: {} (bases are numbers of at least 2)", filename, base_str);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+    let mut seen = std::collections::HashSet::new();
+    bases.retain(|&base| seen.insert(base));
+    (matches.contains_id("bases") || matches.contains_id("base_file")).then_some(bases)
+}
+
+/// The file `-o` names, or `None` when the output is standard output (no `-o`, or `-o -`).
+fn output_file(matches: &ArgMatches) -> Option<&String> {
+    matches.get_one::<String>("output").filter(|name| *name != "-")
+}
+
+/// How a `-g` run over [start, end) finds and filters its primes, for the header of its
+/// output file.
+fn output_metadata(matches: &ArgMatches, options: &GenerateOptions, start: u128, end: u128) -> OutputMetadata {
+    let mut bases = Vec::new();
+    let method = match matches.get_one::<String>("sieve").map(String::as_str) {
+        Some("atkin") => "sieve of Atkin".to_string(),
+        Some(_) => "sieve of Eratosthenes".to_string(),
+        None => match options.resolved_method(start, end) {
+            Method::Fermat => {
+                bases = options.bases.clone();
+                if options.verify {
+                    "strong probable-prime test (GPU), pseudoprimes removed".to_string()
+                } else {
+                    "strong probable-prime test (GPU), unverified".to_string()
+                }
+            }
+            Method::GpuSieve if options.devices.len() > 1 => {
+                format!("segmented sieve (GPU, shared by devices {:?})", options.devices)
+            }
+            Method::GpuSieve => "segmented sieve (GPU)".to_string(),
+            _ => "segmented sieve (CPU)".to_string(),
+        },
+    };
+    let mut filters = Vec::new();
+    if matches.get_flag("sophie_germain") {
+        filters.push("Sophie Germain primes".to_string());
+    } else if matches.get_flag("safe") {
+        filters.push("safe primes".to_string());
+    }
+    if let Some(&modulus) = matches.get_one::<u64>("mod") {
+        let residues: Vec<String> = matches.get_many::<u64>("residue").unwrap().map(u64::to_string).collect();
+        filters.push(format!("p mod {} in {{{}}}", modulus, residues.join(", ")));
+    }
+    OutputMetadata { start, end, method, bases, filters }
+}
+
+/// The device indices `--devices` names: `all`, or a comma-separated list that may repeat
+/// an index to run several queues on one device.
+fn parse_devices(list: &str) -> Result<Vec<usize>, Box<dyn std::error::Error>> {
+    if list == "all" {
+        let count = opencl_devices()?.len();
+        if count == 0 {
+            return Err("No OpenCL devices found.".into());
+        }
+        return Ok((0..count).collect());
+    }
+    list.split(',')
+        .map(|index| {
+            index.trim().parse().map_err(|_| format!("--devices takes indices like 0,1 or all, not {}", list).into())
+        })
+        .collect()
+}
+
+/// The separator of `--format csv` or `--format tsv` rows, if either was asked for.
+fn delimiter(matches: &ArgMatches) -> Option<char> {
+    match matches.get_one::<String>("format").map(String::as_str) {
+        Some("csv") => Some(','),
+        Some("tsv") => Some('\t'),
+        _ => None,
+    }
+}
+
+/// The res64 `--expected-residue` takes: 1 to 16 hex digits in either case, with or
+/// without a leading 0x.
+fn parse_residue(text: &str) -> Result<u64, String> {
+    let text = text.trim();
+    let digits = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")).unwrap_or(text);
+    if digits.is_empty() || digits.len() > 16 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("expected 1 to 16 hex digits, with or without 0x, not {}", text));
+    }
+    u64::from_str_radix(digits, 16).map_err(|e| e.to_string())
+}
+
+/// The sizes `--max-gpu-mem` takes: a whole number of bytes, optionally followed by a unit,
+/// KiB, MiB, GiB or TiB (or K, M, G, T) in powers of 1024, or KB, MB, GB or TB in powers of
+/// 1000, like 2GiB or "512 MiB".
+fn parse_bytes(text: &str) -> Result<u64, String> {
+    let invalid = || format!("expected a size like 2GiB, not {}", text);
+    let text = text.trim();
+    let digits = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let amount: u64 = text[..digits].parse().map_err(|_| invalid())?;
+    let unit: u64 = match text[digits..].trim_start().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kib" => 1 << 10,
+        "m" | "mib" => 1 << 20,
+        "g" | "gib" => 1 << 30,
+        "t" | "tib" => 1 << 40,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        "tb" => 1_000_000_000_000,
+        _ => return Err(invalid()),
+    };
+    match amount.checked_mul(unit) {
+        Some(0) | None => Err(invalid()),
+        Some(bytes) => Ok(bytes),
+    }
+}
+
+/// What `--preallocate-results` takes: auto, none, or a count of primes.
+fn parse_preallocate(text: &str) -> Result<Preallocate, String> {
+    match text {
+        "auto" => Ok(Preallocate::Estimate),
+        "none" => Ok(Preallocate::Grow),
+        count => count.parse().map(Preallocate::Capacity).map_err(|_| format!("expected auto, none or a count of primes, not {}", text)),
+    }
+}
+
+/// How the res64 of a finished run compares with `--expected-residue`, if it was given.
+fn residue_check(name: &str, res64: u64, expected: Option<u64>) -> Option<(String, bool)> {
+    let expected = expected?;
+    Some(if res64 == expected {
+        (format!("{} res64 {:016x}: MATCH", name, res64), true)
+    } else {
+        (format!("{} res64 {:016x}: MISMATCH (expected {:016x})", name, res64, expected), false)
+    })
+}
+
+/// Explains why `number_str` isn't a number the tests take.
+fn report_invalid(number_str: &str) {
+    if number_str.starts_with('-') && number_str[1..].parse::<u128>().is_ok() {
+        eprintln!("Negative numbers are not supported: {}", number_str);
+    } else {
+        eprintln!("Please enter a valid number: {}", number_str);
+    }
+}
+
+/// Whether `number` can be tested, reporting it when it is 0 or 1, which `what` (the
+/// plural of what it is, e.g. "PRP numbers") can't be.
+fn at_least_two(number: u128, what: &str) -> bool {
+    if number < 2 {
+        eprintln!("{} must be at least 2, got {}.", what, number);
+    }
+    number >= 2
+}
+
+/// Prints what `--dry-run` found out about a batch of `test` runs.
+fn print_plan(test: &str, plan: &TestPlan, too_large: &str) {
+    println!("Dry run of {} {} tests, nothing will be run", plan.count, test);
+    println!("Iterations: {}", plan.iterations);
+    println!("Memory: {} bytes", plan.memory_bytes);
+    match device_name() {
+        Some(name) => println!("Device: {}", name),
+        None => println!("Device: none (no OpenCL device found)"),
+    }
+    for number in &plan.too_large {
+        println!("Warning: {} does not fit the 64-bit kernel and {}", number, too_large);
+    }
+}
+
+/// Writes the `--gaps` report, leaving out gaps smaller than `min_gap`.
+fn write_gap_report(writer: &mut dyn Write, stats: &GapStats, min_gap: u128) -> Result<(), Box<dyn std::error::Error>> {
+    writeln!(writer, "Maximal gaps:")?;
+    for (gap, prime) in stats.maximal.iter().filter(|(gap, _)| *gap >= min_gap) {
+        writeln!(writer, "  {} after {}", gap, prime)?;
+    }
+    writeln!(writer, "Histogram:")?;
+    for (gap, count) in stats.histogram.range(min_gap..) {
+        writeln!(writer, "  {}: {}", gap, count)?;
+    }
+    match stats.average() {
+        Some(average) => writeln!(writer, "Average gap: {:.3} over {} gaps", average, stats.count)?,
+        None => writeln!(writer, "Average gap: none, fewer than two primes in the range")?,
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Prints the `--stats` summary of the primes generated, with the x / ln x estimate of
+/// their count when the range was not filtered.
+fn print_range_stats(stats: &RangeStats, estimate: Option<f64>) {
+    match estimate {
+        Some(estimate) => eprintln!("Primes: {} (x / ln x estimates {:.1})", stats.count, estimate),
+        None => eprintln!("Primes: {}", stats.count),
+    }
+    match stats.largest_gap() {
+        Some((gap, prime)) => eprintln!("Largest gap: {} between {} and {}", gap, prime, prime + gap),
+        None => eprintln!("Largest gap: none, fewer than two primes"),
+    }
+    if let Some(average) = stats.gaps.average() {
+        eprintln!("Average gap: {:.3} over {} gaps", average, stats.gaps.count);
+    }
+    eprintln!("Twin pairs: {}", stats.twins);
+}
+
+/// PRP verdicts for `numbers` over every one of `bases` with the time each took, batched on
+/// the GPU when there is an OpenCL device. A GPU batch is timed as a whole and shares its
+/// time evenly.
+fn prp_verdicts(numbers: &[BigUint], bases: &[u128]) -> Vec<(bool, Duration)> {
+    if opencl_available() {
+        let started = Instant::now();
+        match is_prp_batch_bases(numbers, bases) {
+            Ok(verdicts) => {
+                let share = started.elapsed() / numbers.len().max(1) as u32;
+                return verdicts.into_iter().map(|verdict| (verdict, share)).collect();
+            }
+            Err(e) => warn!("GPU PRP test failed ({}), testing on the CPU", e),
+        }
+    }
+    numbers
+        .iter()
+        .map(|n| {
+            let started = Instant::now();
+            let verdict = matches!(miller_rabin_report(n, bases), MillerRabinReport::ProbablyPrime { .. });
+            (verdict, started.elapsed())
+        })
+        .collect()
+}
+
+/// Prints the `--profile` breakdown of a run when dropped, so that every way a mode
+/// returns reports it.
+struct ProfileReport {
+    started: Instant,
+}
+
+impl ProfileReport {
+    /// Starts timing the run, if `--profile` asks for the breakdown.
+    fn start(matches: &ArgMatches) -> Option<ProfileReport> {
+        matches.get_flag("profile").then(|| ProfileReport { started: Instant::now() })
+    }
+}
+
+impl Drop for ProfileReport {
+    fn drop(&mut self) {
+        eprint!("\n{}", TIMINGS.report(self.started.elapsed()));
+    }
+}
+
+/// Exact verdicts for `numbers` by trial division, with the time each took.
+fn trial_verdicts(numbers: &[u128]) -> Vec<(bool, Duration)> {
+    numbers
+        .iter()
+        .map(|&n| {
+            let started = Instant::now();
+            (is_prime_trial(n), started.elapsed())
+        })
+        .collect()
+}
+
+/// Whether runs keep checkpoints: with -m, or checkpoint.enabled in the config file unless
+/// `--no-memory` turns it off, and always under a `--time-limit`, which resumes from them.
+fn keeps_checkpoints(matches: &ArgMatches) -> bool {
+    (matches.get_flag("memory") && !matches.get_flag("no_memory")) || matches.contains_id("time_limit")
+}
+
+/// How `-l` runs each test, from its options.
+fn lucas_lehmer_options(matches: &ArgMatches) -> LucasLehmerOptions {
+    LucasLehmerOptions {
+        mem: keeps_checkpoints(matches),
+        checkpoint_dir: matches.get_one::<PathBuf>("checkpoint_dir").cloned(),
+        shift: *matches.get_one::<u64>("shift").unwrap(),
+        timeout: matches.get_one::<u64>("timeout").map(|&secs| Duration::from_secs(secs)),
+        deadline: matches.get_one::<Duration>("time_limit").map(|&limit| Instant::now() + limit),
+        keep_checkpoint: false,
+        status: matches.get_one::<String>("chunked_progress").map(PathBuf::from),
+        batch_size: matches.get_one::<u64>("batch_size").map_or(DEFAULT_BATCH_SIZE, |&size| u128::from(size)),
+        gpu_threshold: matches.get_one::<u128>("gpu_threshold").copied().unwrap_or(LL_GPU_THRESHOLD),
+        backend: match matches.get_one::<String>("backend").map(String::as_str) {
+            Some("ntt") => Backend::Ntt,
+            _ => Backend::Kernel,
+        },
+        self_check: Some(matches.get_one::<u64>("self_check").map_or(DEFAULT_SELF_CHECK_INTERVAL, |&n| u128::from(n)))
+            .filter(|&interval| interval > 0),
+    }
+}
+
+/// The Gerbicz block of the PRP squarings, none with `--gerbicz-block 0`.
+fn gerbicz_block(matches: &ArgMatches) -> Option<u128> {
+    Some(matches.get_one::<u64>("gerbicz_block").map_or(DEFAULT_GERBICZ_BLOCK, |&block| u128::from(block))).filter(|&block| block > 0)
+}
+
+/// Prints how many errors the Gerbicz checks of this run caught, if any.
+fn report_gerbicz_errors() {
+    let errors = GERBICZ_ERRORS.load(Ordering::Relaxed);
+    info!("Gerbicz checks caught {} errors", errors);
+    if errors > 0 {
+        println!("Gerbicz checks caught {} error{}, each corrected by squaring its block again.", errors, if errors == 1 { "" } else { "s" });
+    }
+}
+
+/// Whether an -l run clears the terminal first: only for a single exponent given on the
+/// command line, printed as plain text to a terminal that --color lets it clear, and
+/// without --no-clear, so that a batch never wipes the results of the runs before it.
+fn clears_screen(matches: &ArgMatches) -> bool {
+    !matches.get_flag("no_clear")
+        && matches.get_many::<String>("number").is_some_and(|numbers| numbers.len() == 1)
+        && matches.get_one::<String>("format").map(String::as_str) == Some("plain")
+        && ansi_enabled(std::io::stdout().is_terminal())
+}
+
+/// Runs `-l`, or `-p` without `ll`, on each number read from stdin as soon as it is
+/// entered, until EOF or `quit`. Lucas-Lehmer runs share one OpenCL context, built on the
+/// first exponent at or above the GPU threshold.
+fn run_repl(matches: &ArgMatches, ll: bool) {
+    // `mp ll` has no bases and `mp prp` no Lucas-Lehmer options
+    let options = if ll { lucas_lehmer_options(matches) } else { LucasLehmerOptions::default() };
+    let json = matches.get_one::<String>("format").map(String::as_str) == Some("json");
+    let bases = if ll { Vec::new() } else { read_bases(matches).unwrap_or_else(|| vec![2]) };
+    let bases: Vec<u128> = bases.into_iter().map(u128::from).collect();
+    let interactive = std::io::stdin().is_terminal();
+    let mut context: Option<GpuContext> = None;
+    let mut lines = std::io::stdin().lock().lines();
+    loop {
+        if interactive {
+            print!("> ");
+            std::io::stdout().flush().expect("Failed to write prompt");
+        }
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            Some(Err(e)) => {
+                eprintln!("Error reading input: {}", e);
+                break;
+            }
+            None => break,
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line == "quit" || line == "exit" {
+            break;
+        }
+        let number = match line.parse::<u128>() {
+            Ok(number) => number,
+            Err(_) => {
+                report_invalid(line);
+                continue;
+            }
+        };
+        if !at_least_two(number, if ll { "Lucas-Lehmer exponents" } else { "PRP numbers" }) {
+            continue;
+        }
+        if ll {
+            match lucas_lehmer_with_threshold(&mut context, number, &options) {
+                Ok(result) if json => println!("{}", json!({"exponent": number, "mersenne_prime": result.is_prime})),
+                Ok(result) => {
+                    let m = (BigUint::from(1u32) << number) - 1u32;
+                    println!("{} is {}a Mersenne prime.", m, if result.is_prime { "" } else { "not " });
+                }
+                Err(e) => eprintln!("Error testing {}: {}", number, e),
+            }
+        } else if let MillerRabinReport::ProbablyPrime { .. } = miller_rabin_report(&BigUint::from(number), &bases) {
+            println!("{}: Probably prime", number);
+        } else {
+            println!("{}: Probably not prime", number);
+        }
+    }
+}
+
+/// The `-v` note on how likely a `bits`-bit probable prime that passed `rounds` bases is to
+/// be composite after all: the worst case, and the average over numbers of its size when
+/// that is lower.
+fn error_note(bits: u64, rounds: u32) -> String {
+    let worst = error_bound(rounds);
+    let average = average_error_bound(bits, rounds);
+    if average < worst {
+        format!("error at most {:.1e}, {:.1e} for a random {}-bit number", worst, average, bits)
+    } else {
+        format!("error at most {:.1e}", worst)
+    }
+}
+
+/// A Lucas-Lehmer verdict that can cross from the worker that reached it to the thread
+/// reporting it. `MpError`s keep their type; other errors keep their message.
+type LlOutcome = Result<LlResult, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Runs `test` on each of `numbers` with up to `jobs` at once and hands the outcomes to
+/// `report` in input order, however the runs finish.
+///
+/// Each worker keeps its own OpenCL context, so concurrent runs enqueue on separate queues.
+/// Workers stop taking numbers on Ctrl-C, past `deadline` or once `report` returns false,
+/// after finishing (and checkpointing) the runs in flight.
+///
+/// # Returns
+///
+/// How many of `numbers` were reported.
+fn lucas_lehmer_jobs(
+    numbers: &[u128],
+    jobs: usize,
+    deadline: Option<Instant>,
+    test: impl Fn(&mut Option<GpuContext>, u128) -> Result<LlResult, Box<dyn std::error::Error>> + Sync,
+    mut report: impl FnMut(u128, LlOutcome, Duration) -> bool,
+) -> usize {
+    let next = AtomicUsize::new(0);
+    let stop = AtomicBool::new(false);
+    let (sender, receiver) = mpsc::channel();
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.min(numbers.len()) {
+            let sender = sender.clone();
+            let (next, stop, test) = (&next, &stop, &test);
+            scope.spawn(move || {
+                let mut context = None;
+                let past_deadline = || deadline.is_some_and(|deadline| Instant::now() >= deadline);
+                while !stop.load(Ordering::SeqCst) && !INTERRUPTED.load(Ordering::SeqCst) && !past_deadline() {
+                    let index = next.fetch_add(1, Ordering::SeqCst);
+                    let Some(&number) = numbers.get(index) else {
+                        break;
+                    };
+                    let started = Instant::now();
+                    let outcome: LlOutcome = test(&mut context, number).map_err(|e| match e.downcast::<MpError>() {
+                        Ok(e) => e as Box<dyn std::error::Error + Send + Sync>,
+                        Err(e) => e.to_string().into(),
+                    });
+                    if sender.send((index, outcome, started.elapsed())).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+
+        let mut pending = BTreeMap::new();
+        let mut reported = 0;
+        'reporting: for (index, outcome, elapsed) in receiver {
+            pending.insert(index, (outcome, elapsed));
+            while let Some((outcome, elapsed)) = pending.remove(&reported) {
+                if !report(numbers[reported], outcome, elapsed) {
+                    stop.store(true, Ordering::SeqCst);
+                    break 'reporting;
+                }
+                reported += 1;
+            }
+        }
+        reported
+    })
+}
+
+/// One row of the summary printed after a `-l`/`-p` batch.
+struct SummaryRow {
+    number: u128,
+    verdict: &'static str,
+    prime: bool,
+    /// The res64 of a Lucas-Lehmer test, which PRP tests don't report.
+    res64: Option<u64>,
+    elapsed: Duration,
+}
+
+/// Inputs the summary after a batch names as the slowest.
+const SLOWEST_SHOWN: usize = 5;
+
+/// Prints a table of every number tested with its verdict and time, then the totals and
+/// the slowest inputs, or with `json` all but the table as one JSON object.
+fn print_summary(rows: &[SummaryRow], json: bool) {
+    let count = |verdict: &str| rows.iter().filter(|row| row.verdict == verdict).count();
+    let (composite, errors) = (count("composite"), count("error"));
+    let prime = rows.iter().filter(|row| row.prime).count();
+    let total: Duration = rows.iter().map(|row| row.elapsed).sum();
+    let mut slowest: Vec<&SummaryRow> = rows.iter().collect();
+    slowest.sort_by_key(|row| std::cmp::Reverse(row.elapsed));
+    slowest.truncate(SLOWEST_SHOWN);
+    if json {
+        let slowest: Vec<serde_json::Value> = slowest
+            .iter()
+            .map(|row| json!({"number": row.number, "verdict": row.verdict, "seconds": row.elapsed.as_secs_f64()}))
+            .collect();
+        let summary = json!({
+            "tested": rows.len(),
+            "prime": prime,
+            "composite": composite,
+            "error": errors,
+            "seconds": total.as_secs_f64(),
+            "slowest": slowest,
+        });
+        println!("{}", json!({"summary": summary}));
+        return;
+    }
+    let width = rows
+        .iter()
+        .map(|row| row.number.to_string().len())
+        .chain(["Number".len()])
+        .max()
+        .unwrap_or(0);
+    println!();
+    println!("{:<width$}  {:<16}  {:>10}", "Number", "Verdict", "Time", width = width);
+    for row in rows {
+        println!(
+            "{:<width$}  {:<16}  {:>9.3}s",
+            row.number,
+            row.verdict,
+            row.elapsed.as_secs_f64(),
+            width = width
+        );
+    }
+    println!(
+        "Total: {} tested, {} prime, {} composite, {} error{}, {:.3}s",
+        rows.len(),
+        prime,
+        composite,
+        errors,
+        if errors == 1 { "" } else { "s" },
+        total.as_secs_f64()
+    );
+    if !slowest.is_empty() {
+        let slowest: Vec<String> = slowest.iter().map(|row| format!("{} in {:.3}s", row.number, row.elapsed.as_secs_f64())).collect();
+        println!("Slowest: {}", slowest.join(", "));
+    }
+}
+
+/// The `--primes-out` and `--composites-out` files, which -l and -p append each number they
+/// test to by its verdict, so that the verdicts of many runs collect in them as in the
+/// results file. Numbers whose test failed go to neither.
+struct VerdictFiles {
+    primes: Option<(String, std::fs::File)>,
+    composites: Option<(String, std::fs::File)>,
+}
+
+impl VerdictFiles {
+    /// Opens the files `matches` names for appending, exiting if one can't be opened.
+    fn open(matches: &ArgMatches) -> VerdictFiles {
+        let open = |id: &str| {
+            matches.get_one::<String>(id).map(|filename| match std::fs::OpenOptions::new().append(true).create(true).open(filename) {
+                Ok(file) => (filename.clone(), file),
+                Err(e) => {
+                    eprintln!("Error opening {}: {}", filename, e);
+                    std::process::exit(1);
+                }
+            })
+        };
+        VerdictFiles { primes: open("primes_out"), composites: open("composites_out") }
+    }
+
+    /// Writes `number` to the file for its verdict, if there is one.
+    fn record(&mut self, number: u128, prime: bool) {
+        let target = if prime { &mut self.primes } else { &mut self.composites };
+        if let Some((filename, file)) = target {
+            if let Err(e) = writeln!(file, "{}", number) {
+                error!("Error writing to {}: {}", filename, e);
+            }
+        }
+    }
+}
+
+/// The file -l, -p and --prp-mersenne append a line per verdict to: `-o`, or
+/// `--result-file` from before -o covered the tests. Appending lets the results of many
+/// runs collect in one file, so every line names the number it is about.
+struct ResultsFile {
+    file: Option<(String, std::fs::File)>,
+}
+
+impl ResultsFile {
+    /// Opens the file `matches` names for appending, exiting if it can't be opened. Without
+    /// one, warns once that results no longer go to out.txt if an old out.txt is here.
+    /// Only `-l` has `--result-file`.
+    fn open(matches: &ArgMatches) -> ResultsFile {
+        let Some(filename) = output_file(matches).or_else(|| matches.try_get_one::<String>("result_file").ok().flatten()) else {
+            out_txt_notice();
+            return ResultsFile { file: None };
+        };
+        match std::fs::OpenOptions::new().append(true).create(true).open(filename) {
+            Ok(file) => ResultsFile { file: Some((filename.clone(), file)) },
+            Err(e) => {
+                eprintln!("Error opening {}: {}", filename, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    /// Appends `line` to the file, if there is one.
+    fn record(&mut self, line: &str) {
+        if let Some((filename, file)) = &mut self.file {
+            if let Err(e) = writeln!(file, "{}", line) {
+                error!("Error writing to {}: {}", filename, e);
+            }
+        }
+    }
+}
+
+/// Points a generation run whose OpenCL kernel didn't build on the driver to `--cpu`,
+/// which sieves without one.
+fn suggest_cpu(e: &(dyn std::error::Error + 'static)) {
+    if let Some(MpError::KernelBuild { .. }) = e.downcast_ref::<MpError>() {
+        eprintln!("The OpenCL driver could not build the kernel; --cpu generates the primes without it.");
+    }
+}
+
+/// Tells the user, the first time a test runs beside an out.txt, that results are no longer
+/// written there. A stamp in the cache directory keeps it from repeating.
+fn out_txt_notice() {
+    let Some(dir) = default_program_cache() else {
+        return;
+    };
+    let stamp = dir.join("out-txt-notice");
+    if !Path::new("out.txt").exists() || stamp.exists() {
+        return;
+    }
+    eprintln!("Note: results are no longer written to out.txt; pass -o FILE to append them to a file.");
+    if let Err(e) = std::fs::create_dir_all(&dir).and_then(|()| std::fs::write(&stamp, "")) {
+        info!("Cannot record the out.txt notice in {}: {}", stamp.display(), e);
+    }
+}
+
+/// Writes the `--save-results-json` report for a batch of `test` runs.
+///
+/// The report goes to a temporary file next to `filename` that is then renamed over it, so
+/// a crash leaves either the old report or the whole new one.
+fn save_results_json(filename: &str, test: &str, format: &str, rows: &[SummaryRow]) -> std::io::Result<()> {
+    let results: Vec<serde_json::Value> = rows
+        .iter()
+        .map(|row| json!({"number": row.number, "verdict": row.verdict, "prime": row.prime, "seconds": row.elapsed.as_secs_f64()}))
+        .collect();
+    let report = json!({"test": test, "device": device_name(), "format": format, "results": results});
+    let mut report = serde_json::to_string_pretty(&report).map_err(std::io::Error::other)?;
+    report.push('\n');
+
+    let temporary = format!("{}.tmp", filename);
+    std::fs::write(&temporary, report)?;
+    std::fs::rename(&temporary, filename)
+}
+
+/// The numbers `--skip-done` passes over: those the results file and `--sqlite` database
+/// record a finished `kind` test of, as `Done` names the kinds. Exits if either can't be read.
+fn done_tests(matches: &ArgMatches, kind: &'static str) -> Done {
+    let mut done = Done::default();
+    if let Some(filename) = output_file(matches).or_else(|| matches.get_one::<String>("result_file")) {
+        if let Err(e) = done.load(Path::new(filename)) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(filename) = matches.get_one::<String>("sqlite") {
+        let finished = open_database(filename).finished_tests(kind).unwrap_or_else(|e| {
+            eprintln!("Error reading database {}: {}", filename, e);
+            std::process::exit(1);
+        });
+        for (number, res64) in finished {
+            done.insert(kind, number, res64.and_then(|res64| u64::from_str_radix(&res64, 16).ok()));
+        }
+    }
+    if output_file(matches).is_none() && !matches.contains_id("result_file") && !matches.contains_id("sqlite") {
+        warn!("--skip-done has no results to check: pass -o FILE or --sqlite PATH");
+    }
+    done
+}
+
+/// Whether `--skip-done` is set, from the command line or the config file, and `--redo`
+/// doesn't override it.
+fn skips_done(matches: &ArgMatches) -> bool {
+    matches.get_flag("skip_done") && !matches.get_flag("redo")
+}
+
+/// Opens the `--sqlite` database, exiting with the reason if it can't be used.
+fn open_database(filename: &str) -> ResultsDb {
+    ResultsDb::open(filename).unwrap_or_else(|e| {
+        eprintln!("Error opening database {}: {}", filename, e);
+        std::process::exit(1);
+    })
+}
+
+/// Adds a batch of `kind` test outcomes to the `--sqlite` database.
+fn record_tests(filename: &str, kind: &'static str, rows: &[SummaryRow]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut database = open_database(filename);
+    for row in rows {
+        database.record_test(&TestRecord {
+            exponent: row.number,
+            kind,
+            verdict: row.verdict,
+            res64: row.res64.map(|res64| format!("{:016x}", res64)),
+            duration: row.elapsed,
+        })?;
+    }
+    Ok(())
+}
 
 fn main() {
-    let matches = Command::new("Prime Checker")
+    let config = read_config();
+    let command = Command::new("Prime Checker")
         .version("1.0")
         .author("Zander Lewis <zander@zanderlewis.dev>")
         .about("Performs Lucas-Lehmer and PRP tests")
         .arg(
-            Arg::new("ll")
-                .short('l')
-                .long("ll")
+            Arg::new("ll")
+                .short('l')
+                .long("ll")
+                .action(clap::ArgAction::SetTrue)
+                .help("Performs the Lucas-Lehmer test"),
+        )
+        .arg(
+            Arg::new("prp")
+                .short('p')
+                .long("prp")
+                .action(clap::ArgAction::SetTrue)
+                .help("Performs the Probable Prime test"),
+        )
+        .arg(
+            Arg::new("no_clear")
+                .long("no-clear")
+                .action(clap::ArgAction::SetTrue)
+                .help("Keeps the terminal as it is before a single -l run instead of clearing it (batches never clear it)"),
+        )
+        .arg(
+            Arg::new("memory")
+                .short('m')
+                .long("memory")
+                .action(clap::ArgAction::SetTrue)
+                .help("Enables the use of a file to lessen the load on memory"),
+        )
+        .arg(
+            Arg::new("no_memory")
+                .long("no-memory")
+                .action(clap::ArgAction::SetTrue)
+                .help("Keeps no checkpoint file, even with checkpoint.enabled set in the config file"),
+        )
+        .arg(
+            Arg::new("checkpoint_dir")
+                .long("checkpoint-dir")
+                .num_args(1)
+                .value_name("DIR")
+                .value_parser(clap::value_parser!(PathBuf))
+                .global(true)
+                .help("Keeps the -m checkpoint files in DIR instead of the working directory"),
+        )
+        .arg(
+            Arg::new("checkpoint_interval")
+                .long("checkpoint-interval")
+                .num_args(1)
+                .value_name("N")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .global(true)
+                .help("Iterations between -m checkpoints (default 100000000 on the Lucas-Lehmer kernel, 10000 on the CPU and NTT)"),
+        )
+        .arg(
+            Arg::new("jobs")
+                .short('j')
+                .long("jobs")
+                .num_args(1)
+                .value_name("N")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .default_value("1")
+                .requires("ll")
+                .help("Runs up to N Lucas-Lehmer tests at once, each on its own OpenCL queue or CPU thread"),
+        )
+        .arg(
+            Arg::new("chunked_progress")
+                .long("chunked-progress")
+                .num_args(1)
+                .value_name("FILE")
+                .requires("ll")
+                .conflicts_with("jobs")
+                .help("Keeps FILE up to date with the Lucas-Lehmer run in progress (iteration, total, ETA, low residue bits) for headless monitoring, rewriting it at every checkpoint interval"),
+        )
+        .arg(
+            Arg::new("batch_size")
+                .long("batch-size")
+                .num_args(1)
+                .value_name("N")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Lucas-Lehmer iterations per kernel launch (default 1024); progress, checkpoints, Ctrl-C and --timeout are handled between launches"),
+        )
+        .arg(
+            Arg::new("backend")
+                .long("backend")
+                .num_args(1)
+                .value_parser(["kernel", "ntt"])
+                .requires("lucas_lehmer")
+                .help("Squares exponents at or above --gpu-threshold with the 64-bit Lucas-Lehmer kernel (default) or with the NTT kernels, which take exponents of any size"),
+        )
+        .arg(
+            Arg::new("self_check")
+                .long("self-check")
+                .num_args(1)
+                .value_name("N")
+                .value_parser(clap::value_parser!(u64))
+                .requires("backend")
+                .help(format!(
+                    "Checks the NTT backend's residue against the CPU every N squarings (default {}, 0 for never)",
+                    DEFAULT_SELF_CHECK_INTERVAL
+                )),
+        )
+        .arg(
+            Arg::new("gerbicz_block")
+                .long("gerbicz-block")
+                .num_args(1)
+                .value_name("N")
+                .value_parser(clap::value_parser!(u64))
+                .requires("mersenne_test")
+                .conflicts_with("ll")
+                .help(format!(
+                    "Checks the PRP squarings of --prp-mersenne and --cross-check with a Gerbicz checksum updated every N squarings and verified every N^2, squaring a block that fails again (default {}, 0 for never)",
+                    DEFAULT_GERBICZ_BLOCK
+                )),
+        )
+        .arg(
+            Arg::new("expected_residue")
+                .long("expected-residue")
+                .num_args(1)
+                .value_name("HEX")
+                .value_parser(parse_residue)
+                .requires("mersenne_test")
+                .conflicts_with("cross_check")
+                .help("Compares the final res64 of a -l or --prp-mersenne run with HEX, exiting with 1 on a mismatch"),
+        )
+        .arg(
+            Arg::new("time_limit")
+                .long("time-limit")
+                .num_args(1)
+                .value_name("DURATION")
+                .value_parser(humantime::parse_duration)
+                .requires("mersenne_test")
+                .conflicts_with("repl")
+                .help(format!(
+                    "Pauses -l, --prp-mersenne or --cross-check after DURATION (e.g. 3h50m), checkpointing as -m does, and exits with {} for the same command to resume",
+                    EXIT_PAUSED
+                )),
+        )
+        .group(ArgGroup::new("mersenne_test").args(["ll", "prp_mersenne", "cross_check"]).multiple(true))
+        .group(ArgGroup::new("lucas_lehmer").args(["ll", "cross_check"]).multiple(true))
+        .group(ArgGroup::new("primality_test").args(["ll", "prp", "prp_mersenne"]).multiple(true))
+        .arg(
+            Arg::new("shift")
+                .long("shift")
+                .num_args(1)
+                .value_parser(clap::value_parser!(u64))
+                .default_value("0")
+                .help("Starts the Lucas-Lehmer residue shifted by 2^SHIFT for an independent double-check"),
+        )
+        .arg(
+            Arg::new("timeout")
+                .long("timeout")
+                .num_args(1)
+                .value_name("SECONDS")
+                .value_parser(clap::value_parser!(u64))
+                .help("Stops a Lucas-Lehmer run after SECONDS, checkpointing first with -m"),
+        )
+        .arg(
+            Arg::new("verify_known")
+                .long("verify-known")
+                .num_args(0..=1)
+                .value_name("BOUND")
+                .default_missing_value("127")
+                .value_parser(clap::value_parser!(u128))
+                .conflicts_with_all(["generate", "from_list", "number", "nth"])
+                .help("Self-tests Lucas-Lehmer on the known Mersenne prime exponents up to BOUND (default 127)"),
+        )
+        .arg(
+            Arg::new("result_file")
+                .long("result-file")
+                .num_args(1)
+                .value_name("PATH")
+                .requires("ll")
+                .conflicts_with("output")
+                .help("Appends each Lucas-Lehmer verdict to PATH, as -o does"),
+        )
+        .arg(
+            Arg::new("skip_done")
+                .long("skip-done")
+                .action(clap::ArgAction::SetTrue)
+                .requires("primality_test")
+                .help("Skips the numbers whose test (-l, -p or --prp-mersenne) the -o file or --sqlite database records as done"),
+        )
+        .arg(
+            Arg::new("redo")
+                .long("redo")
+                .action(clap::ArgAction::SetTrue)
+                .requires("primality_test")
+                .help("Tests every number again, even with --skip-done set in the config file"),
+        )
+        .arg(
+            Arg::new("primes_out")
+                .long("primes-out")
+                .num_args(1)
+                .value_name("PATH")
+                .conflicts_with("repl")
+                .help("Appends each number -l/-p finds prime (the exponent for -l) to PATH, one per line"),
+        )
+        .arg(
+            Arg::new("composites_out")
+                .long("composites-out")
+                .num_args(1)
+                .value_name("PATH")
+                .conflicts_with("repl")
+                .help("Appends each number -l/-p finds composite (the exponent for -l) to PATH, one per line"),
+        )
+        .arg(
+            Arg::new("save_results_json")
+                .long("save-results-json")
+                .num_args(1)
+                .value_name("PATH")
+                .conflicts_with("repl")
+                .help("Writes the -l/-p configuration and every verdict to PATH as a JSON report"),
+        )
+        .arg(
+            Arg::new("sqlite")
+                .long("sqlite")
+                .num_args(1)
+                .value_name("PATH")
+                .conflicts_with_all(["repl", "sieve_output", "twins", "gaps", "count", "mersenne_candidates"])
+                .help("Adds generated primes (instead of printing them) or -l/-p outcomes to the SQLite database at PATH"),
+        )
+        .arg(
+            Arg::new("dry_run")
+                .long("dry-run")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with_all(["generate", "nth", "next", "prev", "verify_known"])
+                .help("Prints how many numbers -l/-p would test, the iterations, memory and device, without testing"),
+        )
+        .arg(
+            Arg::new("dedup")
+                .long("dedup")
+                .action(clap::ArgAction::SetTrue)
+                .help("Tests each number of a -l/-p batch once, keeping the order they first appear in"),
+        )
+        .arg(
+            Arg::new("quiet")
+                .short('q')
+                .long("quiet")
+                .action(clap::ArgAction::SetTrue)
+                .help("Leaves out the summary table after -l/-p runs"),
+        )
+        .arg(
+            Arg::new("repl")
+                .long("repl")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with_all(["generate", "from_list", "number", "nth", "next", "prev", "verify_known", "dry_run"])
+                .help("Reads numbers from stdin and runs -l or -p on each as it is entered, until EOF or quit"),
+        )
+        .arg(
+            Arg::new("from_list")
+                .short('f')
+                .long("from-list")
+                .num_args(1)
+                .conflicts_with("generate")
+                .help("Reads numbers from a file and uses them for the tests"),
+        )
+        .arg(
+            Arg::new("worktodo")
+                .long("worktodo")
+                .num_args(1)
+                .value_name("FILE")
+                .value_parser(clap::value_parser!(PathBuf))
+                .requires("ll")
+                .conflicts_with_all(["from_list", "number", "repl"])
+                .help("Runs -l on the exponents of the Test= and DoubleCheck= lines of a GIMPS worktodo FILE, warning about any other line"),
+        )
+        .arg(
+            Arg::new("read_binary")
+                .long("read-binary")
+                .action(clap::ArgAction::SetTrue)
+                .requires("from_list")
+                .help("Reads --from-list as a binary prime file written with --output-format bin"),
+        )
+        .arg(
+            Arg::new("number")
+                .help("Number(s) for the test")
+                .num_args(1..)
+                .allow_negative_numbers(true)
+                .required_unless_present_any(["generate", "from_list", "nth", "verify_known", "next", "prev", "repl", "llr", "prp_mersenne", "cross_check", "factor", "worktodo", "print_config", "capabilities"])
+                .conflicts_with_all(["generate", "nth"]),
+        )
+        .arg(
+            Arg::new("generate")
+                .short('g')
+                .long("generate")
+                .num_args(2)
+                .value_names(["START", "END"])
+                .value_parser(clap::value_parser!(u128))
+                .help("Generates all primes in the range from START to END"),
+        )
+        .arg(
+            Arg::new("llr")
+                .long("llr")
+                .num_args(2)
+                .value_names(["K", "N"])
+                .value_parser(clap::value_parser!(u64))
+                .conflicts_with_all(["generate", "from_list", "number", "nth", "verify_known", "repl"])
+                .help("Runs the Lucas-Lehmer-Riesel test on K*2^N-1, for odd K < 2^N"),
+        )
+        .arg(
+            Arg::new("prp_mersenne")
+                .long("prp-mersenne")
+                .num_args(1)
+                .value_name("P")
+                .value_parser(clap::value_parser!(u64).range(2..))
+                .conflicts_with_all(["generate", "from_list", "number", "nth", "verify_known", "repl", "llr"])
+                .help("Runs a probable-prime test on 2^P-1 itself, to base 3 unless --bases/--base-file say otherwise"),
+        )
+        .arg(
+            Arg::new("cross_check")
+                .long("cross-check")
+                .visible_alias("compare-tests")
+                .num_args(1..)
+                .value_name("P")
+                .value_parser(clap::value_parser!(u64).range(2..))
+                .conflicts_with_all(["generate", "from_list", "number", "nth", "verify_known", "repl", "llr", "prp_mersenne"])
+                .help("Runs both the Lucas-Lehmer test and a base-3 probable-prime test on 2^P-1 for each P, exiting with 1 if their verdicts disagree on any"),
+        )
+        .arg(
+            Arg::new("factor")
+                .long("factor")
+                .num_args(1)
+                .value_name("N")
+                .value_parser(clap::value_parser!(u64).range(2..))
+                .conflicts_with_all(["generate", "from_list", "number", "nth", "verify_known", "repl", "llr", "prp_mersenne", "cross_check"])
+                .help("Factors N, below 2^64, into primes"),
+        )
+        .arg(
+            Arg::new("nth")
+                .long("nth")
+                .num_args(1)
+                .value_name("N")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .conflicts_with_all(["generate", "from_list"])
+                .help("Prints the Nth prime"),
+        )
+        .arg(
+            Arg::new("next")
+                .long("next")
+                .num_args(1)
+                .value_name("N")
+                .conflicts_with_all(["generate", "from_list", "number", "nth", "verify_known", "prev"])
+                .help("Prints the smallest probable prime greater than N"),
+        )
+        .arg(
+            Arg::new("prev")
+                .long("prev")
+                .num_args(1)
+                .value_name("N")
+                .conflicts_with_all(["generate", "from_list", "number", "nth", "verify_known"])
+                .help("Prints the largest probable prime less than N"),
+        )
+        .arg(
+            Arg::new("verbose")
+                .short('v')
+                .long("verbose")
+                .action(clap::ArgAction::Count)
+                .global(true)
+                .help("Reports how many candidates --next and --prev examined and the Miller-Rabin witness or rounds behind -p verdicts, and logs progress to stderr (-vv for debug detail, -vvv for trace; RUST_LOG overrides)"),
+        )
+        .arg(
+            Arg::new("log_file")
+                .long("log-file")
+                .num_args(1)
+                .value_name("FILE")
+                .value_parser(clap::value_parser!(PathBuf))
+                .global(true)
+                .help("Appends a timestamped log of the run to FILE: devices, program builds, checkpoints, error checks and verdicts, whatever -v shows on stderr"),
+        )
+        .arg(
+            Arg::new("after")
+                .long("after")
+                .num_args(1)
+                .value_name("M")
+                .value_parser(clap::value_parser!(u128))
+                .requires("nth")
+                .help("Counts --nth primes from the first prime greater than M"),
+        )
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .num_args(1)
+                .value_parser(["plain", "json", "csv", "tsv"])
+                .default_value("plain")
+                .help("Prints -l verdicts, the --nth result or --twins pairs as plain text, JSON, CSV or TSV; -p verdicts and -g primes also take CSV and TSV"),
+        )
+        .arg(
+            Arg::new("fermat")
+                .long("fermat")
+                .action(clap::ArgAction::SetTrue)
+                .requires("generate")
+                .conflicts_with_all(["cpu", "gpu", "sieve"])
+                .help("Generates primes with the OpenCL probable-prime test instead of the exact sieve"),
+        )
+        .arg(
+            Arg::new("bases")
+                .long("bases")
+                .num_args(1)
+                .value_delimiter(',')
+                .value_parser(clap::value_parser!(u64).range(2..))
+                .help("Comma-separated bases for -p and the -g --fermat test (defaults 2 and 2,3,5,7; 2 alone is fastest)"),
+        )
+        .arg(
+            Arg::new("base_file")
+                .long("base-file")
+                .num_args(1)
+                .value_name("PATH")
+                .help("Adds the bases listed one per line in PATH to --bases, replacing those of the config file"),
+        )
+        .arg(
+            Arg::new("trial")
+                .long("trial")
+                .action(clap::ArgAction::SetTrue)
+                .requires("prp")
+                .conflicts_with("repl")
+                .help("Proves each -p number prime or composite by trial division instead of the PRP test: exact, but only for numbers below 2^64"),
+        )
+        .arg(
+            Arg::new("no_verify")
+                .long("no-verify")
+                .action(clap::ArgAction::SetTrue)
+                .requires("fermat")
+                .help("Keeps GPU pseudoprimes instead of re-testing survivors with Miller-Rabin"),
+        )
+        .arg(
+            Arg::new("cpu")
+                .long("cpu")
+                .action(clap::ArgAction::SetTrue)
+                .requires("generate")
+                .conflicts_with_all(["gpu", "sieve"])
+                .help("Sieves on all CPU threads regardless of the range size"),
+        )
+        .arg(
+            Arg::new("gpu")
+                .long("gpu")
+                .action(clap::ArgAction::SetTrue)
+                .requires("generate")
+                .conflicts_with("sieve")
+                .help("Marks composites of each sieve segment with OpenCL regardless of the range size"),
+        )
+        .arg(
+            Arg::new("devices")
+                .long("devices")
+                .num_args(1)
+                .value_name("LIST")
+                .requires("generate")
+                .conflicts_with_all(["sieve", "fermat", "cpu"])
+                .help("OpenCL devices to share the GPU sieve between: comma-separated indices (e.g. 0,1) or all"),
+        )
+        .arg(
+            Arg::new("gpu_threshold")
+                .long("gpu-threshold")
+                .num_args(1)
+                .value_name("N")
+                .value_parser(clap::value_parser!(u128))
+                .help("Size from which work goes to the GPU instead of the CPU: the exponent for -l (default 65; the 64-bit kernel only ever takes exponents up to 64, larger ones stay on the CPU unless --backend ntt) and the range length for -g without --gpu or --cpu (default 2^28)"),
+        )
+        .arg(
+            Arg::new("max_gpu_mem")
+                .long("max-gpu-mem")
+                .num_args(1)
+                .value_name("SIZE")
+                .value_parser(parse_bytes)
+                .requires("generate")
+                .help("Caps the device memory the --fermat kernel's buffers and the GPU sieve's base primes take, like 2GiB (default 70% of the device's memory)"),
+        )
+        .arg(
+            Arg::new("preallocate_results")
+                .long("preallocate-results")
+                .num_args(1)
+                .value_name("auto|none|N")
+                .value_parser(parse_preallocate)
+                .requires("sieve")
+                .help("Sizes the list a whole-range --sieve collects the primes in: auto from the expected count of primes (default), none to grow it as they arrive, or room for N"),
+        )
+        .arg(
+            Arg::new("profile")
+                .long("profile")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with("repl")
+                .help("Prints to stderr how long -l or -g spent in OpenCL init, kernel compilation, buffer setup, execution and readback (summed over -j runs)"),
+        )
+        .arg(
+            Arg::new("tune")
+                .long("tune")
+                .action(clap::ArgAction::SetTrue)
+                .requires("fermat")
+                .conflicts_with("local_size")
+                .help("Times the OpenCL local work-group sizes again instead of reusing the one tuned earlier for the device"),
+        )
+        .arg(
+            Arg::new("local_size")
+                .long("local-size")
+                .num_args(1)
+                .value_name("N")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .requires("fermat")
+                .help("Launches the --fermat kernel in work-groups of N rather than the size tuned for the device"),
+        )
+        .arg(
+            Arg::new("sieve")
+                .long("sieve")
+                .num_args(1)
+                .value_parser(["eratosthenes", "atkin"])
+                .requires("generate")
+                .help("Generates primes with a whole-range CPU sieve instead of the segmented sieve"),
+        )
+        .arg(
+            Arg::new("mersenne_candidates")
+                .long("mersenne-candidates")
                 .action(clap::ArgAction::SetTrue)
-                .help("Performs the Lucas-Lehmer test"),
+                .requires("generate")
+                .conflicts_with("output")
+                .help("Reports for each prime p whether 2^p-1 survives small-factor trial division"),
         )
         .arg(
-            Arg::new("prp")
-                .short('p')
-                .long("prp")
+            Arg::new("min_factor")
+                .long("min-factor")
+                .num_args(1)
+                .value_name("Q")
+                .value_parser(clap::value_parser!(u128))
+                .requires("mersenne_candidates")
+                .help("Smallest candidate factor --mersenne-candidates tries, e.g. to carry on from an earlier sweep"),
+        )
+        .arg(
+            Arg::new("max_factor")
+                .long("max-factor")
+                .num_args(1)
+                .value_name("Q")
+                .value_parser(clap::value_parser!(u128))
+                .requires("mersenne_candidates")
+                .help(format!(
+                    "Largest candidate factor --mersenne-candidates tries (default {} candidates 2kp+1 past --min-factor)",
+                    DEFAULT_K_LIMIT
+                )),
+        )
+        .arg(
+            Arg::new("sieve_output")
+                .long("sieve-output")
                 .action(clap::ArgAction::SetTrue)
-                .help("Performs the Probable Prime test"),
+                .requires("generate")
+                .conflicts_with_all(["mersenne_candidates", "output_format", "sieve", "fermat", "gpu"])
+                .help("Outputs every number in the range with its smallest prime factor (1 for primes)"),
         )
         .arg(
-            Arg::new("memory")
-                .short('m')
-                .long("memory")
+            Arg::new("twins")
+                .long("twins")
                 .action(clap::ArgAction::SetTrue)
-                .help("Enables the use of a file to lessen the load on memory"),
+                .requires("generate")
+                .conflicts_with_all(["mersenne_candidates", "sieve_output", "output_format", "sieve", "count", "mod"])
+                .help("Outputs the twin prime pairs (p, p+2) whose p is in the range, one pair per line"),
         )
         .arg(
-            Arg::new("from_list")
-                .short('f')
-                .long("from-list")
+            Arg::new("constellation")
+                .long("constellation")
                 .num_args(1)
-                .conflicts_with("generate")
-                .help("Reads numbers from a file and uses them for the tests"),
+                .value_name("OFFSETS")
+                .value_delimiter(',')
+                .value_parser(clap::value_parser!(u128))
+                .requires("generate")
+                .conflicts_with_all([
+                    "twins", "mersenne_candidates", "sieve_output", "output_format", "sieve", "count", "sqlite", "sophie_germain", "safe", "gaps",
+                    "stats", "resume", "columns", "mod",
+                ])
+                .help("Outputs the tuples p+OFFSETS that are all prime, for p in the range, e.g. 0,2,6 for prime triplets"),
         )
         .arg(
-            Arg::new("number")
-                .help("Number(s) for the test")
-                .num_args(1..)
-                .required_unless_present("generate")
-                .conflicts_with("generate"),
+            Arg::new("sophie_germain")
+                .long("sophie-germain")
+                .action(clap::ArgAction::SetTrue)
+                .requires("generate")
+                .conflicts_with_all(["safe", "sieve_output", "twins"])
+                .help("Keeps only the primes p where 2p+1 is also prime"),
         )
         .arg(
-            Arg::new("generate")
-                .short('g')
-                .long("generate")
-                .num_args(2)
-                .value_names(&["START", "END"])
-                .help("Generates all primes in the range from START to END"),
+            Arg::new("safe")
+                .long("safe")
+                .action(clap::ArgAction::SetTrue)
+                .requires("generate")
+                .conflicts_with_all(["sieve_output", "twins"])
+                .help("Keeps only the safe primes p, where (p-1)/2 is also prime"),
+        )
+        .arg(
+            Arg::new("gaps")
+                .long("gaps")
+                .action(clap::ArgAction::SetTrue)
+                .requires("generate")
+                .conflicts_with_all(["mersenne_candidates", "sieve_output", "twins", "count", "output_format", "sophie_germain", "safe"])
+                .help("Reports the maximal prime gaps, a histogram of gap sizes and the average gap instead of the primes"),
+        )
+        .arg(
+            Arg::new("min_gap")
+                .long("min-gap")
+                .num_args(1)
+                .value_name("N")
+                .value_parser(clap::value_parser!(u128))
+                .requires("gaps")
+                .help("Lists every gap of at least N and leaves smaller ones out of the --gaps report"),
+        )
+        .arg(
+            Arg::new("mod")
+                .long("mod")
+                .num_args(1)
+                .value_name("M")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .requires_all(["generate", "residue"])
+                .conflicts_with("sieve_output")
+                .help("Generates only primes in the residue classes given by --residue modulo M"),
+        )
+        .arg(
+            Arg::new("residue")
+                .long("residue")
+                .num_args(1)
+                .value_name("R")
+                .value_delimiter(',')
+                .value_parser(clap::value_parser!(u64))
+                .requires("mod")
+                .help("Comma-separated residues R for --mod, keeping primes p with p ≡ R (mod M)"),
+        )
+        .arg(
+            Arg::new("count")
+                .long("count")
+                .action(clap::ArgAction::SetTrue)
+                .requires("generate")
+                .conflicts_with_all(["mersenne_candidates", "sieve_output", "output"])
+                .help("Prints only how many primes are in the range, and how long counting took"),
+        )
+        .arg(
+            Arg::new("stats")
+                .long("stats")
+                .action(clap::ArgAction::SetTrue)
+                .requires("generate")
+                .conflicts_with_all(["mersenne_candidates", "sieve_output", "twins", "gaps", "resume"])
+                .help("Follows the primes with their count, largest and average gap and twin pairs on standard error"),
         )
         .arg(
             Arg::new("output")
                 .short('o')
                 .long("output")
                 .num_args(1)
-                .help("Output file for generated primes"),
-        )
-        .get_matches();
-
-    if matches.contains_id("generate") {
-        let mut values = matches.get_many::<String>("generate").unwrap();
-        let start = values
-            .next()
-            .unwrap()
-            .parse::<u128>()
-            .expect("Invalid start number");
-        let end = values
-            .next()
-            .unwrap()
-            .parse::<u128>()
-            .expect("Invalid end number");
-        match generate_primes(start, end) {
-            Ok(p) => {
-                if matches.get_flag("output") {
-                    let filename = matches.get_one::<String>("output").unwrap();
-                    write_primes_to_file(&p, filename).expect("Failed to write primes to file");
-                } else {
-                    for prime in p {
-                        println!("{}", prime);
-                    }
-                }
+                .value_name("FILE")
+                .help("Output file for generated primes, or - for standard output (the default); -l, -p and --prp-mersenne append their verdicts to it"),
+        )
+        .arg(
+            Arg::new("compress")
+                .long("compress")
+                .action(clap::ArgAction::SetTrue)
+                .requires_all(["generate", "output"])
+                .help("Gzips the output file as it is written (implied by a .gz suffix on -o)"),
+        )
+        .arg(
+            Arg::new("no_header")
+                .long("no-header")
+                .action(clap::ArgAction::SetTrue)
+                .requires_all(["generate", "output"])
+                .help("Leaves out the # lines recording the range, method and time at the top of a lines -o file"),
+        )
+        .arg(
+            Arg::new("resume")
+                .long("resume")
+                .action(clap::ArgAction::SetTrue)
+                .requires_all(["generate", "output"])
+                .conflicts_with_all(["compress", "count", "mersenne_candidates", "twins", "gaps", "sieve_output", "sqlite"])
+                .help("Records progress beside the -o file so an interrupted -g run continues where it stopped"),
+        )
+        .arg(
+            Arg::new("output_format")
+                .long("output-format")
+                .num_args(1)
+                .value_parser(["lines", "binary", "bin", "csv", "tsv"])
+                .default_value("lines")
+                .requires_all(["generate", "output"])
+                .help("Layout of the output file: decimal lines, little-endian u64 binary, or index,prime rows as CSV or TSV"),
+        )
+        .arg(
+            Arg::new("columns")
+                .long("columns")
+                .num_args(1)
+                .value_name("N")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .requires("generate")
+                .conflicts_with_all(["output_format", "resume", "count", "mersenne_candidates", "sieve_output", "twins", "gaps"])
+                .help("Lays the primes out in N aligned columns instead of one per line, as wide as the largest prime below END"),
+        )
+        .arg(
+            Arg::new("progress_log")
+                .long("progress-log")
+                .action(clap::ArgAction::SetTrue)
+                .global(true)
+                .help("Prints progress as plain stderr lines, about one a second, instead of a bar (which is hidden off a terminal)"),
+        )
+        .arg(
+            Arg::new("color")
+                .long("color")
+                .num_args(1)
+                .value_name("WHEN")
+                .value_parser(["auto", "always", "never"])
+                .default_value("auto")
+                .global(true)
+                .help("Colors the progress bars and log levels and clears the screen for a single -l run: auto does so on a terminal unless NO_COLOR is set"),
+        )
+        .arg(
+            Arg::new("progress_json")
+                .long("progress-json")
+                .num_args(0..=1)
+                .value_name("DURATION")
+                .value_parser(humantime::parse_duration)
+                .default_missing_value("1s")
+                .conflicts_with("progress_log")
+                .global(true)
+                .help("Prints progress as one JSON object per stderr line every DURATION (default 1s) instead of a bar, with a \"done\" event at the end"),
+        )
+        .arg(
+            Arg::new("status_interval")
+                .long("status-interval")
+                .num_args(1)
+                .value_name("DURATION")
+                .value_parser(humantime::parse_duration)
+                .default_value("1m")
+                .global(true)
+                .help("How often -l prints its rate and ETA as a plain stderr line when stderr isn't a terminal (0s for never)"),
+        )
+        .arg(
+            Arg::new("program_cache")
+                .long("program-cache")
+                .num_args(1)
+                .value_name("DIR")
+                .value_parser(clap::value_parser!(PathBuf))
+                .global(true)
+                .help("Keeps compiled OpenCL programs in DIR between runs (default $XDG_CACHE_HOME/mp or ~/.cache/mp)"),
+        )
+        .arg(
+            Arg::new("no_program_cache")
+                .long("no-program-cache")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with("program_cache")
+                .global(true)
+                .help("Compiles the OpenCL programs from source instead of loading cached binaries"),
+        )
+        .arg(
+            Arg::new("kernel_define")
+                .long("kernel-define")
+                .num_args(1)
+                .value_name("NAME[=VALUE]")
+                .action(clap::ArgAction::Append)
+                .global(true)
+                .hide(true)
+                .help("Passes -D NAME[=VALUE] to the OpenCL compiler, to debug kernel builds"),
+        )
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .num_args(1)
+                .value_name("PATH")
+                .value_parser(clap::value_parser!(PathBuf))
+                .global(true)
+                .help("Reads option defaults from the TOML file at PATH instead of ~/.config/mp/config.toml; options and MP_* variables override them"),
+        )
+        .arg(
+            Arg::new("print_config")
+                .long("print-config")
+                .action(clap::ArgAction::SetTrue)
+                .global(true)
+                .help("Prints the configuration the config file, MP_* variables and options add up to, and exits"),
+        )
+        .arg(
+            Arg::new("capabilities")
+                .long("capabilities")
+                .action(clap::ArgAction::SetTrue)
+                .help("Prints what this machine can run as JSON (OpenCL, its devices, the backends they allow), and exits"),
+        )
+        .subcommand(
+            Command::new("completions").about("Prints a shell completion script").arg(
+                Arg::new("shell")
+                    .required(true)
+                    .value_parser(clap::value_parser!(Shell))
+                    .help("The shell to complete for"),
+            ),
+        );
+    let command = with_modes(with_config(command, &config));
+    let matches = command.clone().get_matches();
+    if let Some(completions) = matches.subcommand_matches("completions") {
+        print_completions(*completions.get_one::<Shell>("shell").unwrap(), command);
+        return;
+    }
+    check_options_before_subcommand(&command, &matches);
+
+    set_color(match matches.get_one::<String>("color").map(String::as_str) {
+        Some("always") => ColorChoice::Always,
+        Some("never") => ColorChoice::Never,
+        _ => ColorChoice::Auto,
+    });
+    let verbosity = matches.get_count("verbose");
+    if let Err(e) = logging::init(verbosity, matches.get_one::<PathBuf>("log_file").map(PathBuf::as_path)) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    if let Some(&interval) = matches.get_one::<Duration>("progress_json") {
+        JSON_PROGRESS.store(true, Ordering::Relaxed);
+        *STATUS_INTERVAL.lock().unwrap() = Some(interval);
+    } else if matches.get_flag("progress_log") {
+        LOG_PROGRESS.store(true, Ordering::Relaxed);
+    } else if !std::io::stderr().is_terminal() {
+        *STATUS_INTERVAL.lock().unwrap() = matches.get_one::<Duration>("status_interval").copied();
+    }
+    if let Some(&every) = matches.get_one::<u64>("checkpoint_interval") {
+        CHECKPOINT_EVERY.store(every, Ordering::Relaxed);
+    }
+    if matches.get_flag("print_config") {
+        print_config(&matches, &config);
+        return;
+    }
+    if matches.get_flag("capabilities") {
+        println!("{}", capabilities_json());
+        return;
+    }
+    if let Some(defines) = matches.get_many::<String>("kernel_define") {
+        *KERNEL_DEFINES.lock().unwrap() = defines.cloned().collect();
+    }
+    if !matches.get_flag("no_program_cache") {
+        *PROGRAM_CACHE.lock().unwrap() = matches.get_one::<PathBuf>("program_cache").cloned().or_else(default_program_cache);
+    }
+
+    match matches.subcommand() {
+        Some(("ll", ll)) if ll.get_flag("repl") => run_repl(ll, true),
+        Some(("ll", ll)) => run_lucas_lehmer(ll),
+        Some(("prp", prp)) if prp.get_flag("repl") => run_repl(prp, false),
+        Some(("prp", prp)) => run_prp(prp),
+        Some(("gen", generate)) => run_generate(generate),
+        Some(("factor", factor)) => print_factorization(*factor.get_one::<u64>("factor").unwrap()),
+        _ => run_top_level(&matches),
+    }
+}
+
+/// Runs the mode the top-level options of `matches` pick, with no subcommand given.
+fn run_top_level(matches: &ArgMatches) {
+    // clap leaves out the -g these require, as it conflicts with the test
+    if !matches.contains_id("generate") {
+        for id in ["compress", "no_header", "output_format"] {
+            if matches.value_source(id) == Some(ValueSource::CommandLine) {
+                clap::Error::raw(
+                    clap::error::ErrorKind::ArgumentConflict,
+                    format!("--{} only applies to the -o file of -g\n", id.replace('_', "-")),
+                )
+                .exit();
             }
-            Err(e) => eprintln!("Error generating primes: {}", e),
-        }
-    } 
-    // Handle Lucas-Lehmer Test
-    else if matches.get_flag("ll") {
-        let use_memory = matches.get_flag("memory");
-        if matches.contains_id("from_list") {
-            let filename = matches.get_one::<String>("from_list").unwrap();
-            println!("Reading numbers from file {}...", filename);
-            let contents = std::fs::read_to_string(filename).expect("Failed to read file");
-            let numbers: Vec<String> = contents.lines().map(|s| s.to_string()).collect();
-            for number_str in numbers {
-                let number: u128 = match number_str.parse() {
-                    Ok(num) => num,
-                    Err(_) => {
-                        eprintln!("Invalid number in file: {}", number_str);
-                        continue;
-                    }
-                };
-                match lucas_lehmer(number, use_memory) {
-                    Ok(_result) => {
-                        print!("");
-                    }
-                    Err(e) => eprintln!("Error testing {}: {}", number, e),
+        }
+    }
+    let verbosity = matches.get_count("verbose");
+    if let Some(&bound) = matches.get_one::<u128>("verify_known") {
+        let results = verify_known_exponents(bound, &lucas_lehmer_options(matches));
+        let mut failures = 0;
+        for (p, verdict) in &results {
+            match verdict {
+                Ok(true) => println!("M{}: prime", p),
+                Ok(false) => {
+                    failures += 1;
+                    println!("M{}: FAILED (reported composite)", p);
+                }
+                Err(e) => {
+                    failures += 1;
+                    println!("M{}: FAILED ({})", p, e);
                 }
             }
-        } else if let Some(numbers) = matches.get_many::<String>("number") {
-            for number_str in numbers {
-                let number: u128 = match number_str.parse() {
-                    Ok(num) => num,
-                    Err(_) => {
-                        eprintln!("Please enter a valid number.");
-                        continue;
-                    }
-                };
-                match lucas_lehmer(number, use_memory) {
-                    Ok(_result) => {
-                        print!("");
-                    }
-                    Err(e) => eprintln!("Error testing {}: {}", number, e),
+        }
+        println!("{} of {} known exponents verified", results.len() - failures, results.len());
+        if failures > 0 {
+            std::process::exit(1);
+        }
+    } else if let Some((flag, n)) = matches
+        .get_one::<String>("next")
+        .map(|n| ("next", n))
+        .or_else(|| matches.get_one::<String>("prev").map(|n| ("prev", n)))
+    {
+        let n: BigUint = match n.parse() {
+            Ok(n) => n,
+            Err(_) => {
+                eprintln!("Please enter a valid number.");
+                std::process::exit(1);
+            }
+        };
+        let search = if flag == "next" { Ok(next_prime(&n)) } else { prev_prime(&n) };
+        match search {
+            Ok(search) => {
+                println!("{}", search.prime);
+                if verbosity > 0 {
+                    eprintln!("Examined {} candidates, {} tested with BPSW", search.examined, search.tested);
                 }
             }
-        } else {
-            eprintln!("No numbers provided for Lucas-Lehmer test.");
-        }
-    } 
-    // Handle Probable Prime Test
-    else if matches.get_flag("prp") {
-        if matches.contains_id("from_list") {
-            let filename = matches.get_one::<String>("from_list").unwrap();
-            println!("Reading numbers from file {}...", filename);
-            let contents = std::fs::read_to_string(filename).expect("Failed to read file");
-            let numbers: Vec<String> = contents.lines().map(|s| s.to_string()).collect();
-            for number_str in numbers {
-                let number: u128 = match number_str.parse() {
-                    Ok(num) => num,
-                    Err(_) => {
-                        eprintln!("Invalid number in file: {}", number_str);
-                        continue;
+            Err(e) => {
+                eprintln!("Error finding a prime: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(mut values) = matches.get_many::<u64>("llr") {
+        let k = *values.next().unwrap();
+        let n = *values.next().unwrap();
+        match llr(k, n) {
+            Ok(prime) => println!("{}*2^{}-1 is {}prime.", k, n, if prime { "" } else { "not " }),
+            Err(e) => {
+                eprintln!("Error running LLR: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(&p) = matches.get_one::<u64>("prp_mersenne") {
+        if skips_done(matches) && done_tests(matches, "mersenne_prp").get("mersenne_prp", p as u128).is_some() {
+            eprintln!("2^{}-1: skipped (already done)", p);
+            return;
+        }
+        // Every 2^p-1 with p prime passes base 2, so GIMPS runs its PRP tests to base 3
+        let bases: Vec<u128> = read_bases(matches).unwrap_or_else(|| vec![3]).into_iter().map(u128::from).collect();
+        let options = PrpOptions {
+            mem: keeps_checkpoints(matches),
+            checkpoint_dir: matches.get_one::<PathBuf>("checkpoint_dir").cloned(),
+            deadline: matches.get_one::<Duration>("time_limit").map(|&limit| Instant::now() + limit),
+            gerbicz_block: gerbicz_block(matches),
+        };
+        let result = match mersenne_prp_report(p as u128, &bases, &options) {
+            Ok(result) => result,
+            Err(e) => {
+                error!("PRP test of 2^{}-1 {}.", p, e);
+                let paused = matches!(e.downcast_ref::<MpError>(), Some(MpError::Paused { .. }));
+                std::process::exit(if paused { EXIT_PAUSED } else { 1 });
+            }
+        };
+        let detail = match result.report {
+            MillerRabinReport::ProbablyPrime { rounds } if verbosity > 0 => {
+                format!("probably prime ({})", error_note(p, rounds))
+            }
+            MillerRabinReport::ProbablyPrime { .. } => "probably prime".to_string(),
+            MillerRabinReport::Composite { witness: Some(witness) } if verbosity > 0 => {
+                format!("not prime (witness {})", witness)
+            }
+            MillerRabinReport::Composite { .. } => "not prime".to_string(),
+        };
+        println!("2^{}-1 is {}.", p, detail);
+        ResultsFile::open(matches).record(&format!("2^{}-1 is {}.", p, detail));
+        report_gerbicz_errors();
+        match result.res64 {
+            Some(residue) => info!("2^{}-1 is {}, res64 {:016x}", p, detail, residue),
+            None => info!("2^{}-1 is {}", p, detail),
+        }
+        let expected_residue = matches.get_one::<u64>("expected_residue").copied();
+        if let Some((line, matched)) = result.res64.and_then(|residue| residue_check(&format!("M{}", p), residue, expected_residue)) {
+            println!("{}", line);
+            if !matched {
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(exponents) = matches.get_many::<u64>("cross_check") {
+        let mut options = lucas_lehmer_options(matches);
+        // A cross-check paused in the PRP test resumes past the Lucas-Lehmer run it finished
+        options.keep_checkpoint = options.deadline.is_some();
+        let json = matches.get_one::<String>("format").map(String::as_str) == Some("json");
+        let mut disagreements = Vec::new();
+        let mut failures = Vec::new();
+        for &p in exponents {
+            let check = match cross_check(p as u128, &options, gerbicz_block(matches)) {
+                Ok(check) => check,
+                Err(e) if matches!(e.downcast_ref::<MpError>(), Some(MpError::Paused { .. })) => {
+                    error!("Cross-check of 2^{}-1 {}.", p, e);
+                    std::process::exit(EXIT_PAUSED);
+                }
+                // The other exponents are worth checking all the same
+                Err(e) => {
+                    error!("Cross-check of 2^{}-1 failed: {}", p, e);
+                    if json {
+                        println!("{}", json!({"exponent": p, "error": e.to_string()}));
                     }
-                };
+                    failures.push(format!("M{}", p));
+                    continue;
+                }
+            };
+            let probable_prime = check.prp.is_probable_prime();
+            let prp_res64 = check.prp.res64.map_or("none".to_string(), |residue| format!("{:016x}", residue));
+            if json {
+                let record = json!({
+                    "exponent": p,
+                    "lucas_lehmer": {"prime": check.lucas_lehmer.is_prime, "res64": format!("{:016x}", check.lucas_lehmer.res64)},
+                    "prp": {"base": 3, "probable_prime": probable_prime, "res64": check.prp.res64.map(|residue| format!("{:016x}", residue))},
+                    "agree": check.agrees(),
+                });
+                println!("{}", record);
+            } else {
                 println!(
-                    "{}: {}",
-                    number,
-                    if is_prp(&BigUint::from(number), 2) {
-                        "Probably prime"
-                    } else {
-                        "Probably not prime"
-                    }
+                    "M{}: Lucas-Lehmer says {} (res64 {:016x}), PRP to base 3 says {} (res64 {}): {}.",
+                    p,
+                    if check.lucas_lehmer.is_prime { "prime" } else { "composite" },
+                    check.lucas_lehmer.res64,
+                    if probable_prime { "probably prime" } else { "composite" },
+                    prp_res64,
+                    if check.agrees() { "the tests agree" } else { "THE TESTS DISAGREE" }
                 );
             }
-        } else if let Some(numbers) = matches.get_many::<String>("number") {
-            for number_str in numbers {
-                let number: u128 = match number_str.parse() {
-                    Ok(num) => num,
-                    Err(_) => {
-                        eprintln!("Please enter a valid number.");
-                        continue;
+            info!("M{} cross-check: Lucas-Lehmer res64 {:016x}, PRP res64 {}", p, check.lucas_lehmer.res64, prp_res64);
+            if !check.agrees() {
+                disagreements.push(format!("M{}", p));
+            }
+        }
+        if !json {
+            report_gerbicz_errors();
+        }
+        if !disagreements.is_empty() {
+            error!(
+                "The Lucas-Lehmer and PRP tests of {} disagree, so the hardware or this program made an error: rerun them, ideally on another device",
+                disagreements.join(", ")
+            );
+        }
+        if !failures.is_empty() {
+            error!("The cross-checks of {} failed, so they have no verdict", failures.join(", "));
+        }
+        if !disagreements.is_empty() || !failures.is_empty() {
+            std::process::exit(1);
+        }
+    } else if let Some(&n) = matches.get_one::<u64>("factor") {
+        print_factorization(n);
+    } else if let Some(&n) = matches.get_one::<u64>("nth") {
+        let after = matches.get_one::<u128>("after").copied().unwrap_or(0);
+        match nth_prime(n, after) {
+            Ok(prime) if matches.get_one::<String>("format").map(String::as_str) == Some("json") => {
+                println!("{}", json!({"index": n, "after": after, "prime": prime}))
+            }
+            Ok(prime) if delimiter(matches).is_some() => {
+                let separator = delimiter(matches).unwrap();
+                println!("{}", delimited_row(&[&"index", &"after", &"prime"], separator));
+                println!("{}", delimited_row(&[&n, &after, &prime], separator));
+            }
+            Ok(prime) => println!("{}", prime),
+            Err(e) => {
+                eprintln!("Error finding prime {}: {}", n, e);
+                std::process::exit(1);
+            }
+        }
+    } else if matches.contains_id("generate") {
+        run_generate(matches);
+    } else if matches.get_flag("repl") {
+        if !matches.get_flag("ll") && !matches.get_flag("prp") {
+            eprintln!("--repl needs -l/--ll or -p/--prp.");
+            std::process::exit(1);
+        }
+        run_repl(matches, matches.get_flag("ll"));
+    } else if matches.get_flag("ll") {
+        run_lucas_lehmer(matches);
+    } else if matches.get_flag("prp") {
+        run_prp(matches);
+    } else {
+        eprintln!("No action specified. Use mp ll, mp prp, mp gen, mp factor, --nth, --next or --prev.");
+    }
+}
+
+/// Generates the primes `mp gen` or `--generate` asks for, and whatever `matches` makes
+/// of them.
+fn run_generate(matches: &ArgMatches) {
+    let _profile = ProfileReport::start(matches);
+    let mut values = matches.get_many::<u128>("generate").unwrap();
+    let start = *values.next().unwrap();
+    let end = *values.next().unwrap();
+    if start >= end {
+        clap::Error::raw(
+            clap::error::ErrorKind::ValueValidation,
+            format!("START must be less than END, but the range {}..{} is empty\n", start, end),
+        )
+        .exit();
+    }
+    if output_file(matches).is_none() {
+        for flag in ["compress", "resume"] {
+            if matches.get_flag(flag) {
+                clap::Error::raw(
+                    clap::error::ErrorKind::ArgumentConflict,
+                    format!("--{} needs -o to name a file, not standard output\n", flag),
+                )
+                .exit();
+            }
+        }
+        // A redrawn bar would land between the primes in the terminal
+        let printing = matches.contains_id("output") || !matches.contains_id("sqlite");
+        if printing && !matches.get_flag("count") {
+            HIDE_PROGRESS.store(true, Ordering::Relaxed);
+        }
+    }
+    if end - start >= LARGE_SPAN {
+        eprintln!("Warning: the range spans {} numbers and will take a long time to generate", end - start);
+    }
+    if !matches.get_flag("fermat") && (given(matches, "bases") || matches.contains_id("base_file")) {
+        clap::Error::raw(
+            clap::error::ErrorKind::MissingRequiredArgument,
+            "--bases and --base-file only apply to generating with --fermat\n",
+        )
+        .exit();
+    }
+    let method = if matches.get_flag("fermat") {
+        Method::Fermat
+    } else if matches.get_flag("gpu") || given(matches, "devices") {
+        Method::GpuSieve
+    } else if matches.get_flag("cpu") {
+        Method::Sieve
+    } else {
+        Method::Auto
+    };
+    let mut options = GenerateOptions {
+        method,
+        bases: read_bases(matches).unwrap_or_else(|| DEFAULT_BASES.to_vec()),
+        tune: matches.get_flag("tune"),
+        local_size: matches.get_one::<u64>("local_size").map(|&size| size as usize),
+        verify: !matches.get_flag("no_verify"),
+        progression: None,
+        devices: Vec::new(),
+        gpu_threshold: matches.get_one::<u128>("gpu_threshold").copied().unwrap_or(GPU_RANGE_THRESHOLD),
+        max_gpu_mem: matches.get_one::<u64>("max_gpu_mem").copied(),
+        preallocate: matches.get_one::<Preallocate>("preallocate_results").copied().unwrap_or(Preallocate::Estimate),
+    };
+    if let Some(list) = matches.get_one::<String>("devices") {
+        options.devices = match parse_devices(list) {
+            Ok(devices) => devices,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        };
+    }
+    if let Some(&modulus) = matches.get_one::<u64>("mod") {
+        let residues: Vec<u64> = matches.get_many::<u64>("residue").unwrap().copied().collect();
+        match Progression::new(modulus, &residues) {
+            Ok(progression) => options.progression = Some(progression),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if matches.get_flag("sieve_output") {
+        let mut writer: Box<dyn Write> = match output_file(matches) {
+            Some(filename) => Box::new(std::fs::File::create(filename).expect("Failed to create output file")),
+            None => Box::new(std::io::stdout()),
+        };
+        let result = smallest_prime_factors(start, end, &mut |pairs| {
+            let lines: String = pairs.iter().map(|(n, factor)| format!("{} {}\n", n, factor)).collect();
+            writer.write_all(lines.as_bytes())?;
+            writer.flush()?;
+            Ok(())
+        });
+        if let Err(e) = result {
+            eprintln!("Error generating factors: {}", e);
+            suggest_cpu(&*e);
+        }
+        return;
+    }
+    if matches.get_flag("gaps") {
+        let min_gap = matches.get_one::<u128>("min_gap").copied();
+        let mut writer: Box<dyn Write> = match output_file(matches) {
+            Some(filename) => Box::new(std::fs::File::create(filename).expect("Failed to create output file")),
+            None => Box::new(std::io::stdout()),
+        };
+        let mut stats = GapStats::default();
+        let result = generate_primes_with(start, end, &options, &mut |chunk| {
+            // Without --min-gap nothing is listed as it is found
+            let large = stats.record(chunk, min_gap.unwrap_or(u128::MAX));
+            let lines: String = large.iter().map(|(gap, prime)| format!("Gap {} after {}\n", gap, prime)).collect();
+            writer.write_all(lines.as_bytes())?;
+            writer.flush()?;
+            Ok(())
+        })
+        .and_then(|_| write_gap_report(&mut writer, &stats, min_gap.unwrap_or(0)));
+        if let Err(e) = result {
+            eprintln!("Error generating gaps: {}", e);
+            suggest_cpu(&*e);
+        }
+        return;
+    }
+    let constellation = match matches.get_many::<u128>("constellation") {
+        Some(offsets) => Some(Constellation::new(offsets.copied().collect()).unwrap_or_else(|e| {
+            clap::Error::raw(clap::error::ErrorKind::ValueValidation, format!("--constellation: {}\n", e)).exit()
+        })),
+        None => matches.get_flag("twins").then(Constellation::twins),
+    };
+    if let Some(mut constellation) = constellation {
+        let json = matches.get_one::<String>("format").map(String::as_str) == Some("json");
+        let separator = delimiter(matches);
+        let mut writer: Box<dyn Write> = match output_file(matches) {
+            Some(filename) => Box::new(std::fs::File::create(filename).expect("Failed to create output file")),
+            None => Box::new(std::io::stdout()),
+        };
+        let offsets = constellation.offsets().to_vec();
+        let header: Vec<String> = match offsets[..] {
+            [0, 2] => vec!["prime".to_string(), "twin".to_string()],
+            _ => offsets.iter().map(|offset| format!("p+{}", offset)).collect(),
+        };
+        let header: Vec<&dyn std::fmt::Display> = header.iter().map(|name| name as &dyn std::fmt::Display).collect();
+        let header = separator.map_or(Ok(()), |separator| writeln!(writer, "{}", delimited_row(&header, separator)));
+        let mut write_tuples = |firsts: Vec<u128>| -> Result<(), Box<dyn std::error::Error>> {
+            let mut lines = String::new();
+            for first in firsts {
+                let members: Vec<u128> = offsets.iter().map(|offset| first + offset).collect();
+                let fields: Vec<&dyn std::fmt::Display> = members.iter().map(|p| p as &dyn std::fmt::Display).collect();
+                let joined = |separator: &str| members.iter().map(u128::to_string).collect::<Vec<_>>().join(separator);
+                lines.push_str(&match separator {
+                    Some(separator) => delimited_row(&fields, separator),
+                    None if json => format!("[{}]", joined(", ")),
+                    None => joined(" "),
+                });
+                lines.push('\n');
+            }
+            writer.write_all(lines.as_bytes())?;
+            writer.flush()?;
+            Ok(())
+        };
+        // Tuples starting just below the end have members past it
+        let span = constellation.span();
+        let result = header.map_err(Into::into).and_then(|_| {
+            generate_primes_with(start, end.saturating_add(span), &options, &mut |chunk| write_tuples(constellation.matches(chunk, end)))
+        });
+        if let Err(e) = result.and_then(|_| write_tuples(constellation.finish(end))) {
+            eprintln!("Error generating prime constellations: {}", e);
+            suggest_cpu(&*e);
+        }
+        return;
+    }
+
+    // Without --output-format, --format csv and tsv pick the layout of the same name
+    let layout = match matches.value_source("output_format") {
+        Some(ValueSource::CommandLine) => "output_format",
+        _ if delimiter(matches).is_some() => "format",
+        _ => "output_format",
+    };
+    let format = match matches.get_one::<u64>("columns") {
+        Some(&columns) => {
+            let width = prev_prime(&BigUint::from(end)).map_or(1, |search| search.prime.to_string().len());
+            OutputFormat::Columns { columns: columns as usize, width }
+        }
+        None => matches
+            .get_one::<String>(layout)
+            .and_then(|name| OutputFormat::from_name(name))
+            .unwrap_or(OutputFormat::Lines),
+    };
+    // With --resume, a progress file from an interrupted run moves the start past what
+    // was already written
+    let mut start = start;
+    let mut resumed = false;
+    let progress = matches.get_flag("resume").then(|| {
+        let filename = output_file(matches).unwrap();
+        if format != OutputFormat::Lines {
+            eprintln!("--resume only supports the lines output format.");
+            std::process::exit(1);
+        }
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        let progress = GenerationProgress::new(filename, &args);
+        let checkpoint = progress.load().and_then(|checkpoint| match checkpoint {
+            Some(checkpoint) => progress.restore(filename, checkpoint).map(|_| Some(checkpoint)),
+            None => Ok(None),
+        });
+        match checkpoint {
+            Ok(Some(checkpoint)) => {
+                eprintln!("Resuming {} from {}", filename, checkpoint.next);
+                start = start.max(checkpoint.next);
+                resumed = true;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                eprintln!("Cannot resume: {}", e);
+                std::process::exit(1);
+            }
+        }
+        progress
+    });
+    let mut sink = match output_file(matches) {
+        Some(filename) if resumed => PrimeSink::append_to_file(filename, format),
+        Some(filename) if matches.get_flag("compress") => PrimeSink::to_compressed_file(filename, format),
+        Some(filename) => PrimeSink::to_file(filename, format),
+        None => PrimeSink::stdout(format),
+    }
+    .expect("Failed to open output for primes");
+    // A resumed file already starts with the header of its first run
+    if output_file(matches).is_some() && !resumed && !matches.get_flag("no_header") {
+        let metadata = output_metadata(matches, &options, start, end);
+        sink.write_metadata(&metadata).expect("Failed to write the output header");
+    }
+    let count_only = matches.get_flag("count");
+    let started = Instant::now();
+    let mersenne_candidates = matches.get_flag("mersenne_candidates");
+    let min_factor = matches.get_one::<u128>("min_factor").copied();
+    let max_factor = matches.get_one::<u128>("max_factor").copied();
+    let filter = if matches.get_flag("sophie_germain") {
+        Some(PrimeFilter::SophieGermain)
+    } else if matches.get_flag("safe") {
+        Some(PrimeFilter::Safe)
+    } else {
+        None
+    };
+    let mut kept = 0u64;
+    let mut stats = matches.get_flag("stats").then(RangeStats::default);
+    // Counting every prime needs no primes, which the GPU sieve can leave on the device
+    let count_on_device = count_only && filter.is_none() && stats.is_none();
+    let mut database = matches.get_one::<String>("sqlite").map(|filename| open_database(filename));
+    // A database takes the place of standard output, but not of an -o file
+    let to_sink = database.is_none() || matches.contains_id("output");
+
+    // Each batch of primes goes straight to the output as soon as it is found
+    let mut emit = |chunk: &[u128]| -> Result<(), Box<dyn std::error::Error>> {
+        let filtered;
+        let chunk = match filter {
+            Some(filter) => {
+                filtered = filter.apply(chunk);
+                &filtered[..]
+            }
+            None => chunk,
+        };
+        kept += chunk.len() as u64;
+        if let Some(stats) = stats.as_mut() {
+            stats.record(chunk);
+        }
+        if count_only {
+            Ok(())
+        } else if mersenne_candidates {
+            for &prime in chunk {
+                let low = min_factor.unwrap_or(0);
+                let high = max_factor.unwrap_or_else(|| {
+                    let span = (2 * DEFAULT_K_LIMIT as u128).saturating_mul(prime);
+                    low.saturating_add(span).max(span + 1)
+                });
+                let sweep = sweep_mersenne_factors(prime, low, high);
+                match (sweep.factor, sweep.checked_up_to) {
+                    (Some(factor), _) => println!("{}: not worth testing (factor {})", prime, factor),
+                    // Bounds given by hand are part of a sweep split across runs, so say where this one got
+                    (None, Some(checked)) if min_factor.is_some() || max_factor.is_some() => {
+                        println!("{}: worth testing (no factor up to {})", prime, checked)
                     }
-                };
-                println!(
-                    "{}: {}",
-                    number,
-                    if is_prp(&BigUint::from(number), 2) {
-                        "Probably prime"
-                    } else {
-                        "Probably not prime"
+                    (None, None) if min_factor.is_some() || max_factor.is_some() => {
+                        println!("{}: worth testing (no candidates in range)", prime)
                     }
-                );
+                    (None, _) => println!("{}: worth testing", prime),
+                }
             }
+            Ok(())
         } else {
-            eprintln!("No numbers provided for Probable Prime test.");
+            if let Some(database) = database.as_mut() {
+                database.insert_primes(chunk)?;
+            }
+            if to_sink {
+                sink.write_chunk(chunk)?;
+            }
+            if let (Some(progress), Some(&last)) = (&progress, chunk.last()) {
+                progress.save(Checkpoint { next: last + 1, bytes: sink.bytes_written() })?;
+            }
+            Ok(())
+        }
+    };
+
+    let mut counted = None;
+    let result = match matches.get_one::<String>("sieve").and_then(|name| Sieve::from_name(name)) {
+        // Everything was written before the interruption
+        _ if start >= end => Ok(()),
+        None if count_on_device => {
+            count_primes(start, end, &options).map(|count| counted = Some(count))
+        }
+        Some(sieve) => profile::time(Phase::Execute, || {
+            sieve.primes_preallocated(start, end, options.preallocate, options.progression.as_ref())
+        })
+        .and_then(|primes| emit(&primes)),
+        None => generate_primes_with(start, end, &options, &mut emit).map(|_| ()),
+    };
+    let generated = result.is_ok();
+    match result {
+        Ok(()) if count_only => {
+            println!("{}", counted.unwrap_or(kept));
+            eprintln!("Counted in {:.3}s", started.elapsed().as_secs_f64());
+        }
+        Ok(()) => {
+            if let Err(e) = sink.finish().and_then(|_| progress.as_ref().map_or(Ok(()), |p| p.clear())) {
+                eprintln!("Error finishing the output: {}", e);
+            }
+        }
+        Err(e) => {
+            eprintln!("Error generating primes: {}", e);
+            suggest_cpu(&*e);
+        }
+    }
+    if let Some(stats) = stats.as_ref().filter(|_| generated) {
+        let unfiltered = filter.is_none() && options.progression.is_none();
+        print_range_stats(stats, unfiltered.then(|| prime_count_estimate(start, end)));
+    }
+}
+
+/// Runs the Lucas-Lehmer tests `mp ll` or `-l` asks for and reports them.
+fn run_lucas_lehmer(matches: &ArgMatches) {
+    let _profile = ProfileReport::start(matches);
+    let mut options = lucas_lehmer_options(matches);
+    let use_memory = options.mem;
+    let jobs = *matches.get_one::<u64>("jobs").unwrap() as usize;
+    let mut numbers = read_numbers(matches);
+    numbers.retain(|&p| at_least_two(p, "Lucas-Lehmer exponents"));
+    let listed = numbers.len();
+    if skips_done(matches) {
+        let done = done_tests(matches, "ll");
+        numbers.retain(|&p| {
+            match done.get("ll", p) {
+                Some(Some(res64)) => eprintln!("M{}: skipped (already done, Res64 {:016x})", p, res64),
+                Some(None) => eprintln!("M{}: skipped (already done)", p),
+                None => return true,
+            }
+            false
+        });
+    }
+    if listed == 0 {
+        eprintln!("No numbers provided for Lucas-Lehmer test.");
+    }
+    if matches.get_flag("dry_run") {
+        print_plan("Lucas-Lehmer", &TestPlan::lucas_lehmer(&numbers), "would run on the CPU");
+        return;
+    }
+    let json = matches.get_one::<String>("format").map(String::as_str) == Some("json");
+    let mut results_file = ResultsFile::open(matches);
+    let mut verdict_files = VerdictFiles::open(matches);
+    // Ctrl-C lets the batch in flight finish and checkpoint instead of killing it
+    ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::SeqCst)).expect("Failed to install the Ctrl-C handler");
+    if jobs > 1 {
+        SHARE_PROGRESS.store(true, Ordering::Relaxed);
+    }
+    if clears_screen(matches) && jobs == 1 {
+        print!("\x1B[2J\x1B[1;1H");
+    }
+    // A paused batch resumes past the runs it finished by keeping their last checkpoints
+    options.keep_checkpoint = options.deadline.is_some() && numbers.len() > 1;
+    let expected_residue = matches.get_one::<u64>("expected_residue").copied();
+    let mut rows = Vec::new();
+    let mut interrupted = false;
+    let mut paused = false;
+    let mut mismatched = false;
+    let separator = delimiter(matches);
+    if let Some(separator) = separator {
+        let mut header: Vec<&dyn std::fmt::Display> = vec![&"exponent", &"mersenne_prime"];
+        if expected_residue.is_some() {
+            header.extend([&"res64" as &dyn std::fmt::Display, &"residue_match"]);
+        }
+        println!("{}", delimited_row(&header, separator));
+    }
+    let test = |context: &mut Option<GpuContext>, number| lucas_lehmer_with_threshold(context, number, &options);
+    let reported = lucas_lehmer_jobs(&numbers, jobs, options.deadline, test, |number, result, elapsed| {
+        let (verdict, prime, res64) = match result {
+            Ok(result) => (if result.is_prime { "prime" } else { "composite" }, result.is_prime, result.res64),
+            Err(e) if matches!(e.downcast_ref::<MpError>(), Some(MpError::Interrupted { .. })) => {
+                if use_memory {
+                    warn!("Lucas-Lehmer test of {} {}; checkpoint saved, rerun with -m to resume.", number, e);
+                } else {
+                    warn!("Lucas-Lehmer test of {} {}; rerun with -m to keep progress across interruptions.", number, e);
+                }
+                interrupted = true;
+                return false;
+            }
+            Err(e) if matches!(e.downcast_ref::<MpError>(), Some(MpError::Paused { .. })) => {
+                warn!("Lucas-Lehmer test of {} {}.", number, e);
+                paused = true;
+                return false;
+            }
+            Err(e) => {
+                error!("Error testing {}: {}", number, e);
+                // A test with no residue can't match the expected one
+                mismatched |= expected_residue.is_some();
+                ("error", false, 0)
+            }
+        };
+        if verdict != "error" {
+            info!("M{} is {}, res64 {:016x} after {:.1?}", number, verdict, res64, elapsed);
+            verdict_files.record(number, prime);
+            let m = (BigUint::from(1u32) << number) - 1u32;
+            let message = format!("{} is {}a Mersenne prime.", m, if prime { "" } else { "not " });
+            let check = residue_check(&format!("M{}", number), res64, expected_residue);
+            mismatched |= matches!(check, Some((_, false)));
+            if let Some(separator) = separator {
+                let res64 = format!("{:016x}", res64);
+                let row: Vec<&dyn std::fmt::Display> = match &check {
+                    Some((_, matched)) => vec![&number, &prime, &res64, matched],
+                    None => vec![&number, &prime],
+                };
+                println!("{}", delimited_row(&row, separator));
+            } else if json {
+                let mut record = json!({"exponent": number, "mersenne_prime": prime});
+                if let Some((_, matched)) = &check {
+                    record["res64"] = json!(format!("{:016x}", res64));
+                    record["residue_match"] = json!(matched);
+                }
+                println!("{}", record);
+            } else {
+                println!("{}", message);
+                if let Some((line, _)) = &check {
+                    println!("{}", line);
+                }
+            }
+            results_file.record(&lucas_lehmer_line(number, prime, res64));
+        }
+        rows.push(SummaryRow { number, verdict, prime, res64: (verdict != "error").then_some(res64), elapsed });
+        true
+    });
+    if interrupted {
+        std::process::exit(130);
+    }
+    if paused {
+        std::process::exit(EXIT_PAUSED);
+    }
+    if options.deadline.is_some() && reported < numbers.len() {
+        warn!(
+            "Time limit reached after {} of {} exponents, resume with the same command.",
+            reported,
+            numbers.len()
+        );
+        std::process::exit(EXIT_PAUSED);
+    }
+    if options.keep_checkpoint {
+        for &p in &numbers {
+            if let Err(e) = remove_lucas_lehmer_checkpoints(options.checkpoint_dir.as_deref(), p) {
+                error!("Error removing the checkpoints of {}: {}", p, e);
+            }
         }
+    }
+    if !matches.get_flag("quiet") {
+        print_summary(&rows, matches.get_one::<String>("format").map(String::as_str) == Some("json"));
+    }
+    if let Some(filename) = matches.get_one::<String>("save_results_json") {
+        let format = matches.get_one::<String>("format").unwrap();
+        if let Err(e) = save_results_json(filename, "lucas-lehmer", format, &rows) {
+            error!("Error saving results to {}: {}", filename, e);
+        }
+    }
+    if let Some(filename) = matches.get_one::<String>("sqlite") {
+        if let Err(e) = record_tests(filename, "ll", &rows) {
+            error!("Error saving results to {}: {}", filename, e);
+        }
+    }
+    if mismatched {
+        std::process::exit(1);
+    }
+}
+
+/// Runs the PRP tests `mp prp` or `-p` asks for and reports them.
+fn run_prp(matches: &ArgMatches) {
+    let mut numbers = read_numbers(matches);
+    numbers.retain(|&n| at_least_two(n, "PRP numbers"));
+    let trial = matches.get_flag("trial");
+    // Trial division takes about sqrt(n)/4 divisions, which past 2^64 runs for years
+    if let Some(n) = numbers.iter().find(|&&n| trial && n > u128::from(u64::MAX)) {
+        clap::Error::raw(
+            clap::error::ErrorKind::ValueValidation,
+            format!("--trial takes numbers below 2^64, but {} is larger: test it with -p alone\n", n),
+        )
+        .exit();
+    }
+    if skips_done(matches) {
+        let done = done_tests(matches, "prp");
+        numbers.retain(|&n| {
+            let skipped = done.get("prp", n).is_some();
+            if skipped {
+                eprintln!("{}: skipped (already done)", n);
+            }
+            !skipped
+        });
+    }
+    if numbers.is_empty() {
+        eprintln!("No numbers provided for Probable Prime test.");
+    }
+    if matches.get_flag("dry_run") {
+        print_plan("PRP", &TestPlan::prp(&numbers), "would run on the CPU");
+        return;
+    }
+
+    let bases: Vec<u128> = read_bases(matches).unwrap_or_else(|| vec![2]).into_iter().map(u128::from).collect();
+    let verdicts = if trial {
+        trial_verdicts(&numbers)
     } else {
-        eprintln!("No action specified. Use -l/--ll, -p/--prp, or -g/--generate.");
+        prp_verdicts(&numbers.iter().map(|&n| BigUint::from(n)).collect::<Vec<_>>(), &bases)
+    };
+    let mut verdict_files = VerdictFiles::open(matches);
+    let mut results_file = ResultsFile::open(matches);
+    let mut rows = Vec::new();
+    let verbose = matches.get_count("verbose") > 0 && !trial;
+    let separator = delimiter(matches);
+    if let Some(separator) = separator {
+        let column = if trial { "prime" } else { "probably_prime" };
+        println!("{}", delimited_row(&[&"number", &column], separator));
+    }
+    for (&number, (probably_prime, elapsed)) in numbers.iter().zip(verdicts) {
+        let detail = if verbose && separator.is_none() {
+            match miller_rabin_report(&BigUint::from(number), &bases) {
+                MillerRabinReport::Composite { witness: Some(witness) } => format!(" (witness {})", witness),
+                MillerRabinReport::Composite { witness: None } => " (no witness needed)".to_string(),
+                MillerRabinReport::ProbablyPrime { rounds } => format!(
+                    " ({} round{} passed, {})",
+                    rounds,
+                    if rounds == 1 { "" } else { "s" },
+                    error_note(u64::from(128 - number.leading_zeros()), rounds)
+                ),
+            }
+        } else {
+            String::new()
+        };
+        let line = format!(
+            "{}: {}{}",
+            number,
+            match (trial, probably_prime) {
+                (true, true) => "Prime",
+                (true, false) => "Not prime",
+                (false, true) => "Probably prime",
+                (false, false) => "Probably not prime",
+            },
+            detail
+        );
+        match separator {
+            Some(separator) => println!("{}", delimited_row(&[&number, &probably_prime], separator)),
+            None => println!("{}", line),
+        }
+        results_file.record(&line);
+        let verdict = match (trial, probably_prime) {
+            (true, true) => "prime",
+            (false, true) => "probable prime",
+            (_, false) => "composite",
+        };
+        info!("{} is {} after {:.1?}", number, verdict, elapsed);
+        verdict_files.record(number, probably_prime);
+        rows.push(SummaryRow { number, verdict, prime: probably_prime, res64: None, elapsed });
+    }
+    if !matches.get_flag("quiet") {
+        print_summary(&rows, matches.get_one::<String>("format").map(String::as_str) == Some("json"));
+    }
+    if let Some(filename) = matches.get_one::<String>("save_results_json") {
+        let format = matches.get_one::<String>("format").unwrap();
+        if let Err(e) = save_results_json(filename, "prp", format, &rows) {
+            error!("Error saving results to {}: {}", filename, e);
+        }
+    }
+    if let Some(filename) = matches.get_one::<String>("sqlite") {
+        if let Err(e) = record_tests(filename, "prp", &rows) {
+            error!("Error saving results to {}: {}", filename, e);
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,587 @@
+use log::{debug, info};
+use num_bigint::BigUint;
+use num_traits::{One, Zero};
+use ocl::{flags, Buffer, Device, Kernel, Platform, Queue};
+use std::error::Error;
+use std::time::Instant;
+
+use crate::profile::{self, Phase, TIMINGS};
+use crate::squarer::{squarer, Squarer};
+use crate::test_prime::cached_program;
+
+/// The prime 2^64 - 2^32 + 1 the transforms work mod. 2^32 divides the order of its
+/// multiplicative group, so it has roots of unity for every power-of-two length up to that.
+pub const GOLDILOCKS: u64 = 0xffff_ffff_0000_0001;
+
+/// 2^64 mod `GOLDILOCKS`.
+const EPSILON: u64 = 0xffff_ffff;
+
+/// A generator of the multiplicative group mod `GOLDILOCKS`.
+const GENERATOR: u64 = 7;
+
+/// Squarings between `NttSquarer` self-checks unless told otherwise.
+pub const DEFAULT_SELF_CHECK_INTERVAL: u128 = 10_000;
+
+/// Squarings each self-check repeats on the CPU.
+pub const SELF_CHECK_ITERATIONS: usize = 8;
+
+/// The 128-bit `hi * 2^64 + lo` mod `GOLDILOCKS`, using 2^64 = 2^32 - 1 and 2^96 = -1.
+fn reduce(lo: u64, hi: u64) -> u64 {
+    let (hi_hi, hi_lo) = (hi >> 32, hi & EPSILON);
+    let (mut t0, borrow) = lo.overflowing_sub(hi_hi);
+    if borrow {
+        t0 = t0.wrapping_sub(EPSILON);
+    }
+    let (mut t1, carry) = t0.overflowing_add(hi_lo * EPSILON);
+    if carry {
+        t1 = t1.wrapping_add(EPSILON);
+    }
+    if t1 >= GOLDILOCKS {
+        t1 - GOLDILOCKS
+    } else {
+        t1
+    }
+}
+
+/// a * b mod `GOLDILOCKS` for a, b below it.
+pub fn mul_mod(a: u64, b: u64) -> u64 {
+    let product = a as u128 * b as u128;
+    reduce(product as u64, (product >> 64) as u64)
+}
+
+/// base^exponent mod `GOLDILOCKS`.
+pub fn pow_mod(mut base: u64, mut exponent: u64) -> u64 {
+    let mut result = 1;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = mul_mod(result, base);
+        }
+        base = mul_mod(base, base);
+        exponent >>= 1;
+    }
+    result
+}
+
+/// w^0, w^1, ... w^(length/2 - 1) for a primitive length-th root of unity w, or for its
+/// inverse, which undoes the transform up to a factor of `length`.
+fn roots(length: usize, inverse: bool) -> Vec<u64> {
+    let root = pow_mod(GENERATOR, (GOLDILOCKS - 1) / length as u64);
+    // w^(length - 1) = w^-1
+    let step = if inverse { pow_mod(root, length as u64 - 1) } else { root };
+    let mut power = 1;
+    (0..length / 2)
+        .map(|_| {
+            let current = power;
+            power = mul_mod(power, step);
+            current
+        })
+        .collect()
+}
+
+/// How `NttSquarer` cuts a residue mod 2^p - 1 into digits.
+///
+/// Without the irrational weights of `IbdwtSquarer` there is no wraparound to get for free,
+/// so the transform computes the whole product, zero padded to twice the digits, and the
+/// carry pass folds the bits past p back onto the bottom.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NttLayout {
+    pub p: u128,
+    /// Bits in every digit but the top one, which holds the rest of the p bits.
+    pub width: u32,
+    /// Number of digits.
+    pub count: usize,
+    /// Transform length, a power of two of at least twice `count`.
+    pub length: usize,
+}
+
+impl NttLayout {
+    /// The layout with the widest digits whose product sums, at most
+    /// count * (2^width - 1)^2, stay below half of `GOLDILOCKS`, which leaves the carry
+    /// pass room to add its carries without overflowing 64 bits.
+    pub fn new(p: u128) -> Result<NttLayout, Box<dyn Error>> {
+        for width in (1..=31u32).rev() {
+            let count = p.div_ceil(width as u128);
+            let largest = (1u128 << width) - 1;
+            if count < 2 || count * largest * largest >= GOLDILOCKS as u128 / 2 {
+                continue;
+            }
+            let length = (2 * count).next_power_of_two();
+            if length > 1 << 31 {
+                break;
+            }
+            return Ok(NttLayout { p, width, count: count as usize, length: length as usize });
+        }
+        Err(format!("2^{}-1 is too large or too small for the NTT backend.", p).into())
+    }
+
+    /// Bits in the top digit.
+    fn top_width(&self) -> u32 {
+        (self.p - (self.count as u128 - 1) * self.width as u128) as u32
+    }
+
+    /// `value` mod 2^p - 1 as digits, zero padded to the transform length.
+    pub fn split(&self, value: &BigUint) -> Vec<u64> {
+        let modulus = (BigUint::one() << self.p) - 1u32;
+        let limbs = (value % &modulus).to_u64_digits();
+        let bit = |position: usize| limbs.get(position / 64).map_or(0, |limb| (limb >> (position % 64)) & 1);
+        let mut digits = vec![0u64; self.length];
+        for (i, digit) in digits[..self.count].iter_mut().enumerate() {
+            let start = i * self.width as usize;
+            *digit = (0..self.width as usize).fold(0, |digit, j| digit | (bit(start + j) << j));
+        }
+        digits
+    }
+
+    /// The residue held in digits that `carry_fold` has left in range.
+    pub fn join(&self, digits: &[u64]) -> BigUint {
+        let mut value = BigUint::zero();
+        for &digit in digits[..self.count].iter().rev() {
+            value = (value << self.width) | BigUint::from(digit);
+        }
+        // 2^p - 1 itself is the other form of zero
+        if value == (BigUint::one() << self.p) - 1u32 {
+            BigUint::zero()
+        } else {
+            value
+        }
+    }
+
+    /// Turns the coefficients of a product of two residues into the digits of the product
+    /// minus `subtract`, mod 2^p - 1. This is what the `carry_fold` kernel does, one digit
+    /// at a time.
+    pub fn carry_fold(&self, coefficients: &mut [u64], subtract: u32) {
+        let (width, top_width, top) = (self.width, self.top_width(), self.count - 1);
+        let mask = (1u64 << width) - 1;
+        // The product in base 2^width, which 2 * count digits hold
+        let mut carry = 0;
+        for coefficient in &mut coefficients[..2 * self.count] {
+            let value = *coefficient + carry;
+            *coefficient = value & mask;
+            carry = value >> width;
+        }
+
+        // The low p bits plus the bits above them, which 2^p = 1 folds back to the bottom
+        let mut carry = -(subtract as i64);
+        for i in 0..self.count {
+            let high = (coefficients[top + i] >> top_width) | ((coefficients[top + i + 1] << (width - top_width)) & mask);
+            let (low, bits) = if i < top {
+                (coefficients[i], width)
+            } else {
+                (coefficients[top] & ((1 << top_width) - 1), top_width)
+            };
+            let value = low as i64 + high as i64 + carry;
+            coefficients[i] = (value & ((1 << bits) - 1)) as u64;
+            carry = (value - coefficients[i] as i64) >> bits;
+        }
+        while carry != 0 {
+            for (i, coefficient) in coefficients[..self.count].iter_mut().enumerate() {
+                let bits = if i < top { width } else { top_width };
+                let value = *coefficient as i64 + carry;
+                *coefficient = (value & ((1 << bits) - 1)) as u64;
+                carry = (value - *coefficient as i64) >> bits;
+                if carry == 0 {
+                    break;
+                }
+            }
+        }
+        coefficients[self.count..].fill(0);
+    }
+}
+
+/// OpenCL source of the transform, the pointwise squaring and the carry pass.
+const NTT_SRC: &str = r#"
+    #define GOLDILOCKS 0xffffffff00000001UL
+    #define EPSILON 0xffffffffUL
+
+    ulong reduce(ulong lo, ulong hi) {
+        ulong hi_hi = hi >> 32;
+        ulong hi_lo = hi & EPSILON;
+        ulong t0 = lo - hi_hi;
+        if (lo < hi_hi) {
+            t0 -= EPSILON;
+        }
+        ulong t1 = t0 + hi_lo * EPSILON;
+        if (t1 < t0) {
+            t1 += EPSILON;
+        }
+        return t1 >= GOLDILOCKS ? t1 - GOLDILOCKS : t1;
+    }
+
+    ulong mul_mod(ulong a, ulong b) {
+        return reduce(a * b, mul_hi(a, b));
+    }
+
+    ulong add_mod(ulong a, ulong b) {
+        ulong sum = a + b;
+        return (sum < a || sum >= GOLDILOCKS) ? sum - GOLDILOCKS : sum;
+    }
+
+    ulong sub_mod(ulong a, ulong b) {
+        return a >= b ? a - b : a + (GOLDILOCKS - b);
+    }
+
+    __kernel void bit_reverse(__global ulong* a, __global const uint* reversed) {
+        uint i = get_global_id(0);
+        uint j = reversed[i];
+        if (i < j) {
+            ulong t = a[i];
+            a[i] = a[j];
+            a[j] = t;
+        }
+    }
+
+    // One radix-2 stage: butterflies of points half apart, one per work item
+    __kernel void ntt_stage(__global ulong* a, __global const ulong* roots, uint half, uint stride) {
+        uint gid = get_global_id(0);
+        uint k = gid % half;
+        uint i = (gid / half) * 2 * half + k;
+        ulong u = a[i];
+        ulong v = mul_mod(a[i + half], roots[k * stride]);
+        a[i] = add_mod(u, v);
+        a[i + half] = sub_mod(u, v);
+    }
+
+    // Squares each point, with the inverse transform's 1 / length folded in
+    __kernel void square_points(__global ulong* a, ulong scale) {
+        uint i = get_global_id(0);
+        a[i] = mul_mod(mul_mod(a[i], a[i]), scale);
+    }
+
+    __kernel void carry_fold(__global ulong* c, uint count, uint width, uint top_width, uint length, uint subtract) {
+        ulong mask = (1UL << width) - 1;
+        ulong carry = 0;
+        for (uint j = 0; j < 2 * count; j++) {
+            ulong value = c[j] + carry;
+            c[j] = value & mask;
+            carry = value >> width;
+        }
+
+        uint top = count - 1;
+        long folded = -(long)subtract;
+        for (uint i = 0; i < count; i++) {
+            ulong high = (c[top + i] >> top_width) | ((c[top + i + 1] << (width - top_width)) & mask);
+            uint bits = i < top ? width : top_width;
+            ulong low = i < top ? c[i] : c[top] & ((1UL << top_width) - 1);
+            long value = (long)low + (long)high + folded;
+            c[i] = (ulong)value & ((1UL << bits) - 1);
+            folded = (value - (long)c[i]) / (1L << bits);
+        }
+        while (folded != 0) {
+            for (uint i = 0; i < count && folded != 0; i++) {
+                uint bits = i < top ? width : top_width;
+                long value = (long)c[i] + folded;
+                c[i] = (ulong)value & ((1UL << bits) - 1);
+                folded = (value - (long)c[i]) / (1L << bits);
+            }
+        }
+        for (uint j = count; j < length; j++) {
+            c[j] = 0;
+        }
+    }
+"#;
+
+/// Squaring mod 2^p - 1 on the first OpenCL device with a number-theoretic transform mod
+/// `GOLDILOCKS`, for exponents far past the 64-bit Lucas-Lehmer kernel.
+///
+/// The residue stays on the device between squarings. With a self-check interval, every
+/// that many squarings the residue is read back, the next `SELF_CHECK_ITERATIONS`
+/// squarings are repeated by `squarer` on the CPU, and the low 64 bits of the two results
+/// must agree.
+pub struct NttSquarer {
+    layout: NttLayout,
+    queue: Queue,
+    digits: Buffer<u64>,
+    /// Bit reversal, then one kernel per stage, for each direction.
+    forward: Vec<Kernel>,
+    inverse: Vec<Kernel>,
+    square: Kernel,
+    carry: Kernel,
+    /// The root tables and the bit-reversal permutation the kernels read.
+    _tables: (Buffer<u64>, Buffer<u64>, Buffer<u32>),
+    self_check: Option<SelfCheck>,
+}
+
+struct SelfCheck {
+    interval: u128,
+    squarings: u128,
+    /// The residue the check started from and the subtractions since.
+    pending: Option<(BigUint, Vec<u32>)>,
+}
+
+impl NttSquarer {
+    /// Puts `initial` on the device, self-checking every `self_check` squarings if given.
+    pub fn new(p: u128, initial: &BigUint, self_check: Option<u128>) -> Result<NttSquarer, Box<dyn Error>> {
+        let layout = NttLayout::new(p)?;
+        let length = layout.length;
+        let device = profile::time(Phase::Init, || Device::first(Platform::first()?))?;
+        let (context, program) = cached_program(device, NTT_SRC)?;
+        let queue = profile::time(Phase::Init, || Queue::new(&context, device, None))?;
+        let setting_up = Instant::now();
+        debug!("NTT squaring of 2^{}-1 on {}: {} digits of {} bits, length {}", p, device.name()?, layout.count, layout.width, length);
+
+        let digits = Buffer::<u64>::builder()
+            .queue(queue.clone())
+            .flags(flags::MEM_READ_WRITE)
+            .len(length)
+            .copy_host_slice(&layout.split(initial))
+            .build()?;
+        let bits = length.trailing_zeros();
+        let reversed: Vec<u32> = (0..length as u32).map(|i| i.reverse_bits() >> (32 - bits)).collect();
+        let reversed = Buffer::<u32>::builder()
+            .queue(queue.clone())
+            .flags(flags::MEM_READ_ONLY | flags::MEM_COPY_HOST_PTR)
+            .len(length)
+            .copy_host_slice(&reversed)
+            .build()?;
+        let table = |inverse| {
+            Buffer::<u64>::builder()
+                .queue(queue.clone())
+                .flags(flags::MEM_READ_ONLY | flags::MEM_COPY_HOST_PTR)
+                .len(length / 2)
+                .copy_host_slice(&roots(length, inverse))
+                .build()
+        };
+        let (forward_roots, inverse_roots) = (table(false)?, table(true)?);
+
+        let transform = |roots: &Buffer<u64>| -> Result<Vec<Kernel>, Box<dyn Error>> {
+            let mut kernels = vec![Kernel::builder()
+                .program(&program)
+                .name("bit_reverse")
+                .queue(queue.clone())
+                .global_work_size(length)
+                .arg(&digits)
+                .arg(&reversed)
+                .build()?];
+            let mut half = 1;
+            while half < length {
+                kernels.push(
+                    Kernel::builder()
+                        .program(&program)
+                        .name("ntt_stage")
+                        .queue(queue.clone())
+                        .global_work_size(length / 2)
+                        .arg(&digits)
+                        .arg(roots)
+                        .arg(half as u32)
+                        .arg((length / (2 * half)) as u32)
+                        .build()?,
+                );
+                half *= 2;
+            }
+            Ok(kernels)
+        };
+        let (forward, inverse) = (transform(&forward_roots)?, transform(&inverse_roots)?);
+        let square = Kernel::builder()
+            .program(&program)
+            .name("square_points")
+            .queue(queue.clone())
+            .global_work_size(length)
+            .arg(&digits)
+            .arg(pow_mod(length as u64, GOLDILOCKS - 2))
+            .build()?;
+        let carry = Kernel::builder()
+            .program(&program)
+            .name("carry_fold")
+            .queue(queue.clone())
+            .global_work_size(1)
+            .arg(&digits)
+            .arg(layout.count as u32)
+            .arg(layout.width)
+            .arg(layout.top_width())
+            .arg(length as u32)
+            .arg(0u32) // Placeholder for the subtraction
+            .build()?;
+        TIMINGS.record(Phase::Buffers, setting_up.elapsed());
+
+        Ok(NttSquarer {
+            layout,
+            queue,
+            digits,
+            forward,
+            inverse,
+            square,
+            carry,
+            _tables: (forward_roots, inverse_roots, reversed),
+            self_check: self_check.filter(|&interval| interval > 0).map(|interval| SelfCheck {
+                interval,
+                squarings: 0,
+                pending: None,
+            }),
+        })
+    }
+
+    /// How the residue is laid out on the device.
+    pub fn layout(&self) -> NttLayout {
+        self.layout
+    }
+
+    /// Name of the device the residue is squared on.
+    pub fn device_name(&self) -> Result<String, Box<dyn Error>> {
+        Ok(self.queue.device().name()?)
+    }
+}
+
+impl Squarer for NttSquarer {
+    fn square_sub(&mut self, subtract: u32) -> Result<(), Box<dyn Error>> {
+        self.carry.set_arg(5, subtract)?;
+        profile::time(Phase::Execute, || {
+            for kernel in self.forward.iter().chain([&self.square]).chain(&self.inverse).chain([&self.carry]) {
+                unsafe {
+                    kernel.enq()?;
+                }
+            }
+            Ok::<_, ocl::Error>(())
+        })?;
+
+        let Some(mut check) = self.self_check.take() else {
+            return Ok(());
+        };
+        check.squarings += 1;
+        match check.pending.take() {
+            Some((start, mut subtracts)) => {
+                subtracts.push(subtract);
+                if subtracts.len() < SELF_CHECK_ITERATIONS {
+                    check.pending = Some((start, subtracts));
+                } else {
+                    let mut cpu = squarer(self.layout.p, &start);
+                    for &subtract in &subtracts {
+                        cpu.square_sub(subtract)?;
+                    }
+                    let (expected, found) = (res64(&cpu.residue()?), res64(&self.residue()?));
+                    if expected != found {
+                        return Err(format!(
+                            "The device's residue of 2^{}-1 after squaring {} is {:016x}, but the CPU's is {:016x}.",
+                            self.layout.p, check.squarings, found, expected
+                        )
+                        .into());
+                    }
+                    info!("Self-check of 2^{}-1 passed at squaring {}", self.layout.p, check.squarings);
+                }
+            }
+            None if check.squarings.is_multiple_of(check.interval) => {
+                check.pending = Some((self.residue()?, Vec::new()));
+            }
+            None => {}
+        }
+        self.self_check = Some(check);
+        Ok(())
+    }
+
+    fn residue(&self) -> Result<BigUint, Box<dyn Error>> {
+        // The squarings queued before the read finish first, so that they count as execution
+        profile::time(Phase::Execute, || self.queue.finish())?;
+        let mut digits = vec![0u64; self.layout.length];
+        profile::time(Phase::Readback, || self.digits.read(&mut digits).queue(&self.queue).enq())?;
+        Ok(self.layout.join(&digits))
+    }
+}
+
+/// The low 64 bits of a residue.
+pub fn res64(residue: &BigUint) -> u64 {
+    residue.iter_u64_digits().next().unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::squarer::BigUintSquarer;
+
+    /// The transform the `bit_reverse` and `ntt_stage` kernels run, on the CPU.
+    fn transform(points: &mut [u64], roots: &[u64]) {
+        let length = points.len();
+        let bits = length.trailing_zeros();
+        for i in 0..length {
+            let j = (i as u32).reverse_bits() as usize >> (32 - bits);
+            if i < j {
+                points.swap(i, j);
+            }
+        }
+        let mut half = 1;
+        while half < length {
+            for gid in 0..length / 2 {
+                let (k, i) = (gid % half, (gid / half) * 2 * half + gid % half);
+                let (u, v) = (points[i], mul_mod(points[i + half], roots[k * (length / (2 * half))]));
+                points[i] = (u as u128 + v as u128).rem_euclid(GOLDILOCKS as u128) as u64;
+                points[i + half] = (u as u128 + GOLDILOCKS as u128 - v as u128).rem_euclid(GOLDILOCKS as u128) as u64;
+            }
+            half *= 2;
+        }
+    }
+
+    /// One squaring the way `NttSquarer` runs it, with the kernels replaced by the CPU.
+    fn square_sub(layout: &NttLayout, digits: &mut [u64], subtract: u32) {
+        transform(digits, &roots(layout.length, false));
+        let scale = pow_mod(layout.length as u64, GOLDILOCKS - 2);
+        for point in digits.iter_mut() {
+            *point = mul_mod(mul_mod(*point, *point), scale);
+        }
+        transform(digits, &roots(layout.length, true));
+        layout.carry_fold(digits, subtract);
+    }
+
+    #[test]
+    fn goldilocks_products_match_u128_arithmetic() {
+        let values = [0, 1, 2, EPSILON, EPSILON + 1, 1 << 63, GOLDILOCKS - 2, GOLDILOCKS - 1, 0x1234_5678_9abc_def0];
+        for &a in &values {
+            for &b in &values {
+                let expected = (a as u128 * b as u128 % GOLDILOCKS as u128) as u64;
+                assert_eq!(mul_mod(a, b), expected, "{} * {}", a, b);
+            }
+        }
+        let root = pow_mod(GENERATOR, (GOLDILOCKS - 1) >> 32);
+        assert_eq!(pow_mod(root, 1 << 32), 1);
+        assert_ne!(pow_mod(root, 1 << 31), 1);
+    }
+
+    #[test]
+    fn layouts_keep_the_product_sums_below_the_modulus() {
+        for p in [89, 1279, 86_243, 1_000_003, 100_000_007] {
+            let layout = NttLayout::new(p).unwrap();
+            let largest = (1u128 << layout.width) - 1;
+            assert!(layout.count as u128 * largest * largest < GOLDILOCKS as u128 / 2, "{:?}", layout);
+            assert!(layout.length >= 2 * layout.count && layout.length.is_power_of_two(), "{:?}", layout);
+            assert!(layout.top_width() >= 1 && layout.top_width() <= layout.width, "{:?}", layout);
+        }
+        assert_eq!(NttLayout::new(86_243).unwrap().length, 8192);
+        assert!(NttLayout::new(1).is_err());
+    }
+
+    #[test]
+    fn squarings_on_the_cpu_match_biguint() {
+        for (p, iterations) in [(89, 87), (127, 125), (1279, 1277), (4423, 200), (86_243, 3)] {
+            let layout = NttLayout::new(p).unwrap();
+            let four = BigUint::from(4u32);
+            let mut digits = layout.split(&four);
+            let mut reference = BigUintSquarer::new(p, &four);
+            for _ in 0..iterations {
+                square_sub(&layout, &mut digits, 2);
+                reference.square_sub(2).unwrap();
+            }
+            assert_eq!(layout.join(&digits), reference.residue().unwrap(), "M{}", p);
+        }
+        // Subtracting from zero wraps around to 2^p - 3
+        let layout = NttLayout::new(89).unwrap();
+        let mut digits = layout.split(&BigUint::zero());
+        square_sub(&layout, &mut digits, 2);
+        assert_eq!(layout.join(&digits), (BigUint::one() << 89u32) - 3u32);
+    }
+
+    #[test]
+    #[ignore = "needs an OpenCL device"]
+    fn the_device_finds_m86243_prime() {
+        let p = 86_243;
+        let mut ntt = NttSquarer::new(p, &BigUint::from(4u32), Some(DEFAULT_SELF_CHECK_INTERVAL)).unwrap();
+        for _ in 0..p - 2 {
+            ntt.square_sub(2).unwrap();
+        }
+        assert!(ntt.residue().unwrap().is_zero());
+
+        let mut ntt = NttSquarer::new(p + 6, &BigUint::from(4u32), Some(1000)).unwrap();
+        let mut ibdwt = squarer(p + 6, &BigUint::from(4u32));
+        for _ in 0..p + 4 {
+            ntt.square_sub(2).unwrap();
+            ibdwt.square_sub(2).unwrap();
+        }
+        assert_eq!(ntt.residue().unwrap(), ibdwt.residue().unwrap());
+    }
+}
@@ -0,0 +1,116 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// A phase of a run whose time `--profile` reports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Finding the OpenCL platform and device and creating contexts and queues.
+    Init,
+    /// Building OpenCL programs from source or loading them from the program cache.
+    Compile,
+    /// Creating buffers and kernels and uploading their first contents.
+    Buffers,
+    /// Running the kernels, or on the CPU the squarings and sieving themselves.
+    Execute,
+    /// Reading residues and results back from the device.
+    Readback,
+}
+
+impl Phase {
+    /// Every phase, in the order a run goes through them.
+    pub const ALL: [Phase; 5] = [Phase::Init, Phase::Compile, Phase::Buffers, Phase::Execute, Phase::Readback];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::Init => "OpenCL init",
+            Phase::Compile => "compilation",
+            Phase::Buffers => "buffer setup",
+            Phase::Execute => "execution",
+            Phase::Readback => "readback",
+        }
+    }
+}
+
+/// Time spent in each phase. Runs on several threads add up, so with -j the phases can
+/// take longer than the run did.
+#[derive(Debug, Default)]
+pub struct Timings {
+    /// Nanoseconds, by the index of the phase in `Phase::ALL`.
+    nanos: [AtomicU64; 5],
+}
+
+/// The timings of this run, which the instrumented code records into.
+pub static TIMINGS: Timings = Timings::new();
+
+impl Timings {
+    pub const fn new() -> Timings {
+        Timings { nanos: [const { AtomicU64::new(0) }; 5] }
+    }
+
+    /// Adds `elapsed` to the time spent in `phase`.
+    pub fn record(&self, phase: Phase, elapsed: Duration) {
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        self.nanos[phase as usize].fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    /// Runs `f`, adding the time it takes to `phase`.
+    pub fn time<R>(&self, phase: Phase, f: impl FnOnce() -> R) -> R {
+        let started = Instant::now();
+        let result = f();
+        self.record(phase, started.elapsed());
+        result
+    }
+
+    /// The time spent in each phase so far.
+    pub fn breakdown(&self) -> [(Phase, Duration); 5] {
+        Phase::ALL.map(|phase| (phase, Duration::from_nanos(self.nanos[phase as usize].load(Ordering::Relaxed))))
+    }
+
+    /// The breakdown as a table of each phase's time and share of `total`, with what no
+    /// phase accounts for (parsing, output, CPU work between launches) as "other".
+    pub fn report(&self, total: Duration) -> String {
+        let phases = self.breakdown();
+        let measured: Duration = phases.iter().map(|(_, elapsed)| *elapsed).sum();
+        let share = |elapsed: Duration| match total.is_zero() {
+            true => 0.0,
+            false => 100.0 * elapsed.as_secs_f64() / total.as_secs_f64(),
+        };
+        let mut table = format!("{:<14}  {:>10}  {:>6}\n", "Phase", "Time", "Share");
+        let rows = phases.iter().map(|&(phase, elapsed)| (phase.name(), elapsed)).chain([("other", total.saturating_sub(measured))]);
+        for (name, elapsed) in rows {
+            table.push_str(&format!("{:<14}  {:>9.3}s  {:>5.1}%\n", name, elapsed.as_secs_f64(), share(elapsed)));
+        }
+        table.push_str(&format!("{:<14}  {:>9.3}s\n", "total", total.as_secs_f64()));
+        table
+    }
+}
+
+/// Runs `f`, adding the time it takes to `phase` in `TIMINGS`.
+pub fn time<R>(phase: Phase, f: impl FnOnce() -> R) -> R {
+    TIMINGS.time(phase, f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phases_add_up_to_the_time_they_were_measured_over() {
+        let timings = Timings::new();
+        let started = Instant::now();
+        for phase in Phase::ALL {
+            timings.time(phase, || std::thread::sleep(Duration::from_millis(20)));
+        }
+        let total = started.elapsed();
+
+        let phases = timings.breakdown();
+        assert!(phases.iter().all(|(_, elapsed)| *elapsed >= Duration::from_millis(20)), "{:?}", phases);
+        let measured: Duration = phases.iter().map(|(_, elapsed)| *elapsed).sum();
+        assert!(measured <= total && measured >= total.mul_f64(0.9), "{:?} of {:?}", measured, total);
+
+        let report = timings.report(total);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines.len(), 8, "{}", report);
+        assert!(lines[4].starts_with("execution") && lines[6].starts_with("other") && lines[7].starts_with("total"), "{}", report);
+    }
+}
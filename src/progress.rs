@@ -0,0 +1,675 @@
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressState, ProgressStyle, TermLike, WeakProgressBar};
+use serde_json::{json, Map, Value};
+use std::borrow::Cow;
+use std::collections::{HashSet, VecDeque};
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Template shared by the progress bars of the long-running loops.
+pub const DEFAULT_TEMPLATE: &str = "{msg} [{bar:40.cyan/blue}] {pos}/{len} ({percent}%, {eta_precise})";
+
+/// Template for bars counted in candidates, showing how many are covered per second.
+pub const THROUGHPUT_TEMPLATE: &str =
+    "{msg} [{bar:40.cyan/blue}] {human_pos}/{human_len} ({percent}%, {per_sec}, ETA {eta_precise})";
+
+/// Template for Lucas-Lehmer bars, followed by the `Throughput` summary kept in the prefix.
+pub const LUCAS_LEHMER_TEMPLATE: &str = "{msg} [{bar:40.cyan/blue}] {pos}/{len} ({prefix})";
+
+/// Set to print progress as plain lines on stderr instead of a redrawn bar, which is
+/// hidden when stderr isn't a terminal.
+pub static LOG_PROGRESS: AtomicBool = AtomicBool::new(false);
+
+/// Set to write progress to stderr as one JSON object per line every `STATUS_INTERVAL`,
+/// instead of any bar or status line: from each test's `Throughput`, and from the bars of
+/// everything else. Takes precedence over `LOG_PROGRESS` and `HIDE_PROGRESS`.
+pub static JSON_PROGRESS: AtomicBool = AtomicBool::new(false);
+
+/// Set to draw no progress at all, e.g. while results stream to standard output.
+/// `LOG_PROGRESS` takes precedence.
+pub static HIDE_PROGRESS: AtomicBool = AtomicBool::new(false);
+
+/// Set while several runs report at once, so that each bar takes its own line of a shared
+/// display instead of drawing over the others.
+pub static SHARE_PROGRESS: AtomicBool = AtomicBool::new(false);
+
+/// Whether the terminal gets ANSI escapes: the colors of the progress bars and log levels,
+/// and the clear before a single -l run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// Only on a terminal, and not while the NO_COLOR variable is set.
+    Auto,
+    /// Even when the output is piped.
+    Always,
+    /// Never, for terminals that show them as garbage.
+    Never,
+}
+
+/// The `ColorChoice` in effect, `Auto` unless `set_color` says otherwise.
+static COLOR: Mutex<ColorChoice> = Mutex::new(ColorChoice::Auto);
+
+/// Sets the `ColorChoice` for everything drawn from now on, including the styling indicatif
+/// leaves to console, which would otherwise decide for itself.
+pub fn set_color(choice: ColorChoice) {
+    *COLOR.lock().unwrap_or_else(|e| e.into_inner()) = choice;
+    let enabled = ansi_enabled(std::io::stderr().is_terminal());
+    console::set_colors_enabled(enabled);
+    console::set_colors_enabled_stderr(enabled);
+}
+
+/// Whether ANSI escapes may be written to an output that `is_terminal` or not, under the
+/// `ColorChoice` in effect. NO_COLOR counts when set to anything but the empty string.
+pub fn ansi_enabled(is_terminal: bool) -> bool {
+    ansi_under(*COLOR.lock().unwrap_or_else(|e| e.into_inner()), is_terminal)
+}
+
+/// `ansi_enabled` under `choice` rather than the `ColorChoice` in effect.
+fn ansi_under(choice: ColorChoice, is_terminal: bool) -> bool {
+    match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => is_terminal && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty()),
+    }
+}
+
+/// `template` with the styles of its placeholders removed, e.g. `{bar:40.cyan/blue}`
+/// becoming `{bar:40}`, so that indicatif draws it without color.
+fn plain_template(template: &str) -> String {
+    let mut plain = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        let close = rest[open..].find('}').map_or(rest.len(), |close| open + close + 1);
+        let placeholder = &rest[open..close];
+        plain.push_str(&rest[..open]);
+        match placeholder.split_once(':') {
+            Some((key, spec)) if spec.contains('.') => {
+                let width = spec.split('.').next().unwrap_or("");
+                plain.push_str(key);
+                if !width.is_empty() {
+                    plain.push(':');
+                    plain.push_str(width);
+                }
+                plain.push('}');
+            }
+            _ => plain.push_str(placeholder),
+        }
+        rest = &rest[close..];
+    }
+    plain.push_str(rest);
+    plain
+}
+
+/// The style of a bar drawn with `template`, in color if `color`.
+fn bar_style(template: &str, color: bool) -> Result<ProgressStyle, indicatif::style::TemplateError> {
+    match color {
+        true => ProgressStyle::default_bar().template(template),
+        false => ProgressStyle::default_bar().template(&plain_template(template)),
+    }
+}
+
+/// How often a `Throughput` writes its status line to stderr, if at all. Meant for when
+/// stderr isn't a terminal and the bar is hidden.
+pub static STATUS_INTERVAL: Mutex<Option<Duration>> = Mutex::new(None);
+
+/// The display behind `SHARE_PROGRESS`.
+static SHARED: OnceLock<MultiProgress> = OnceLock::new();
+
+/// Bars drawn to stderr on their own rather than through `SHARED`, for `suspend`.
+static STANDALONE: Mutex<Vec<WeakProgressBar>> = Mutex::new(Vec::new());
+
+/// How often `LOG_PROGRESS` lines are written while the message stays the same.
+const LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Draw target behind `LOG_PROGRESS`: one line per `LOG_INTERVAL`, plus the first line
+/// under each new message so that every phase of the work shows up.
+#[derive(Debug, Default)]
+struct LogLines {
+    /// When the last line was written, and every message seen so far.
+    state: Mutex<(Option<Instant>, HashSet<String>)>,
+}
+
+impl TermLike for LogLines {
+    fn width(&self) -> u16 {
+        160
+    }
+
+    fn move_cursor_up(&self, _: usize) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_down(&self, _: usize) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_right(&self, _: usize) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_left(&self, _: usize) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn write_line(&self, s: &str) -> std::io::Result<()> {
+        self.write_str(s)
+    }
+
+    fn write_str(&self, s: &str) -> std::io::Result<()> {
+        // indicatif pads lines and returns the cursor with whitespace-only writes
+        let line = s.trim_end();
+        if line.trim().is_empty() {
+            return Ok(());
+        }
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let (last, messages) = &mut *state;
+        let message = line.split(" [").next().unwrap_or(line);
+        let new_message = messages.insert(message.to_string());
+        if new_message || last.is_none_or(|last| last.elapsed() >= LOG_INTERVAL) {
+            *last = Some(Instant::now());
+            writeln!(std::io::stderr(), "{}", line.trim_start())?;
+        }
+        Ok(())
+    }
+
+    fn clear_line(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn flush(&self) -> std::io::Result<()> {
+        std::io::stderr().flush()
+    }
+}
+
+/// Separates the message from the rest of a line rendered for `JsonLines`.
+const JSON_SEPARATOR: char = '\u{1f}';
+
+/// Draw target behind `JSON_PROGRESS` for the bars that have no `Throughput`: a
+/// "progress" object per `STATUS_INTERVAL` and under each new message, and a "done" object
+/// when the bar finishes, e.g.
+/// {"kind": "generate", "stage": "Sieving", "event": "progress", "position": 1000,
+/// "total": 4000, "per_sec": 512.0, "eta_secs": 5.9}.
+#[derive(Debug, Default)]
+struct JsonLines {
+    /// When the last object was written, and the message it had.
+    state: Mutex<(Option<Instant>, String)>,
+}
+
+impl JsonLines {
+    /// The style that renders the message and then the fields `write_str` wraps in an object.
+    fn style() -> ProgressStyle {
+        let template = format!("{{msg}}{}{{json}}", JSON_SEPARATOR);
+        ProgressStyle::with_template(&template).expect("the JSON template is valid").with_key(
+            "json",
+            |state: &ProgressState, w: &mut dyn std::fmt::Write| {
+                let fields = json!({
+                    "event": if state.is_finished() { "done" } else { "progress" },
+                    "position": state.pos(),
+                    "total": state.len().unwrap_or(0),
+                    "per_sec": tenths(Some(state.per_sec())),
+                    "eta_secs": tenths(Some(state.eta().as_secs_f64())),
+                });
+                let _ = write!(w, "{}", fields);
+            },
+        )
+    }
+}
+
+impl TermLike for JsonLines {
+    fn width(&self) -> u16 {
+        u16::MAX
+    }
+
+    fn move_cursor_up(&self, _: usize) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_down(&self, _: usize) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_right(&self, _: usize) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_left(&self, _: usize) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn write_line(&self, s: &str) -> std::io::Result<()> {
+        self.write_str(s)
+    }
+
+    fn write_str(&self, s: &str) -> std::io::Result<()> {
+        let Some((message, fields)) = s.trim().split_once(JSON_SEPARATOR) else {
+            return Ok(());
+        };
+        let Ok(Value::Object(fields)) = serde_json::from_str(fields) else {
+            return Ok(());
+        };
+        let interval = STATUS_INTERVAL.lock().unwrap_or_else(|e| e.into_inner()).unwrap_or(LOG_INTERVAL);
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let (last, last_message) = &mut *state;
+        let due = fields["event"] == "done"
+            || message != last_message
+            || last.is_none_or(|last| last.elapsed() >= interval);
+        if due {
+            *last = Some(Instant::now());
+            *last_message = message.to_string();
+            let mut object = Map::from_iter([("kind".to_string(), json!("generate")), ("stage".to_string(), json!(message))]);
+            object.extend(fields);
+            writeln!(std::io::stderr(), "{}", Value::Object(object))?;
+        }
+        Ok(())
+    }
+
+    fn clear_line(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn flush(&self) -> std::io::Result<()> {
+        std::io::stderr().flush()
+    }
+}
+
+/// `value` to one decimal place, as JSON progress reports rates and ETAs, or null when it
+/// isn't known or isn't finite.
+fn tenths(value: Option<f64>) -> Value {
+    value.filter(|value| value.is_finite()).map_or(Value::Null, |value| json!((value * 10.0).round() / 10.0))
+}
+
+/// Creates a progress bar of `len` steps labelled with `message`.
+///
+/// The bar is purely cosmetic, so an invalid `template` falls back to indicatif's default
+/// style with a warning rather than aborting the computation it reports on.
+///
+/// # Arguments
+///
+/// * `len` - The number of steps in the bar.
+/// * `template` - The indicatif template for the bar.
+/// * `message` - The label shown before the bar.
+pub fn progress_bar(len: u64, template: &str, message: impl Into<Cow<'static, str>>) -> ProgressBar {
+    let pb = ProgressBar::new(len);
+    let style = match bar_style(template, ansi_enabled(std::io::stderr().is_terminal())) {
+        Ok(style) => style,
+        Err(e) => {
+            eprintln!("Warning: invalid progress bar template ({}), using the default", e);
+            ProgressStyle::default_bar()
+        }
+    };
+    if JSON_PROGRESS.load(Ordering::Relaxed) {
+        // Lucas-Lehmer bars leave the JSON to the `Throughput` that keeps their prefix
+        if template == LUCAS_LEHMER_TEMPLATE {
+            pb.set_draw_target(ProgressDrawTarget::hidden());
+        } else {
+            pb.set_draw_target(ProgressDrawTarget::term_like(Box::new(JsonLines::default())));
+            pb.set_style(JsonLines::style());
+            pb.set_message(message);
+            return pb;
+        }
+    } else if LOG_PROGRESS.load(Ordering::Relaxed) {
+        pb.set_draw_target(ProgressDrawTarget::term_like(Box::new(LogLines::default())));
+    } else if HIDE_PROGRESS.load(Ordering::Relaxed) {
+        pb.set_draw_target(ProgressDrawTarget::hidden());
+    } else if SHARE_PROGRESS.load(Ordering::Relaxed) {
+        SHARED.get_or_init(MultiProgress::new).add(pb.clone());
+    } else {
+        let mut standalone = STANDALONE.lock().unwrap_or_else(|e| e.into_inner());
+        standalone.retain(|bar| bar.upgrade().is_some());
+        standalone.push(pb.downgrade());
+    }
+    pb.set_style(style.progress_chars("=>-"));
+    pb.set_message(message);
+    pb
+}
+
+/// Runs `f` with the bars on stderr cleared, drawing them again afterwards, so that a line
+/// `f` writes there doesn't land in the middle of a bar.
+pub fn suspend<R>(f: impl FnOnce() -> R) -> R {
+    if let Some(shared) = SHARED.get() {
+        return shared.suspend(f);
+    }
+    let drawn = STANDALONE
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .rev()
+        .filter_map(WeakProgressBar::upgrade)
+        .find(|bar| !bar.is_finished());
+    match drawn {
+        Some(bar) => bar.suspend(f),
+        None => f(),
+    }
+}
+
+/// Least time between the samples a `Throughput` takes, so that loops can hand it every
+/// step without paying for more than the clock.
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How far back the current rate of a `Throughput` looks.
+const RATE_WINDOW: Duration = Duration::from_secs(30);
+
+/// Time constant of the exponential smoothing behind the ETA of a `Throughput`.
+const ETA_SMOOTHING: Duration = Duration::from_secs(60);
+
+/// Iterations per second of a long loop, timestamped by the loop itself: the current rate
+/// over the last `RATE_WINDOW`, the average since the start, and an ETA from the current
+/// rate smoothed over `ETA_SMOOTHING`, which doesn't jump about with every sample.
+#[derive(Debug)]
+pub struct Throughput {
+    /// The test the loop runs, "ll" or "prp", and on which exponent.
+    kind: &'static str,
+    exponent: u128,
+    total: u64,
+    started: Instant,
+    start_position: u64,
+    /// Samples of (time, position) from the last `RATE_WINDOW`, plus the one before them.
+    samples: VecDeque<(Instant, u64)>,
+    smoothed_rate: Option<f64>,
+    /// The `STATUS_INTERVAL` when the loop started, and when its last status line went out.
+    status_interval: Option<Duration>,
+    last_line: Instant,
+    /// Whether the loop reached its total and said so in a `JSON_PROGRESS` "done" object.
+    done: bool,
+}
+
+impl Throughput {
+    /// Starts timing a `kind` test of 2^exponent - 1 of `total` steps from `position`, e.g.
+    /// where a checkpoint left it, and moves `pb` there.
+    pub fn new(kind: &'static str, exponent: u128, pb: &ProgressBar, position: u64, total: u64) -> Throughput {
+        let throughput = Throughput::starting_at(kind, exponent, position, total, Instant::now());
+        pb.set_position(position);
+        pb.set_prefix(throughput.summary());
+        throughput
+    }
+
+    fn starting_at(kind: &'static str, exponent: u128, position: u64, total: u64, now: Instant) -> Throughput {
+        let status_interval = *STATUS_INTERVAL.lock().unwrap_or_else(|e| e.into_inner());
+        Throughput {
+            kind,
+            exponent,
+            total,
+            started: now,
+            start_position: position,
+            samples: VecDeque::from([(now, position)]),
+            smoothed_rate: None,
+            status_interval: status_interval.filter(|interval| !interval.is_zero()),
+            last_line: now,
+            done: false,
+        }
+    }
+
+    /// Moves `pb` to `position` and, once `SAMPLE_INTERVAL` has passed since the last
+    /// sample, takes another: the summary goes to the bar's prefix, and to stderr as a
+    /// status line, or a `JSON_PROGRESS` object, when `STATUS_INTERVAL` has passed since the
+    /// last one. Reaching the total always takes a sample, for the "done" object.
+    pub fn update(&mut self, pb: &ProgressBar, position: u64) {
+        if let Some(line) = self.update_at(pb, position, Instant::now()) {
+            eprintln!("{}", line);
+        }
+    }
+
+    /// `update` at `now`, returning the line for stderr rather than writing it.
+    fn update_at(&mut self, pb: &ProgressBar, position: u64, now: Instant) -> Option<String> {
+        pb.set_position(position);
+        let (last, _) = *self.samples.back().expect("there is always a sample");
+        let finishing = position >= self.total && !self.done;
+        if !finishing && now.duration_since(last) < SAMPLE_INTERVAL {
+            return None;
+        }
+        self.record(position, now);
+        pb.set_prefix(self.summary());
+        let json = JSON_PROGRESS.load(Ordering::Relaxed);
+        if finishing && json {
+            self.done = true;
+            Some(self.json_line("done", now))
+        } else if self.status_interval.is_some_and(|interval| now.duration_since(self.last_line) >= interval) {
+            self.last_line = now;
+            Some(if json { self.json_line("progress", now) } else { self.status_line() })
+        } else {
+            None
+        }
+    }
+
+    /// The `JSON_PROGRESS` object for `event` at the latest sample, e.g.
+    /// {"kind": "ll", "event": "progress", "exponent": 9941, "iteration": 5000, "total": 9939,
+    /// "iters_per_sec": 8123.4, "eta_secs": 0.6, "elapsed_secs": 0.6}, with null for a rate
+    /// or ETA not known yet.
+    fn json_line(&self, event: &str, now: Instant) -> String {
+        let &(_, position) = self.samples.back().expect("there is always a sample");
+        let (rate, eta) = match event {
+            "done" => (self.average_rate(), Some(0.0)),
+            _ => (self.current_rate(), self.eta().map(|eta| eta.as_secs_f64())),
+        };
+        let elapsed = now.duration_since(self.started).as_secs_f64();
+        json!({
+            "kind": self.kind,
+            "event": event,
+            "exponent": self.exponent,
+            "iteration": position,
+            "total": self.total,
+            "iters_per_sec": tenths(rate),
+            "eta_secs": tenths(eta),
+            "elapsed_secs": (elapsed * 1000.0).round() / 1000.0,
+        })
+        .to_string()
+    }
+
+    fn record(&mut self, position: u64, now: Instant) {
+        self.samples.push_back((now, position));
+        while self.samples.len() > 2 && now.duration_since(self.samples[1].0) >= RATE_WINDOW {
+            self.samples.pop_front();
+        }
+        if let Some(rate) = self.current_rate() {
+            let (previous, _) = self.samples[self.samples.len() - 2];
+            let weight = 1.0 - (-now.duration_since(previous).as_secs_f64() / ETA_SMOOTHING.as_secs_f64()).exp();
+            self.smoothed_rate = Some(self.smoothed_rate.map_or(rate, |smoothed| smoothed + weight * (rate - smoothed)));
+        }
+    }
+
+    /// Steps per second over the last `RATE_WINDOW`, once there are two samples.
+    pub fn current_rate(&self) -> Option<f64> {
+        let (&(first, from), &(last, to)) = (self.samples.front()?, self.samples.back()?);
+        rate(to - from, last.duration_since(first))
+    }
+
+    /// Steps per second since the loop started.
+    pub fn average_rate(&self) -> Option<f64> {
+        let &(last, to) = self.samples.back()?;
+        rate(to - self.start_position, last.duration_since(self.started))
+    }
+
+    /// Time left at the smoothed current rate.
+    pub fn eta(&self) -> Option<Duration> {
+        let &(_, position) = self.samples.back()?;
+        let rate = self.smoothed_rate.filter(|&rate| rate > 0.0)?;
+        Some(Duration::from_secs_f64(self.total.saturating_sub(position) as f64 / rate))
+    }
+
+    /// The current rate, the average and the ETA, e.g. "2345 it/s now, 2210 it/s average, ETA 2m 05s".
+    pub fn summary(&self) -> String {
+        let per_second = |rate: Option<f64>| rate.map_or_else(|| "-- it/s".to_string(), |rate| format!("{:.0} it/s", rate));
+        format!(
+            "{} now, {} average, ETA {}",
+            per_second(self.current_rate()),
+            per_second(self.average_rate()),
+            self.eta().map_or_else(|| "--".to_string(), format_eta)
+        )
+    }
+
+    /// The summary with the label and the position, as written to stderr.
+    pub fn status_line(&self) -> String {
+        let &(_, position) = self.samples.back().expect("there is always a sample");
+        let percent = if self.total == 0 { 100.0 } else { 100.0 * position as f64 / self.total as f64 };
+        format!("M{}: iteration {} of {} ({:.1}%), {}", self.exponent, position, self.total, percent, self.summary())
+    }
+}
+
+/// `steps` over `elapsed` in steps per second, unless no time has passed.
+fn rate(steps: u64, elapsed: Duration) -> Option<f64> {
+    (!elapsed.is_zero()).then(|| steps as f64 / elapsed.as_secs_f64())
+}
+
+/// `eta` to the second, in the largest units that fit: "1h 02m 03s", "2m 05s" or "7s".
+fn format_eta(eta: Duration) -> String {
+    let seconds = eta.as_secs_f64().round() as u64;
+    match (seconds / 3600, seconds / 60 % 60, seconds % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m {:02}s", m, s),
+        (h, m, s) => format!("{}h {:02}m {:02}s", h, m, s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A terminal that keeps what is drawn on it.
+    #[derive(Debug, Default)]
+    struct Captured(std::sync::Arc<Mutex<String>>);
+
+    impl TermLike for Captured {
+        fn width(&self) -> u16 {
+            160
+        }
+        fn move_cursor_up(&self, _: usize) -> std::io::Result<()> {
+            Ok(())
+        }
+        fn move_cursor_down(&self, _: usize) -> std::io::Result<()> {
+            Ok(())
+        }
+        fn move_cursor_right(&self, _: usize) -> std::io::Result<()> {
+            Ok(())
+        }
+        fn move_cursor_left(&self, _: usize) -> std::io::Result<()> {
+            Ok(())
+        }
+        fn write_line(&self, s: &str) -> std::io::Result<()> {
+            self.write_str(s)
+        }
+        fn write_str(&self, s: &str) -> std::io::Result<()> {
+            self.0.lock().unwrap().push_str(s);
+            Ok(())
+        }
+        fn clear_line(&self) -> std::io::Result<()> {
+            Ok(())
+        }
+        fn flush(&self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn bars_without_color_draw_no_ansi_codes() {
+        assert_eq!(plain_template(LUCAS_LEHMER_TEMPLATE), "{msg} [{bar:40}] {pos}/{len} ({prefix})");
+        assert_eq!(plain_template("{msg:.bold} {pos:>7}"), "{msg} {pos:>7}");
+
+        assert!(!ansi_under(ColorChoice::Never, true));
+        assert!(ansi_under(ColorChoice::Always, false));
+        assert!(!ansi_under(ColorChoice::Auto, false));
+
+        // The plain templates name no style for console to draw, whatever it is set to
+        for template in [DEFAULT_TEMPLATE, THROUGHPUT_TEMPLATE, LUCAS_LEHMER_TEMPLATE] {
+            assert_ne!(plain_template(template), template);
+            let screen = Captured::default();
+            let text = screen.0.clone();
+            let pb = ProgressBar::with_draw_target(Some(100), ProgressDrawTarget::term_like(Box::new(screen)));
+            pb.set_style(bar_style(template, false).unwrap());
+            pb.set_message("Sieving Segments");
+            pb.finish();
+            let plain = text.lock().unwrap().clone();
+            assert!(plain.contains("Sieving Segments [") && !plain.contains('\x1b'), "{:?}", plain);
+        }
+    }
+
+    #[test]
+    fn bad_templates_fall_back_without_stopping_the_work() {
+        // A letter where the width goes is rejected by indicatif, leaving its default bar
+        let template = "{msg} {pos:>7x}";
+        assert!(bar_style(template, true).is_err() && bar_style(template, false).is_err());
+        let pb = progress_bar(100, template, "Testing");
+        let screen = Captured::default();
+        let text = screen.0.clone();
+        pb.set_draw_target(ProgressDrawTarget::term_like(Box::new(screen)));
+        let mut sum = 0u64;
+        for i in 0..100 {
+            sum += i;
+            pb.inc(1);
+        }
+        pb.finish();
+        assert_eq!(sum, 4950);
+        assert_eq!(pb.position(), 100);
+        assert!(pb.is_finished());
+        // indicatif's default bar draws the position but, unlike the template, no message
+        let drawn = text.lock().unwrap().clone();
+        assert!(drawn.contains("100/100") && !drawn.contains("Testing"), "{:?}", drawn);
+    }
+
+    #[test]
+    fn steady_loops_report_their_rate_and_eta() {
+        let started = Instant::now();
+        let mut throughput = Throughput::starting_at("ll", 127, 100, 10_100, started);
+        assert_eq!(throughput.summary(), "-- it/s now, -- it/s average, ETA --");
+        for second in 1..=90 {
+            throughput.record(100 + 50 * second, started + Duration::from_secs(second));
+        }
+        // The window keeps the last 30 seconds, plus the sample just before them
+        assert!(throughput.samples.len() <= 32, "{}", throughput.samples.len());
+        assert_eq!(throughput.current_rate().map(f64::round), Some(50.0));
+        assert_eq!(throughput.average_rate().map(f64::round), Some(50.0));
+        assert_eq!(throughput.eta().map(|eta| eta.as_secs_f64().round()), Some(110.0));
+        assert_eq!(throughput.summary(), "50 it/s now, 50 it/s average, ETA 1m 50s");
+        assert_eq!(
+            throughput.status_line(),
+            "M127: iteration 4600 of 10100 (45.5%), 50 it/s now, 50 it/s average, ETA 1m 50s"
+        );
+    }
+
+    #[test]
+    fn status_lines_go_out_once_per_interval() {
+        let started = Instant::now();
+        let pb = ProgressBar::hidden();
+        let mut throughput = Throughput::starting_at("ll", 127, 0, 125, started);
+        throughput.status_interval = Some(Duration::from_secs(1));
+        let at = |millis| started + Duration::from_millis(millis);
+        // Samples every 100 ms, so a line every tenth one
+        let lines: Vec<String> =
+            (1..=25).filter_map(|tick| throughput.update_at(&pb, 5 * tick, at(100 * tick))).collect();
+        assert_eq!(
+            lines,
+            [
+                "M127: iteration 50 of 125 (40.0%), 50 it/s now, 50 it/s average, ETA 2s",
+                "M127: iteration 100 of 125 (80.0%), 50 it/s now, 50 it/s average, ETA 1s",
+            ]
+        );
+        // The last iteration is always sampled, but gets a line only once the interval is up
+        assert_eq!(throughput.update_at(&pb, 125, at(2550)), None);
+        assert_eq!(throughput.samples.back(), Some(&(at(2550), 125)));
+        throughput.status_interval = None;
+        assert_eq!(throughput.update_at(&pb, 125, at(4000)), None);
+        assert_eq!(pb.position(), 125);
+    }
+
+    #[test]
+    fn a_change_of_pace_moves_the_current_rate_before_the_eta() {
+        let started = Instant::now();
+        let mut throughput = Throughput::starting_at("ll", 127, 0, 1_000_000, started);
+        for second in 1..=60 {
+            throughput.record(100 * second, started + Duration::from_secs(second));
+        }
+        for second in 61..=70 {
+            throughput.record(6000 + 1000 * (second - 60), started + Duration::from_secs(second));
+        }
+        // 20 seconds at 100 it/s and 10 at 1000 it/s in the window
+        assert_eq!(throughput.current_rate().map(f64::round), Some(400.0));
+        let smoothed = throughput.smoothed_rate.unwrap();
+        assert!(smoothed > 100.0 && smoothed < 400.0, "{}", smoothed);
+    }
+
+    #[test]
+    fn etas_read_in_the_largest_units() {
+        assert_eq!(format_eta(Duration::from_secs(7)), "7s");
+        assert_eq!(format_eta(Duration::from_secs(125)), "2m 05s");
+        assert_eq!(format_eta(Duration::from_secs(3723)), "1h 02m 03s");
+        assert_eq!(format_eta(Duration::from_millis(59_600)), "1m 00s");
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+/// The line a results file (-o or --result-file) records a Lucas-Lehmer verdict with.
+pub fn lucas_lehmer_line(p: u128, prime: bool, res64: u64) -> String {
+    format!("2^{}-1 is {}a Mersenne prime (res64 {:016x}).", p, if prime { "" } else { "not " }, res64)
+}
+
+/// The exponent and res64 of a line `lucas_lehmer_line` wrote. Lines from before the res64
+/// was recorded give the exponent alone.
+fn parse_lucas_lehmer_line(line: &str) -> Option<(u128, Option<u64>)> {
+    let (p, verdict) = line.strip_prefix("2^")?.split_once("-1 is ")?;
+    let (verdict, res64) = match verdict.split_once(" (res64 ") {
+        Some((verdict, res64)) => (verdict, Some(u64::from_str_radix(res64.strip_suffix(").")?, 16).ok()?)),
+        None => (verdict.strip_suffix('.')?, None),
+    };
+    matches!(verdict, "a Mersenne prime" | "not a Mersenne prime").then_some(())?;
+    Some((p.parse().ok()?, res64))
+}
+
+/// The number a line `run_prp` wrote to a results file, "97: Probably prime (...)" and the
+/// like, is about.
+fn parse_prp_line(line: &str) -> Option<u128> {
+    let (n, verdict) = line.split_once(": ")?;
+    ["Prime", "Not prime", "Probably prime", "Probably not prime"]
+        .iter()
+        .any(|&word| verdict.strip_prefix(word).is_some_and(|rest| rest.is_empty() || rest.starts_with(" (")))
+        .then_some(())?;
+    n.parse().ok()
+}
+
+/// The exponent of a line `--prp-mersenne` wrote to a results file, "2^p-1 is probably
+/// prime." or "2^p-1 is not prime (witness w).".
+fn parse_mersenne_prp_line(line: &str) -> Option<u128> {
+    let (p, verdict) = line.strip_prefix("2^")?.split_once("-1 is ")?;
+    let verdict = verdict.strip_suffix('.')?;
+    let verdict = verdict.split_once(" (").map_or(verdict, |(verdict, _)| verdict);
+    matches!(verdict, "probably prime" | "not prime").then_some(())?;
+    p.parse().ok()
+}
+
+/// The tests a results file or database records as finished, which `--skip-done` doesn't
+/// run again, by kind as the `--sqlite` database names them: "ll" for a Lucas-Lehmer test
+/// of an exponent, "prp" for a probable-prime test of a number and "mersenne_prp" for one
+/// of 2^p-1 by its exponent. Read once up front, so checking a number costs a lookup however
+/// long the logs have grown.
+#[derive(Clone, Debug, Default)]
+pub struct Done {
+    res64: HashMap<(&'static str, u128), Option<u64>>,
+}
+
+impl Done {
+    /// Records that a `kind` test of `number` finished, keeping a res64 already known if
+    /// this record has none.
+    pub fn insert(&mut self, kind: &'static str, number: u128, res64: Option<u64>) {
+        let known = self.res64.entry((kind, number)).or_insert(res64);
+        *known = res64.or(*known);
+    }
+
+    /// Records the results in the lines of `reader`, skipping every other line.
+    pub fn read(&mut self, reader: impl BufRead) -> std::io::Result<()> {
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+            if let Some((p, res64)) = parse_lucas_lehmer_line(line) {
+                self.insert("ll", p, res64);
+            } else if let Some(p) = parse_mersenne_prp_line(line) {
+                self.insert("mersenne_prp", p, None);
+            } else if let Some(n) = parse_prp_line(line) {
+                self.insert("prp", n, None);
+            }
+        }
+        Ok(())
+    }
+
+    /// Records the results of the results file at `path`, which not existing yet means none.
+    pub fn load(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
+        match std::fs::File::open(path) {
+            Ok(file) => self.read(BufReader::new(file)).map_err(|e| format!("Cannot read the results file {}: {}", path.display(), e).into()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(format!("Cannot read the results file {}: {}", path.display(), e).into()),
+        }
+    }
+
+    /// Whether a `kind` test of `number` finished, with its res64 if that was recorded.
+    pub fn get(&self, kind: &'static str, number: u128) -> Option<Option<u64>> {
+        self.res64.get(&(kind, number)).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn results_lines_are_read_back_into_exponents() {
+        let text = [
+            lucas_lehmer_line(7, true, 0),
+            lucas_lehmer_line(11, false, 0x1234_5678_9abc_def0),
+            "2^13-1 is a Mersenne prime.".to_string(),
+            "2^17-1 is probably prime.".to_string(),
+            "19: Probably prime".to_string(),
+            "21: Probably not prime (witness 2)".to_string(),
+            "2^29-1 is not prime (witness 3).".to_string(),
+            "31: Probably".to_string(),
+            "2^23-1 is not a Mersenne prime (res64 banana).".to_string(),
+            lucas_lehmer_line(13, true, 0),
+        ]
+        .join("\n");
+        let mut done = Done::default();
+        done.read(text.as_bytes()).unwrap();
+        done.insert("ll", 11, None);
+
+        assert_eq!(done.get("ll", 7), Some(Some(0)));
+        assert_eq!(done.get("ll", 11), Some(Some(0x1234_5678_9abc_def0)));
+        assert_eq!(done.get("ll", 13), Some(Some(0)));
+        for p in [17, 19, 23] {
+            assert_eq!(done.get("ll", p), None, "{}", p);
+        }
+        // The PRP tests are kept apart from the Lucas-Lehmer ones of the same numbers
+        assert_eq!(done.get("mersenne_prp", 17), Some(None));
+        assert_eq!(done.get("prp", 19), Some(None));
+        assert_eq!(done.get("prp", 17), None);
+        assert_eq!(done.get("mersenne_prp", 7), None);
+        assert_eq!((done.get("prp", 21), done.get("mersenne_prp", 29), done.get("prp", 31)), (Some(None), Some(None), None));
+        assert!(done.load(Path::new("no such results file")).is_ok());
+    }
+}
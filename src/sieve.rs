@@ -0,0 +1,560 @@
+use crate::error::MpError;
+use crate::generate_primes::Progression;
+use log::warn;
+use std::error::Error;
+
+/// CPU sieves that can stand in for the OpenCL kernel when generating primes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Sieve {
+    Eratosthenes,
+    Atkin,
+}
+
+impl Sieve {
+    /// Parses the name given to `--sieve`.
+    pub fn from_name(name: &str) -> Option<Sieve> {
+        match name {
+            "eratosthenes" => Some(Sieve::Eratosthenes),
+            "atkin" => Some(Sieve::Atkin),
+            _ => None,
+        }
+    }
+
+    /// Generates the primes in the range [start_n, end_n) with this sieve.
+    pub fn primes(self, start_n: u128, end_n: u128) -> Result<Vec<u128>, Box<dyn Error>> {
+        self.primes_preallocated(start_n, end_n, Preallocate::Estimate, None)
+    }
+
+    /// Generates the primes in the range [start_n, end_n) with this sieve, into a vector
+    /// sized up front as `preallocate` says, keeping to the members of `progression` when
+    /// there is one.
+    pub fn primes_preallocated(
+        self,
+        start_n: u128,
+        end_n: u128,
+        preallocate: Preallocate,
+        progression: Option<&Progression>,
+    ) -> Result<Vec<u128>, Box<dyn Error>> {
+        let limit = sieve_limit(end_n)?;
+        let is_prime = match self {
+            Sieve::Eratosthenes => eratosthenes_marks(limit),
+            Sieve::Atkin => atkin_marks(limit),
+        };
+        Ok(match progression {
+            // A progression keeps too few of the primes for the estimate of the range to fit it
+            Some(progression) => {
+                let primes = match preallocate {
+                    Preallocate::Estimate => Vec::new(),
+                    preallocate => preallocate.vector(start_n, end_n),
+                };
+                collect_marked_members(&is_prime, start_n, progression, primes)
+            }
+            None => collect_marked(&is_prime, start_n, preallocate.vector(start_n, end_n)),
+        })
+    }
+}
+
+/// The x / ln x estimate of how many primes lie in [start, end): the difference of its
+/// values at the two ends, each taken as 0 below 2.
+pub fn prime_count_estimate(start: u128, end: u128) -> f64 {
+    let estimate = |x: u128| if x < 2 { 0.0 } else { x as f64 / (x as f64).ln() };
+    (estimate(end) - estimate(start)).max(0.0)
+}
+
+/// Most primes `Preallocate::Estimate` makes room for, 1 GiB of them, past which the vector
+/// grows as they arrive rather than claim memory the run may never reach.
+const PREALLOCATE_LIMIT: usize = 1 << 26;
+
+/// Share added to `prime_count_estimate` when sizing a vector of primes. x / ln x falls
+/// about a tenth short of the count around 10^5 and less further up, so this covers it
+/// with room for a range holding a few more primes than usual.
+const PREALLOCATE_MARGIN: f64 = 0.15;
+
+/// How a vector collecting every prime of a range is sized before the first one arrives.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Preallocate {
+    /// Room for `prime_count_estimate` and a margin, up to `PREALLOCATE_LIMIT`, so the
+    /// vector is rarely reallocated.
+    Estimate,
+    /// None: the vector grows as the primes arrive.
+    Grow,
+    /// Room for this many primes.
+    Capacity(usize),
+}
+
+impl Preallocate {
+    /// The primes to make room for in a vector collecting those in [start, end), never more
+    /// than the range has numbers.
+    pub fn capacity(self, start: u128, end: u128) -> usize {
+        let numbers = usize::try_from(end.saturating_sub(start)).unwrap_or(usize::MAX);
+        let capacity = match self {
+            Preallocate::Estimate => {
+                let expected = prime_count_estimate(start, end) * (1.0 + PREALLOCATE_MARGIN);
+                (expected.ceil() as usize).min(PREALLOCATE_LIMIT)
+            }
+            Preallocate::Grow => 0,
+            Preallocate::Capacity(capacity) => capacity,
+        };
+        capacity.min(numbers)
+    }
+
+    /// An empty vector with room for `capacity(start, end)` primes, or for none, with a
+    /// warning, when that much memory can't be had.
+    pub fn vector(self, start: u128, end: u128) -> Vec<u128> {
+        let capacity = self.capacity(start, end);
+        let mut primes = Vec::new();
+        if let Err(e) = primes.try_reserve_exact(capacity) {
+            warn!("Cannot make room for {} primes up front ({}), collecting them as they come", capacity, e);
+        }
+        primes
+    }
+}
+
+/// Largest range end the whole-range sieves take. They keep a mark per number below the
+/// end, so this bounds them to 4 GiB; the segmented sieve handles anything larger.
+pub const MAX_SIEVE_END: u128 = 1 << 32;
+
+/// Converts the end of a range into a sieve length, refusing ends above `MAX_SIEVE_END`.
+fn sieve_limit(end_n: u128) -> Result<usize, Box<dyn Error>> {
+    if end_n > MAX_SIEVE_END {
+        return Err(format!(
+            "Range end {} is above {}: the Eratosthenes and Atkin sieves keep a mark per number below the end, use the segmented sieve instead.",
+            end_n, MAX_SIEVE_END
+        )
+        .into());
+    }
+    usize::try_from(end_n).map_err(|_| "Range end is too large to sieve on the CPU.".into())
+}
+
+/// Number of values `PrimeSieve` marks at a time.
+const BASE_SEGMENT_SIZE: u128 = 1 << 18;
+
+/// Returns the primes whose square is below `end_n`, which are all a segmented sieve needs.
+///
+/// They are sieved a segment at a time, so even the 2^32 square root of a 64-bit range end
+/// never needs a mark per number below it at once, and kept as u32, which holds every one
+/// of them for an `end_n` up to 2^64.
+pub fn base_primes(end_n: u128) -> Vec<u32> {
+    let mut sieve = PrimeSieve::new();
+    sieve.extend_to(end_n);
+    sieve.base
+}
+
+/// A segmented sieve that keeps its base primes between calls, so a process generating
+/// several ranges in turn only sieves the base primes past those it already has.
+#[derive(Clone, Debug, Default)]
+pub struct PrimeSieve {
+    /// Every prime below `root`, ascending.
+    base: Vec<u32>,
+    root: u128,
+}
+
+impl PrimeSieve {
+    /// A sieve with no base primes yet.
+    pub fn new() -> PrimeSieve {
+        PrimeSieve::default()
+    }
+
+    /// The base primes sieved so far.
+    pub fn base_primes(&self) -> &[u32] {
+        &self.base
+    }
+
+    /// Sieves further base primes until they cover ranges ending at `end_n`, keeping
+    /// those already found.
+    pub fn extend_to(&mut self, end_n: u128) {
+        let limit = root_bound(end_n).min(1 << 32);
+        if limit <= self.root {
+            return;
+        }
+        if self.root < 2 {
+            let first = limit.min(BASE_SEGMENT_SIZE);
+            self.base = sieve_of_eratosthenes(0, first).unwrap_or_default().into_iter().map(|p| p as u32).collect();
+            self.root = first;
+        }
+
+        // Every prime below the square root of a later segment's end is below its start
+        let mut segment = Vec::new();
+        while self.root < limit {
+            let low = self.root;
+            let high = (low + BASE_SEGMENT_SIZE).min(limit).min(low * low);
+            segment.resize((high - low) as usize, false);
+            mark_segment(&mut segment, low, &self.base);
+            for (offset, _) in segment.iter().enumerate().filter(|(_, &is_composite)| !is_composite) {
+                self.base.push((low + offset as u128) as u32);
+            }
+            self.root = high;
+        }
+    }
+
+    /// Generates the primes in the range [start_n, end_n) a segment at a time, first
+    /// extending the base primes if the range ends past those cached.
+    pub fn primes_in(&mut self, start_n: u128, end_n: u128) -> Result<Vec<u128>, Box<dyn Error>> {
+        if start_n > end_n {
+            return Err(MpError::ReversedRange { start: start_n, end: end_n }.into());
+        }
+        if end_n > u64::MAX as u128 {
+            return Err(MpError::UnsupportedRange { end: end_n }.into());
+        }
+        self.extend_to(end_n);
+
+        let mut primes = Preallocate::Estimate.vector(start_n, end_n);
+        let mut segment = Vec::new();
+        let mut low = start_n;
+        while low < end_n {
+            let high = (low + BASE_SEGMENT_SIZE).min(end_n);
+            segment.resize((high - low) as usize, false);
+            mark_segment(&mut segment, low, &self.base);
+            primes.extend(
+                segment
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, &is_composite)| !is_composite)
+                    .map(|(offset, _)| low + offset as u128),
+            );
+            low = high;
+        }
+        Ok(primes)
+    }
+}
+
+/// The smallest r with r * r >= end_n, so the primes below r are the base primes for end_n.
+fn root_bound(end_n: u128) -> u128 {
+    let mut root = (end_n as f64).sqrt() as u128;
+    while root > 0 && root * root >= end_n {
+        root -= 1;
+    }
+    while root * root < end_n {
+        root += 1;
+    }
+    root
+}
+
+/// Marks the composites in `segment`, which holds the numbers starting at `low`.
+///
+/// `primes` must contain every prime whose square falls below the end of the segment.
+/// Numbers below 2 are marked composite as well.
+pub fn mark_segment(segment: &mut [bool], low: u128, primes: &[u32]) {
+    segment.fill(false);
+    let high = low + segment.len() as u128;
+
+    for n in low..high.min(2) {
+        segment[(n - low) as usize] = true;
+    }
+
+    for p in primes.iter().map(|&p| p as u128) {
+        if p * p >= high {
+            break;
+        }
+        let first = (low.div_ceil(p) * p).max(p * p);
+        let mut multiple = first;
+        while multiple < high {
+            segment[(multiple - low) as usize] = true;
+            multiple += p;
+        }
+    }
+}
+
+/// Number of values handled per segment by `smallest_prime_factors`.
+const FACTOR_SEGMENT_SIZE: usize = 1 << 16;
+
+/// Receives each segment of `(n, smallest factor)` pairs as `smallest_prime_factors` finishes it.
+pub type FactorCallback<'a> = dyn FnMut(&[(u128, u128)]) -> Result<(), Box<dyn Error>> + 'a;
+
+/// Records the smallest prime factor of every number in [start_n, end_n) during a segmented sieve.
+///
+/// Each composite takes the first (and therefore smallest) base prime that crosses it off;
+/// primes are reported with a factor of 1. Numbers below 2 are skipped. The base primes
+/// stop at 2^32, so a range ending past `u64::MAX` is `MpError::UnsupportedRange`.
+///
+/// # Arguments
+///
+/// * `start_n` - The starting number of the range.
+/// * `end_n` - The ending number of the range.
+/// * `on_chunk` - Called with each segment's `(n, smallest factor)` pairs, in ascending order.
+pub fn smallest_prime_factors(
+    start_n: u128,
+    end_n: u128,
+    on_chunk: &mut FactorCallback,
+) -> Result<(), Box<dyn Error>> {
+    if start_n > end_n {
+        return Err(MpError::ReversedRange { start: start_n, end: end_n }.into());
+    }
+    if end_n > u64::MAX as u128 {
+        return Err(MpError::UnsupportedRange { end: end_n }.into());
+    }
+    let primes = base_primes(end_n);
+    let mut factors = vec![0u128; FACTOR_SEGMENT_SIZE];
+    let mut low = start_n.max(2);
+
+    while low < end_n {
+        let high = (low + FACTOR_SEGMENT_SIZE as u128).min(end_n);
+        let segment = &mut factors[..(high - low) as usize];
+        segment.fill(0);
+
+        for p in primes.iter().map(|&p| p as u128) {
+            if p * p >= high {
+                break;
+            }
+            let mut multiple = (low.div_ceil(p) * p).max(p * p);
+            while multiple < high {
+                let slot = &mut segment[(multiple - low) as usize];
+                if *slot == 0 {
+                    *slot = p;
+                }
+                multiple += p;
+            }
+        }
+
+        let pairs: Vec<(u128, u128)> = segment
+            .iter()
+            .enumerate()
+            .map(|(offset, &factor)| (low + offset as u128, if factor == 0 { 1 } else { factor }))
+            .collect();
+        on_chunk(&pairs)?;
+        low = high;
+    }
+
+    Ok(())
+}
+
+/// Collects the numbers in [start_n, limit) marked prime in `is_prime` into `primes`.
+fn collect_marked(is_prime: &[bool], start_n: u128, mut primes: Vec<u128>) -> Vec<u128> {
+    let start = usize::try_from(start_n).unwrap_or(usize::MAX).min(is_prime.len());
+    primes.extend(
+        is_prime[start..]
+            .iter()
+            .enumerate()
+            .filter(|(_, &prime)| prime)
+            .map(|(offset, _)| (start + offset) as u128),
+    );
+    primes
+}
+
+/// `collect_marked` looking only at the members of `progression`.
+fn collect_marked_members(is_prime: &[bool], start_n: u128, progression: &Progression, mut primes: Vec<u128>) -> Vec<u128> {
+    primes.extend(progression.members(start_n, is_prime.len() as u128).into_iter().filter(|&n| is_prime[n as usize]));
+    primes
+}
+
+/// Generates prime numbers in the range [start_n, end_n) with the Sieve of Eratosthenes.
+///
+/// # Arguments
+///
+/// * `start_n` - The starting number of the range.
+/// * `end_n` - The ending number of the range.
+///
+/// # Returns
+///
+/// A vector containing all prime numbers within the specified range.
+pub fn sieve_of_eratosthenes(start_n: u128, end_n: u128) -> Result<Vec<u128>, Box<dyn Error>> {
+    Sieve::Eratosthenes.primes(start_n, end_n)
+}
+
+/// Marks the primes below `limit` with the Sieve of Eratosthenes.
+fn eratosthenes_marks(limit: usize) -> Vec<bool> {
+    let mut is_prime = vec![true; limit];
+    for n in is_prime.iter_mut().take(2) {
+        *n = false;
+    }
+
+    let mut p = 2;
+    while p * p < limit {
+        if is_prime[p] {
+            for multiple in (p * p..limit).step_by(p) {
+                is_prime[multiple] = false;
+            }
+        }
+        p += 1;
+    }
+
+    is_prime
+}
+
+/// Generates prime numbers in the range [start_n, end_n) with the Sieve of Atkin.
+///
+/// # Arguments
+///
+/// * `start_n` - The starting number of the range.
+/// * `end_n` - The ending number of the range.
+///
+/// # Returns
+///
+/// A vector containing all prime numbers within the specified range.
+pub fn sieve_of_atkin(start_n: u128, end_n: u128) -> Result<Vec<u128>, Box<dyn Error>> {
+    Sieve::Atkin.primes(start_n, end_n)
+}
+
+/// Marks the primes below `limit` with the Sieve of Atkin.
+fn atkin_marks(limit: usize) -> Vec<bool> {
+    let mut is_prime = vec![false; limit];
+
+    // Step 1: Flip every n that has an odd number of solutions to one of the three quadratic forms
+    let mut x = 1;
+    while x * x < limit {
+        let mut y = 1;
+        while y * y < limit {
+            let n = 4 * x * x + y * y;
+            if n < limit && (n % 12 == 1 || n % 12 == 5) {
+                is_prime[n] = !is_prime[n];
+            }
+
+            let n = 3 * x * x + y * y;
+            if n < limit && n % 12 == 7 {
+                is_prime[n] = !is_prime[n];
+            }
+
+            if x > y {
+                let n = 3 * x * x - y * y;
+                if n < limit && n % 12 == 11 {
+                    is_prime[n] = !is_prime[n];
+                }
+            }
+            y += 1;
+        }
+        x += 1;
+    }
+
+    // Step 2: Eliminate the survivors that are not squarefree
+    let mut r = 5;
+    while r * r < limit {
+        if is_prime[r] {
+            for multiple in (r * r..limit).step_by(r * r) {
+                is_prime[multiple] = false;
+            }
+        }
+        r += 1;
+    }
+
+    // Step 3: The quadratic forms only cover primes above 3
+    for p in [2, 3] {
+        if p < limit {
+            is_prime[p] = true;
+        }
+    }
+
+    is_prime
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preallocation_covers_the_primes_and_stays_within_the_range() {
+        assert!((prime_count_estimate(1, 100) - 21.715).abs() < 1e-3);
+        assert_eq!(prime_count_estimate(0, 2), 2.0 / 2f64.ln());
+        for (start, end, primes) in [(1, 100_000, 9592), (1, 1_000_000, 78_498), (1 << 40, (1 << 40) + 100_000, 3_653)] {
+            let capacity = Preallocate::Estimate.capacity(start, end);
+            assert!((primes..primes * 6 / 5).contains(&capacity), "[{}, {}): {}", start, end, capacity);
+        }
+        assert_eq!(Preallocate::Estimate.capacity(0, 2), 2);
+        assert_eq!(Preallocate::Estimate.capacity(0, 1 << 62), PREALLOCATE_LIMIT);
+        assert_eq!(Preallocate::Grow.capacity(1, 100_000), 0);
+        assert_eq!(Preallocate::Capacity(usize::MAX).capacity(5, 8), 3);
+        // More room than the machine has leaves the vector to grow instead of panicking
+        assert_eq!(Preallocate::Capacity(usize::MAX).vector(0, u128::MAX).capacity(), 0);
+        assert_eq!(Sieve::Eratosthenes.primes_preallocated(0, 100, Preallocate::Capacity(1 << 60), None).unwrap().len(), 25);
+    }
+
+    #[test]
+    fn atkin_marks_the_same_primes_as_eratosthenes() {
+        for limit in [0, 1, 2, 3, 4, 5, 6, 7, 11, 12, 13, 25, 49, 100, 1000, 1 << 16, 1_000_003] {
+            assert_eq!(sieve_of_atkin(0, limit).unwrap(), sieve_of_eratosthenes(0, limit).unwrap(), "limit = {}", limit);
+        }
+        assert_eq!(Sieve::Atkin.primes(1000, 2000).unwrap(), Sieve::Eratosthenes.primes(1000, 2000).unwrap());
+        assert_eq!(sieve_of_atkin(0, 30).unwrap(), [2, 3, 5, 7, 11, 13, 17, 19, 23, 29]);
+    }
+
+    #[test]
+    fn whole_range_sieves_collect_only_the_members_of_a_progression() {
+        let progression = Progression::new(4, &[1]).unwrap();
+        for sieve in [Sieve::Eratosthenes, Sieve::Atkin] {
+            let mut expected = sieve.primes(10, 100_000).unwrap();
+            expected.retain(|&p| p % 4 == 1);
+            assert_eq!(sieve.primes_preallocated(10, 100_000, Preallocate::Estimate, Some(&progression)).unwrap(), expected);
+        }
+        let progression = Progression::new(10, &[3, 7]).unwrap();
+        assert_eq!(Sieve::Atkin.primes_preallocated(0, 40, Preallocate::Grow, Some(&progression)).unwrap(), [3, 7, 13, 17, 23, 37]);
+    }
+
+    #[test]
+    fn whole_range_sieves_refuse_ends_past_their_limit() {
+        for sieve in [Sieve::Eratosthenes, Sieve::Atkin] {
+            let error = sieve.primes(1_000_000_000_000, 1_000_000_000_100).unwrap_err();
+            assert!(error.to_string().contains("use the segmented sieve"), "{}", error);
+            assert!(sieve.primes(MAX_SIEVE_END - 10, MAX_SIEVE_END + 1).is_err());
+        }
+        assert!(sieve_limit(MAX_SIEVE_END).is_ok());
+        assert!(sieve_of_atkin(0, MAX_SIEVE_END + 1).is_err());
+        assert!(sieve_of_eratosthenes(0, MAX_SIEVE_END + 1).is_err());
+    }
+
+    #[test]
+    fn base_primes_are_those_whose_square_is_below_the_end() {
+        assert_eq!(base_primes(0), Vec::<u32>::new());
+        assert_eq!(base_primes(4), [] as [u32; 0]);
+        assert_eq!(base_primes(5), [2]);
+        assert_eq!(base_primes(50), [2, 3, 5, 7]);
+        assert_eq!(base_primes(10u128.pow(12)).len(), 78498);
+        assert_eq!(base_primes(10u128.pow(12) + 1).len(), 78498);
+    }
+
+    #[test]
+    fn a_cached_sieve_extends_across_adjacent_ranges() {
+        let mut sieve = PrimeSieve::new();
+        let mut primes = sieve.primes_in(1, 100).unwrap();
+        assert_eq!(sieve.base_primes(), [2, 3, 5, 7]);
+        primes.extend(sieve.primes_in(100, 200).unwrap());
+        assert_eq!(primes, PrimeSieve::new().primes_in(1, 200).unwrap());
+        assert_eq!(primes, sieve_of_eratosthenes(1, 200).unwrap());
+
+        // Growing a step at a time, past the first segment, keeps the base primes exact
+        for end_n in [2, 5, 1000, 10u128.pow(11), 10u128.pow(12) + 1] {
+            sieve.extend_to(end_n);
+        }
+        assert_eq!(sieve.base_primes(), base_primes(10u128.pow(12) + 1));
+        let start = BASE_SEGMENT_SIZE - 500;
+        assert_eq!(sieve.primes_in(start, start + 1000).unwrap(), sieve_of_eratosthenes(start, start + 1000).unwrap());
+
+        let error = sieve.primes_in(200, 100).unwrap_err();
+        assert_eq!(error.to_string(), "range start 200 is past its end 100");
+        assert!(sieve.primes_in(0, u64::MAX as u128 + 1).is_err());
+    }
+
+    /// Collects every pair `smallest_prime_factors` reports for [start_n, end_n).
+    fn factors(start_n: u128, end_n: u128) -> Vec<(u128, u128)> {
+        let mut pairs = Vec::new();
+        smallest_prime_factors(start_n, end_n, &mut |chunk| {
+            pairs.extend_from_slice(chunk);
+            Ok(())
+        })
+        .unwrap();
+        pairs
+    }
+
+    #[test]
+    fn smallest_prime_factors_of_small_numbers() {
+        let pairs = factors(0, 20);
+        assert_eq!(pairs.first(), Some(&(2, 1)));
+        assert!(pairs.contains(&(15, 3)));
+        assert!(pairs.contains(&(13, 1)));
+        assert!(pairs.contains(&(9, 3)));
+        assert!(pairs.contains(&(16, 2)));
+        assert_eq!(pairs.len(), 18);
+
+        // Across a segment boundary, every factor divides its number and only primes get 1
+        let start = FACTOR_SEGMENT_SIZE as u128 - 50;
+        let primes = sieve_of_eratosthenes(start, start + 100).unwrap();
+        for (n, factor) in factors(start, start + 100) {
+            assert_eq!(factor == 1, primes.contains(&n), "{}", n);
+            assert!(factor == 1 || (n % factor == 0 && factor * factor <= n), "{} {}", n, factor);
+        }
+
+        let error = smallest_prime_factors(20, 10, &mut |_| Ok(())).unwrap_err();
+        assert_eq!(error.to_string(), "range start 20 is past its end 10");
+        // 4294967311 * 4294967357 has no factor below 2^32 for the base primes to find
+        let error = smallest_prime_factors(18446744400127067027, 18446744400127067028, &mut |_| Ok(())).unwrap_err();
+        assert_eq!(error.downcast_ref::<MpError>(), Some(&MpError::UnsupportedRange { end: 18446744400127067028 }));
+    }
+}
@@ -0,0 +1,542 @@
+use log::info;
+use num_bigint::BigUint;
+use num_traits::{One, Zero};
+use std::error::Error;
+use std::f64::consts::PI;
+
+/// Exponents from which `squarer` picks the FFT over BigUint squaring, about where the FFT
+/// starts to win.
+pub const IBDWT_THRESHOLD: u128 = 8192;
+
+/// Largest rounding error an FFT squaring may show before it is redone at twice the length.
+/// Past 0.5 a product digit may have rounded to the wrong integer.
+pub const MAX_ROUNDOFF: f64 = 0.4;
+
+/// 2^53, from which doubles can no longer hold every integer.
+const LARGEST_EXACT: f64 = 9_007_199_254_740_992.0;
+
+/// Repeated squaring mod 2^p - 1, the step the Lucas-Lehmer and Mersenne PRP loops run.
+///
+/// The CPU squarers never fail; device-backed ones fail when the device does.
+pub trait Squarer {
+    /// Replaces the residue x by x^2 - subtract mod 2^p - 1.
+    fn square_sub(&mut self, subtract: u32) -> Result<(), Box<dyn Error>>;
+
+    /// Replaces the residue x by x^2 - subtract * 2^shift mod 2^p - 1, for a residue that
+    /// carries a factor 2^shift once squared, with `shift` below p. Only the CPU squarers
+    /// take a nonzero `shift`.
+    fn square_sub_shifted(&mut self, subtract: u32, shift: u128) -> Result<(), Box<dyn Error>> {
+        match shift {
+            0 => self.square_sub(subtract),
+            _ => Err("This squarer does not support shifts.".into()),
+        }
+    }
+
+    /// The residue, in [0, 2^p - 1).
+    fn residue(&self) -> Result<BigUint, Box<dyn Error>>;
+
+    /// The residue a checkpoint should hold, with how many squarings ago it was reached.
+    /// Squarers that check their work hand back the last residue a check confirmed,
+    /// confirming everything first when the run is `stopping`; the rest vouch for the
+    /// residue they hold.
+    fn checkpoint(&mut self, _stopping: bool) -> Result<(BigUint, u128), Box<dyn Error>> {
+        Ok((self.residue()?, 0))
+    }
+}
+
+/// A squarer for 2^p - 1 starting from `initial`: BigUint arithmetic below
+/// `IBDWT_THRESHOLD`, the FFT from there on.
+pub fn squarer(p: u128, initial: &BigUint) -> Box<dyn Squarer + Send> {
+    if p < IBDWT_THRESHOLD {
+        Box::new(BigUintSquarer::new(p, initial))
+    } else {
+        Box::new(IbdwtSquarer::new(p, initial))
+    }
+}
+
+/// The Lucas-Lehmer squarings of another squarer on a residue carrying a factor 2^shift,
+/// which every squaring doubles mod p, so that runs with different shifts square different
+/// numbers to the same final residue. The inner squarer holds the shifted residue, which
+/// is what checkpoints keep; `residue` takes the shift out.
+pub struct ShiftedSquarer {
+    p: u128,
+    shift: u128,
+    inner: Box<dyn Squarer + Send>,
+}
+
+impl ShiftedSquarer {
+    /// Squares `inner`, whose residue carries a factor 2^shift.
+    pub fn new(p: u128, shift: u128, inner: Box<dyn Squarer + Send>) -> ShiftedSquarer {
+        ShiftedSquarer { p, shift: shift % p, inner }
+    }
+
+    /// The power of two the residue carries.
+    pub fn shift(&self) -> u128 {
+        self.shift
+    }
+}
+
+impl Squarer for ShiftedSquarer {
+    fn square_sub(&mut self, subtract: u32) -> Result<(), Box<dyn Error>> {
+        self.shift = 2 * self.shift % self.p;
+        self.inner.square_sub_shifted(subtract, self.shift)
+    }
+
+    fn residue(&self) -> Result<BigUint, Box<dyn Error>> {
+        let shifted = self.inner.residue()?;
+        if self.shift == 0 {
+            return Ok(shifted);
+        }
+        // 2^-shift = 2^(p - shift) mod 2^p - 1
+        let modulus = (BigUint::one() << self.p) - 1u32;
+        Ok((shifted << (self.p - self.shift)) % modulus)
+    }
+
+    fn checkpoint(&mut self, stopping: bool) -> Result<(BigUint, u128), Box<dyn Error>> {
+        self.inner.checkpoint(stopping)
+    }
+}
+
+/// Squaring with BigUint multiplication and the shift-and-add reduction mod 2^p - 1.
+pub struct BigUintSquarer {
+    p: u128,
+    modulus: BigUint,
+    value: BigUint,
+}
+
+impl BigUintSquarer {
+    pub fn new(p: u128, initial: &BigUint) -> BigUintSquarer {
+        let modulus = (BigUint::one() << p) - 1u32;
+        let value = initial % &modulus;
+        BigUintSquarer { p, modulus, value }
+    }
+}
+
+impl Squarer for BigUintSquarer {
+    fn square_sub(&mut self, subtract: u32) -> Result<(), Box<dyn Error>> {
+        self.square_sub_shifted(subtract, 0)
+    }
+
+    fn square_sub_shifted(&mut self, subtract: u32, shift: u128) -> Result<(), Box<dyn Error>> {
+        let square = &self.value * &self.value;
+        // 2^p = 1 mod 2^p - 1, so the bits past p fold back onto the low ones
+        let mut folded = (&square >> self.p) + (square & &self.modulus);
+        if folded >= self.modulus {
+            folded -= &self.modulus;
+        }
+        let subtract = (BigUint::from(subtract) << shift) % &self.modulus;
+        self.value = (folded + &self.modulus - subtract) % &self.modulus;
+        Ok(())
+    }
+
+    fn residue(&self) -> Result<BigUint, Box<dyn Error>> {
+        Ok(self.value.clone())
+    }
+}
+
+/// Squaring with the irrational-base discrete weighted transform (Crandall and Fagin).
+///
+/// The residue is held as `length` signed digits of `p / length` or one more bits, digit j
+/// starting at bit ceil(p * j / length). Weighting digit j by 2^(ceil(pj/N) - pj/N) makes
+/// the cyclic convolution of the digits, computed with a floating-point FFT, come out
+/// reduced mod 2^p - 1 with nothing else to do. Digits are kept balanced, in
+/// [-2^(b-1), 2^(b-1)), which keeps the products, and with them the rounding errors, small.
+///
+/// Every squaring measures how far the FFT's outputs were from integers. Past `MAX_ROUNDOFF`
+/// the squaring is redone at twice the length, which carries on from then on.
+pub struct IbdwtSquarer {
+    p: u128,
+    digits: Vec<i64>,
+    /// Bits in each digit.
+    widths: Vec<u32>,
+    /// The weight of each digit, and 1 / (weight * length / 2) to undo it and the inverse
+    /// FFT's scaling.
+    weights: Vec<f64>,
+    unweights: Vec<f64>,
+    /// e^(-2 pi i k / length) for k below length / 2.
+    twiddles: Vec<Complex>,
+    /// The bit-reversal permutation of length / 2 points.
+    reversed: Vec<usize>,
+    /// Largest rounding error seen so far.
+    max_roundoff: f64,
+}
+
+impl IbdwtSquarer {
+    /// A squarer for 2^p - 1 starting from `initial`, at the length `fft_length` picks.
+    pub fn new(p: u128, initial: &BigUint) -> IbdwtSquarer {
+        IbdwtSquarer::with_length(p, fft_length(p), initial)
+    }
+
+    /// A squarer using `length` digits, a power of two from 4 to p that leaves the digits
+    /// under 63 bits.
+    pub fn with_length(p: u128, length: usize, initial: &BigUint) -> IbdwtSquarer {
+        let n = length as u128;
+        assert!(
+            length.is_power_of_two() && length >= 4 && n <= p && p.div_ceil(n) < 63,
+            "no FFT of length {} for 2^{}-1",
+            length,
+            p
+        );
+        // Digit j starts at bit ceil(p * j / n)
+        let start = |j: u128| (p * j).div_ceil(n);
+        let widths = (0..n).map(|j| (start(j + 1) - start(j)) as u32).collect();
+        let weights: Vec<f64> = (0..n)
+            .map(|j| {
+                let fraction = (start(j) * n - p * j) as f64 / n as f64;
+                fraction.exp2()
+            })
+            .collect();
+        let half = length / 2;
+        let unweights = weights.iter().map(|weight| 1.0 / (weight * half as f64)).collect();
+        let twiddles = (0..half)
+            .map(|k| {
+                let angle = -2.0 * PI * k as f64 / length as f64;
+                Complex { re: angle.cos(), im: angle.sin() }
+            })
+            .collect();
+        let bits = half.trailing_zeros();
+        let reversed = (0..half).map(|i| if bits == 0 { 0 } else { i.reverse_bits() >> (usize::BITS - bits) }).collect();
+        let mut squarer = IbdwtSquarer {
+            p,
+            digits: Vec::new(),
+            widths,
+            weights,
+            unweights,
+            twiddles,
+            reversed,
+            max_roundoff: 0.0,
+        };
+        squarer.digits = squarer.split(initial);
+        squarer
+    }
+
+    /// The number of digits, which is the FFT length.
+    pub fn length(&self) -> usize {
+        self.digits.len()
+    }
+
+    /// The largest rounding error any squaring has shown so far.
+    pub fn max_roundoff(&self) -> f64 {
+        self.max_roundoff
+    }
+
+    /// `value` mod 2^p - 1 as balanced digits.
+    fn split(&self, value: &BigUint) -> Vec<i64> {
+        let modulus = (BigUint::one() << self.p) - 1u32;
+        let limbs = (value % &modulus).to_u64_digits();
+        let bit = |position: usize| limbs.get(position / 64).map_or(0, |limb| (limb >> (position % 64)) & 1);
+        let mut position = 0;
+        let mut digits: Vec<i64> = self
+            .widths
+            .iter()
+            .map(|&width| {
+                let digit = (0..width as usize).fold(0, |digit, i| digit | (bit(position + i) << i));
+                position += width as usize;
+                digit as i64
+            })
+            .collect();
+        carry(&mut digits, &self.widths, true);
+        digits
+    }
+
+    /// One squaring of the digits, less subtract * 2^shift, returning the largest rounding
+    /// error it showed.
+    fn square_digits(&mut self, subtract: u32, shift: u128) -> f64 {
+        let half = self.digits.len() / 2;
+        // Adjacent weighted digits share a complex point, so the FFT is half the length
+        let mut points: Vec<Complex> = (0..half)
+            .map(|m| Complex {
+                re: self.digits[2 * m] as f64 * self.weights[2 * m],
+                im: self.digits[2 * m + 1] as f64 * self.weights[2 * m + 1],
+            })
+            .collect();
+        self.fft(&mut points, false);
+
+        // Untangle the transforms of the even and odd digits to get the full-length
+        // transform X at k and k + half, square both, and tangle the squares back up
+        let squared: Vec<Complex> = (0..half)
+            .map(|k| {
+                let z = points[k];
+                let mirror = points[(half - k) % half].conj();
+                let even = z.add(mirror).scale(0.5);
+                let odd = z.sub(mirror).mul(Complex { re: 0.0, im: -0.5 });
+                let twiddle = self.twiddles[k];
+                let rotated = twiddle.mul(odd);
+                let low = even.add(rotated);
+                let high = even.sub(rotated);
+                let (low, high) = (low.mul(low), high.mul(high));
+                let even = low.add(high).scale(0.5);
+                let odd = low.sub(high).mul(twiddle.conj()).scale(0.5);
+                even.add(odd.mul(Complex { re: 0.0, im: 1.0 }))
+            })
+            .collect();
+        points = squared;
+        self.fft(&mut points, true);
+
+        let mut roundoff = 0f64;
+        for (m, point) in points.iter().enumerate() {
+            for (j, value) in [(2 * m, point.re), (2 * m + 1, point.im)] {
+                let value = value * self.unweights[j];
+                let rounded = value.round();
+                // Past 2^53 every double is an integer, so there is no error left to see
+                let error = if rounded.abs() < LARGEST_EXACT { (value - rounded).abs() } else { 0.5 };
+                roundoff = roundoff.max(error);
+                self.digits[j] = rounded as i64;
+            }
+        }
+        if roundoff > MAX_ROUNDOFF {
+            return roundoff;
+        }
+        // Bit `shift` falls in the digit whose widths so far pass it
+        let (mut digit, mut start) = (0, 0u128);
+        while start + self.widths[digit] as u128 <= shift {
+            start += self.widths[digit] as u128;
+            digit += 1;
+        }
+        self.digits[digit] -= (subtract as i64) << (shift - start);
+        carry(&mut self.digits, &self.widths, true);
+        roundoff
+    }
+
+    /// An in-place radix-2 FFT of `points`, with the inverse unscaled.
+    fn fft(&self, points: &mut [Complex], inverse: bool) {
+        let n = points.len();
+        for i in 0..n {
+            let j = self.reversed[i];
+            if i < j {
+                points.swap(i, j);
+            }
+        }
+        // The twiddles are for twice this length, so every other one is this length's
+        let mut span = 1;
+        while span < n {
+            let stride = n / span;
+            for start in (0..n).step_by(2 * span) {
+                for k in 0..span {
+                    let twiddle = self.twiddles[k * stride];
+                    let twiddle = if inverse { twiddle.conj() } else { twiddle };
+                    let (u, v) = (points[start + k], points[start + k + span].mul(twiddle));
+                    points[start + k] = u.add(v);
+                    points[start + k + span] = u.sub(v);
+                }
+            }
+            span *= 2;
+        }
+    }
+}
+
+impl Squarer for IbdwtSquarer {
+    fn square_sub(&mut self, subtract: u32) -> Result<(), Box<dyn Error>> {
+        self.square_sub_shifted(subtract, 0)
+    }
+
+    fn square_sub_shifted(&mut self, subtract: u32, shift: u128) -> Result<(), Box<dyn Error>> {
+        let before = self.digits.clone();
+        let roundoff = self.square_digits(subtract, shift);
+        if roundoff > MAX_ROUNDOFF {
+            let length = 2 * self.length();
+            info!(
+                "Rounding error {:.3} squaring mod 2^{}-1 at FFT length {}, redoing it at {}",
+                roundoff,
+                self.p,
+                self.length(),
+                length
+            );
+            self.digits = before;
+            let value = self.residue()?;
+            let roundoff = self.max_roundoff;
+            *self = IbdwtSquarer::with_length(self.p, length, &value);
+            self.max_roundoff = roundoff;
+            return self.square_sub_shifted(subtract, shift);
+        }
+        self.max_roundoff = self.max_roundoff.max(roundoff);
+        Ok(())
+    }
+
+    fn residue(&self) -> Result<BigUint, Box<dyn Error>> {
+        let mut digits = self.digits.clone();
+        carry(&mut digits, &self.widths, false);
+        let mut limbs = vec![0u64; (self.p as usize).div_ceil(64) + 1];
+        let mut position = 0;
+        for (&digit, &width) in digits.iter().zip(&self.widths) {
+            let digit = digit as u64;
+            limbs[position / 64] |= digit << (position % 64);
+            if position % 64 + width as usize > 64 {
+                limbs[position / 64 + 1] |= digit >> (64 - position % 64);
+            }
+            position += width as usize;
+        }
+        let digits: Vec<u32> = limbs.iter().flat_map(|&limb| [limb as u32, (limb >> 32) as u32]).collect();
+        let value = BigUint::from_slice(&digits);
+        // 2^p - 1 itself is the other form of zero
+        if value == (BigUint::one() << self.p) - 1u32 {
+            Ok(BigUint::zero())
+        } else {
+            Ok(value)
+        }
+    }
+}
+
+/// Propagates carries through `digits` of the given widths until every digit is in range:
+/// [-2^(b-1), 2^(b-1)) when `balanced`, else [0, 2^b). A carry out of the top digit wraps
+/// around to the bottom, as 2^p = 1 mod 2^p - 1.
+fn carry(digits: &mut [i64], widths: &[u32], balanced: bool) {
+    let split = |value: i64, width: u32| {
+        let mut low = value & ((1 << width) - 1);
+        if balanced && low >= 1 << (width - 1) {
+            low -= 1 << width;
+        }
+        (low, (value - low) >> width)
+    };
+    let mut carry = 0;
+    for (digit, &width) in digits.iter_mut().zip(widths) {
+        (*digit, carry) = split(*digit + carry, width);
+    }
+    // Past the first pass only the wrapped carry is left, and it dies out within a few digits
+    while carry != 0 {
+        for (digit, &width) in digits.iter_mut().zip(widths) {
+            (*digit, carry) = split(*digit + carry, width);
+            if carry == 0 {
+                break;
+            }
+        }
+    }
+}
+
+/// The FFT length `IbdwtSquarer::new` uses for 2^p - 1: the shortest power of two whose
+/// digits stay within the bits a 53-bit mantissa leaves room for. Each doubling of the
+/// length costs about half a bit of precision to the longer sums.
+pub fn fft_length(p: u128) -> usize {
+    let mut length = 4usize;
+    while (p as f64 / length as f64) > (46.0 - 0.5 * length.ilog2() as f64) / 2.0 && (2 * length) as u128 <= p {
+        length *= 2;
+    }
+    length
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Complex {
+    re: f64,
+    im: f64,
+}
+
+impl Complex {
+    fn add(self, other: Complex) -> Complex {
+        Complex { re: self.re + other.re, im: self.im + other.im }
+    }
+
+    fn sub(self, other: Complex) -> Complex {
+        Complex { re: self.re - other.re, im: self.im - other.im }
+    }
+
+    fn mul(self, other: Complex) -> Complex {
+        Complex {
+            re: self.re * other.re - self.im * other.im,
+            im: self.re * other.im + self.im * other.re,
+        }
+    }
+
+    fn scale(self, factor: f64) -> Complex {
+        Complex { re: self.re * factor, im: self.im * factor }
+    }
+
+    fn conj(self) -> Complex {
+        Complex { re: self.re, im: -self.im }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The Lucas-Lehmer residue after `iterations` squarings, through `squarer`.
+    fn residue_after(mut squarer: impl Squarer, iterations: u128) -> BigUint {
+        for _ in 0..iterations {
+            squarer.square_sub(2).unwrap();
+        }
+        squarer.residue().unwrap()
+    }
+
+    #[test]
+    fn ibdwt_residues_match_biguint_for_whole_tests() {
+        // Mersenne primes, whose final residue is zero, and composites either side
+        for p in [89, 107, 127, 521, 607, 1279, 1277, 1283, 2203, 2281, 3217] {
+            let four = BigUint::from(4u32);
+            let expected = residue_after(BigUintSquarer::new(p, &four), p - 2);
+            let ibdwt = IbdwtSquarer::new(p, &four);
+            assert_eq!(residue_after(ibdwt, p - 2), expected, "M{}", p);
+            assert_eq!(expected.is_zero(), [89, 107, 127, 521, 607, 1279, 2203, 2281, 3217].contains(&p));
+        }
+    }
+
+    #[test]
+    fn ibdwt_residues_match_biguint_for_large_exponents() {
+        for p in [9_689, 23_209, 99_991] {
+            let four = BigUint::from(4u32);
+            let mut ibdwt = IbdwtSquarer::new(p, &four);
+            let mut reference = BigUintSquarer::new(p, &four);
+            for _ in 0..40 {
+                ibdwt.square_sub(2).unwrap();
+                reference.square_sub(2).unwrap();
+            }
+            assert_eq!(ibdwt.residue().unwrap(), reference.residue().unwrap(), "M{}", p);
+            assert!(ibdwt.max_roundoff() < MAX_ROUNDOFF, "M{}: {}", p, ibdwt.max_roundoff());
+        }
+    }
+
+    #[test]
+    fn shifted_squarings_reach_the_unshifted_residue() {
+        for p in [89, 1277, 9_689] {
+            let four = BigUint::from(4u32);
+            let modulus = (BigUint::one() << p) - 1u32;
+            let expected = residue_after(BigUintSquarer::new(p, &four), 40);
+            for shift in [1, 37, p - 1] {
+                let seed = (&four << shift) % &modulus;
+                let mut shifted = ShiftedSquarer::new(p, shift, squarer(p, &seed));
+                for _ in 0..40 {
+                    shifted.square_sub(2).unwrap();
+                }
+                assert_eq!(shifted.residue().unwrap(), expected, "M{} shifted by {}", p, shift);
+                // What a checkpoint keeps is the residue times 2^(shift * 2^40)
+                let carried = shifted.checkpoint(false).unwrap().0;
+                assert_eq!(carried, (&expected << shifted.shift()) % &modulus, "M{} shifted by {}", p, shift);
+            }
+        }
+    }
+
+    #[test]
+    fn rounding_errors_move_the_squaring_to_a_longer_fft() {
+        // 2203 bits in 64 digits is far more than a 53-bit mantissa can convolve exactly
+        let three = BigUint::from(3u32);
+        let mut ibdwt = IbdwtSquarer::with_length(2203, 64, &three);
+        let mut reference = BigUintSquarer::new(2203, &three);
+        for _ in 0..50 {
+            ibdwt.square_sub(0).unwrap();
+            reference.square_sub(0).unwrap();
+        }
+        assert!(ibdwt.length() > 64);
+        assert_eq!(ibdwt.residue().unwrap(), reference.residue().unwrap());
+    }
+
+    #[test]
+    fn residues_are_reduced_below_the_modulus() {
+        let p: u128 = 4_423;
+        let modulus = (BigUint::one() << p) - 1u32;
+        for value in [BigUint::zero(), BigUint::one(), &modulus - 1u32, modulus.clone(), &modulus + 5u32] {
+            assert_eq!(IbdwtSquarer::new(p, &value).residue().unwrap(), &value % &modulus);
+        }
+        // Squaring 1 and subtracting 1 leaves zero, whichever of its two forms the digits hold
+        let mut ibdwt = IbdwtSquarer::new(p, &BigUint::one());
+        ibdwt.square_sub(1).unwrap();
+        assert!(ibdwt.residue().unwrap().is_zero());
+    }
+
+    #[test]
+    fn fft_lengths_grow_with_the_exponent() {
+        assert_eq!(fft_length(4), 4);
+        let lengths: Vec<usize> = [1_000, 100_000, 10_000_000, 100_000_000].into_iter().map(fft_length).collect();
+        assert!(lengths.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", lengths);
+        // GIMPS fits exponents near 100M in about 5.5M words; a power of two above that is fine
+        assert!(lengths[3] <= 1 << 23, "{}", lengths[3]);
+    }
+}
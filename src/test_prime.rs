@@ -1,54 +1,834 @@
 use num_bigint::BigUint;
-use num_traits::One;
+use num_traits::{One, ToPrimitive, Zero};
 use num_integer::Integer;
-use ocl::{flags, ProQue};
+use ocl::enums::{DeviceInfo, ProgramBuildInfo, ProgramBuildInfoResult, ProgramInfo, ProgramInfoResult};
+use ocl::{flags, Context, Device, Platform, Program, ProQue, Queue};
+use std::collections::BTreeSet;
 use std::error::Error;
-use indicatif::{ProgressBar, ProgressStyle};
-use std::fs::{File, OpenOptions};
-use std::io::{Write, Read};
-use std::path::Path;
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-pub fn lucas_lehmer(p: u128, mem: bool) -> Result<bool, Box<dyn Error>> {
-    if p == 2 {
-        return Ok(true);
+use crate::arith::MontgomeryCtx;
+use crate::error::MpError;
+use crate::gerbicz::{GerbiczSquarer, GERBICZ_ERRORS};
+use crate::ntt::{res64, NttSquarer, DEFAULT_SELF_CHECK_INTERVAL};
+use crate::profile::{self, Phase, TIMINGS};
+use crate::progress::{progress_bar, Throughput, LUCAS_LEHMER_TEMPLATE};
+use crate::squarer::{squarer, ShiftedSquarer, Squarer};
+use log::{debug, info, warn};
+use serde::Serialize;
+
+/// Number of iterations between checkpoints in memory mode.
+const CHECKPOINT_INTERVAL: u128 = 100_000_000;
+
+/// Iterations between checkpoints in memory mode instead of `CHECKPOINT_INTERVAL` on the
+/// kernel and `SQUARER_CHECKPOINT_INTERVAL` on the CPU and NTT, or 0 (the default) for those.
+pub static CHECKPOINT_EVERY: AtomicU64 = AtomicU64::new(0);
+
+/// `CHECKPOINT_EVERY` when it is set, `default` otherwise.
+fn checkpoint_interval(default: u128) -> u128 {
+    match CHECKPOINT_EVERY.load(Ordering::Relaxed) {
+        0 => default,
+        every => u128::from(every),
     }
+}
 
-    // Construct Mersenne number M = 2^p - 1
-    let m = (&BigUint::one() << p) - 1u32;
-    let iterations = p - 2;
+/// The checkpoint file `name` in `dir`, or in the working directory without one.
+fn checkpoint_path(dir: Option<&Path>, name: String) -> String {
+    match dir {
+        Some(dir) => dir.join(name).to_string_lossy().into_owned(),
+        None => name,
+    }
+}
 
-    // OpenCL kernel source code
-    let src = r#"
-    __kernel void lucas_lehmer(__global ulong* s, __global const ulong* m) {
-        ulong a = s[0];
-        // Perform s = (s * s - 2) mod m
-        ulong result = (a * a - 2) % m[0];
+/// Creates the directory of the checkpoint file `path` if it is missing.
+fn create_checkpoint_dir(path: &str) -> Result<(), Box<dyn Error>> {
+    match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => Ok(std::fs::create_dir_all(dir)?),
+        _ => Ok(()),
+    }
+}
+
+/// Exponents below this run the Lucas-Lehmer test on the CPU unless told otherwise.
+///
+/// The kernel takes exponents up to `LL_KERNEL_MAX_EXPONENT` and launches once per
+/// iteration, while BigUint squaring of numbers that small takes microseconds, so building
+/// the OpenCL program never pays off by default. A lower threshold sends exponents to the
+/// kernel, e.g. to check it against the CPU; the NTT backend takes every exponent from it.
+pub const LL_GPU_THRESHOLD: u128 = 65;
+
+/// Largest exponent the 64-bit Lucas-Lehmer kernel takes: its residues are single u64s.
+pub const LL_KERNEL_MAX_EXPONENT: u128 = 64;
+
+/// Number of progress milestones a Lucas-Lehmer run logs at debug level.
+const LOG_MILESTONES: u128 = 10;
+
+/// Lucas-Lehmer iterations per kernel launch unless told otherwise. The host only looks
+/// at a run (progress, checkpoints, Ctrl-C, the timeout) between launches.
+pub const DEFAULT_BATCH_SIZE: u128 = 1024;
+
+/// Set by the CLI's Ctrl-C handler. `lucas_lehmer` notices it after the batch in flight,
+/// checkpoints in memory mode and fails with `MpError::Interrupted`.
+pub static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// An OpenCL program built for a device from a source with some compiler options, and its
+/// context.
+type BuiltProgram = (Device, String, String, Context, Program);
+
+/// Every OpenCL program built so far.
+static PROGRAMS: Mutex<Vec<BuiltProgram>> = Mutex::new(Vec::new());
+
+/// Directory where compiled program binaries are kept between runs, or `None` (the
+/// default) to compile from source in every process.
+pub static PROGRAM_CACHE: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Preprocessor definitions, like `NAME` or `NAME=VALUE`, passed to the OpenCL compiler as
+/// `-D` options to debug kernel builds. None by default.
+pub static KERNEL_DEFINES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Number of programs compiled from source so far, rather than loaded from `PROGRAM_CACHE`.
+pub static COMPILATIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// A context on `device` with `src` built for it. The first request for a device and source
+/// builds the program and every later one shares it, so repeated library calls only pay
+/// for their own queues and buffers. With `PROGRAM_CACHE` set, the build loads the binary
+/// an earlier run saved for the same device and source, and saves one when it compiles.
+/// The program is compiled with `KERNEL_DEFINES`.
+pub(crate) fn cached_program(device: Device, src: &str) -> Result<(Context, Program), Box<dyn Error>> {
+    let options = compiler_options(&KERNEL_DEFINES.lock().unwrap_or_else(|e| e.into_inner()));
+    cached_program_with_options(device, src, &options)
+}
+
+/// `cached_program` with the compiler `options` rather than those for `KERNEL_DEFINES`.
+/// Programs built with different options are kept apart.
+fn cached_program_with_options(device: Device, src: &str, options: &str) -> Result<(Context, Program), Box<dyn Error>> {
+    let mut programs = PROGRAMS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((.., context, program)) = programs.iter().find(|(d, s, o, ..)| *d == device && s == src && o == options) {
+        return Ok((context.clone(), program.clone()));
+    }
+    let context = profile::time(Phase::Init, || Context::builder().platform(Platform::first()?).devices(device).build())?;
+    let cache_dir = PROGRAM_CACHE.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    let binary_path = match &cache_dir {
+        Some(dir) => {
+            let mut fingerprint = device_fingerprint(device)?;
+            if !options.is_empty() {
+                fingerprint = format!("{}\n{}", fingerprint, options);
+            }
+            Some(program_binary_path(dir, &fingerprint, src))
+        }
+        None => None,
+    };
+    let compiling = Instant::now();
+    let loaded = binary_path.as_ref().and_then(|path| {
+        let binary = std::fs::read(path).ok()?;
+        // A driver that rejects the binary gets the source instead
+        match Program::builder().binaries(&[&binary]).devices(device).build(&context) {
+            Ok(program) => Some(program),
+            Err(e) => {
+                debug!("Ignoring the cached program {}: {}", path.display(), e);
+                None
+            }
+        }
+    });
+    let program = match loaded {
+        Some(program) => {
+            debug!("Loaded an OpenCL program for {} from the cache", device.name()?);
+            program
+        }
+        None => {
+            let started = Instant::now();
+            let program = build_from_source(&context, device, src, options)?;
+            COMPILATIONS.fetch_add(1, Ordering::Relaxed);
+            info!("Built an OpenCL program on {} in {:.1?}", device.name()?, started.elapsed());
+            if let Some(path) = &binary_path {
+                if let Err(e) = save_program_binary(&program, path) {
+                    warn!("Could not cache the OpenCL program at {}: {}", path.display(), e);
+                }
+            }
+            program
+        }
+    };
+    TIMINGS.record(Phase::Compile, compiling.elapsed());
+    programs.push((device, src.to_string(), options.to_string(), context.clone(), program.clone()));
+    Ok((context, program))
+}
+
+/// The compiler options for the preprocessor `defines`.
+fn compiler_options(defines: &[String]) -> String {
+    defines.iter().map(|define| format!("-D {}", define)).collect::<Vec<_>>().join(" ")
+}
+
+/// Compiles `src` for `device` with the compiler `options`. When the compiler rejects it,
+/// the error is an `MpError::KernelBuild` with the device's build log, where the one ocl
+/// returns would bury it in a generic message.
+fn build_from_source(context: &Context, device: Device, src: &str, options: &str) -> Result<Program, Box<dyn Error>> {
+    let program = ocl::core::create_program_with_source(context, &[CString::new(src)?])?;
+    if let Err(e) = ocl::core::build_program(&program, Some(&[device]), &CString::new(options)?, None, None) {
+        return Err(match ocl::core::get_program_build_info(&program, device, ProgramBuildInfo::BuildLog) {
+            Ok(ProgramBuildInfoResult::BuildLog(log)) if !log.trim().is_empty() => {
+                MpError::KernelBuild { device: device.name()?, log: with_source_lines(log.trim_end(), src) }.into()
+            }
+            _ => e.into(),
+        });
+    }
+    Ok(Program::from(program))
+}
+
+/// `log` followed by the lines of `src` it refers to, numbered. Compilers cite them as
+/// `<source>:LINE:COLUMN: error: ...`, so the first two numbers in a row of colon-separated
+/// fields are taken as a line and column.
+fn with_source_lines(log: &str, src: &str) -> String {
+    let lines: Vec<&str> = src.lines().collect();
+    let cited: BTreeSet<usize> = log
+        .lines()
+        .filter_map(|entry| {
+            let fields: Vec<&str> = entry.split(':').map(str::trim).collect();
+            fields.windows(2).find_map(|pair| {
+                pair[1].parse::<usize>().ok()?;
+                pair[0].parse::<usize>().ok()
+            })
+        })
+        .filter(|line| (1..=lines.len()).contains(line))
+        .collect();
+    if cited.is_empty() {
+        return log.to_string();
+    }
+    let quoted: Vec<String> = cited.into_iter().map(|line| format!("{:>5} | {}", line, lines[line - 1])).collect();
+    format!("{}\n\nKernel source:\n{}", log, quoted.join("\n"))
+}
+
+/// What a compiled binary depends on besides the source: the device and its driver.
+fn device_fingerprint(device: Device) -> Result<String, Box<dyn Error>> {
+    Ok(format!(
+        "{}\n{}\n{}\n{}",
+        device.vendor()?,
+        device.name()?,
+        device.info(DeviceInfo::Version)?,
+        device.info(DeviceInfo::DriverVersion)?
+    ))
+}
+
+/// 64-bit FNV-1a hash of `bytes`, which unlike `DefaultHasher` is the same in every build,
+/// for names and checks stored on disk.
+pub(crate) fn fnv1a(bytes: impl IntoIterator<Item = u8>) -> u64 {
+    bytes.into_iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// The file in `dir` holding the binary of `src` built for the device with `fingerprint`.
+fn program_binary_path(dir: &Path, fingerprint: &str, src: &str) -> PathBuf {
+    let hash = fnv1a(fingerprint.bytes().chain([0]).chain(src.bytes()));
+    dir.join(format!("{:016x}.bin", hash))
+}
+
+/// Writes the binary of a program built for one device to `path`, through a temporary
+/// file so a concurrent run never loads half of it.
+fn save_program_binary(program: &Program, path: &Path) -> Result<(), Box<dyn Error>> {
+    let binary = match program.info(ProgramInfo::Binaries)? {
+        ProgramInfoResult::Binaries(mut binaries) if binaries.len() == 1 => binaries.remove(0),
+        _ => return Err("the program has no binary for its device".into()),
+    };
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+    std::fs::write(&tmp, binary)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// A queue of its own on the first OpenCL device, with the cached program built from `src`.
+fn pro_que_for<D: Into<ocl::SpatialDims>>(src: &str, dims: D) -> Result<ProQue, Box<dyn Error>> {
+    let device = profile::time(Phase::Init, || Device::first(Platform::first()?))?;
+    let (context, program) = cached_program(device, src)?;
+    let queue = profile::time(Phase::Init, || Queue::new(&context, device, None))?;
+    Ok(ProQue::new(context, queue, program, Some(dims)))
+}
+
+/// OpenCL helpers for arithmetic mod a 64-bit n, prepended to the kernels that need them.
+pub const MOD_ARITH_SRC: &str = r#"
+    // (a + b) mod n for a, b < n, without overflowing 64 bits
+    ulong add_mod(ulong a, ulong b, ulong n) {
+        return (a >= n - b) ? a - (n - b) : a + b;
+    }
+
+    // (a * b) mod n for a, b < n, correct for every n < 2^64
+    ulong mul_mod(ulong a, ulong b, ulong n) {
+        if (mul_hi(a, b) == 0) {
+            return (a * b) % n;
+        }
+        // The product needs more than 64 bits, so fall back to a doubling chain
+        ulong result = 0;
+        while (b > 0) {
+            if (b & 1) {
+                result = add_mod(result, a, n);
+            }
+            a = add_mod(a, a, n);
+            b >>= 1;
+        }
+        return result;
+    }
+
+    // a^e mod n by square-and-multiply
+    ulong pow_mod(ulong a, ulong e, ulong n) {
+        ulong result = 1;
+        a %= n;
+        while (e > 0) {
+            if (e & 1) {
+                result = mul_mod(result, a, n);
+            }
+            a = mul_mod(a, a, n);
+            e >>= 1;
+        }
+        return result;
+    }
 
+    // Strong probable-prime test of odd n > 2 to base a, where n - 1 = d * 2^s
+    int is_sprp(ulong n, ulong a, ulong d, uint s) {
+        a %= n;
         if (a == 0) {
-            s[0] = 0;
-        } else {
-            s[0] = result;
+            return 1;
         }
+        ulong x = pow_mod(a, d, n);
+        if (x == 1 || x == n - 1) {
+            return 1;
+        }
+        for (uint r = 1; r < s; r++) {
+            x = mul_mod(x, x, n);
+            if (x == n - 1) {
+                return 1;
+            }
+        }
+        return 0;
     }
     "#;
 
-    // Initialize OpenCL
-    let pro_que = ProQue::builder()
-        .src(src)
-        .dims(1)
-        .build()?;
+/// Starts every checkpoint file, ahead of its `CHECKPOINT_VERSION` byte.
+const CHECKPOINT_MAGIC: &[u8; 8] = b"MPCHKPT\0";
 
-    // Ensure M fits in u64
-    let m_u64 = match m.to_u64_digits().get(0) {
-        Some(&num) => num,
-        None => {
-            return Err("Mersenne number exceeds u64 limit.".into());
+/// Layout of what follows `CHECKPOINT_MAGIC`, bumped whenever it changes so that a run
+/// refuses a checkpoint it would misread rather than resuming from garbage.
+const CHECKPOINT_VERSION: u8 = 1;
+
+/// Writes `payload` (a residue and its iteration) to the checkpoint file `path` after the
+/// magic and version and before its `fnv1a` checksum, through a temporary file so that a run
+/// killed while writing keeps its previous checkpoint.
+fn write_checkpoint(path: &str, payload: &[u8], iteration: u128) -> Result<(), Box<dyn Error>> {
+    write_framed(path, payload)?;
+    debug!("Checkpoint written to {} at iteration {}", path, iteration);
+    Ok(())
+}
+
+/// The framing and temporary-file dance of `write_checkpoint`, shared with the rewrite of
+/// a headerless checkpoint in `read_checkpoint`.
+fn write_framed(path: &str, payload: &[u8]) -> Result<(), Box<dyn Error>> {
+    let mut bytes = CHECKPOINT_MAGIC.to_vec();
+    bytes.push(CHECKPOINT_VERSION);
+    bytes.extend(payload);
+    bytes.extend(fnv1a(payload.iter().copied()).to_le_bytes());
+    let temporary = format!("{}.tmp", path);
+    create_checkpoint_dir(path)?;
+    std::fs::write(&temporary, bytes)?;
+    std::fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+/// The payload `write_checkpoint` left in `path`, if there is a checkpoint. A file without
+/// the magic predates versioned checkpoints, whose payloads are the whole file: it is read
+/// as one and rewritten with the header, so the callers' length checks still guard it. A
+/// file of another version or failing its checksum is an error telling the user to remove
+/// it, since resuming from it would give a wrong verdict.
+fn read_checkpoint(path: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let Some(rest) = bytes.strip_prefix(CHECKPOINT_MAGIC) else {
+        write_framed(path, &bytes)?;
+        info!("Rewrote the checkpoint {} from before versioned checkpoints with a header", path);
+        return Ok(Some(bytes));
+    };
+    match rest.first() {
+        Some(&CHECKPOINT_VERSION) => {}
+        Some(&version) => {
+            return Err(format!(
+                "The checkpoint {} has format version {}, but this version reads version {}; resume it with the version that wrote it or remove it to start the test over.",
+                path, version, CHECKPOINT_VERSION
+            )
+            .into())
         }
+        None => return Err(format!("The checkpoint {} is truncated.", path).into()),
+    }
+    if rest.len() < 1 + 8 {
+        return Err(format!("The checkpoint {} is truncated.", path).into());
+    }
+    let (payload, checksum) = rest[1..].split_at(rest.len() - 1 - 8);
+    if fnv1a(payload.iter().copied()).to_le_bytes() != checksum {
+        return Err(format!("The checkpoint {} is corrupted (its checksum does not match); remove it to start the test over.", path).into());
+    }
+    Ok(Some(payload.to_vec()))
+}
+
+/// Saves the Lucas-Lehmer residue and the number of completed iterations.
+fn save_state(state_file: &str, s: u64, iteration: u128) -> Result<(), Box<dyn Error>> {
+    let mut payload = s.to_le_bytes().to_vec();
+    payload.extend(iteration.to_le_bytes());
+    write_checkpoint(state_file, &payload, iteration)
+}
+
+/// The residue and iteration `save_state` left in `state_file`, if there is a checkpoint.
+fn load_state(state_file: &str) -> Result<Option<(u64, u128)>, Box<dyn Error>> {
+    let Some(payload) = read_checkpoint(state_file)? else {
+        return Ok(None);
+    };
+    if payload.len() != 24 {
+        return Err(format!("The checkpoint {} holds {} bytes instead of a 64-bit residue and its iteration.", state_file, payload.len()).into());
+    }
+    let (s, iteration) = payload.split_at(8);
+    Ok(Some((u64::from_le_bytes(s.try_into()?), u128::from_le_bytes(iteration.try_into()?))))
+}
+
+/// The checkpoint file of the memory-mode Lucas-Lehmer run on M = 2^p - 1, named after `p`
+/// so that runs on different exponents keep their own.
+pub fn state_file(dir: Option<&Path>, p: u128) -> String {
+    checkpoint_path(dir, format!("lucas_lehmer_state_{}.bin", p))
+}
+
+/// The checkpoint file of the memory-mode Lucas-Lehmer run on M = 2^p - 1 when it squares
+/// on the CPU or with the NTT, which keep the whole residue rather than the kernel's 64 bits.
+pub fn residue_state_file(dir: Option<&Path>, p: u128) -> String {
+    checkpoint_path(dir, format!("lucas_lehmer_residue_{}.bin", p))
+}
+
+/// The checkpoint file of the `mersenne_prp_report` squarings of `base` mod 2^p - 1.
+pub fn prp_state_file(dir: Option<&Path>, p: u128, base: u128) -> String {
+    checkpoint_path(dir, format!("prp_state_{}_{}.bin", p, base))
+}
+
+/// Saves a residue of any size and the number of completed iterations.
+fn save_residue_state(path: &str, residue: &BigUint, iteration: u128) -> Result<(), Box<dyn Error>> {
+    let mut payload = iteration.to_le_bytes().to_vec();
+    payload.extend(residue.to_bytes_le());
+    write_checkpoint(path, &payload, iteration)
+}
+
+/// The iteration and residue `save_residue_state` left in `path`, if there is a checkpoint.
+fn load_residue_state(path: &str) -> Result<Option<(u128, BigUint)>, Box<dyn Error>> {
+    let Some(bytes) = read_checkpoint(path)? else {
+        return Ok(None);
     };
-    let mut s_host = vec![4u64];
+    if bytes.len() < 16 {
+        return Err(format!("The checkpoint {} is truncated.", path).into());
+    }
+    let (iteration, residue) = bytes.split_at(16);
+    Ok(Some((u128::from_le_bytes(iteration.try_into()?), BigUint::from_bytes_le(residue))))
+}
+
+/// Where a squarer run of `total` iterations starts: from the checkpoint in `path` when
+/// there is one, from `initial` at iteration 0 otherwise. The callers say which in their
+/// result's `checkpointed`.
+fn resume_from(path: Option<&str>, initial: BigUint, total: u128) -> Result<(u128, BigUint), Box<dyn Error>> {
+    let Some(path) = path else {
+        return Ok((0, initial));
+    };
+    match load_residue_state(path)? {
+        Some((iteration, _)) if iteration > total => {
+            Err(format!("The checkpoint {} is at iteration {}, past the {} of this run.", path, iteration, total).into())
+        }
+        Some((iteration, residue)) => {
+            info!("Resuming from the checkpoint in {} at iteration {}", path, iteration);
+            Ok((iteration, residue))
+        }
+        None => Ok((0, initial)),
+    }
+}
+
+/// Deals with the checkpoint of a run that finished with `residue` after all `total`
+/// iterations: saved there when `keep` is set, so that a batch picking up after a pause
+/// does not run it again, and removed otherwise.
+fn finish_residue_state(path: Option<&str>, keep: bool, residue: &BigUint, total: u128) -> Result<(), Box<dyn Error>> {
+    match path {
+        Some(path) if keep => save_residue_state(path, residue, total),
+        Some(path) => remove_checkpoint(path),
+        None => Ok(()),
+    }
+}
+
+/// Removes the checkpoint in `path`, if there is one.
+fn remove_checkpoint(path: &str) -> Result<(), Box<dyn Error>> {
+    if Path::new(path).exists() {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Removes the checkpoints the Lucas-Lehmer runs on M = 2^p - 1 kept in `dir` with
+/// `LucasLehmerOptions::keep_checkpoint`.
+pub fn remove_lucas_lehmer_checkpoints(dir: Option<&Path>, p: u128) -> Result<(), Box<dyn Error>> {
+    remove_checkpoint(&state_file(dir, p))?;
+    remove_checkpoint(&residue_state_file(dir, p))
+}
+
+/// Where a Lucas-Lehmer run stands, as written to a `--chunked-progress` status file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RunStatus {
+    pub exponent: u128,
+    pub iteration: u128,
+    pub total: u128,
+    /// Time left at the pace since the run (or its resumption) started, once it has one.
+    pub eta: Option<Duration>,
+    /// Low 64 bits of the residue, which carries the run's shift until the last iteration.
+    pub res64: Option<u64>,
+    /// `"running"`, `"done"`, `"interrupted"`, `"timed out"` or `"paused"`.
+    pub state: &'static str,
+}
+
+impl RunStatus {
+    /// The status of M`exponent` after `iteration` of `total` iterations, `resumed_at` of
+    /// which were done before `started`.
+    fn at(exponent: u128, iteration: u128, total: u128, resumed_at: u128, started: Instant) -> RunStatus {
+        let done = iteration.saturating_sub(resumed_at);
+        let eta = (done > 0).then(|| started.elapsed().mul_f64((total - iteration) as f64 / done as f64));
+        RunStatus { exponent, iteration, total, eta, res64: None, state: "running" }
+    }
+}
+
+/// Replaces `path` with `status` as `key value` lines, through a temporary file so that a
+/// monitor polling it never reads half of one.
+pub fn write_status(path: &Path, status: &RunStatus) -> Result<(), Box<dyn Error>> {
+    let mut text = format!(
+        "exponent {}\niteration {}\ntotal {}\nstate {}\n",
+        status.exponent, status.iteration, status.total, status.state
+    );
+    if let Some(eta) = status.eta {
+        text.push_str(&format!("eta_seconds {}\n", eta.as_secs()));
+    }
+    if let Some(res64) = status.res64 {
+        text.push_str(&format!("res64 {:016x}\n", res64));
+    }
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    std::fs::write(&temporary, text)?;
+    std::fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+/// `write_status` for runs asked to report to `path`. A status file that can't be written
+/// is worth a warning, not the run.
+fn report_status(path: Option<&Path>, status: RunStatus) {
+    if let Some(path) = path {
+        if let Err(e) = write_status(path, &status) {
+            warn!("Could not write the status file {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Why a run of `total` iterations should stop after `completed`, if it should. Runs ask
+/// between batches, so Ctrl-C, the timeout and the deadline are noticed a batch late at most.
+fn stop_reason(
+    completed: u128,
+    total: u128,
+    started: Instant,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    interrupted: &AtomicBool,
+) -> Option<MpError> {
+    if completed >= total {
+        return None;
+    }
+    if interrupted.load(Ordering::Relaxed) {
+        return Some(MpError::Interrupted { iteration: completed, total });
+    }
+    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+        return Some(MpError::Paused { iteration: completed, total });
+    }
+    match timeout {
+        Some(limit) if started.elapsed() >= limit => {
+            Some(MpError::Timeout { iteration: completed, total })
+        }
+        _ => None,
+    }
+}
+
+/// The `RunStatus` state of a run that stopped for `reason`.
+fn stop_state(reason: &MpError) -> &'static str {
+    match reason {
+        MpError::Interrupted { .. } => "interrupted",
+        MpError::Paused { .. } => "paused",
+        _ => "timed out",
+    }
+}
+
+/// Squarings between the checkpoints of a memory-mode run on a `Squarer`.
+const SQUARER_CHECKPOINT_INTERVAL: u128 = 10_000;
+
+/// A run of squarings on a `Squarer`, as the CPU and NTT Lucas-Lehmer tests and the
+/// Mersenne PRP test do them, that stops as `stop_reason` says.
+struct SquaringRun<'a> {
+    /// Squarings in the whole run.
+    total: u128,
+    /// What each squaring subtracts.
+    subtract: u32,
+    started: Instant,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    /// Where the residue `Squarer::checkpoint` vouches for is saved every
+    /// `SQUARER_CHECKPOINT_INTERVAL` squarings (or `CHECKPOINT_EVERY`) and when the run
+    /// stops, if anywhere.
+    checkpoint: Option<&'a str>,
+}
+
+impl SquaringRun<'_> {
+    /// Squares `s` until `completed` reaches the total, handing `look` each count reached.
+    ///
+    /// # Returns
+    ///
+    /// Why the run stopped short, if it did, with `completed` where it stopped.
+    fn run(
+        &self,
+        s: &mut dyn Squarer,
+        completed: &mut u128,
+        mut look: impl FnMut(u128, &dyn Squarer) -> Result<(), Box<dyn Error>>,
+    ) -> Result<Option<MpError>, Box<dyn Error>> {
+        let interval = checkpoint_interval(SQUARER_CHECKPOINT_INTERVAL);
+        while *completed < self.total {
+            s.square_sub(self.subtract)?;
+            *completed += 1;
+            look(*completed, s)?;
+            let stop = stop_reason(*completed, self.total, self.started, self.timeout, self.deadline, &INTERRUPTED);
+            if let Some(path) = self.checkpoint {
+                if stop.is_some() || completed.is_multiple_of(interval) {
+                    let (residue, unchecked) = s.checkpoint(stop.is_some())?;
+                    save_residue_state(path, &residue, *completed - unchecked)?;
+                }
+            }
+            if stop.is_some() {
+                return Ok(stop);
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// OpenCL source of the Lucas-Lehmer squaring step.
+const LUCAS_LEHMER_SRC: &str = r#"
+    __kernel void lucas_lehmer(__global ulong* s, __global const ulong* m, __global ulong* shift, ulong p, ulong count) {
+        ulong a = s[0];
+        ulong n = m[0];
+        ulong k = shift[0];
+
+        for (ulong i = 0; i < count; i++) {
+            // The residue carries a factor 2^shift, which squaring doubles
+            k = (2 * k) % p;
+
+            // Perform s = (s * s - 2 * 2^k) mod m
+            ulong two = (1UL << ((k + 1) % p)) % n;
+            a = add_mod(mul_mod(a, a, n), n - two, n);
+        }
+        s[0] = a;
+        shift[0] = k;
+    }
+    "#;
+
+/// An OpenCL queue with the Lucas-Lehmer program built, so a session testing many
+/// exponents sets up OpenCL once. Contexts share the compiled program, but each has its
+/// own queue.
+pub struct GpuContext {
+    pro_que: ProQue,
+}
+
+impl GpuContext {
+    /// Opens a queue on the first OpenCL device, building the Lucas-Lehmer program unless
+    /// an earlier context already has.
+    pub fn new() -> Result<GpuContext, Box<dyn Error>> {
+        let pro_que = pro_que_for(&format!("{}{}", MOD_ARITH_SRC, LUCAS_LEHMER_SRC), 1)?;
+        info!("Opened a Lucas-Lehmer queue on {}", pro_que.device().name()?);
+        Ok(GpuContext { pro_que })
+    }
+
+    /// The context held in `slot`, built there on first use.
+    pub fn get_or_init(slot: &mut Option<GpuContext>) -> Result<&GpuContext, Box<dyn Error>> {
+        if slot.is_none() {
+            *slot = Some(GpuContext::new()?);
+        }
+        Ok(slot.as_ref().expect("context was just built"))
+    }
+}
+
+/// How `lucas_lehmer_with_context` and `lucas_lehmer_with_threshold` run a test.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LucasLehmerOptions {
+    /// Checkpoint to `state_file(p)` and resume from it.
+    pub mem: bool,
+    /// Directory the checkpoint files are kept in, or `None` for the working directory.
+    pub checkpoint_dir: Option<PathBuf>,
+    /// See `lucas_lehmer`.
+    pub shift: u64,
+    /// See `lucas_lehmer`.
+    pub timeout: Option<Duration>,
+    /// When to stop with `MpError::Paused`, checkpointing first in memory mode, if ever.
+    pub deadline: Option<Instant>,
+    /// Leave the final checkpoint of a memory-mode run in place rather than removing it,
+    /// so that a batch resumed after pausing skips the runs it finished. The caller
+    /// removes it once the batch is done.
+    pub keep_checkpoint: bool,
+    /// Where to keep a `RunStatus` of the run up to date, if anywhere.
+    pub status: Option<PathBuf>,
+    /// Iterations per kernel launch. The last launch of a run takes what is left.
+    pub batch_size: u128,
+    /// Exponents below this run on the CPU in `lucas_lehmer_with_threshold`.
+    pub gpu_threshold: u128,
+    /// What `lucas_lehmer_with_threshold` runs the exponents at or above the threshold on,
+    /// as far as the backend takes them.
+    pub backend: Backend,
+    /// Squarings between self-checks on the NTT backend, or `None` for none.
+    pub self_check: Option<u128>,
+}
+
+/// Where `lucas_lehmer_with_threshold` squares exponents at or above the GPU threshold.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// The Lucas-Lehmer kernel, for exponents up to `LL_KERNEL_MAX_EXPONENT`. Larger ones
+    /// run on the CPU.
+    #[default]
+    Kernel,
+    /// `NttSquarer`, for exponents of any size the device has memory for.
+    Ntt,
+}
+
+impl Default for LucasLehmerOptions {
+    fn default() -> Self {
+        LucasLehmerOptions {
+            mem: false,
+            checkpoint_dir: None,
+            shift: 0,
+            timeout: None,
+            deadline: None,
+            keep_checkpoint: false,
+            status: None,
+            batch_size: DEFAULT_BATCH_SIZE,
+            gpu_threshold: LL_GPU_THRESHOLD,
+            backend: Backend::Kernel,
+            self_check: Some(DEFAULT_SELF_CHECK_INTERVAL),
+        }
+    }
+}
+
+/// The outcome of a Lucas-Lehmer run, which the CLI reports however it is asked to.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct LlResult {
+    /// The exponent of the Mersenne number 2^p - 1 tested.
+    pub p: u128,
+    /// Whether 2^p - 1 is prime, i.e. the final residue is zero.
+    pub is_prime: bool,
+    /// The low 64 bits of the final residue, the res64 GIMPS publishes for its runs.
+    pub res64: u64,
+    /// Iterations of the whole test, p - 2, including any done before a checkpoint.
+    pub iterations: u128,
+    /// How long this run took, not counting any run it resumed.
+    pub elapsed: Duration,
+    /// The OpenCL device the residue was squared on, or `None` on the CPU.
+    pub device_name: Option<String>,
+    /// Whether the run picked up from a checkpoint an earlier one left, at `resumed_at`.
+    pub checkpointed: bool,
+    /// The iteration the run started from, 0 unless `checkpointed`.
+    pub resumed_at: u128,
+}
+
+impl LlResult {
+    /// The result for M2 = 3, which is prime without any iteration.
+    fn m2(started: Instant) -> LlResult {
+        LlResult {
+            p: 2,
+            is_prime: true,
+            res64: 0,
+            iterations: 0,
+            elapsed: started.elapsed(),
+            device_name: None,
+            checkpointed: false,
+            resumed_at: 0,
+        }
+    }
+
+    /// The result of a run of `iterations` on 2^p - 1 that started from `resumed_at` and
+    /// left `residue`.
+    fn finished(p: u128, residue: &BigUint, iterations: u128, resumed_at: u128, started: Instant, device_name: Option<String>) -> LlResult {
+        LlResult {
+            p,
+            is_prime: residue.is_zero(),
+            res64: res64(residue),
+            iterations,
+            elapsed: started.elapsed(),
+            device_name,
+            checkpointed: resumed_at > 0,
+            resumed_at,
+        }
+    }
+}
+
+/// Runs the Lucas-Lehmer test on M = 2^p - 1.
+///
+/// A nonzero `shift` starts from 4 * 2^shift mod M instead of 4, doubling the shift each
+/// iteration and removing it at the end, so two runs with different shifts square
+/// different residues and can be cross-checked against each other.
+///
+/// If `timeout` elapses before the last iteration, the run stops (checkpointing first in
+/// memory mode) and fails with `MpError::Timeout` carrying the iteration reached.
+pub fn lucas_lehmer(
+    p: u128,
+    mem: bool,
+    shift: u64,
+    timeout: Option<Duration>,
+) -> Result<LlResult, Box<dyn Error>> {
+    let options = LucasLehmerOptions { mem, shift, timeout, ..LucasLehmerOptions::default() };
+    lucas_lehmer_with_context(&mut None, p, &options)
+}
+
+/// `lucas_lehmer` on the OpenCL context in `context`, building it there if it is empty
+/// and the exponent needs the GPU at all.
+///
+/// With a `status` path, the run also writes a `RunStatus` there when it starts, at every
+/// checkpoint interval, and when it ends or stops.
+pub fn lucas_lehmer_with_context(
+    context: &mut Option<GpuContext>,
+    p: u128,
+    options: &LucasLehmerOptions,
+) -> Result<LlResult, Box<dyn Error>> {
+    let started = Instant::now();
+    let (mem, shift, timeout) = (options.mem, options.shift, options.timeout);
+    let status = options.status.as_deref();
+
+    if p < 2 {
+        return Err(format!("Lucas-Lehmer exponents must be at least 2, got {}.", p).into());
+    }
+    if p == 2 {
+        return Ok(LlResult::m2(started));
+    }
+
+    // Construct Mersenne number M = 2^p - 1
+    let m = (&BigUint::one() << p) - 1u32;
+    let iterations = p - 2;
+
+    // Ensure M fits in u64
+    if p > LL_KERNEL_MAX_EXPONENT {
+        return Err(format!("M{} exceeds the 64-bit Lucas-Lehmer kernel; test it on the CPU or with the NTT backend.", p).into());
+    }
+
+    // Initialize OpenCL, or reuse the program built for an earlier exponent
+    let pro_que = &GpuContext::get_or_init(context)?.pro_que;
+    let m_u64 = m.to_u64_digits()[0];
+    let shift = (shift as u128 % p) as u64;
+    let seed = (BigUint::from(4u32) << shift) % &m;
+    let mut s_host = vec![seed.to_u64_digits().first().copied().unwrap_or(0)];
     let m_host = vec![m_u64];
+    let mut shift_host = vec![shift];
+    info!("Lucas-Lehmer test of M{}: {} iterations, shift {}", p, iterations, shift);
 
     // Create buffers using buffer_builder from ProQue
+    let setting_up = Instant::now();
     let s_buffer = pro_que.buffer_builder()
         .flags(flags::MEM_READ_WRITE)
         .len(1)
@@ -61,126 +841,1913 @@ pub fn lucas_lehmer(p: u128, mem: bool) -> Result<bool, Box<dyn Error>> {
         .copy_host_slice(&m_host)
         .build()?;
 
+    let shift_buffer = pro_que.buffer_builder()
+        .flags(flags::MEM_READ_WRITE)
+        .len(1)
+        .copy_host_slice(&shift_host)
+        .build()?;
+
     // Build the kernel and set arguments
     let kernel = pro_que.kernel_builder("lucas_lehmer")
         .arg(&s_buffer)
         .arg(&m_buffer)
+        .arg(&shift_buffer)
+        .arg(p as u64)
+        .arg(0u64) // Placeholder for the batch length
         .build()?;
-
-    // Clear terminal
-    print!("\x1B[2J\x1B[1;1H");
+    TIMINGS.record(Phase::Buffers, setting_up.elapsed());
 
     // Initialize the progress bar
-    let pb = ProgressBar::new(iterations as u64);
-    let style = ProgressStyle::default_bar()
-        .template("{msg} [{bar:40.cyan/blue}] {pos}/{len} ({eta_precise})")?
-        .progress_chars("=>-");
-    pb.set_style(style);
-    pb.set_message("Performing Lucas-Lehmer Test");
+    let pb = progress_bar(
+        iterations as u64,
+        LUCAS_LEHMER_TEMPLATE,
+        format!("Performing Lucas-Lehmer Test of M{}", p),
+    );
 
     let mut current_iteration = 0u128;
-    let state_file = "lucas_lehmer_state.bin";
+    let state_file = &state_file(options.checkpoint_dir.as_deref(), p);
 
     if mem {
         // Initialize or load state
-        if Path::new(state_file).exists() {
-            // Load saved state
-            let mut file = File::open(state_file)?;
-            let mut buffer = [0u8; 8];
-            file.read_exact(&mut buffer)?;
-            s_host[0] = u64::from_le_bytes(buffer);
-            let mut buffer_iter = [0u8; 16];
-            file.read_exact(&mut buffer_iter)?;
-            current_iteration = u128::from_le_bytes(buffer_iter);
-            // Update buffer
+        if let Some((s, iteration)) = load_state(state_file)? {
+            s_host[0] = s;
+            current_iteration = iteration;
+            // The shift after i iterations is shift * 2^i mod p
+            shift_host[0] = BigUint::from(2u32)
+                .modpow(&BigUint::from(current_iteration), &BigUint::from(p))
+                .to_u64_digits()
+                .first()
+                .map_or(0, |&factor| ((factor as u128 * shift as u128) % p) as u64);
+            // Update buffers
             s_buffer.write(&s_host).enq()?;
-            println!("Resuming from iteration {}", current_iteration);
+            shift_buffer.write(&shift_host).enq()?;
+            info!("Resuming from the checkpoint in {} at iteration {}", state_file, current_iteration);
         }
     }
 
-    pb.set_position(current_iteration as u64);
+    let mut throughput = Throughput::new("ll", p, &pb, current_iteration as u64, iterations as u64);
+    let resumed_at = current_iteration;
+    report_status(status, RunStatus { res64: Some(s_host[0]), ..RunStatus::at(p, current_iteration, iterations, resumed_at, started) });
 
-    if mem {
-        for i in current_iteration..iterations {
+    let milestone = (iterations / LOG_MILESTONES).max(1);
+    let mut completed = current_iteration;
+    let interval = checkpoint_interval(CHECKPOINT_INTERVAL);
+    while completed < iterations {
+        // A batch never runs past the next checkpoint, so checkpoints land where they did
+        let to_checkpoint = interval - completed % interval;
+        let batch = options.batch_size.max(1).min(iterations - completed).min(to_checkpoint);
+        kernel.set_arg(4, batch as u64)?;
+        profile::time(Phase::Execute, || {
             unsafe {
                 kernel.enq()?;
             }
-            pb.inc(1);
-            
-            // Every 100,000,000 iterations, save state
-            if (i + 1) % 100_000_000 == 0 {
-                // Read the current s_host
-                s_buffer.read(&mut s_host).enq()?;
+            pro_que.finish()
+        })?;
+        let before = completed;
+        completed += batch;
+        throughput.update(&pb, completed as u64);
+        if completed / milestone > before / milestone {
+            debug!("M{}: iteration {} of {} done after {:.1?}", p, completed, iterations, started.elapsed());
+        }
 
-                // Save s_host[0] and current_iteration to file
-                let mut file = OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .truncate(true)
-                    .open(state_file)?;
-                file.write_all(&s_host[0].to_le_bytes())?;
-                file.write_all(&(i + 1).to_le_bytes())?;
-                file.flush()?;
+        // Every 100,000,000 iterations, save state and report it
+        if (mem || status.is_some()) && completed.is_multiple_of(interval) {
+            profile::time(Phase::Readback, || s_buffer.read(&mut s_host).enq())?;
+            if mem {
+                save_state(state_file, s_host[0], completed)?;
             }
+            let running = RunStatus::at(p, completed, iterations, resumed_at, started);
+            report_status(status, RunStatus { res64: Some(s_host[0]), ..running });
         }
-    } else {
-        for _ in current_iteration..iterations {
-            unsafe {
-                kernel.enq()?;
+
+        // Stop on Ctrl-C or once the time limit has passed, keeping the progress made so far
+        if let Some(reason) = stop_reason(completed, iterations, started, timeout, options.deadline, &INTERRUPTED) {
+            if mem || status.is_some() {
+                profile::time(Phase::Readback, || s_buffer.read(&mut s_host).enq())?;
+            }
+            if mem {
+                save_state(state_file, s_host[0], completed)?;
             }
-            pb.inc(1);
+            let stopped = RunStatus::at(p, completed, iterations, resumed_at, started);
+            report_status(status, RunStatus { res64: Some(s_host[0]), state: stop_state(&reason), ..stopped });
+            pb.abandon_with_message(match reason {
+                MpError::Interrupted { .. } => format!("Lucas-Lehmer Test of M{} Interrupted", p),
+                MpError::Paused { .. } => format!("Lucas-Lehmer Test of M{} Paused", p),
+                _ => format!("Lucas-Lehmer Test of M{} Timed Out", p),
+            });
+            return Err(reason.into());
         }
     }
 
     // Finish the progress bar
-    pb.finish_with_message("Lucas-Lehmer Test Completed");
+    pb.finish_with_message(format!("Lucas-Lehmer Test of M{} Completed", p));
 
-    // Read the result back to host
-    s_buffer.read(&mut s_host).enq()?;
+    // Read the result back to host and remove the shift: 2^-k = 2^(p-k) mod M
+    profile::time(Phase::Readback, || {
+        s_buffer.read(&mut s_host).enq()?;
+        shift_buffer.read(&mut shift_host).enq()
+    })?;
+    if mem && options.keep_checkpoint {
+        save_state(state_file, s_host[0], iterations)?;
+    }
+    let unshifted = (BigUint::from(s_host[0]) << (p as u64 - shift_host[0]) as usize) % &m;
+    s_host[0] = unshifted.to_u64_digits().first().copied().unwrap_or(0);
+    let done = RunStatus::at(p, iterations, iterations, resumed_at, started);
+    report_status(status, RunStatus { res64: Some(s_host[0]), state: "done", ..done });
 
-    if mem {
+    if mem && !options.keep_checkpoint {
         // Remove saved state file
         if Path::new(state_file).exists() {
             std::fs::remove_file(state_file)?;
         }
     }
 
-    // Print the result
-    let message = format!("{} is {}a Mersenne prime.", m, if s_host[0] == 0 { "" } else { "not " });
-    let mut file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open("out.txt")?;
-    file.write_all(message.as_bytes())?;
-    file.flush()?;
+    let device_name = pro_que.device().name()?;
+    Ok(LlResult::finished(p, &BigUint::from(s_host[0]), iterations, resumed_at, started, Some(device_name)))
+}
 
-    Ok(s_host[0] == 0)
+/// `lucas_lehmer_with_context`, or `lucas_lehmer_ntt` with `Backend::Ntt`, for exponents
+/// of at least `options.gpu_threshold`, and `lucas_lehmer_squarer` on the CPU otherwise,
+/// without touching OpenCL. The kernel only takes exponents up to `LL_KERNEL_MAX_EXPONENT`,
+/// so with `Backend::Kernel` larger ones run on the CPU whatever the threshold.
+pub fn lucas_lehmer_with_threshold(
+    context: &mut Option<GpuContext>,
+    p: u128,
+    options: &LucasLehmerOptions,
+) -> Result<LlResult, Box<dyn Error>> {
+    let gpu_threshold = options.gpu_threshold;
+    match options.backend {
+        _ if p < 2 => Err(format!("Lucas-Lehmer exponents must be at least 2, got {}.", p).into()),
+        _ if p < gpu_threshold => {
+            debug!("M{} is below the GPU threshold of {}, testing on the CPU", p, gpu_threshold);
+            lucas_lehmer_squarer(p, options)
+        }
+        Backend::Kernel if p > LL_KERNEL_MAX_EXPONENT => {
+            debug!("M{} is past the {}-bit kernel, testing on the CPU", p, LL_KERNEL_MAX_EXPONENT);
+            lucas_lehmer_squarer(p, options)
+        }
+        Backend::Kernel => lucas_lehmer_with_context(context, p, options),
+        Backend::Ntt => lucas_lehmer_ntt(p, options),
+    }
 }
 
-pub fn is_prp(n: &BigUint, base: u128) -> bool {
-    let mut d = n - 1u32;
-    let mut s = 0;
+/// Runs the Lucas-Lehmer test on M = 2^p - 1 on the CPU with the `squarer` for p, which
+/// takes exponents of any size.
+///
+/// The timeout, the deadline, Ctrl-C and the status file work as in `lucas_lehmer_ntt`,
+/// and memory mode checkpoints the whole residue to `residue_state_file(p)` the same way.
+/// A shift works as in `lucas_lehmer`: the checkpoints hold the shifted residue, and a
+/// resumed run works out the shift it had reached from the iteration.
+pub fn lucas_lehmer_squarer(p: u128, options: &LucasLehmerOptions) -> Result<LlResult, Box<dyn Error>> {
+    let started = Instant::now();
+    let status = options.status.as_deref();
+    if p < 2 {
+        return Err(format!("Lucas-Lehmer exponents must be at least 2, got {}.", p).into());
+    }
+    if p == 2 {
+        return Ok(LlResult::m2(started));
+    }
+    let total = p - 2;
+    let shift = options.shift as u128 % p;
+    let seed = (BigUint::from(4u32) << shift) % ((BigUint::one() << p) - 1u32);
+    let checkpoint = options.mem.then(|| residue_state_file(options.checkpoint_dir.as_deref(), p));
+    let (resumed_at, initial) = resume_from(checkpoint.as_deref(), seed, total)?;
+    // The shift after i iterations is shift * 2^i mod p
+    let reached = BigUint::from(shift) * BigUint::from(2u32).modpow(&BigUint::from(resumed_at), &BigUint::from(p)) % p;
+    let mut s = ShiftedSquarer::new(p, reached.to_u128().unwrap_or(0), squarer(p, &initial));
+    debug!("Lucas-Lehmer test of M{} on the CPU: {} iterations, shift {}", p, total, shift);
+    let pb = progress_bar(total as u64, LUCAS_LEHMER_TEMPLATE, format!("Performing Lucas-Lehmer Test of M{}", p));
+    let mut throughput = Throughput::new("ll", p, &pb, resumed_at as u64, total as u64);
+    report_status(status, RunStatus::at(p, resumed_at, total, resumed_at, started));
+    let run = SquaringRun {
+        total,
+        subtract: 2,
+        started,
+        timeout: options.timeout,
+        deadline: options.deadline,
+        checkpoint: checkpoint.as_deref(),
+    };
+    let mut completed = resumed_at;
+    let stop = profile::time(Phase::Execute, || {
+        run.run(&mut s, &mut completed, |completed, s| {
+            throughput.update(&pb, completed as u64);
+            if status.is_some() && completed.is_multiple_of(NTT_STATUS_INTERVAL) {
+                let running = RunStatus::at(p, completed, total, resumed_at, started);
+                report_status(status, RunStatus { res64: Some(res64(&s.residue()?)), ..running });
+            }
+            Ok(())
+        })
+    })?;
+    if let Some(reason) = stop {
+        let stopped = RunStatus::at(p, completed, total, resumed_at, started);
+        report_status(status, RunStatus { res64: Some(res64(&s.residue()?)), state: stop_state(&reason), ..stopped });
+        pb.abandon_with_message(format!("Lucas-Lehmer Test of M{} Stopped", p));
+        return Err(reason.into());
+    }
+    let residue = s.residue()?;
+    pb.finish_with_message(format!("Lucas-Lehmer Test of M{} Completed", p));
+    finish_residue_state(checkpoint.as_deref(), options.keep_checkpoint, &residue, total)?;
+    let done = RunStatus::at(p, total, total, resumed_at, started);
+    report_status(status, RunStatus { res64: Some(res64(&residue)), state: "done", ..done });
+    Ok(LlResult::finished(p, &residue, total, resumed_at, started, None))
+}
 
-    while d.is_even() {
-        d >>= 1;
-        s += 1;
+/// Squarings between the status reports of `lucas_lehmer_ntt`.
+const NTT_STATUS_INTERVAL: u128 = 10_000;
+
+/// Runs the Lucas-Lehmer test on M = 2^p - 1 with `NttSquarer`, which takes exponents far
+/// past the 64-bit kernel. `options.self_check` sets how often the device is checked
+/// against the CPU; the run fails if they disagree.
+///
+/// The timeout, the deadline, Ctrl-C and the status file work as in
+/// `lucas_lehmer_with_context`, with the status written every `NTT_STATUS_INTERVAL`
+/// squarings. Memory mode checkpoints the whole residue to `residue_state_file(p)` every
+/// `SQUARER_CHECKPOINT_INTERVAL` squarings. Shifts are not supported. The final res64 is
+/// logged at info level.
+pub fn lucas_lehmer_ntt(p: u128, options: &LucasLehmerOptions) -> Result<LlResult, Box<dyn Error>> {
+    let started = Instant::now();
+    let status = options.status.as_deref();
+    if p < 2 {
+        return Err(format!("Lucas-Lehmer exponents must be at least 2, got {}.", p).into());
     }
+    if p == 2 {
+        return Ok(LlResult::m2(started));
+    }
+    if options.shift != 0 {
+        return Err("The NTT backend does not support --shift.".into());
+    }
+    let iterations = p - 2;
+    let checkpoint = options.mem.then(|| residue_state_file(options.checkpoint_dir.as_deref(), p));
+    let (resumed_at, initial) = resume_from(checkpoint.as_deref(), BigUint::from(4u32), iterations)?;
+    let mut s = NttSquarer::new(p, &initial, options.self_check)?;
+    let layout = s.layout();
+    info!(
+        "Lucas-Lehmer test of M{} with the NTT: {} iterations, {} digits of {} bits, length {}",
+        p, iterations, layout.count, layout.width, layout.length
+    );
 
-    let mut x = BigUint::from(base).modpow(&d, n);
-    if x.is_one() || x == n - 1u32 {
-        return true;
+    let pb = progress_bar(
+        iterations as u64,
+        LUCAS_LEHMER_TEMPLATE,
+        format!("Performing Lucas-Lehmer Test of M{}", p),
+    );
+    let mut throughput = Throughput::new("ll", p, &pb, resumed_at as u64, iterations as u64);
+    report_status(status, RunStatus::at(p, resumed_at, iterations, resumed_at, started));
+    let run = SquaringRun {
+        total: iterations,
+        subtract: 2,
+        started,
+        timeout: options.timeout,
+        deadline: options.deadline,
+        checkpoint: checkpoint.as_deref(),
+    };
+    let mut completed = resumed_at;
+    let stop = run.run(&mut s, &mut completed, |completed, s| {
+        throughput.update(&pb, completed as u64);
+        if status.is_some() && completed.is_multiple_of(NTT_STATUS_INTERVAL) {
+            let running = RunStatus::at(p, completed, iterations, resumed_at, started);
+            report_status(status, RunStatus { res64: Some(res64(&s.residue()?)), ..running });
+        }
+        Ok(())
+    })?;
+    if let Some(reason) = stop {
+        let stopped = RunStatus::at(p, completed, iterations, resumed_at, started);
+        report_status(status, RunStatus { res64: Some(res64(&s.residue()?)), state: stop_state(&reason), ..stopped });
+        pb.abandon_with_message(format!("Lucas-Lehmer Test of M{} Stopped", p));
+        return Err(reason.into());
+    }
+    let residue = s.residue()?;
+    pb.finish_with_message(format!("Lucas-Lehmer Test of M{} Completed", p));
+    info!("M{}: res64 {:016x} after {:.1?}", p, res64(&residue), started.elapsed());
+    finish_residue_state(checkpoint.as_deref(), options.keep_checkpoint, &residue, iterations)?;
+    let done = RunStatus::at(p, iterations, iterations, resumed_at, started);
+    report_status(status, RunStatus { res64: Some(res64(&residue)), state: "done", ..done });
+    Ok(LlResult::finished(p, &residue, iterations, resumed_at, started, Some(s.device_name()?)))
+}
+
+/// Exponents p of every known Mersenne prime 2^p - 1, in ascending order.
+pub const KNOWN_MERSENNE_EXPONENTS: [u128; 52] = [
+    2, 3, 5, 7, 13, 17, 19, 31, 61, 89, 107, 127, 521, 607, 1279, 2203, 2281, 3217, 4253, 4423,
+    9689, 9941, 11213, 19937, 21701, 23209, 44497, 86243, 110503, 132049, 216091, 756839, 859433,
+    1257787, 1398269, 2976221, 3021377, 6972593, 13466917, 20996011, 24036583, 25964951, 30402457,
+    32582657, 37156667, 42643801, 43112609, 57885161, 74207281, 77232917, 82589933, 136279841,
+];
+
+/// Runs the Lucas-Lehmer test on M = 2^p - 1 on the CPU, squaring with BigUint arithmetic
+/// or, from `IBDWT_THRESHOLD`, the FFT.
+///
+/// This is the reference the OpenCL kernel is checked against, and the only way to test
+/// exponents whose Mersenne number does not fit the kernel's 64-bit residue.
+pub fn lucas_lehmer_cpu(p: u128) -> bool {
+    p >= 2 && lucas_lehmer_cpu_residue(p).is_zero()
+}
+
+/// The final Lucas-Lehmer residue s_(p-2) mod 2^p - 1 for p of at least 2, worked out on
+/// the CPU as in `lucas_lehmer_cpu`. It is zero exactly when 2^p - 1 is prime.
+pub fn lucas_lehmer_cpu_residue(p: u128) -> BigUint {
+    if p == 2 {
+        return BigUint::zero();
     }
+    let mut s = squarer(p, &BigUint::from(4u32));
+    for _ in 0..p - 2 {
+        s.square_sub(2).expect("CPU squarers do not fail");
+    }
+    s.residue().expect("CPU squarers do not fail")
+}
 
-    for _ in 0..s - 1 {
-        x = (&x * &x) % n;
-        if x.is_one() {
-            return false;
+/// What a batch of tests would cost, worked out by `--dry-run` without running any of them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TestPlan {
+    /// How many numbers would be tested.
+    pub count: usize,
+    /// Squarings the tests would run in total.
+    pub iterations: u128,
+    /// The largest residue and modulus held at once, in bytes.
+    pub memory_bytes: u128,
+    /// Inputs too large for the 64-bit OpenCL kernels.
+    pub too_large: Vec<u128>,
+}
+
+impl TestPlan {
+    /// Plans `lucas_lehmer` on each exponent: p - 2 squarings mod a p-bit Mersenne number.
+    pub fn lucas_lehmer(exponents: &[u128]) -> TestPlan {
+        TestPlan {
+            count: exponents.len(),
+            iterations: exponents.iter().map(|&p| p.saturating_sub(2)).sum(),
+            memory_bytes: exponents.iter().map(|&p| 2 * p.div_ceil(8)).max().unwrap_or(0),
+            too_large: exponents.iter().copied().filter(|&p| p > 64).collect(),
         }
-        if x == n - 1u32 {
-            return true;
+    }
+
+    /// Plans `is_prp` on each number: about one squaring per bit of n.
+    pub fn prp(numbers: &[u128]) -> TestPlan {
+        let bits = |n: u128| (u128::BITS - n.leading_zeros()) as u128;
+        TestPlan {
+            count: numbers.len(),
+            iterations: numbers.iter().map(|&n| bits(n)).sum(),
+            memory_bytes: numbers.iter().map(|&n| 2 * bits(n).div_ceil(8)).max().unwrap_or(0),
+            too_large: numbers.iter().copied().filter(|&n| n > u64::MAX as u128).collect(),
         }
     }
+}
 
-    false
+/// The outcome of testing one exponent: its verdict, or the error that stopped the test.
+pub type ExponentResult = (u128, Result<bool, Box<dyn Error>>);
+
+/// Runs the Lucas-Lehmer test over every known Mersenne prime exponent up to `bound`.
+///
+/// Each goes through `lucas_lehmer_with_threshold` with `options`, so by default all of
+/// them run on the CPU, and a lower `gpu_threshold` checks the kernel or the NTT instead.
+/// Every one of them should come back prime.
+///
+/// # Returns
+///
+/// The result for each exponent tested, in ascending order.
+pub fn verify_known_exponents(bound: u128, options: &LucasLehmerOptions) -> Vec<ExponentResult> {
+    let mut context = None;
+    KNOWN_MERSENNE_EXPONENTS
+        .iter()
+        .take_while(|&&p| p <= bound)
+        .map(|&p| (p, lucas_lehmer_with_threshold(&mut context, p, options).map(|result| result.is_prime)))
+        .collect()
+}
+
+/// Strong probable-prime test of n to `base`, defined for every n: 0 and 1 are not prime,
+/// 2 and 3 are, and so is no other even number.
+///
+/// The base is reduced mod n. A base of 0 or ±1 mod n tells primes from composites no
+/// better than a coin, so 2 stands in for it, which for n > 3 is none of them.
+pub fn is_prp(n: &BigUint, base: u128) -> bool {
+    if *n < BigUint::from(2u32) {
+        return false;
+    }
+    is_prp_with(&MontgomeryCtx::new(n), base)
+}
+
+/// `is_prp` for the modulus of `ctx`, so that several bases can share its precomputation.
+fn is_prp_with(ctx: &MontgomeryCtx, base: u128) -> bool {
+    let n = ctx.modulus();
+    // Settle the small and even cases before n - 1 is split into d * 2^s
+    if *n < BigUint::from(2u32) {
+        return false;
+    }
+    if *n == BigUint::from(2u32) || *n == BigUint::from(3u32) {
+        return true;
+    }
+    if n.is_even() {
+        return false;
+    }
+
+    let mut d = n - 1u32;
+    let mut s: u32 = 0;
+
+    while d.is_even() {
+        d >>= 1;
+        s += 1;
+    }
+
+    // Compared in Montgomery form, where the squarings take place
+    let one = ctx.one();
+    let minus_one = ctx.to_montgomery(&(n - 1u32));
+    let base = match BigUint::from(base) % n {
+        base if base.is_zero() || base.is_one() || base == n - 1u32 => BigUint::from(2u32),
+        base => base,
+    };
+    let mut x = ctx.pow(&ctx.to_montgomery(&base), &d);
+    if x == one || x == minus_one {
+        return true;
+    }
+
+    for _ in 0..s.saturating_sub(1) {
+        x = ctx.square(&x);
+        if x == one {
+            return false;
+        }
+        if x == minus_one {
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Worst-case chance that a composite passes `rounds` Miller-Rabin rounds: Rabin's 4^-rounds.
+///
+/// The bound holds for bases drawn at random. The fixed bases used here carry no such
+/// guarantee, but no composite below 3.3 * 10^24 passes the first 13 primes.
+pub fn error_bound(rounds: u32) -> f64 {
+    4f64.powi(-(rounds as i32))
+}
+
+/// Chance that a random odd `bits`-bit number that passed `rounds` Miller-Rabin rounds is
+/// composite, from the bounds of Damgård, Landrock and Pomerance (1993). They fall far below
+/// `error_bound` for large numbers, since few composites pass even one round; where none of
+/// them applies this is `error_bound(rounds)`.
+pub fn average_error_bound(bits: u64, rounds: u32) -> f64 {
+    let (k, t) = (bits as f64, rounds as f64);
+    let mut bound = error_bound(rounds);
+    if rounds == 1 && bits >= 2 {
+        bound = bound.min(k * k * 4f64.powf(2.0 - k.sqrt()));
+    }
+    if (rounds == 2 && bits >= 88) || (rounds >= 3 && bits >= 21 && t <= k / 9.0) {
+        bound = bound.min(k.powf(1.5) * 2f64.powf(t) / t.sqrt() * 4f64.powf(2.0 - (t * k).sqrt()));
+    }
+    if bits >= 21 && t >= k / 9.0 && t <= k / 4.0 {
+        bound = bound.min(
+            0.35 * k * 2f64.powf(-5.0 * t)
+                + k.powf(3.75) / 7.0 * 2f64.powf(-k / 2.0 - 2.0 * t)
+                + 12.0 * k * 2f64.powf(-k / 4.0 - 3.0 * t),
+        );
+    }
+    if bits >= 21 && t >= k / 4.0 {
+        bound = bound.min(k.powf(3.75) / 7.0 * 2f64.powf(-k / 2.0 - 2.0 * t));
+    }
+    bound
+}
+
+/// What `miller_rabin_report` found out about n.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum MillerRabinReport {
+    /// n is composite. `witness` is the base that proved it, or `None` when n is below 2
+    /// or even and no base was needed.
+    Composite { witness: Option<u128> },
+    /// n passed `rounds` bases, skipping those that are multiples of n.
+    ProbablyPrime { rounds: u32 },
+}
+
+/// Runs `is_prp` for each base in turn, stopping at the first that witnesses n composite.
+pub fn miller_rabin_report(n: &BigUint, bases: &[u128]) -> MillerRabinReport {
+    if *n < BigUint::from(2u32) || (n.is_even() && *n != BigUint::from(2u32)) {
+        return MillerRabinReport::Composite { witness: None };
+    }
+    let ctx = MontgomeryCtx::new(n);
+    let mut rounds = 0;
+    for &base in bases {
+        if (BigUint::from(base) % n).is_zero() {
+            continue;
+        }
+        if !is_prp_with(&ctx, base) {
+            return MillerRabinReport::Composite { witness: Some(base) };
+        }
+        rounds += 1;
+    }
+    MillerRabinReport::ProbablyPrime { rounds }
+}
+
+/// `miller_rabin_report` for the Mersenne number 2^p - 1, with the squarings done by a
+/// `Squarer`, so large exponents get the FFT.
+///
+/// n - 1 = 2d with d = 2^(p-1) - 1, so a base b passes when b^d = ±1. That is
+/// b^(2^(p-1)) = ±b for any b coprime to n, which takes p - 1 squarings and nothing else.
+pub fn mersenne_miller_rabin_report(p: u128, bases: &[u128]) -> MillerRabinReport {
+    mersenne_prp_report(p, bases, &PrpOptions::default())
+        .expect("runs without checkpoints or a deadline only stop on Ctrl-C")
+        .report
+}
+
+/// How `mersenne_prp_report` runs its squarings.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PrpOptions {
+    /// Checkpoint the squarings of each base to `prp_state_file` and resume from it.
+    pub mem: bool,
+    /// Directory the checkpoint files are kept in, or `None` for the working directory.
+    pub checkpoint_dir: Option<PathBuf>,
+    /// When to stop with `MpError::Paused`, checkpointing first in memory mode, if ever.
+    pub deadline: Option<Instant>,
+    /// Squarings between the checksum updates of a `GerbiczSquarer`, if the squarings are
+    /// checked at all. The errors it catches add up in `GERBICZ_ERRORS`.
+    pub gerbicz_block: Option<u128>,
+}
+
+/// The outcome of a `mersenne_prp_report` run, which the CLI reports however it is asked to.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PrpResult {
+    /// The exponent of the Mersenne number 2^p - 1 tested.
+    pub p: u128,
+    /// The verdict, with the witness or the rounds passed.
+    pub report: MillerRabinReport,
+    /// The res64 of the first base squared: the low 64 bits of b^(n - 1) mod n, the Fermat
+    /// residue GIMPS publishes for its PRP runs. `None` when no base was squared.
+    pub res64: Option<u64>,
+    /// Squarings of every base tested, p - 1 each, including any done before a checkpoint.
+    pub iterations: u128,
+    /// How long this run took, not counting any run it resumed.
+    pub elapsed: Duration,
+    /// Whether the run picked up from a checkpoint an earlier one left, at `resumed_at`.
+    pub checkpointed: bool,
+    /// The squaring the first base resumed from started at, 0 unless `checkpointed`.
+    pub resumed_at: u128,
+}
+
+impl PrpResult {
+    /// Whether 2^p - 1 passed every base.
+    pub fn is_probable_prime(&self) -> bool {
+        matches!(self.report, MillerRabinReport::ProbablyPrime { .. })
+    }
+}
+
+/// `mersenne_miller_rabin_report`, along with the res64 of the first base squared.
+///
+/// The squarings leave x = b^(2^(p-1)), and b^(n - 1) = b^(2^p - 2) = x^2 / b^2, so the
+/// residue costs one inverse mod n on top of them. There is none when no base is squared.
+///
+/// In memory mode a base keeps its checkpoint once squared, so that a run paused on a later
+/// base does not square it again; they all go once there is a verdict.
+pub fn mersenne_prp_report(p: u128, bases: &[u128], options: &PrpOptions) -> Result<PrpResult, Box<dyn Error>> {
+    let started = Instant::now();
+    let mut result = PrpResult {
+        p,
+        report: MillerRabinReport::Composite { witness: None },
+        res64: None,
+        iterations: 0,
+        elapsed: Duration::ZERO,
+        checkpointed: false,
+        resumed_at: 0,
+    };
+    if p < 2 {
+        return Ok(result);
+    }
+    let n = (BigUint::one() << p) - 1u32;
+    let mut rounds = 0;
+    let mut witness = None;
+    for &base in bases {
+        let mut b = BigUint::from(base) % &n;
+        if b.is_zero() {
+            continue;
+        }
+        // As in is_prp, a base of +-1 mod n is replaced, but by 3: 2^p = 1 mod 2^p - 1, so
+        // every Mersenne number passes base 2
+        if p > 2 && (b.is_one() || b == &n - 1u32) {
+            b = BigUint::from(3u32);
+        }
+        if !b.gcd(&n).is_one() {
+            witness = Some(base);
+            break;
+        }
+        let checkpoint = options.mem.then(|| prp_state_file(options.checkpoint_dir.as_deref(), p, base));
+        let (mut completed, initial) = resume_from(checkpoint.as_deref(), b.clone(), p - 1)?;
+        if completed > 0 && !result.checkpointed {
+            (result.checkpointed, result.resumed_at) = (true, completed);
+        }
+        let pb = progress_bar((p - 1) as u64, LUCAS_LEHMER_TEMPLATE, format!("Performing PRP Test of M{} to base {}", p, base));
+        let mut throughput = Throughput::new("prp", p, &pb, completed as u64, (p - 1) as u64);
+        let run = SquaringRun {
+            total: p - 1,
+            subtract: 0,
+            started,
+            timeout: None,
+            deadline: options.deadline,
+            checkpoint: checkpoint.as_deref(),
+        };
+        let mut squares = |x: &mut dyn Squarer| -> Result<(), Box<dyn Error>> {
+            let stop = profile::time(Phase::Execute, || {
+                run.run(x, &mut completed, |completed, _| {
+                    throughput.update(&pb, completed as u64);
+                    Ok(())
+                })
+            })?;
+            if let Some(reason) = stop {
+                pb.abandon_with_message(format!("PRP Test of M{} Stopped", p));
+                return Err(reason.into());
+            }
+            Ok(())
+        };
+        let x = match options.gerbicz_block {
+            Some(block) => {
+                let mut x = GerbiczSquarer::new(p, &initial, block, Box::new(move |x: &BigUint| squarer(p, x)));
+                let finished = squares(&mut x).and_then(|_| x.finish());
+                GERBICZ_ERRORS.fetch_add(x.errors(), Ordering::Relaxed);
+                finished?
+            }
+            None => {
+                let mut x = squarer(p, &initial);
+                squares(&mut *x)?;
+                x.residue()?
+            }
+        };
+        pb.finish_with_message(format!("PRP Test of M{} to base {} Completed", p, base));
+        finish_residue_state(checkpoint.as_deref(), true, &x, p - 1)?;
+        result.iterations += p - 1;
+        if result.res64.is_none() {
+            let b_inverse = b.modinv(&n).expect("b is coprime to n");
+            result.res64 = Some(res64(&(&x * &x % &n * &b_inverse % &n * &b_inverse % &n)));
+        }
+        if x != b && x != &n - &b {
+            witness = Some(base);
+            break;
+        }
+        rounds += 1;
+    }
+    if options.mem {
+        for &base in bases {
+            remove_checkpoint(&prp_state_file(options.checkpoint_dir.as_deref(), p, base))?;
+        }
+    }
+    result.report = match witness {
+        Some(base) => MillerRabinReport::Composite { witness: Some(base) },
+        None => MillerRabinReport::ProbablyPrime { rounds },
+    };
+    result.elapsed = started.elapsed();
+    Ok(result)
+}
+
+/// The verdicts of the Lucas-Lehmer test and the base-3 PRP test on the same 2^p - 1.
+/// The PRP res64 is 1 for a probable prime, and `None` when 3 divides 2^p - 1 and there
+/// was nothing to square.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct CrossCheck {
+    pub lucas_lehmer: LlResult,
+    pub prp: PrpResult,
+}
+
+impl CrossCheck {
+    /// Whether both tests call 2^p - 1 prime, or both call it composite. A Mersenne prime
+    /// is a probable prime to every base, and no known composite 2^p - 1 is a base-3
+    /// probable prime, so a disagreement means an error in the hardware or the program.
+    pub fn agrees(&self) -> bool {
+        self.lucas_lehmer.is_prime == self.prp.is_probable_prime()
+    }
+}
+
+/// Runs `lucas_lehmer_squarer` and then `mersenne_prp_report` to base 3 on 2^p - 1, both on
+/// the CPU, the second taking its memory mode and deadline from `options`, checked by
+/// Gerbicz blocks of `gerbicz_block` squarings if given. Each test checkpoints to
+/// its own state files, so a paused cross-check resumes whichever test it stopped in.
+pub fn cross_check(p: u128, options: &LucasLehmerOptions, gerbicz_block: Option<u128>) -> Result<CrossCheck, Box<dyn Error>> {
+    let lucas_lehmer = lucas_lehmer_squarer(p, options)?;
+    let prp_options = PrpOptions {
+        mem: options.mem,
+        checkpoint_dir: options.checkpoint_dir.clone(),
+        deadline: options.deadline,
+        gerbicz_block,
+    };
+    let prp = mersenne_prp_report(p, &[3], &prp_options)?;
+    Ok(CrossCheck { lucas_lehmer, prp })
+}
+
+/// Number of candidates `is_prp_batch` hands the GPU per dispatch.
+const PRP_BATCH_SIZE: usize = 1 << 20;
+
+/// Runs `is_prp` over a batch of numbers, with the strong probable-prime squaring chain of
+/// every n below 2^64 done by an OpenCL kernel, `PRP_BATCH_SIZE` candidates per dispatch.
+///
+/// Numbers past 64 bits, or every number when `base` itself doesn't fit 64 bits, go through
+/// `is_prp` on the CPU, so a batch with nothing for the GPU never touches OpenCL.
+///
+/// # Returns
+///
+/// The verdict for each number, in the order given, exactly as `is_prp` would return them.
+pub fn is_prp_batch(numbers: &[BigUint], base: u128) -> Result<Vec<bool>, Box<dyn Error>> {
+    let mut verdicts = vec![false; numbers.len()];
+    let mut on_device = Vec::new();
+    for (idx, n) in numbers.iter().enumerate() {
+        match (n.to_u64(), u64::try_from(base)) {
+            (Some(small), Ok(_)) => on_device.push((idx, small)),
+            _ => verdicts[idx] = is_prp(n, base),
+        }
+    }
+    if on_device.is_empty() {
+        return Ok(verdicts);
+    }
+
+    let src = r#"
+    __kernel void prp_kernel(__global const ulong* numbers, __global uchar* results, ulong base, ulong count) {
+        size_t gid = get_global_id(0);
+        if (gid >= count) {
+            return;
+        }
+        ulong n = numbers[gid];
+        if (n < 4) {
+            results[gid] = (n == 2 || n == 3);
+            return;
+        }
+        if ((n & 1) == 0) {
+            results[gid] = 0;
+            return;
+        }
+        // Like is_prp, 2 stands in for a base of 0 or +-1 mod n
+        ulong a = base % n;
+        if (a == 0 || a == 1 || a == n - 1) {
+            a = 2;
+        }
+
+        ulong d = n - 1;
+        uint s = 0;
+        while ((d & 1) == 0) {
+            d >>= 1;
+            s++;
+        }
+        results[gid] = is_sprp(n, a, d, s);
+    }
+    "#;
+
+    let batch_len = on_device.len().min(PRP_BATCH_SIZE);
+    let pro_que = pro_que_for(&format!("{}{}", MOD_ARITH_SRC, src), batch_len)?;
+    debug!(
+        "PRP base {} on {}: {} numbers in batches of {}",
+        base,
+        pro_que.device().name()?,
+        on_device.len(),
+        batch_len
+    );
+    let numbers_buffer = pro_que.buffer_builder::<u64>().flags(flags::MEM_READ_ONLY).build()?;
+    let results_buffer = pro_que.buffer_builder::<u8>().flags(flags::MEM_WRITE_ONLY).build()?;
+    let kernel = pro_que.kernel_builder("prp_kernel")
+        .arg(&numbers_buffer)
+        .arg(&results_buffer)
+        .arg(base as u64)
+        .arg(0u64) // Placeholder for the batch length
+        .build()?;
+
+    let mut values = vec![0u64; batch_len];
+    let mut results = vec![0u8; batch_len];
+    for batch in on_device.chunks(batch_len) {
+        for (slot, &(_, n)) in values.iter_mut().zip(batch) {
+            *slot = n;
+        }
+        numbers_buffer.write(&values[..batch.len()]).enq()?;
+        kernel.set_arg(3, batch.len() as u64)?;
+        unsafe {
+            kernel.cmd().global_work_size(batch.len()).enq()?;
+        }
+        results_buffer.read(&mut results[..batch.len()]).enq()?;
+        for (&(idx, _), &result) in batch.iter().zip(&results) {
+            verdicts[idx] = result != 0;
+        }
+    }
+
+    Ok(verdicts)
+}
+
+/// `is_prp_batch` to every one of `bases`, a number above 1 passing when each base either
+/// passes it or, as in `miller_rabin_report`, is a multiple of it and so proves nothing.
+pub fn is_prp_batch_bases(numbers: &[BigUint], bases: &[u128]) -> Result<Vec<bool>, Box<dyn Error>> {
+    let initial = numbers.iter().map(|n| *n > BigUint::one()).collect();
+    bases.iter().try_fold(initial, |mut verdicts: Vec<bool>, &base| {
+        let passed = is_prp_batch(numbers, base)?;
+        for ((verdict, n), passed) in verdicts.iter_mut().zip(numbers).zip(passed) {
+            *verdict &= passed || (BigUint::from(base) % n).is_zero();
+        }
+        Ok(verdicts)
+    })
+}
+
+/// Witnesses that make Miller-Rabin deterministic for every n < 2^64.
+const DETERMINISTIC_BASES: [u64; 12] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37];
+
+fn mul_mod(a: u64, b: u64, n: u64) -> u64 {
+    ((a as u128 * b as u128) % n as u128) as u64
+}
+
+fn pow_mod(mut base: u64, mut exponent: u64, n: u64) -> u64 {
+    let mut result = 1 % n;
+    base %= n;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = mul_mod(result, base, n);
+        }
+        base = mul_mod(base, base, n);
+        exponent >>= 1;
+    }
+    result
+}
+
+/// Strong probable-prime test of a 64-bit n against every base, matching the GPU kernel.
+///
+/// A base that is a multiple of n is skipped rather than counted as a witness.
+pub fn is_sprp_u64(n: u64, bases: &[u64]) -> bool {
+    if n < 2 {
+        return false;
+    }
+    if n == 2 {
+        return true;
+    }
+    if n.is_multiple_of(2) {
+        return false;
+    }
+
+    let mut d = n - 1;
+    let mut s = 0;
+    while d.is_multiple_of(2) {
+        d /= 2;
+        s += 1;
+    }
+
+    'witness: for &a in bases {
+        if a.is_multiple_of(n) {
+            continue;
+        }
+        let mut x = pow_mod(a, d, n);
+        if x == 1 || x == n - 1 {
+            continue;
+        }
+        for _ in 1..s {
+            x = mul_mod(x, x, n);
+            if x == n - 1 {
+                continue 'witness;
+            }
+        }
+        return false;
+    }
+
+    true
+}
+
+/// Deterministic Miller-Rabin for 64-bit n, exact for every input.
+pub fn is_prime_u64(n: u64) -> bool {
+    if n < 2 {
+        return false;
+    }
+    for &p in &DETERMINISTIC_BASES {
+        if n.is_multiple_of(p) {
+            return n == p;
+        }
+    }
+
+    is_sprp_u64(n, &DETERMINISTIC_BASES)
+}
+
+/// Gaps between the numbers coprime to 30 from 7 on: 7, 11, 13, 17, 19, 23, 29, 31, 37, ...
+const WHEEL_30: [u128; 8] = [4, 2, 4, 2, 4, 6, 2, 6];
+
+/// Exact primality by trial division up to sqrt(n), skipping the multiples of 2, 3 and 5.
+///
+/// Needing neither an OpenCL device nor bases, it is the ground truth for the probabilistic
+/// and GPU tests, but it takes about sqrt(n)/4 divisions, so it suits numbers of up to 64 bits.
+pub fn is_prime_trial(n: u128) -> bool {
+    if n < 2 {
+        return false;
+    }
+    for p in [2, 3, 5] {
+        if n.is_multiple_of(p) {
+            return n == p;
+        }
+    }
+    let mut divisor = 7;
+    // divisor <= n / divisor rather than divisor^2 <= n, which overflows near 2^128
+    for gap in WHEEL_30.iter().cycle() {
+        if divisor > n / divisor {
+            break;
+        }
+        if n.is_multiple_of(divisor) {
+            return false;
+        }
+        divisor += gap;
+    }
+    true
+}
+
+/// The Jacobi symbol (a/n) for odd n, as -1, 0 or 1.
+fn jacobi(a: &BigUint, n: &BigUint) -> i32 {
+    let mut a = a % n;
+    let mut n = n.clone();
+    let mut result = 1;
+    while !a.is_zero() {
+        while a.is_even() {
+            a >>= 1;
+            // (2/n) is -1 exactly when n is 3 or 5 mod 8
+            let r = (&n % 8u32).to_u32().unwrap_or(0);
+            if r == 3 || r == 5 {
+                result = -result;
+            }
+        }
+        std::mem::swap(&mut a, &mut n);
+        // Quadratic reciprocity flips the sign when both are 3 mod 4
+        if (&a % 4u32).to_u32() == Some(3) && (&n % 4u32).to_u32() == Some(3) {
+            result = -result;
+        }
+        a %= &n;
+    }
+    if n.is_one() {
+        result
+    } else {
+        0
+    }
+}
+
+/// Reduces a small signed value mod n.
+fn signed_mod(x: i64, n: &BigUint) -> BigUint {
+    let magnitude = BigUint::from(x.unsigned_abs()) % n;
+    if x < 0 && !magnitude.is_zero() {
+        n - magnitude
+    } else {
+        magnitude
+    }
+}
+
+/// Halves x mod odd n.
+fn half_mod(x: BigUint, n: &BigUint) -> BigUint {
+    if x.is_even() {
+        x >> 1
+    } else {
+        (x + n) >> 1
+    }
+}
+
+/// Strong Lucas probable-prime test with Selfridge's parameters: D is the first of
+/// 5, -7, 9, -11, ... with (D/n) = -1, P = 1 and Q = (1 - D) / 4.
+///
+/// Perfect squares are rejected up front, since no such D exists for them.
+pub fn is_strong_lucas_prp(n: &BigUint) -> bool {
+    if *n < BigUint::from(2u32) {
+        return false;
+    }
+    is_strong_lucas_prp_with(&MontgomeryCtx::new(n))
+}
+
+/// `is_strong_lucas_prp` for the modulus of `ctx`. The sequences are kept in Montgomery
+/// form, where the additions and halvings mod n carry over unchanged.
+fn is_strong_lucas_prp_with(ctx: &MontgomeryCtx) -> bool {
+    let n = ctx.modulus();
+    if *n < BigUint::from(2u32) {
+        return false;
+    }
+    if *n == BigUint::from(2u32) {
+        return true;
+    }
+    if n.is_even() || n.sqrt().pow(2) == *n {
+        return false;
+    }
+
+    let mut d = 5i64;
+    loop {
+        match jacobi(&signed_mod(d, n), n) {
+            -1 => break,
+            // A common factor with D proves n composite, unless n is |D| itself
+            0 if BigUint::from(d.unsigned_abs()) != *n => return false,
+            _ => d = if d > 0 { -(d + 2) } else { -d + 2 },
+        }
+    }
+    let q = (1 - d) / 4;
+    let d_mod = ctx.to_montgomery(&signed_mod(d, n));
+    let q_mod = ctx.to_montgomery(&signed_mod(q, n));
+
+    // n + 1 = k * 2^s with k odd
+    let mut k = n + 1u32;
+    let mut s = 0u32;
+    while k.is_even() {
+        k >>= 1;
+        s += 1;
+    }
+
+    // U_k, V_k and Q^k by the binary method from the top bit of k, with P = 1
+    let mut u = ctx.one();
+    let mut v = ctx.one();
+    let mut q_k = q_mod.clone();
+    for bit in (0..k.bits() - 1).rev() {
+        u = ctx.mul(&u, &v);
+        v = (ctx.square(&v) + n - (&q_k << 1u32) % n) % n;
+        q_k = ctx.square(&q_k);
+        if k.bit(bit) {
+            let next_u = half_mod(&u + &v, n);
+            v = half_mod((ctx.mul(&d_mod, &u) + &v) % n, n);
+            u = next_u % n;
+            q_k = ctx.mul(&q_k, &q_mod);
+        }
+    }
+
+    if u.is_zero() || v.is_zero() {
+        return true;
+    }
+    for _ in 1..s {
+        v = (ctx.square(&v) + n - (&q_k << 1u32) % n) % n;
+        if v.is_zero() {
+            return true;
+        }
+        q_k = ctx.square(&q_k);
+    }
+
+    false
+}
+
+/// Baillie-PSW: a base-2 strong probable-prime test followed by a strong Lucas test.
+///
+/// No composite is known to pass both, and below 2^64 it is exact, where the
+/// deterministic Miller-Rabin of `is_prime_u64` answers instead.
+pub fn is_bpsw(n: &BigUint) -> bool {
+    match n.to_u64() {
+        Some(small) => is_prime_u64(small),
+        None => {
+            let ctx = MontgomeryCtx::new(n);
+            is_prp_with(&ctx, 2) && is_strong_lucas_prp_with(&ctx)
+        }
+    }
+}
+
+/// V_k(P, 1) mod n, by the Lucas sequence ladder V_2m = V_m^2 - 2, V_2m+1 = V_m V_m+1 - P.
+fn lucas_v(k: u64, p: u32, n: &BigUint) -> BigUint {
+    let p = BigUint::from(p) % n;
+    let two = BigUint::from(2u32) % n;
+    let (mut v, mut v_next) = (two.clone(), p.clone());
+    for bit in (0..64 - k.leading_zeros()).rev() {
+        let product = (&v * &v_next + n - &p) % n;
+        if (k >> bit) & 1 == 1 {
+            v = product;
+            v_next = (&v_next * &v_next + n - &two) % n;
+        } else {
+            v_next = product;
+            v = (&v * &v + n - &two) % n;
+        }
+    }
+    v
+}
+
+/// Runs the Lucas-Lehmer-Riesel test on N = k * 2^n - 1 with BigUint arithmetic on the CPU.
+///
+/// The seed is V_k(P, 1) mod N for the smallest P >= 3 with (P-2 / N) = 1 and
+/// (P+2 / N) = -1, after which the squarings are those of `lucas_lehmer_cpu`: N is prime
+/// exactly when n - 2 steps of u -> u^2 - 2 reach 0. For k = 1 this is the Lucas-Lehmer test.
+///
+/// # Returns
+///
+/// Whether N is prime, or an error unless k is odd and k < 2^n.
+pub fn llr(k: u64, n: u64) -> Result<bool, Box<dyn Error>> {
+    if k.is_multiple_of(2) {
+        return Err(format!("LLR needs an odd k, got {}.", k).into());
+    }
+    if n < 2 || (n < 64 && k >= 1 << n) {
+        return Err(format!("LLR needs k < 2^n, got k = {} and n = {}.", k, n).into());
+    }
+    let modulus = (BigUint::from(k) << n) - 1u32;
+
+    // N is 3 mod 4 and so never a square, which guarantees some P qualifies
+    let mut p = 3u32;
+    loop {
+        let minus = BigUint::from(p - 2);
+        let plus = BigUint::from(p + 2);
+        // A shared factor short of N itself proves N composite
+        for x in [&minus, &plus] {
+            let common = x.gcd(&modulus);
+            if !common.is_one() && common != modulus {
+                return Ok(false);
+            }
+        }
+        if jacobi(&minus, &modulus) == 1 && jacobi(&plus, &modulus) == -1 {
+            break;
+        }
+        p += 1;
+    }
+
+    let mut u = lucas_v(k, p, &modulus);
+    for _ in 0..n - 2 {
+        u = (&u * &u + &modulus - 2u32) % &modulus;
+    }
+    Ok(u.is_zero())
+}
+
+/// Outcome of `is_prime`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PrimeVerdict {
+    /// Proven prime.
+    Prime,
+    /// Proven composite. Also returned for 0 and 1, which are not prime.
+    Composite,
+    /// Passed every probabilistic round without being proven prime.
+    ProbablyPrime,
+}
+
+/// Primality test `is_prime` dispatches to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    /// A single base-2 strong probable-prime test via `is_prp`.
+    Prp,
+    /// Miller-Rabin with the first `rounds` primes as bases.
+    MillerRabin,
+    /// Exact Miller-Rabin for n < 2^64, falling back to `MillerRabin` above that.
+    Deterministic,
+}
+
+/// Settings for `is_prime`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PrimeConfig {
+    pub strategy: Strategy,
+    /// Number of Miller-Rabin bases, used by `MillerRabin` and by `Deterministic` above 2^64.
+    pub rounds: u32,
+}
+
+impl Default for PrimeConfig {
+    fn default() -> Self {
+        PrimeConfig {
+            strategy: Strategy::Deterministic,
+            rounds: 20,
+        }
+    }
+}
+
+/// The test `test_many` runs on every number of a batch.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TestKind {
+    /// Lucas-Lehmer on 2^p - 1, each number being the exponent p, with these options.
+    LucasLehmer(LucasLehmerOptions),
+    /// Strong probable-prime tests to each of these bases, batched on the GPU when there is one.
+    Prp(Vec<u128>),
+    /// `is_prime` with this configuration, on the CPU.
+    IsPrime(PrimeConfig),
+}
+
+/// Runs `test` on each of `numbers`, the programmatic counterpart of the -l and -p batches.
+/// The Lucas-Lehmer runs share one `GpuContext` and the PRP tests go to the GPU as one batch
+/// per base, falling back to the CPU if that fails.
+///
+/// # Returns
+///
+/// A verdict for each number, in the order given: `Prime` for a Mersenne prime, which
+/// Lucas-Lehmer proves, and `ProbablyPrime` for a number that passed every PRP base. The
+/// first Lucas-Lehmer run to fail ends the batch with its error.
+pub fn test_many(numbers: &[BigUint], test: TestKind) -> Result<Vec<PrimeVerdict>, Box<dyn Error>> {
+    let verdict = |passed, verdict| if passed { verdict } else { PrimeVerdict::Composite };
+    match test {
+        TestKind::LucasLehmer(options) => {
+            let mut context = None;
+            numbers
+                .iter()
+                .map(|n| {
+                    let p = n.to_u128().ok_or_else(|| format!("the exponent {} is too large for a Lucas-Lehmer test", n))?;
+                    match p {
+                        0 | 1 => Ok(PrimeVerdict::Composite),
+                        p => Ok(verdict(lucas_lehmer_with_threshold(&mut context, p, &options)?.is_prime, PrimeVerdict::Prime)),
+                    }
+                })
+                .collect()
+        }
+        TestKind::Prp(bases) => {
+            if bases.is_empty() {
+                return Err("at least one base is needed for a PRP test".into());
+            }
+            let batched = match Platform::first().and_then(Device::first) {
+                Ok(_) => is_prp_batch_bases(numbers, &bases).inspect_err(|e| warn!("GPU PRP test failed ({}), testing on the CPU", e)).ok(),
+                Err(_) => None,
+            };
+            let passed = batched.unwrap_or_else(|| {
+                numbers.iter().map(|n| matches!(miller_rabin_report(n, &bases), MillerRabinReport::ProbablyPrime { .. })).collect()
+            });
+            Ok(passed.into_iter().map(|passed| verdict(passed, PrimeVerdict::ProbablyPrime)).collect())
+        }
+        TestKind::IsPrime(config) => Ok(numbers.iter().map(|n| is_prime(n, config)).collect()),
+    }
+}
+
+/// Returns the first `count` primes, used as Miller-Rabin bases.
+fn first_primes(count: u32) -> Vec<u128> {
+    let mut primes = Vec::with_capacity(count as usize);
+    let mut candidate = 2u64;
+    while primes.len() < count as usize {
+        if is_prime_u64(candidate) {
+            primes.push(candidate as u128);
+        }
+        candidate += 1;
+    }
+    primes
+}
+
+/// Runs `is_prp` with the first `rounds` primes as bases, skipping multiples of n.
+fn miller_rabin(n: &BigUint, rounds: u32) -> PrimeVerdict {
+    match miller_rabin_report(n, &first_primes(rounds.max(1))) {
+        MillerRabinReport::ProbablyPrime { .. } => PrimeVerdict::ProbablyPrime,
+        MillerRabinReport::Composite { .. } => PrimeVerdict::Composite,
+    }
+}
+
+/// Finds a and k >= 2 with n = a^k, taking the largest such k.
+///
+/// # Returns
+///
+/// `Some((a, k))` when n > 1 is a perfect power, `None` otherwise.
+pub fn is_perfect_power(n: &BigUint) -> Option<(BigUint, u32)> {
+    if *n < BigUint::from(4u32) {
+        return None;
+    }
+    // A root of 2 or more needs k < bits(n), and trying large k first finds the smallest base
+    (2..n.bits() as u32).rev().find_map(|k| {
+        let root = n.nth_root(k);
+        (root.pow(k) == *n).then_some((root, k))
+    })
+}
+
+/// Tests n for primality with the strategy chosen in `config`.
+///
+/// Perfect powers are rejected before any strategy runs, which the Lucas half of BPSW
+/// needs and which makes the probabilistic verdicts for them certain.
+///
+/// # Arguments
+///
+/// * `n` - The number to test.
+/// * `config` - The strategy and number of rounds to test with.
+///
+/// # Returns
+///
+/// `Prime` only when the strategy proves it, `ProbablyPrime` when n passed every
+/// probabilistic round, and `Composite` otherwise.
+pub fn is_prime(n: &BigUint, config: PrimeConfig) -> PrimeVerdict {
+    if *n < BigUint::from(2u32) || is_perfect_power(n).is_some() {
+        return PrimeVerdict::Composite;
+    }
+
+    match config.strategy {
+        Strategy::Prp => {
+            if is_prp(n, 2) {
+                PrimeVerdict::ProbablyPrime
+            } else {
+                PrimeVerdict::Composite
+            }
+        }
+        Strategy::MillerRabin => miller_rabin(n, config.rounds),
+        Strategy::Deterministic => match n.to_u64() {
+            Some(small) if is_prime_u64(small) => PrimeVerdict::Prime,
+            Some(_) => PrimeVerdict::Composite,
+            None => miller_rabin(n, config.rounds),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_prp_settles_small_numbers() {
+        let expected = [(0u32, false), (1, false), (2, true), (3, true), (4, false), (5, true), (9, false)];
+        for (n, prime) in expected {
+            for base in [2, 3, 7] {
+                assert_eq!(is_prp(&BigUint::from(n), base), prime, "n = {}, base = {}", n, base);
+            }
+        }
+        // 2047 = 23 * 89 is the smallest strong pseudoprime to base 2
+        assert!(is_prp(&BigUint::from(2047u32), 2));
+    }
+
+    #[test]
+    fn is_prp_agrees_with_a_sieve_below_ten_thousand() {
+        let primes = crate::sieve::sieve_of_eratosthenes(0, 10_000).unwrap();
+        for n in 0..10_000u128 {
+            let big = BigUint::from(n);
+            // Below 3215031751 no composite is a strong probable prime to all of 2, 3, 5 and 7
+            let probably_prime = [2, 3, 5, 7].iter().all(|&base| is_prp(&big, base));
+            assert_eq!(probably_prime, primes.binary_search(&n).is_ok(), "{}", n);
+            // Bases of 0 and +-1 mod n, and bases past n, give what 2 gives
+            for base in [0, 1, n.saturating_sub(1), n, n + 1, 2 * n + 1, u128::MAX] {
+                let reduced = if n < 2 { 0 } else { base % n };
+                let stand_in = if n > 3 && (reduced < 2 || reduced == n - 1) { 2 } else { reduced };
+                assert_eq!(is_prp(&big, base), is_prp(&big, stand_in), "n = {}, base = {}", n, base);
+            }
+        }
+    }
+
+    #[test]
+    fn is_prp_handles_the_inputs_that_used_to_break_it() {
+        for n in [0u32, 1, 2, 3, 4, 6, 7] {
+            for base in [0, 1, 2, 3, 6, 7, 8, u128::MAX] {
+                let prime = [2, 3, 7].contains(&n);
+                assert_eq!(is_prp(&BigUint::from(n), base), prime, "n = {}, base = {}", n, base);
+            }
+        }
+        // Mersenne primes to a base that is one of them, and 2^127-1 to a base of n + 1
+        let m127: BigUint = (BigUint::one() << 127) - 1u32;
+        assert!(is_prp(&BigUint::from(8191u32), 8191) && is_prp(&BigUint::from(8191u32), 8190));
+        assert!(is_prp(&m127, u128::MAX) && is_prp(&m127, 1u128 << 127));
+        // 2 stands in for -1 mod 2047, and 2047 fools base 2
+        assert!(is_prp(&BigUint::from(2047u32), 2046) && !is_prp(&BigUint::from(2047u32 * 3), 2047 * 3));
+    }
+
+    #[test]
+    fn error_bounds_shrink_with_rounds_and_size() {
+        assert_eq!(error_bound(10), 4f64.powi(-10));
+        assert_eq!(error_bound(0), 1.0);
+        // Small numbers are beyond the averaged bounds, large ones well within them
+        assert_eq!(average_error_bound(11, 3), error_bound(3));
+        assert!(average_error_bound(512, 1) < 1e-6);
+        for bits in [2, 21, 64, 88, 100, 512, 4096] {
+            for rounds in 1..=40 {
+                let average = average_error_bound(bits, rounds);
+                assert!(average > 0.0 && average <= error_bound(rounds), "{} bits, {} rounds", bits, rounds);
+                assert!(average_error_bound(bits, rounds + 1) <= average, "{} bits, {} rounds", bits, rounds);
+            }
+        }
+    }
+
+    #[test]
+    fn reports_name_the_witness_or_the_rounds_passed() {
+        let report = |n: u32, bases: &[u128]| miller_rabin_report(&BigUint::from(n), bases);
+        assert_eq!(report(2047, &[2, 3]), MillerRabinReport::Composite { witness: Some(3) });
+        assert_eq!(report(2047, &[2]), MillerRabinReport::ProbablyPrime { rounds: 1 });
+        // Bases that are multiples of n are skipped, not counted
+        assert_eq!(report(97, &[2, 3, 97, 5]), MillerRabinReport::ProbablyPrime { rounds: 3 });
+        assert_eq!(report(2, &[2, 3]), MillerRabinReport::ProbablyPrime { rounds: 1 });
+        for n in [0, 1, 100] {
+            assert_eq!(report(n, &[2, 3]), MillerRabinReport::Composite { witness: None });
+        }
+    }
+
+    #[test]
+    fn mersenne_reports_match_the_general_ones() {
+        let bases = [2, 3, 5, 7, 6, 2047, 1 << 70];
+        for p in 2..=130u128 {
+            let n = (BigUint::one() << p) - 1u32;
+            for base in bases.iter().map(|&base| [base]) {
+                assert_eq!(mersenne_miller_rabin_report(p, &base), miller_rabin_report(&n, &base), "M{} base {:?}", p, base);
+            }
+            assert_eq!(mersenne_miller_rabin_report(p, &bases), miller_rabin_report(&n, &bases), "M{}", p);
+        }
+        let m9689 = mersenne_miller_rabin_report(9689, &[3]);
+        assert_eq!(m9689, MillerRabinReport::ProbablyPrime { rounds: 1 });
+        assert_eq!(mersenne_miller_rabin_report(9697, &[3]), MillerRabinReport::Composite { witness: Some(3) });
+    }
+
+    #[test]
+    fn mersenne_prp_residues_are_the_fermat_residue() {
+        for p in [3u128, 11, 29, 31, 61, 67, 127] {
+            let n = (BigUint::one() << p) - 1u32;
+            let fermat = BigUint::from(3u32).modpow(&(&n - 1u32), &n);
+            let result = mersenne_prp_report(p, &[3, 5], &PrpOptions::default()).unwrap();
+            assert_eq!(result.res64, Some(res64(&fermat)), "M{}", p);
+        }
+        assert_eq!(mersenne_prp_report(127, &[3], &PrpOptions::default()).unwrap().res64, Some(1));
+        let m2 = mersenne_prp_report(2, &[3], &PrpOptions::default()).unwrap();
+        assert_eq!((m2.report, m2.res64, m2.iterations), (MillerRabinReport::ProbablyPrime { rounds: 0 }, None, 0));
+
+        // Bases of +-1 test 3 in their place, not 2, which every 2^p - 1 passes
+        for base in [1, 2047 - 1] {
+            let m11 = mersenne_prp_report(11, &[base], &PrpOptions::default()).unwrap();
+            assert!(!m11.is_probable_prime(), "base {}", base);
+            assert_eq!(m11.res64, Some(res64(&BigUint::from(3u32).modpow(&BigUint::from(2046u32), &BigUint::from(2047u32)))));
+        }
+    }
+
+    #[test]
+    #[ignore = "needs an OpenCL device"]
+    fn the_kernel_finds_m31_prime_with_and_without_a_shift() {
+        for shift in [0, 17] {
+            assert!(lucas_lehmer(31, false, shift, None).unwrap().is_prime, "shift = {}", shift);
+            assert!(!lucas_lehmer(29, false, shift, None).unwrap().is_prime, "shift = {}", shift);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs an OpenCL device"]
+    fn batch_sizes_leave_the_verdicts_alone() {
+        let mut context = None;
+        for batch_size in [1, 7, 60, DEFAULT_BATCH_SIZE] {
+            for p in 3..=64 {
+                let options = LucasLehmerOptions { shift: 11, batch_size, ..LucasLehmerOptions::default() };
+                let verdict = lucas_lehmer_with_context(&mut context, p, &options).unwrap().is_prime;
+                assert_eq!(verdict, lucas_lehmer_cpu(p), "M{} in batches of {}", p, batch_size);
+            }
+        }
+    }
+
+    #[test]
+    #[ignore = "needs an OpenCL device"]
+    fn repeated_runs_compile_the_kernel_once() {
+        for p in (3..=61).cycle().take(100) {
+            assert_eq!(lucas_lehmer(p, false, 0, None).unwrap().is_prime, lucas_lehmer_cpu(p), "M{}", p);
+        }
+        let src = format!("{}{}", MOD_ARITH_SRC, LUCAS_LEHMER_SRC);
+        let programs = PROGRAMS.lock().unwrap();
+        assert_eq!(programs.iter().filter(|(_, s, ..)| *s == src).count(), 1);
+    }
+
+    #[test]
+    fn cached_binaries_are_keyed_by_device_and_source() {
+        let dir = Path::new("cache");
+        let path = program_binary_path(dir, "Vendor\nDevice\nOpenCL 1.2\n1.0", LUCAS_LEHMER_SRC);
+        assert_eq!(path.parent(), Some(dir));
+        assert_eq!(path, program_binary_path(dir, "Vendor\nDevice\nOpenCL 1.2\n1.0", LUCAS_LEHMER_SRC));
+        assert_ne!(path, program_binary_path(dir, "Vendor\nDevice\nOpenCL 1.2\n1.1", LUCAS_LEHMER_SRC));
+        assert_ne!(path, program_binary_path(dir, "Vendor\nDevice\nOpenCL 1.2\n1.0", MOD_ARITH_SRC));
+        // The published FNV-1a values, which the names must keep across builds
+        assert_eq!(fnv1a([]), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(*b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn build_logs_quote_the_source_lines_they_cite() {
+        let src = "__kernel void k(__global ulong* out) {\n    out[0] = u64;\n}\n";
+        let log = "<source>:2:14: error: use of undeclared identifier 'u64'\n    out[0] = u64;\n             ^";
+        assert_eq!(
+            with_source_lines(log, src),
+            format!("{}\n\nKernel source:\n    2 |     out[0] = u64;", log)
+        );
+        assert_eq!(with_source_lines("error: 1 warning", src), "error: 1 warning");
+        assert_eq!(with_source_lines("<source>:40:1: error: expected '}'", src), "<source>:40:1: error: expected '}'");
+    }
+
+    #[test]
+    #[ignore = "needs an OpenCL device"]
+    fn a_bad_define_fails_the_build_with_its_log() {
+        let src = format!("{}\n// bad define test {}\n", LUCAS_LEHMER_SRC, std::process::id());
+        let device = Device::first(Platform::first().unwrap()).unwrap();
+        let result = cached_program_with_options(device, &src, &compiler_options(&["ulong=undeclared_type".to_string()]));
+
+        let e = result.expect_err("the define breaks the kernel");
+        match e.downcast_ref::<MpError>() {
+            Some(MpError::KernelBuild { device: name, log }) => {
+                assert_eq!(*name, device.name().unwrap());
+                assert!(log.contains("Kernel source:"), "{}", log);
+            }
+            _ => panic!("not a kernel build error: {}", e),
+        }
+    }
+
+    #[test]
+    #[ignore = "needs an OpenCL device"]
+    fn a_second_process_loads_the_program_from_the_cache() {
+        let dir = std::env::temp_dir().join(format!("mp-program-cache-{}", std::process::id()));
+        *PROGRAM_CACHE.lock().unwrap() = Some(dir.clone());
+        let src = format!("{}\n// cache test {}\n", LUCAS_LEHMER_SRC, std::process::id());
+        let device = Device::first(Platform::first().unwrap()).unwrap();
+
+        let compiled = COMPILATIONS.load(Ordering::Relaxed);
+        cached_program(device, &src).unwrap();
+        assert_eq!(COMPILATIONS.load(Ordering::Relaxed), compiled + 1);
+
+        // Forgetting the in-memory program stands in for a new process
+        PROGRAMS.lock().unwrap().retain(|(_, s, ..)| *s != src);
+        cached_program(device, &src).unwrap();
+        assert_eq!(COMPILATIONS.load(Ordering::Relaxed), compiled + 1);
+
+        *PROGRAM_CACHE.lock().unwrap() = None;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs an OpenCL device"]
+    fn gpu_prp_verdicts_match_the_cpu() {
+        // Straddling 2^32, where the kernel's products stop fitting 64 bits
+        let ranges = [0u64..20_000, 4_294_967_000..4_294_968_000, u64::MAX - 2_000..u64::MAX];
+        let numbers: Vec<BigUint> = ranges.into_iter().flatten().map(BigUint::from).collect();
+        for base in [2, 3, 7, 2047] {
+            let expected: Vec<bool> = numbers.iter().map(|n| is_prp(n, base)).collect();
+            assert_eq!(is_prp_batch(&numbers, base).unwrap(), expected, "base {}", base);
+        }
+    }
+
+    #[test]
+    fn exponents_below_two_are_refused() {
+        for p in [0, 1] {
+            let error = lucas_lehmer(p, false, 0, None).unwrap_err();
+            assert_eq!(error.to_string(), format!("Lucas-Lehmer exponents must be at least 2, got {}.", p));
+            assert!(!lucas_lehmer_cpu(p));
+        }
+    }
+
+    #[test]
+    fn a_tiny_timeout_stops_the_cpu_run_partway() {
+        let options = LucasLehmerOptions { timeout: Some(Duration::from_nanos(1)), ..LucasLehmerOptions::default() };
+        let error = lucas_lehmer_with_threshold(&mut None, 521, &options).unwrap_err();
+        // The first squaring already takes longer than the timeout, and the run stops after it
+        assert_eq!(error.downcast_ref::<MpError>(), Some(&MpError::Timeout { iteration: 1, total: 519 }));
+        assert_eq!(error.to_string(), "timed out at iteration 1 of 519");
+    }
+
+    #[test]
+    fn exponents_past_the_kernel_stay_on_the_cpu() {
+        // Even opting every exponent into the GPU, those past 64 bits never reach OpenCL
+        let options = LucasLehmerOptions { gpu_threshold: 2, ..LucasLehmerOptions::default() };
+        for p in [67, 89, 127] {
+            let result = lucas_lehmer_with_threshold(&mut None, p, &options).unwrap();
+            assert_eq!((result.is_prime, result.device_name), (lucas_lehmer_cpu(p), None), "M{}", p);
+        }
+        let error = lucas_lehmer(127, false, 0, None).unwrap_err();
+        assert!(error.to_string().contains("exceeds the 64-bit Lucas-Lehmer kernel"), "{}", error);
+    }
+
+    #[test]
+    fn llr_finds_the_riesel_primes() {
+        // n with 3 * 2^n - 1 prime, for 2 <= n <= 110
+        let riesel_3 = [2, 3, 4, 6, 7, 11, 18, 34, 38, 43, 55, 64, 76, 94, 103];
+        for n in 2..=110 {
+            assert_eq!(llr(3, n).unwrap(), riesel_3.contains(&n), "3 * 2^{} - 1", n);
+        }
+        for k in (1..64u64).step_by(2) {
+            for n in (2..40).filter(|&n| k < 1 << n) {
+                let riesel = (BigUint::from(k) << n) - 1u32;
+                assert_eq!(llr(k, n).unwrap(), is_bpsw(&riesel), "{} * 2^{} - 1", k, n);
+            }
+        }
+        for p in [61, 89, 107, 127] {
+            assert!(llr(1, p).unwrap(), "M{}", p);
+        }
+        assert!(llr(4, 10).is_err());
+        assert!(llr(9, 3).is_err());
+    }
+
+    /// Keeps every log message, so tests can check what was reported.
+    struct CapturingLogger(std::sync::Mutex<Vec<String>>);
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            self.0.lock().unwrap().push(format!("{} {}", record.level(), record.args()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CapturingLogger = CapturingLogger(std::sync::Mutex::new(Vec::new()));
+
+    #[test]
+    fn checkpoints_are_logged_at_debug_level() {
+        // The logger is global to the test binary, so other tests' messages may be mixed in
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Debug);
+        let path = std::env::temp_dir().join(format!("mp-checkpoint-log-{}.bin", std::process::id()));
+        let state_file = path.to_str().unwrap();
+        save_state(state_file, 42, 1234).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let expected = format!("DEBUG Checkpoint written to {} at iteration 1234", state_file);
+        assert!(LOGGER.0.lock().unwrap().contains(&expected));
+    }
+
+    #[test]
+    fn status_files_are_replaced_with_each_report() {
+        let dir = std::env::temp_dir().join(format!("mp-status-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("status.txt");
+
+        let started = Instant::now() - Duration::from_secs(10);
+        let status = RunStatus::at(127, 25, 125, 0, started);
+        assert_eq!(status.eta.map(|eta| eta.as_secs()), Some(40));
+        write_status(&path, &RunStatus { res64: Some(0xbeef), ..status }).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "exponent 127\niteration 25\ntotal 125\nstate running\neta_seconds 40\nres64 000000000000beef\n"
+        );
+
+        // A resumed run has no pace until it has iterated, and a finished one overwrites it
+        assert_eq!(RunStatus::at(127, 25, 125, 25, Instant::now()).eta, None);
+        report_status(Some(&path), RunStatus { state: "done", ..RunStatus::at(127, 125, 125, 0, started) });
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.contains("iteration 125\n") && text.contains("state done\n"), "{}", text);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn exponents_below_the_threshold_never_touch_opencl() {
+        let mut context = None;
+        let options = LucasLehmerOptions { shift: 5, ..LucasLehmerOptions::default() };
+        for (p, prime) in [(3, true), (7, true), (11, false), (61, true), (64, false)] {
+            let verdict = lucas_lehmer_with_threshold(&mut context, p, &options).unwrap().is_prime;
+            assert_eq!(verdict, prime, "M{}", p);
+        }
+        assert!(context.is_none());
+        assert!(lucas_lehmer_with_threshold(&mut context, 1, &options).is_err());
+    }
+
+    #[test]
+    fn runs_stop_when_interrupted_or_out_of_time() {
+        let interrupted = AtomicBool::new(false);
+        let started = Instant::now();
+        assert_eq!(stop_reason(5, 100, started, None, None, &interrupted), None);
+        let out_of_time = Some(MpError::Timeout { iteration: 1024, total: 2000 });
+        assert_eq!(stop_reason(1024, 2000, started, Some(Duration::ZERO), None, &interrupted), out_of_time);
+        assert_eq!(stop_reason(1024, 2000, started, Some(Duration::from_secs(3600)), None, &interrupted), None);
+        let paused = Some(MpError::Paused { iteration: 1024, total: 2000 });
+        assert_eq!(stop_reason(1024, 2000, started, Some(Duration::ZERO), Some(started), &interrupted), paused);
+        let later = started + Duration::from_secs(3600);
+        assert_eq!(stop_reason(1024, 2000, started, None, Some(later), &interrupted), None);
+
+        interrupted.store(true, Ordering::Relaxed);
+        let stopped = Some(MpError::Interrupted { iteration: 7, total: 100 });
+        assert_eq!(stop_reason(7, 100, started, None, Some(started), &interrupted), stopped);
+        // A run that just finished has nothing left to save
+        assert_eq!(stop_reason(100, 100, started, None, Some(started), &interrupted), None);
+    }
+
+    #[test]
+    fn residue_checkpoints_survive_the_round_trip() {
+        let path = std::env::temp_dir().join(format!("mp-residue-state-{}.bin", std::process::id()));
+        let path = path.to_str().unwrap();
+        assert_eq!(load_residue_state(path).unwrap(), None);
+        let residue = (BigUint::one() << 200u32) - 12345u32;
+        save_residue_state(path, &residue, 9000).unwrap();
+        assert_eq!(load_residue_state(path).unwrap(), Some((9000, residue.clone())));
+        assert_eq!(resume_from(Some(path), BigUint::from(4u32), 9000).unwrap(), (9000, residue));
+        assert!(resume_from(Some(path), BigUint::from(4u32), 8999).is_err());
+        remove_checkpoint(path).unwrap();
+        assert_eq!(resume_from(Some(path), BigUint::from(4u32), 9000).unwrap(), (0, BigUint::from(4u32)));
+    }
+
+    #[test]
+    fn checkpoints_of_other_versions_or_corrupted_are_refused() {
+        let path = std::env::temp_dir().join(format!("mp-versioned-state-{}.bin", std::process::id()));
+        let path = path.to_str().unwrap();
+        save_state(path, 0xdead_beef, 77).unwrap();
+        let valid = std::fs::read(path).unwrap();
+        assert!(valid.starts_with(CHECKPOINT_MAGIC) && valid[8] == CHECKPOINT_VERSION);
+        assert_eq!(load_state(path).unwrap(), Some((0xdead_beef, 77)));
+
+        let mut other_version = valid.clone();
+        other_version[8] = CHECKPOINT_VERSION + 1;
+        std::fs::write(path, &other_version).unwrap();
+        let error = load_state(path).unwrap_err().to_string();
+        assert!(error.contains(&format!("has format version {}", CHECKPOINT_VERSION + 1)), "{}", error);
+
+        let mut corrupted = valid.clone();
+        corrupted[9] ^= 1;
+        std::fs::write(path, &corrupted).unwrap();
+        let error = load_state(path).unwrap_err().to_string();
+        assert!(error.contains("is corrupted (its checksum does not match)"), "{}", error);
+
+        // A headerless file from before the versioned layout is read and rewritten with one
+        std::fs::write(path, &valid[9..33]).unwrap();
+        assert_eq!(load_state(path).unwrap(), Some((0xdead_beef, 77)));
+        assert_eq!(std::fs::read(path).unwrap(), valid);
+        assert_eq!(load_state(path).unwrap(), Some((0xdead_beef, 77)));
+        let mut residue = 77u128.to_le_bytes().to_vec();
+        residue.extend(BigUint::from(0xdead_beefu32).to_bytes_le());
+        std::fs::write(path, &residue).unwrap();
+        assert_eq!(load_residue_state(path).unwrap(), Some((77, BigUint::from(0xdead_beefu32))));
+        assert!(std::fs::read(path).unwrap().starts_with(CHECKPOINT_MAGIC));
+        std::fs::write(path, &valid[..12]).unwrap();
+        assert!(load_state(path).unwrap_err().to_string().contains("is truncated"));
+        remove_checkpoint(path).unwrap();
+    }
+
+    #[test]
+    fn results_describe_the_run_that_reached_them() {
+        let dir = std::env::temp_dir().join(format!("mp-results-{}", std::process::id()));
+        let options = LucasLehmerOptions::default();
+        let result = lucas_lehmer_with_threshold(&mut None, 127, &options).unwrap();
+        assert_eq!((result.p, result.is_prime, result.res64, result.iterations), (127, true, 0, 125));
+        assert_eq!((result.device_name.as_deref(), result.checkpointed, result.resumed_at), (None, false, 0));
+        let result = lucas_lehmer_with_threshold(&mut None, 67, &options).unwrap();
+        assert!(!result.is_prime && result.res64 != 0);
+        assert_eq!(lucas_lehmer_with_threshold(&mut None, 2, &options).unwrap().iterations, 0);
+
+        // A run picking up from a checkpoint says where
+        let path = dir.join("lucas_lehmer_residue_89.bin");
+        let seed = (0..10).fold(BigUint::from(4u32), |s, _| (&s * &s - 2u32) % ((BigUint::one() << 89u32) - 1u32));
+        save_residue_state(path.to_str().unwrap(), &seed, 10).unwrap();
+        let resumed = LucasLehmerOptions { mem: true, checkpoint_dir: Some(dir.clone()), ..options };
+        let resumed = lucas_lehmer_with_threshold(&mut None, 89, &resumed).unwrap();
+        assert!(resumed.is_prime && resumed.checkpointed && !path.exists());
+        assert_eq!((resumed.resumed_at, resumed.iterations), (10, 87));
+        let json = serde_json::to_value(&resumed).unwrap();
+        assert_eq!((&json["p"], &json["is_prime"], &json["checkpointed"]), (&89.into(), &true.into(), &true.into()));
+
+        let result = mersenne_prp_report(89, &[3, 5], &PrpOptions::default()).unwrap();
+        assert!(result.is_probable_prime() && !result.checkpointed);
+        assert_eq!((result.report, result.res64, result.iterations), (MillerRabinReport::ProbablyPrime { rounds: 2 }, Some(1), 176));
+        let result = mersenne_prp_report(67, &[3, 5], &PrpOptions::default()).unwrap();
+        assert_eq!((result.report, result.iterations), (MillerRabinReport::Composite { witness: Some(3) }, 66));
+        let json = serde_json::to_value(&result).unwrap();
+        assert_eq!(json["report"]["Composite"]["witness"], 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn shifted_cpu_runs_checkpoint_other_residues_with_the_same_res64() {
+        let dir = std::env::temp_dir().join(format!("mp-shifted-{}", std::process::id()));
+        let path = residue_state_file(Some(&dir), 29);
+        let mut checkpoints = Vec::new();
+        let mut results = Vec::new();
+        for shift in [0, 17] {
+            let options = LucasLehmerOptions { mem: true, checkpoint_dir: Some(dir.clone()), shift, ..LucasLehmerOptions::default() };
+            let paused = LucasLehmerOptions { deadline: Some(Instant::now()), ..options.clone() };
+            let error = lucas_lehmer_with_threshold(&mut None, 29, &paused).unwrap_err();
+            assert_eq!(error.downcast_ref::<MpError>(), Some(&MpError::Paused { iteration: 1, total: 27 }));
+            checkpoints.push(load_residue_state(&path).unwrap().unwrap());
+            let result = lucas_lehmer_with_threshold(&mut None, 29, &options).unwrap();
+            assert!(result.checkpointed && !result.is_prime);
+            results.push(result.res64);
+        }
+        // 4^2 - 2 = 14 unshifted; shifted by 17, the residue carries 2^34 = 2^5 mod 29
+        assert_eq!(checkpoints[0], (1, BigUint::from(14u32)));
+        assert_eq!(checkpoints[1], (1, BigUint::from(14u32 << 5)));
+        assert_eq!(results[0], results[1]);
+        assert_eq!(results[0], res64(&lucas_lehmer_cpu_residue(29)));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn squaring_runs_pause_with_a_checkpoint_and_resume_from_it() {
+        let path = std::env::temp_dir().join(format!("mp-squaring-run-{}.bin", std::process::id()));
+        let path = path.to_str().unwrap();
+        let p = 521;
+        let started = Instant::now();
+        let run = SquaringRun { total: p - 2, subtract: 2, started, timeout: None, deadline: None, checkpoint: Some(path) };
+        let paused = SquaringRun { deadline: Some(started), ..run };
+        let mut s = squarer(p, &BigUint::from(4u32));
+        let mut completed = 0;
+        let reason = paused.run(&mut *s, &mut completed, |_, _| Ok(())).unwrap();
+        assert_eq!(reason, Some(MpError::Paused { iteration: 1, total: p - 2 }));
+
+        let (resumed_at, initial) = resume_from(Some(path), BigUint::from(4u32), p - 2).unwrap();
+        assert_eq!((resumed_at, initial), (1, BigUint::from(14u32)));
+        let mut s = squarer(p, &BigUint::from(14u32));
+        let mut completed = resumed_at;
+        assert_eq!(run.run(&mut *s, &mut completed, |_, _| Ok(())).unwrap(), None);
+        assert_eq!(completed, p - 2);
+        assert!(s.residue().unwrap().is_zero());
+
+        // A checked run confirms its squarings before saving where it paused
+        let prp = SquaringRun { total: p - 1, subtract: 0, ..paused };
+        let mut x = GerbiczSquarer::new(p, &BigUint::from(3u32), 4, Box::new(move |x: &BigUint| squarer(p, x)));
+        let mut completed = 0;
+        assert!(prp.run(&mut x, &mut completed, |_, _| Ok(())).unwrap().is_some());
+        assert_eq!(resume_from(Some(path), BigUint::from(3u32), p - 1).unwrap(), (1, BigUint::from(9u32)));
+        remove_checkpoint(path).unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs an OpenCL device"]
+    fn interrupted_runs_checkpoint_in_memory_mode() {
+        let state_file = state_file(None, 61);
+        assert_ne!(state_file, super::state_file(None, 31));
+        INTERRUPTED.store(true, Ordering::SeqCst);
+        let result = lucas_lehmer(61, true, 0, None);
+        INTERRUPTED.store(false, Ordering::SeqCst);
+        let error = result.unwrap_err();
+        assert_eq!(error.downcast_ref::<MpError>(), Some(&MpError::Interrupted { iteration: 1, total: 59 }));
+        assert_eq!(load_state(&state_file).unwrap().map(|(_, iteration)| iteration), Some(1));
+        // Resuming picks up from the checkpoint and still finds M61 prime
+        let resumed = lucas_lehmer(61, true, 0, None).unwrap();
+        assert!(resumed.is_prime && resumed.checkpointed && resumed.device_name.is_some());
+        assert_eq!((resumed.resumed_at, resumed.iterations), (1, 59));
+        assert!(!Path::new(&state_file).exists());
+    }
+
+    #[test]
+    fn plans_count_the_iterations_without_testing() {
+        let plan = TestPlan::lucas_lehmer(&[31, 127, 82_589_933]);
+        assert_eq!(plan.count, 3);
+        assert_eq!(plan.iterations, 29 + 125 + 82_589_931);
+        assert_eq!(plan.memory_bytes, 2 * 10_323_742);
+        assert_eq!(plan.too_large, [127, 82_589_933]);
+        assert_eq!(TestPlan::lucas_lehmer(&[]).iterations, 0);
+
+        let plan = TestPlan::prp(&[97, 1 << 64]);
+        assert_eq!((plan.count, plan.iterations, plan.memory_bytes), (2, 7 + 65, 18));
+        assert_eq!(plan.too_large, [1 << 64]);
+    }
+
+    #[test]
+    fn prp_batches_past_64_bits_stay_on_the_cpu() {
+        let m127: BigUint = (BigUint::one() << 127) - 1u32;
+        let m67: BigUint = (BigUint::one() << 67) - 1u32;
+        assert_eq!(is_prp_batch(&[m127.clone(), m67], 3).unwrap(), [true, false]);
+        // A base past 64 bits sends even small numbers to the CPU
+        let numbers: Vec<BigUint> = [97u32, 2047, 4].map(BigUint::from).to_vec();
+        assert_eq!(is_prp_batch(&numbers, u64::MAX as u128 + 2).unwrap(), [true, false, false]);
+        assert_eq!(is_prp_batch(&[], 2).unwrap(), Vec::<bool>::new());
+    }
+
+    #[test]
+    fn strong_lucas_pseudoprimes_below_100000() {
+        let passing: Vec<u32> = (3..100_000u32)
+            .filter(|&n| is_strong_lucas_prp(&BigUint::from(n)) && !is_prime_u64(n as u64))
+            .collect();
+        assert_eq!(passing, [5459, 5777, 10877, 16109, 18971, 22499, 24569, 25199, 40309, 58519, 75077, 97439]);
+        for n in [2u32, 3, 5, 7, 11, 13, 99_991] {
+            assert!(is_strong_lucas_prp(&BigUint::from(n)), "{}", n);
+        }
+        for n in [0u32, 1, 4, 9, 25, 49, 121] {
+            assert!(!is_strong_lucas_prp(&BigUint::from(n)), "{}", n);
+        }
+
+        // None of them is a base-2 strong probable prime, so BPSW rejects them past 2^64 too
+        let m127: BigUint = (BigUint::one() << 127) - 1u32;
+        assert!(is_bpsw(&m127));
+        assert!(!is_bpsw(&(&m127 * 3u32)));
+        assert!(!is_bpsw(&(BigUint::from(4_294_967_311u64) * 4_294_967_311u64)));
+    }
+
+    #[test]
+    fn perfect_powers_are_found_with_their_largest_exponent() {
+        let power = |a: u32, k: u32| Some((BigUint::from(a), k));
+        assert_eq!(is_perfect_power(&BigUint::from(1024u32)), power(2, 10));
+        assert_eq!(is_perfect_power(&BigUint::from(3125u32)), power(5, 5));
+        assert_eq!(is_perfect_power(&BigUint::from(36u32)), power(6, 2));
+        assert_eq!(is_perfect_power(&BigUint::from(4u32)), power(2, 2));
+        assert_eq!(is_perfect_power(&BigUint::from(3u32).pow(80)), power(3, 80));
+        for n in [0u32, 1, 2, 3, 97, 1023, 3126] {
+            assert_eq!(is_perfect_power(&BigUint::from(n)), None, "{}", n);
+        }
+        let m127: BigUint = (BigUint::one() << 127) - 1u32;
+        assert_eq!(is_perfect_power(&m127), None);
+        // A prime square is rejected before any witness is tried
+        assert_eq!(is_prime(&BigUint::from(7919u32 * 7919), PrimeConfig::default()), PrimeVerdict::Composite);
+    }
+
+    #[test]
+    fn is_prime_verdicts_for_each_strategy() {
+        let config = |strategy| PrimeConfig { strategy, ..PrimeConfig::default() };
+        let m127: BigUint = (BigUint::one() << 127) - 1u32;
+        let cases = [
+            // Exact below 2^64, probabilistic past it
+            (Strategy::Deterministic, BigUint::from(97u32), PrimeVerdict::Prime),
+            (Strategy::Deterministic, BigUint::from(2047u32), PrimeVerdict::Composite),
+            (Strategy::Deterministic, m127.clone(), PrimeVerdict::ProbablyPrime),
+            (Strategy::Prp, BigUint::from(97u32), PrimeVerdict::ProbablyPrime),
+            (Strategy::Prp, BigUint::from(2047u32), PrimeVerdict::ProbablyPrime),
+            (Strategy::MillerRabin, BigUint::from(2047u32), PrimeVerdict::Composite),
+            (Strategy::MillerRabin, m127, PrimeVerdict::ProbablyPrime),
+        ];
+        for (strategy, n, verdict) in cases {
+            assert_eq!(is_prime(&n, config(strategy)), verdict, "{:?} on {}", strategy, n);
+        }
+
+        // 0, 1 and perfect powers are composite whatever the strategy
+        for strategy in [Strategy::Prp, Strategy::MillerRabin, Strategy::Deterministic] {
+            for n in [0u32, 1, 1024, 3125] {
+                assert_eq!(is_prime(&BigUint::from(n), config(strategy)), PrimeVerdict::Composite, "{:?} on {}", strategy, n);
+            }
+        }
+    }
+
+    #[test]
+    fn trial_division_agrees_with_the_prp_test() {
+        for n in 0..10_000u128 {
+            let big = BigUint::from(n);
+            // Below 3215031751 no composite passes all of 2, 3, 5 and 7
+            let probably_prime = n >= 2 && [2, 3, 5, 7].iter().filter(|&&base| base % n != 0).all(|&base| is_prp(&big, base));
+            assert_eq!(is_prime_trial(n), probably_prime, "{}", n);
+        }
+        assert!(is_prime_trial(1_000_000_007));
+        assert!(!is_prime_trial(1_000_003 * 1_000_033));
+        assert!(!is_prime_trial(49) && !is_prime_trial(121) && !is_prime_trial(u128::MAX));
+    }
+
+    #[test]
+    fn lucas_lehmer_and_prp_agree_on_small_exponents() {
+        let options = LucasLehmerOptions::default();
+        for p in 2..=130u128 {
+            let check = cross_check(p, &options, Some(4)).unwrap();
+            assert!(check.agrees(), "M{}: {:?}", p, check);
+            let mersenne_prime = [2, 3, 5, 7, 13, 17, 19, 31, 61, 89, 107, 127].contains(&p);
+            assert_eq!(check.lucas_lehmer.is_prime, mersenne_prime, "M{}", p);
+            if mersenne_prime && p > 2 {
+                assert_eq!(check.prp.res64, Some(1), "M{}", p);
+            }
+        }
+
+        let mut wrong = cross_check(127, &options, None).unwrap();
+        wrong.lucas_lehmer.is_prime = false;
+        assert!(!wrong.agrees());
+    }
+
+    #[test]
+    fn test_many_answers_every_number_in_order() {
+        use PrimeVerdict::{Composite, Prime, ProbablyPrime};
+        let numbers: Vec<BigUint> = [97u32, 1, 2047, 2, 561, 7919, 0, 100].into_iter().map(BigUint::from).collect();
+        let verdicts = test_many(&numbers, TestKind::IsPrime(PrimeConfig::default())).unwrap();
+        assert_eq!(verdicts, [Prime, Composite, Composite, Prime, Composite, Prime, Composite, Composite]);
+        // 2047 is a strong pseudoprime to base 2, but not to base 3
+        let verdicts = test_many(&numbers, TestKind::Prp(vec![2])).unwrap();
+        assert_eq!(verdicts, [ProbablyPrime, Composite, ProbablyPrime, ProbablyPrime, Composite, ProbablyPrime, Composite, Composite]);
+        let verdicts = test_many(&numbers, TestKind::Prp(vec![2, 3])).unwrap();
+        assert_eq!(verdicts[2], Composite);
+        assert!(test_many(&numbers, TestKind::Prp(Vec::new())).is_err());
+
+        let exponents: Vec<BigUint> = [61u32, 11, 2, 0, 13, 4, 89].into_iter().map(BigUint::from).collect();
+        let options = LucasLehmerOptions::default();
+        let verdicts = test_many(&exponents, TestKind::LucasLehmer(options)).unwrap();
+        assert_eq!(verdicts, [Prime, Composite, Prime, Composite, Prime, Composite, Prime]);
+        assert_eq!(test_many(&[], TestKind::IsPrime(PrimeConfig::default())).unwrap(), []);
+    }
 }
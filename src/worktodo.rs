@@ -0,0 +1,82 @@
+use std::error::Error;
+use std::path::Path;
+
+/// The kinds of worktodo line whose exponent gets a Lucas-Lehmer test.
+const LUCAS_LEHMER_WORK: [&str; 2] = ["Test", "DoubleCheck"];
+
+/// Reads the exponents of a GIMPS worktodo file in `text`: the lines
+/// `Test=[AID,]exponent[,...]` and `DoubleCheck=[AID,]exponent[,...]`, where AID is the
+/// 32-digit hex assignment ID or N/A and the fields after the exponent (how far it was
+/// trial factored, whether P-1 was done) are skipped. Blank lines and comments starting
+/// with # or ; are skipped too.
+///
+/// # Returns
+///
+/// The exponents in file order, with a warning for every other line, which is ignored.
+pub fn parse(text: &str) -> (Vec<u128>, Vec<String>) {
+    let mut exponents = Vec::new();
+    let mut warnings = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        match exponent(line) {
+            Some(exponent) => exponents.push(exponent),
+            None => warnings.push(format!("ignoring line {}, which is not a Test= or DoubleCheck= assignment: {}", index + 1, line)),
+        }
+    }
+    (exponents, warnings)
+}
+
+/// The exponent of a Lucas-Lehmer assignment line.
+fn exponent(line: &str) -> Option<u128> {
+    let (kind, fields) = line.split_once('=')?;
+    if !LUCAS_LEHMER_WORK.iter().any(|work| work.eq_ignore_ascii_case(kind.trim())) {
+        return None;
+    }
+    let mut fields = fields.split(',').map(str::trim);
+    let first = fields.next()?;
+    let is_assignment_id = first.eq_ignore_ascii_case("N/A") || (first.len() == 32 && first.chars().all(|c| c.is_ascii_hexdigit()));
+    let exponent = if is_assignment_id { fields.next()? } else { first };
+    exponent.parse().ok().filter(|&p| p >= 2)
+}
+
+/// Reads the worktodo file at `path`, as `parse` does, prefixing its warnings with the path.
+pub fn load(path: &Path) -> Result<(Vec<u128>, Vec<String>), Box<dyn Error>> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("Cannot read the worktodo file {}: {}", path.display(), e))?;
+    let (exponents, warnings) = parse(&text);
+    let warnings = warnings.into_iter().map(|warning| format!("{}: {}", path.display(), warning)).collect();
+    Ok((exponents, warnings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assignments_become_exponents_and_other_lines_warnings() {
+        let (exponents, warnings) = parse(
+            "Test=127\n\
+             this is not an assignment\n\
+             \n\
+             # a comment\n\
+             DoubleCheck=0123456789ABCDEF0123456789abcdef,86243,68,1\n\
+             Test=N/A,9689,60,0\n\
+             test = 521 \n\
+             PRP=1,2,3217,-1,75,0\n\
+             Test=banana\n\
+             Test=1\n",
+        );
+        assert_eq!(exponents, [127, 86243, 9689, 521]);
+        assert_eq!(
+            warnings,
+            [
+                "ignoring line 2, which is not a Test= or DoubleCheck= assignment: this is not an assignment",
+                "ignoring line 8, which is not a Test= or DoubleCheck= assignment: PRP=1,2,3217,-1,75,0",
+                "ignoring line 9, which is not a Test= or DoubleCheck= assignment: Test=banana",
+                "ignoring line 10, which is not a Test= or DoubleCheck= assignment: Test=1",
+            ]
+        );
+    }
+}
@@ -0,0 +1,1434 @@
+//! End-to-end checks of the `mersenne-prime` binary.
+
+use std::path::{Path, PathBuf};
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A new empty directory for one run, unique across the tests of this process.
+fn scratch_dir() -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!("mp-cli-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed)));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Runs the binary with `args` and the given extra environment in `dir`, which also stands in
+/// for the config and cache directories.
+fn run_in(dir: &Path, args: &[&str], env: &[(&str, &str)]) -> Output {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_mersenne-prime"));
+    command.args(args).current_dir(dir).env("XDG_CONFIG_HOME", dir).env("XDG_CACHE_HOME", dir);
+    for (key, value) in env {
+        command.env(key, value);
+    }
+    command.output().unwrap()
+}
+
+/// Runs the binary with `args` and the given extra environment in a fresh directory, so
+/// the files it writes stay out of the way.
+fn run_env(args: &[&str], env: &[(&str, &str)]) -> Output {
+    run_in(&scratch_dir(), args, env)
+}
+
+/// Runs the binary with `args` in a fresh directory, feeding it `input` on stdin.
+fn run_with_stdin(args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_mersenne-prime"))
+        .args(args)
+        .current_dir(scratch_dir())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+/// `text` without its `#` comment lines, such as the header of a prime file.
+fn without_comments(text: &str) -> String {
+    text.lines().filter(|line| !line.starts_with('#')).map(|line| format!("{}\n", line)).collect()
+}
+
+fn run(args: &[&str]) -> Output {
+    run_env(args, &[])
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn atkin_and_eratosthenes_write_the_same_primes() {
+    let atkin = run(&["-g", "1", "100000", "--sieve", "atkin"]);
+    let eratosthenes = run(&["-g", "1", "100000", "--sieve", "eratosthenes"]);
+    assert!(atkin.status.success() && eratosthenes.status.success());
+    assert_eq!(stdout(&atkin).lines().count(), 9592);
+    assert_eq!(atkin.stdout, eratosthenes.stdout);
+}
+
+#[test]
+fn whole_range_sieves_refuse_huge_ends() {
+    for sieve in ["atkin", "eratosthenes"] {
+        let output = run(&["-g", "1000000000000", "1000000000100", "--sieve", sieve]);
+        assert!(stderr(&output).contains("use the segmented sieve instead"), "{}", stderr(&output));
+        assert!(stdout(&output).is_empty());
+    }
+}
+
+#[test]
+fn counts_the_primes_below_a_million() {
+    let output = run(&["-g", "2", "1000000", "--count"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output).trim(), "78498");
+}
+
+#[test]
+#[ignore = "needs an OpenCL device"]
+fn gpu_counts_match_the_prime_counting_function() {
+    let output = run(&["-g", "2", "100000000", "--gpu", "--count"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output).trim(), "5761455");
+}
+
+#[test]
+fn writes_78498_primes_below_a_million() {
+    let output = run(&["-g", "2", "1000000"]);
+    assert!(output.status.success());
+    let text = stdout(&output);
+    assert_eq!(text.lines().count(), 78498);
+    assert_eq!(text.lines().next(), Some("2"));
+    assert_eq!(text.lines().last(), Some("999983"));
+}
+
+#[test]
+fn mersenne_candidates_cull_exponents_with_small_factors() {
+    let output = run(&["-g", "2", "30", "--mersenne-candidates"]);
+    assert!(output.status.success());
+    let text = stdout(&output);
+    assert!(text.contains("11: not worth testing (factor 23)\n"), "{}", text);
+    assert!(text.contains("13: worth testing\n"), "{}", text);
+    assert!(text.contains("29: not worth testing (factor 233)\n"), "{}", text);
+    assert_eq!(text.lines().count(), 10);
+}
+
+#[test]
+fn factor_bounds_pick_up_where_a_sweep_left_off() {
+    let sweep = |bounds: &[&str]| stdout(&run(&[&["-g", "29", "30", "--mersenne-candidates"][..], bounds].concat()));
+    assert_eq!(sweep(&["--max-factor", "200"]), "29: worth testing (no factor up to 175)\n");
+    assert_eq!(sweep(&["--min-factor", "176", "--max-factor", "1000"]), "29: not worth testing (factor 233)\n");
+    assert_eq!(sweep(&["--min-factor", "234", "--max-factor", "1100"]), "29: worth testing (no factor up to 1045)\n");
+    assert_eq!(sweep(&["--min-factor", "1046"]), "29: not worth testing (factor 1103)\n");
+    assert_eq!(sweep(&["--min-factor", "60", "--max-factor", "110"]), "29: worth testing (no candidates in range)\n");
+    assert_eq!(run(&["-g", "29", "30", "--min-factor", "60"]).status.code(), Some(2));
+}
+
+#[test]
+fn prp_verdicts_for_small_numbers() {
+    let output = run(&["-p", "2", "3", "4", "5", "9"]);
+    assert!(output.status.success());
+    let verdicts: Vec<String> = stdout(&output).lines().take(5).map(str::to_string).collect();
+    assert_eq!(verdicts, ["2: Probably prime", "3: Probably prime", "4: Probably not prime", "5: Probably prime", "9: Probably not prime"]);
+}
+
+#[test]
+#[ignore = "needs an OpenCL device"]
+fn gpu_and_cpu_sieves_write_identical_files() {
+    let dir = scratch_dir();
+    for (method, file) in [("--cpu", "cpu.txt"), ("--gpu", "gpu.txt")] {
+        let output = run_in(&dir, &["-g", "1", "1000000", method, "-o", file, "--no-header"], &[]);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    }
+    let cpu = std::fs::read(dir.join("cpu.txt")).unwrap();
+    assert_eq!(cpu.iter().filter(|&&byte| byte == b'\n').count(), 78498);
+    assert!(cpu == std::fs::read(dir.join("gpu.txt")).unwrap());
+}
+
+#[test]
+fn sieve_output_lists_smallest_factors() {
+    let output = run(&["-g", "10", "16", "--sieve-output"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "10 2\n11 1\n12 2\n13 1\n14 2\n15 3\n");
+    // Past 2^64 the base primes miss factors, so nothing is reported as prime
+    let output = run(&["-g", "18446744400127067027", "18446744400127067028", "--sieve-output"]);
+    assert_eq!(stdout(&output), "");
+    assert!(stderr(&output).contains("exceeds the 64-bit limit"), "{}", stderr(&output));
+}
+
+#[test]
+fn nth_prints_the_millionth_prime() {
+    let output = run(&["--nth", "1000000"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output).trim(), "15485863");
+    let output = run(&["--nth", "1", "--after", "89"]);
+    assert_eq!(stdout(&output).trim(), "97");
+    let output = run(&["--nth", "1", "--after", "340282366920938463463374607431768211455"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("beyond the 64-bit sieve limit"), "{}", stderr(&output));
+}
+
+#[test]
+fn next_and_prev_find_the_neighbouring_primes() {
+    let output = run(&["--next", "1000000000"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output).trim(), "1000000007");
+    let output = run(&["--prev", "1000000000", "--verbose"]);
+    assert_eq!(stdout(&output).trim(), "999999937");
+    assert!(stderr(&output).contains("Examined 63 candidates"), "{}", stderr(&output));
+    let output = run(&["--prev", "2"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("There is no prime below 2."));
+}
+
+#[test]
+fn from_list_skips_comments_and_blank_lines() {
+    let dir = scratch_dir();
+    std::fs::write(dir.join("list.txt"), "# Exponents to check\n\n  97\n# 2047 is a base-2 pseudoprime\n2047\t\n\n   \n100\n").unwrap();
+    let output = run_in(&dir, &["-p", "-f", "list.txt", "--quiet"], &[]);
+    assert!(output.status.success());
+    assert!(stderr(&output).is_empty(), "{}", stderr(&output));
+    let text = stdout(&output);
+    let verdicts: Vec<&str> = text.lines().skip(1).collect();
+    assert_eq!(verdicts, ["97: Probably prime", "2047: Probably prime", "100: Probably not prime"]);
+}
+
+#[test]
+fn verdict_files_split_a_list_into_primes_and_composites() {
+    let dir = scratch_dir();
+    std::fs::write(dir.join("list.txt"), "2\n11\n13\n23\n31\n").unwrap();
+    let output = run_in(&dir, &["-l", "-q", "-f", "list.txt", "--primes-out", "primes.txt", "--composites-out", "composites.txt"], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(std::fs::read_to_string(dir.join("primes.txt")).unwrap(), "2\n13\n31\n");
+    assert_eq!(std::fs::read_to_string(dir.join("composites.txt")).unwrap(), "11\n23\n");
+
+    std::fs::write(dir.join("list.txt"), "97\n100\n2047\n7919\n9\n").unwrap();
+    let output = run_in(&dir, &["-p", "-q", "-f", "list.txt", "--composites-out", "composites.txt"], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    // The verdicts of the second run follow those of the first
+    assert_eq!(std::fs::read_to_string(dir.join("composites.txt")).unwrap(), "11\n23\n100\n9\n");
+    assert_eq!(std::fs::read_to_string(dir.join("primes.txt")).unwrap(), "2\n13\n31\n");
+}
+
+#[test]
+fn twins_lists_the_pairs_below_1000() {
+    let output = run(&["-g", "1", "1000", "--twins"]);
+    assert!(output.status.success());
+    let text = stdout(&output);
+    assert_eq!(text.lines().count(), 35);
+    assert!(text.starts_with("3 5\n5 7\n11 13\n"), "{}", text);
+    assert!(text.ends_with("881 883\n"));
+
+    let output = run(&["-g", "1", "20", "--twins", "--format", "json"]);
+    assert_eq!(stdout(&output), "[3, 5]\n[5, 7]\n[11, 13]\n[17, 19]\n");
+    // 29 is in the range, so its twin 31 past the end is found too
+    let output = run(&["-g", "1", "30", "--twins"]);
+    assert_eq!(stdout(&output), "3 5\n5 7\n11 13\n17 19\n29 31\n");
+    let output = run(&["-g", "17", "29", "--twins"]);
+    assert_eq!(stdout(&output), "17 19\n");
+
+    let output = run(&["-g", "1", "200", "--constellation", "0,2,6", "--format", "csv"]);
+    assert_eq!(stdout(&output), "p+0,p+2,p+6\n5,7,11\n11,13,17\n17,19,23\n41,43,47\n101,103,107\n107,109,113\n191,193,197\n");
+    let output = run(&["-g", "1", "200", "--constellation", "0,6,2"]);
+    assert!(stderr(&output).contains("offsets must start at 0 and ascend"), "{}", stderr(&output));
+
+    // The partners of a tuple fall outside the residue classes of its first prime
+    for args in [&["--twins"][..], &["--constellation", "0,2,6"]] {
+        for tuples in [&["-g", "1", "200"][..], &["gen", "1", "200"]] {
+            let output = run(&[tuples, args, &["--mod", "4", "--residue", "1"]].concat());
+            assert_eq!(output.status.code(), Some(2), "{:?}", args);
+        }
+    }
+}
+
+#[test]
+fn verify_known_checks_every_mersenne_prime_up_to_the_bound() {
+    let output = run(&["--verify-known"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    let expected: String = [2, 3, 5, 7, 13, 17, 19, 31, 61, 89, 107, 127].iter().map(|p| format!("M{}: prime\n", p)).collect();
+    assert_eq!(stdout(&output), expected + "12 of 12 known exponents verified\n");
+
+    let output = run(&["--verify-known", "4500"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert!(stdout(&output).ends_with("M4423: prime\n20 of 20 known exponents verified\n"), "{}", stdout(&output));
+    assert!(!stdout(&output).contains("FAILED"), "{}", stdout(&output));
+}
+
+#[test]
+fn dry_run_plans_without_testing() {
+    let dir = scratch_dir();
+    let output = run_in(&dir, &["-l", "--dry-run", "31", "61"], &[]);
+    assert!(output.status.success());
+    let text = stdout(&output);
+    assert!(text.starts_with("Dry run of 2 Lucas-Lehmer tests"), "{}", text);
+    assert!(text.contains("Iterations: 88\n"), "{}", text);
+    assert!(stderr(&output).is_empty(), "{}", stderr(&output));
+    // A real run prints its verdicts, or reports that no kernel could be built
+    assert!(!text.contains("Mersenne prime"), "{}", text);
+
+    let output = run(&["-l", "--dry-run", "82589933"]);
+    assert!(stdout(&output).contains("Warning: 82589933 does not fit the 64-bit kernel and would run on the CPU"));
+}
+
+#[test]
+fn sophie_germain_and_safe_filters_match_normal_output() {
+    let output = run(&["-g", "1", "60", "--sophie-germain"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "2\n3\n5\n11\n23\n29\n41\n53\n");
+    let output = run(&["-g", "1000", "100000", "--safe", "--count"]);
+    assert_eq!(stdout(&output).trim(), "645");
+
+    let dir = scratch_dir();
+    let output = run_in(&dir, &["-g", "1", "60", "--safe", "-o", "safe.txt", "--no-header"], &[]);
+    assert!(output.status.success());
+    assert_eq!(std::fs::read_to_string(dir.join("safe.txt")).unwrap(), "5\n7\n11\n23\n47\n59\n");
+}
+
+#[test]
+fn batch_runs_end_with_a_summary_table() {
+    let output = run(&["-p", "97", "2047", "100", "7919"]);
+    assert!(output.status.success());
+    let text = stdout(&output);
+    let table: Vec<&str> = text.lines().skip_while(|line| !line.starts_with("Number")).collect();
+    assert_eq!(table.len(), 7, "{}", text);
+    assert!(table[1].starts_with("97      probable prime"), "{}", text);
+    assert!(table[3].starts_with("100     composite"), "{}", text);
+    assert!(table[5].starts_with("Total: 4 tested, 3 prime, 1 composite, 0 errors, "), "{}", text);
+    let slowest: Vec<&str> = table[6].strip_prefix("Slowest: ").unwrap().split(", ").map(|entry| entry.split(" in ").next().unwrap()).collect();
+    assert_eq!(slowest.iter().copied().collect::<std::collections::BTreeSet<_>>(), ["100", "2047", "7919", "97"].into());
+
+    let numbers: Vec<String> = (1000..1012).map(|n| n.to_string()).collect();
+    let args: Vec<&str> = ["-l", "--format", "json"].into_iter().chain(numbers.iter().map(String::as_str)).collect();
+    let output = run(&args);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let summary: serde_json::Value = serde_json::from_str(stdout(&output).lines().last().unwrap()).unwrap();
+    let summary = &summary["summary"];
+    assert_eq!((summary["tested"].as_u64(), summary["prime"].as_u64(), summary["composite"].as_u64()), (Some(12), Some(0), Some(12)));
+    assert_eq!(summary["error"], 0);
+    let slowest = summary["slowest"].as_array().unwrap();
+    assert_eq!(slowest.len(), 5);
+    let seconds: Vec<f64> = slowest.iter().map(|entry| entry["seconds"].as_f64().unwrap()).collect();
+    assert!(seconds.windows(2).all(|pair| pair[0] >= pair[1]), "{:?}", seconds);
+    assert!(summary["seconds"].as_f64().unwrap() >= seconds.iter().sum::<f64>() - 1e-5, "{}", summary);
+
+    let output = run(&["-p", "97", "2047", "--quiet"]);
+    assert_eq!(stdout(&output), "97: Probably prime\n2047: Probably prime\n");
+}
+
+#[test]
+fn gaps_report_records_and_filters() {
+    let output = run(&["-g", "1", "100", "--gaps"]);
+    assert!(output.status.success());
+    let text = stdout(&output);
+    assert!(text.starts_with("Maximal gaps:\n  1 after 2\n  2 after 3\n  4 after 7\n  6 after 23\n  8 after 89\nHistogram:\n"), "{}", text);
+    assert!(text.contains("  2: 8\n"), "{}", text);
+    assert!(text.ends_with("Average gap: 3.958 over 24 gaps\n"), "{}", text);
+
+    let output = run(&["-g", "9000", "20000", "--gaps", "--min-gap", "36"]);
+    let text = stdout(&output);
+    assert!(text.starts_with("Gap 36 after 9551\n"), "{}", text);
+    assert!(text.contains("Maximal gaps:\n  36 after 9551\n  44 after 15683\n  52 after 19609\nHistogram:\n"), "{}", text);
+}
+
+#[test]
+fn stats_follow_the_primes_of_a_range() {
+    let output = run(&["-g", "1", "100", "--stats"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output).lines().count(), 25);
+    assert_eq!(
+        stderr(&output),
+        "Primes: 25 (x / ln x estimates 21.7)\nLargest gap: 8 between 89 and 97\nAverage gap: 3.958 over 24 gaps\nTwin pairs: 8\n"
+    );
+
+    let output = run(&["-g", "1", "100", "--stats", "--twins"]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn max_gpu_mem_takes_sizes_with_units() {
+    for size in ["2GiB", "512 MiB", "1g", "4096", "3KB"] {
+        let output = run(&["-g", "1", "100", "--count", "--max-gpu-mem", size]);
+        assert_eq!(stdout(&output), "25\n", "{}: {}", size, stderr(&output));
+    }
+    for size in ["0", "2XB", "GiB", "99999999999TiB"] {
+        let output = run(&["-g", "1", "100", "--count", "--max-gpu-mem", size]);
+        assert_eq!(output.status.code(), Some(2), "{}", size);
+        assert!(stderr(&output).contains("expected a size like 2GiB"), "{}", stderr(&output));
+    }
+}
+
+#[test]
+fn preallocate_results_sizes_the_sieve_without_changing_its_primes() {
+    for preallocate in ["auto", "none", "5", "100000", "999999999999999999"] {
+        let output = run(&["-g", "1", "100", "--sieve", "atkin", "--count", "--preallocate-results", preallocate]);
+        assert_eq!(stdout(&output), "25\n", "{}: {}", preallocate, stderr(&output));
+    }
+    let output = run(&["-g", "1", "100", "--sieve", "atkin", "--preallocate-results", "lots"]);
+    assert!(stderr(&output).contains("expected auto, none or a count of primes"), "{}", stderr(&output));
+    assert_eq!(run(&["-g", "1", "100", "--preallocate-results", "none"]).status.code(), Some(2));
+}
+
+#[test]
+fn local_size_is_a_positive_size_for_the_fermat_kernel() {
+    for args in [
+        &["-g", "1", "100", "--fermat", "--local-size", "0"][..],
+        &["-g", "1", "100", "--fermat", "--local-size", "64", "--tune"],
+        &["-g", "1", "100", "--local-size", "64"],
+    ] {
+        let output = run(args);
+        assert_eq!(output.status.code(), Some(2), "{:?}: {}", args, stderr(&output));
+    }
+}
+
+#[test]
+fn mod_and_residue_keep_one_progression() {
+    let output = run(&["-g", "1", "100000", "--mod", "4", "--residue", "1", "--count"]);
+    assert_eq!(stdout(&output).trim(), "4783");
+    let output = run(&["-g", "1", "100000", "--mod", "4", "--residue", "3", "--count"]);
+    assert_eq!(stdout(&output).trim(), "4808");
+    let output = run(&["-g", "1", "100000", "--mod", "10", "--residue", "3,7", "--count", "--sieve", "atkin"]);
+    assert_eq!(stdout(&output).trim(), "4813");
+    let output = run(&["-g", "1", "50", "--mod", "4", "--residue", "5"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Residue 5 is not below the modulus 4."), "{}", stderr(&output));
+}
+
+#[test]
+fn result_file_is_only_written_on_request() {
+    // M2 is settled without a kernel, so this runs without an OpenCL device
+    let dir = scratch_dir();
+    let output = run_in(&dir, &["-l", "2", "--quiet"], &[]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "3 is a Mersenne prime.\n");
+    assert!(!dir.join("out.txt").exists());
+
+    let output = run_in(&dir, &["-l", "2", "--quiet", "--result-file", "results.txt"], &[]);
+    assert!(output.status.success());
+    assert_eq!(std::fs::read_to_string(dir.join("results.txt")).unwrap(), "2^2-1 is a Mersenne prime (res64 0000000000000000).\n");
+    assert!(!dir.join("out.txt").exists());
+
+    let output = run_in(&dir, &["-l", "2", "--quiet", "--format", "json"], &[]);
+    assert_eq!(stdout(&output), "{\"exponent\":2,\"mersenne_prime\":true}\n");
+}
+
+#[test]
+fn test_results_append_to_the_output_file_with_their_numbers() {
+    let dir = scratch_dir();
+    let output = run_in(&dir, &["-l", "2", "7", "11", "--quiet", "-o", "results.txt"], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "3 is a Mersenne prime.\n127 is a Mersenne prime.\n2047 is not a Mersenne prime.\n");
+    let output = run_in(&dir, &["-p", "7", "8", "--quiet", "-o", "results.txt"], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        std::fs::read_to_string(dir.join("results.txt")).unwrap(),
+        "2^2-1 is a Mersenne prime (res64 0000000000000000).\n\
+         2^7-1 is a Mersenne prime (res64 0000000000000000).\n\
+         2^11-1 is not a Mersenne prime (res64 00000000000006c8).\n\
+         7: Probably prime\n\
+         8: Probably not prime\n"
+    );
+    assert!(!dir.join("out.txt").exists());
+
+    let output = run_in(&dir, &["-l", "2", "--compress", "-o", "results.txt"], &[]);
+    assert!(stderr(&output).contains("--compress only applies to the -o file of -g"), "{}", stderr(&output));
+
+    // Users of versions that wrote out.txt hear once that it is no longer written
+    std::fs::write(dir.join("out.txt"), "3 is a Mersenne prime.\n").unwrap();
+    let notice = "Note: results are no longer written to out.txt; pass -o FILE to append them to a file.";
+    let output = run_in(&dir, &["-l", "2", "--quiet"], &[]);
+    assert!(stderr(&output).contains(notice), "{}", stderr(&output));
+    let output = run_in(&dir, &["-l", "2", "--quiet"], &[]);
+    assert!(output.status.success());
+    assert!(!stderr(&output).contains(notice), "{}", stderr(&output));
+    assert_eq!(std::fs::read_to_string(dir.join("out.txt")).unwrap(), "3 is a Mersenne prime.\n");
+}
+
+#[test]
+fn small_exponents_run_on_the_cpu_by_default() {
+    let started = std::time::Instant::now();
+    let output = run(&["-l", "-q", "7", "11", "61"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        stdout(&output),
+        "127 is a Mersenne prime.\n2047 is not a Mersenne prime.\n2305843009213693951 is a Mersenne prime.\n"
+    );
+    assert!(!stderr(&output).contains("platform"), "{}", stderr(&output));
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+}
+
+#[test]
+fn concurrent_jobs_report_in_input_order() {
+    // The long run comes first, so the others finish before it with several jobs
+    let args = ["-l", "-q", "2281", "11", "521", "23", "607", "1279"];
+    let sequential = run(&args);
+    assert!(sequential.status.success(), "{}", stderr(&sequential));
+    assert_eq!(stdout(&sequential).lines().count(), 6);
+    let concurrent = run(&[&args[..], &["--jobs", "4"]].concat());
+    assert!(concurrent.status.success(), "{}", stderr(&concurrent));
+    assert_eq!(stdout(&concurrent), stdout(&sequential));
+
+    let output = run(&["-l", "2", "--jobs", "0"]);
+    assert!(!output.status.success());
+}
+
+#[test]
+#[ignore = "needs several CPU cores"]
+fn concurrent_jobs_take_less_wall_time() {
+    let args = ["-l", "-q", "4253", "4423", "3217", "4253"];
+    let started = std::time::Instant::now();
+    let sequential = run(&args);
+    let sequential_time = started.elapsed();
+    let started = std::time::Instant::now();
+    let concurrent = run(&[&args[..], &["--jobs", "4"]].concat());
+    assert!(started.elapsed() < sequential_time, "{:?} vs {:?}", started.elapsed(), sequential_time);
+    assert_eq!(stdout(&concurrent), stdout(&sequential));
+}
+
+#[test]
+fn chunked_progress_leaves_the_final_status_behind() {
+    let dir = scratch_dir();
+    let args = ["-l", "-q", "4423", "--chunked-progress", "status.txt"];
+    let output = run_in(&dir, &args, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let status = std::fs::read_to_string(dir.join("status.txt")).unwrap();
+    assert!(status.starts_with("exponent 4423\niteration 4421\ntotal 4421\nstate done\n"), "{}", status);
+    assert!(!dir.join("status.txt.tmp").exists());
+
+    let output = run(&["-l", "7", "--jobs", "2", "--chunked-progress", "status.txt"]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn batch_sizes_must_be_positive() {
+    let output = run(&["-l", "-q", "7", "--batch-size", "0"]);
+    assert_eq!(output.status.code(), Some(2));
+    let output = run(&["-l", "-q", "7", "--batch-size", "3"]);
+    assert_eq!(stdout(&output), "127 is a Mersenne prime.\n");
+}
+
+#[test]
+fn the_ntt_backend_is_an_ll_option() {
+    assert_eq!(run(&["-p", "-q", "7", "--backend", "ntt"]).status.code(), Some(2));
+    assert_eq!(run(&["-l", "-q", "7", "--self-check", "100"]).status.code(), Some(2));
+    // Below the GPU threshold the backend never comes into it
+    let output = run(&["-l", "-q", "7", "--backend", "ntt"]);
+    assert_eq!(stdout(&output), "127 is a Mersenne prime.\n", "{}", stderr(&output));
+    let output = run(&["-l", "-q", "89", "--backend", "ntt", "--gpu-threshold", "3", "--shift", "5"]);
+    assert!(stderr(&output).contains("The NTT backend does not support --shift."), "{}", stderr(&output));
+}
+
+#[test]
+fn expected_residues_match_or_fail_the_run() {
+    // s_9 mod 2047 is 1736
+    let output = run(&["-l", "-q", "11", "--expected-residue", "0x6C8"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "2047 is not a Mersenne prime.\nM11 res64 00000000000006c8: MATCH\n");
+    let output = run(&["-l", "-q", "7", "11", "--expected-residue", "0"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stdout(&output).contains("M7 res64 0000000000000000: MATCH\n"), "{}", stdout(&output));
+    assert!(stdout(&output).contains("M11 res64 00000000000006c8: MISMATCH (expected 0000000000000000)\n"));
+    // A test that fails has no residue to match, which fails the run too; here a directory
+    // stands where M13's checkpoint goes
+    let dir = scratch_dir();
+    std::fs::create_dir(dir.join("lucas_lehmer_residue_13.bin")).unwrap();
+    let output = run_in(&dir, &["-l", "-q", "-m", "7", "13", "--expected-residue", "0"], &[]);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "127 is a Mersenne prime.\nM7 res64 0000000000000000: MATCH\n");
+    assert!(stderr(&output).contains("Error testing 13: "), "{}", stderr(&output));
+    let output = run_in(&dir, &["-l", "-q", "-m", "7", "13"], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    // A PRP residue is 3^(n-1) mod n, 1 for a prime
+    let output = run(&["--prp-mersenne", "31", "--expected-residue", " 0000000000000001 "]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).ends_with("M31 res64 0000000000000001: MATCH\n"), "{}", stdout(&output));
+    assert_eq!(run(&["--prp-mersenne", "29", "--expected-residue", "1"]).status.code(), Some(1));
+    for bad in ["", "0x", "12345678901234567", "6c8g"] {
+        assert_eq!(run(&["-l", "-q", "11", "--expected-residue", bad]).status.code(), Some(2), "{:?}", bad);
+    }
+    assert_eq!(run(&["-p", "-q", "7", "--expected-residue", "1"]).status.code(), Some(2));
+}
+
+#[test]
+fn time_limits_pause_with_a_checkpoint_that_the_same_command_resumes() {
+    let dir = scratch_dir();
+    // A limit that is already up is deterministic however fast the machine is: the batch
+    // starts nothing, and a run already under way pauses at its first check
+    let args = ["-l", "-q", "9689", "--time-limit", "0s"];
+    let output = run_in(&dir, &args, &[]);
+    assert_eq!(output.status.code(), Some(75), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Time limit reached after 0 of 1 exponents, resume with the same command."), "{}", stderr(&output));
+    let output = run_in(&dir, &[&args[..4], &["1h"]].concat(), &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).ends_with(" is a Mersenne prime.\n"), "{}", stdout(&output));
+
+    let args = ["--prp-mersenne", "9689", "--time-limit", "0s"];
+    let output = run_in(&dir, &args, &[]);
+    assert_eq!(output.status.code(), Some(75), "{}", stderr(&output));
+    assert!(stderr(&output).contains("paused at iteration 1 of 9688, resume with the same command."), "{}", stderr(&output));
+    assert!(dir.join("prp_state_9689_3.bin").exists());
+    let output = run_in(&dir, &[&args[..3], &["1h 30min", "-v"]].concat(), &[]);
+    assert!(stderr(&output).contains("Resuming from the checkpoint in prp_state_9689_3.bin at iteration 1\n"), "{}", stderr(&output));
+    assert!(stdout(&output).starts_with("2^9689-1 is probably prime"), "{}", stdout(&output));
+    assert!(!dir.join("prp_state_9689_3.bin").exists());
+
+    for bad in ["", "3", "3x", "h", "1h-5m"] {
+        assert_eq!(run(&["-l", "-q", "7", "--time-limit", bad]).status.code(), Some(2), "{:?}", bad);
+    }
+    assert_eq!(run(&["-p", "-q", "7", "--time-limit", "1h"]).status.code(), Some(2));
+}
+
+#[test]
+fn runs_off_a_terminal_print_their_rate_as_status_lines() {
+    // Test output is captured, so stderr is a pipe. The last squaring is always sampled, and
+    // with an interval this short it always gets a line, however quickly the run went
+    let args = ["-l", "-q", "521", "--status-interval", "1ns"];
+    let output = run(&args);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let text = stderr(&output);
+    let lines: Vec<&str> = text.lines().filter(|line| line.starts_with("M521: iteration ")).collect();
+    assert!(lines.last().is_some_and(|line| line.starts_with("M521: iteration 519 of 519 (100.0%), ")), "{}", text);
+    for line in lines {
+        let (position, summary) = line["M521: iteration ".len()..].split_once(", ").unwrap();
+        assert!(position.contains(" of 519 (") && position.ends_with("%)"), "{}", line);
+        let fields: Vec<&str> = summary.split(", ").collect();
+        assert_eq!(fields.len(), 3, "{}", line);
+        assert!(fields[0].ends_with(" it/s now") && fields[1].ends_with(" it/s average"), "{}", line);
+        assert!(fields[2].starts_with("ETA "), "{}", line);
+    }
+    let output = run(&[&args[..3], &["--status-interval", "0s"]].concat());
+    assert!(!stderr(&output).contains("M521: iteration "), "{}", stderr(&output));
+}
+
+#[test]
+fn log_files_record_checkpoints_and_verdicts_with_timestamps() {
+    let dir = scratch_dir();
+    // A cross-check with a limit that is already up pauses its Lucas-Lehmer run at the first
+    // squaring, leaving a checkpoint that -m picks up
+    let output = run_in(&dir, &["--cross-check", "9689", "--time-limit", "0s", "--log-file", "run.log"], &[]);
+    assert_eq!(output.status.code(), Some(75), "{}", stderr(&output));
+    let output = run_in(&dir, &["-l", "-q", "-m", "11", "9689", "--log-file", "run.log"], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    // Without -v the info lines go to the file only
+    assert!(!stderr(&output).contains("INFO"), "{}", stderr(&output));
+
+    let log = std::fs::read_to_string(dir.join("run.log")).unwrap();
+    for line in log.lines() {
+        let (timestamp, rest) = line.split_once(' ').unwrap();
+        assert!(timestamp.len() == 20 && timestamp.ends_with('Z') && timestamp.as_bytes()[10] == b'T', "{}", line);
+        assert!(["ERROR ", "WARN ", "INFO ", "DEBUG ", "TRACE "].iter().any(|level| rest.starts_with(level)), "{}", line);
+    }
+    let position = |event: &str| log.find(event).unwrap_or_else(|| panic!("no {:?} in\n{}", event, log));
+    position("INFO  mersenne_prime: M11 is composite, res64 00000000000006c8");
+    let events = [
+        "DEBUG test_prime: Checkpoint written to lucas_lehmer_residue_9689.bin at iteration 1\n",
+        "ERROR mersenne_prime: Cross-check of 2^9689-1 paused at iteration 1 of 9687",
+        "INFO  test_prime: Resuming from the checkpoint in lucas_lehmer_residue_9689.bin at iteration 1\n",
+        "INFO  mersenne_prime: M9689 is prime, res64 0000000000000000",
+    ];
+    for pair in events.windows(2) {
+        assert!(position(pair[0]) < position(pair[1]), "{:?} before {:?} in\n{}", pair[0], pair[1], log);
+    }
+}
+
+#[test]
+fn batches_never_clear_the_terminal() {
+    // --color always lets a run clear the screen as it would a terminal
+    let dir = scratch_dir();
+    std::fs::write(dir.join("exponents.txt"), "61\n89\n").unwrap();
+    let clears = |args: &[&str]| {
+        let output = run_in(&dir, &[&["--color", "always", "ll", "-q"], args].concat(), &[]);
+        assert!(output.status.success(), "{:?}: {}", args, stderr(&output));
+        output.stdout.windows(4).any(|bytes| bytes == b"\x1B[2J")
+    };
+    assert!(clears(&["127"]));
+    assert!(!clears(&["61", "89", "107"]));
+    assert!(!clears(&["--from-list", "exponents.txt"]));
+    assert!(!clears(&["--no-clear", "127"]));
+    assert!(!clears(&["--format", "json", "127"]));
+}
+
+/// The config file fixture the configuration tests read.
+fn config_fixture() -> String {
+    format!("{}/tests/fixtures/config.toml", env!("CARGO_MANIFEST_DIR"))
+}
+
+#[test]
+fn flags_override_variables_which_override_the_config_file() {
+    let config = config_fixture();
+    let setting = |output: &Output, key: &str| {
+        let text = stdout(output);
+        let prefix = format!("{} = ", key);
+        text.lines().find(|line| line.starts_with(&prefix)).map(String::from).unwrap_or_else(|| panic!("{}", text))
+    };
+    let output = run(&["--config", &config, "--print-config"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("ignoring the unknown key device.colour"), "{}", stderr(&output));
+    assert_eq!(setting(&output, "program_cache"), r#"program_cache = "cache-from-config"  # config file"#);
+    assert_eq!(setting(&output, "bases"), "bases = [2, 3]  # config file");
+    assert_eq!(setting(&output, "password"), r#"password = "********"  # config file"#);
+    assert!(!stdout(&output).contains("not-a-real-password"), "{}", stdout(&output));
+
+    let env = [("MP_PROGRAM_CACHE", "cache-from-env"), ("MP_PRIMENET_USER", "someone")];
+    let output = run_env(&["--config", &config, "--print-config"], &env);
+    assert_eq!(setting(&output, "program_cache"), r#"program_cache = "cache-from-env"  # MP_PROGRAM_CACHE"#);
+    assert_eq!(setting(&output, "user"), r#"user = "someone"  # MP_PRIMENET_USER"#);
+
+    let output = run_env(&["--config", &config, "--print-config", "--program-cache", "cache-from-flag"], &env);
+    assert_eq!(setting(&output, "program_cache"), r#"program_cache = "cache-from-flag"  # command line"#);
+    // Options given to a subcommand count as well
+    let output = run(&["--config", &config, "prp", "--bases", "5", "7", "--print-config"]);
+    assert_eq!(setting(&output, "bases"), "bases = [5]  # command line");
+
+    // Without --config, the file under XDG_CONFIG_HOME is read if there is one
+    let dir = scratch_dir();
+    std::fs::create_dir_all(dir.join("mp")).unwrap();
+    std::fs::copy(&config, dir.join("mp/config.toml")).unwrap();
+    assert_eq!(setting(&run_in(&dir, &["--print-config"], &[]), "interval"), "interval = 1000  # config file");
+    std::fs::write(dir.join("broken.toml"), "[checkpoint]\ninterval = \"often\"\n").unwrap();
+    let output = run_in(&dir, &["--config", "broken.toml", "-p", "7"], &[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("checkpoint.interval must be a non-negative integer"), "{}", stderr(&output));
+}
+
+#[test]
+fn config_settings_apply_to_runs() {
+    let dir = scratch_dir();
+    let config = config_fixture();
+    let output = run_in(&dir, &["--config", &config, "--cross-check", "4423", "--time-limit", "0s"], &[]);
+    assert_eq!(output.status.code(), Some(75), "{}", stderr(&output));
+    assert!(dir.join("checkpoints/lucas_lehmer_residue_4423.bin").exists());
+    let output = run_in(&dir, &["--config", &config, "-l", "-q", "-v", "4423"], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Resuming from the checkpoint in checkpoints/lucas_lehmer_residue_4423.bin at iteration 1\n"), "{}", stderr(&output));
+    assert_eq!(std::fs::read_to_string(dir.join("results.txt")).unwrap(), "2^4423-1 is a Mersenne prime (res64 0000000000000000).\n");
+
+    // The bases from the file make -p run two rounds, but don't count as --bases where -g
+    // has no use for them
+    let output = run_in(&dir, &["--config", &config, "-p", "-q", "-v", "7"], &[]);
+    assert!(stdout(&output).starts_with("7: Probably prime (2 rounds passed"), "{}", stdout(&output));
+    let output = run_in(&dir, &["--config", &config, "-g", "1", "10"], &[]);
+    assert_eq!(stdout(&output), "2\n3\n5\n7\n", "{}", stderr(&output));
+    // A --base-file replaces them instead of adding to them
+    std::fs::write(dir.join("bases.txt"), "5\n").unwrap();
+    let output = run_in(&dir, &["--config", &config, "-p", "-q", "-v", "13", "--base-file", "bases.txt"], &[]);
+    assert!(stdout(&output).starts_with("13: Probably prime (1 round passed"), "{}", stdout(&output));
+
+    // checkpoint.enabled writes checkpoints every checkpoint.interval, unless --no-memory
+    // turns it off
+    let runs = [
+        (&["-l", "-q", "-vv", "2203"][..], true),
+        (&["-l", "-q", "-vv", "--no-memory", "2203"], false),
+        (&["ll", "-q", "-vv", "--no-memory", "2203"], false),
+    ];
+    for (args, written) in runs {
+        let output = run_in(&dir, &[&["--config", &config][..], args].concat(), &[]);
+        assert!(output.status.success(), "{}", stderr(&output));
+        assert_eq!(stderr(&output).contains("Checkpoint written to "), written, "{:?}", args);
+    }
+    let output = run(&["--config", &config, "ll", "--no-memory", "127", "--print-config"]);
+    assert!(stdout(&output).contains("enabled = false  # command line\n"), "{}", stdout(&output));
+}
+
+/// The rows of `text` split on `separator`, checking each has as many fields as the header.
+fn delimited_rows(text: &str, separator: char) -> Vec<Vec<String>> {
+    let rows: Vec<Vec<String>> = text.lines().map(|line| line.split(separator).map(String::from).collect()).collect();
+    for row in &rows {
+        assert_eq!(row.len(), rows[0].len(), "{:?} in {:?}", row, text);
+    }
+    rows
+}
+
+#[test]
+fn tsv_results_and_primes_have_a_header_and_fixed_columns() {
+    let output = run(&["-l", "-q", "7", "11", "--format", "tsv", "--expected-residue", "0"]);
+    let rows = delimited_rows(&stdout(&output), '\t');
+    assert_eq!(rows[0], ["exponent", "mersenne_prime", "res64", "residue_match"]);
+    assert_eq!(rows[1], ["7", "true", "0000000000000000", "true"]);
+    assert_eq!(rows[2], ["11", "false", "00000000000006c8", "false"]);
+
+    let output = run(&["-p", "-q", "97", "100", "-v", "--format", "tsv"]);
+    assert_eq!(delimited_rows(&stdout(&output), '\t'), [["number", "probably_prime"], ["97", "true"], ["100", "false"]]);
+    let output = run(&["-p", "-q", "97", "--format", "csv"]);
+    assert_eq!(stdout(&output), "number,probably_prime\n97,true\n");
+
+    let output = run(&["-g", "1", "30", "--format", "tsv"]);
+    let rows = delimited_rows(&stdout(&output), '\t');
+    assert_eq!(rows.len(), 11);
+    assert_eq!(rows[0], ["index", "prime"]);
+    assert_eq!(rows[10], ["10", "29"]);
+    let output = run(&["-g", "1", "20", "--twins", "--format", "tsv"]);
+    assert_eq!(stdout(&output), "prime\ttwin\n3\t5\n5\t7\n11\t13\n17\t19\n");
+}
+
+#[test]
+fn the_program_cache_can_be_moved_or_turned_off_but_not_both() {
+    let output = run(&["-l", "-q", "7", "--program-cache", "cache", "--no-program-cache"]);
+    assert_eq!(output.status.code(), Some(2));
+    for flag in [&["--program-cache", "cache"][..], &["--no-program-cache"]] {
+        let output = run(&[&["-l", "-q", "7"][..], flag].concat());
+        assert_eq!(stdout(&output), "127 is a Mersenne prime.\n", "{}", stderr(&output));
+    }
+}
+
+#[test]
+fn repl_tests_each_line_until_quit() {
+    let output = run_with_stdin(&["-p", "--repl"], "97\n100\nquit\n101\n");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "97: Probably prime\n100: Probably not prime\n");
+}
+
+#[test]
+fn repl_keeps_going_after_bad_input() {
+    let output = run_with_stdin(&["-l", "--repl"], "seven\n2\n");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Please enter a valid number: seven"), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "3 is a Mersenne prime.\n");
+}
+
+#[test]
+fn generating_past_64_bits_is_an_error() {
+    let output = run(&["-g", "18446744073709551617", "18446744073709551700"]);
+    assert!(stderr(&output).contains("exceeds the 64-bit limit"), "{}", stderr(&output));
+    assert!(stdout(&output).is_empty());
+}
+
+#[test]
+fn empty_generate_ranges_are_usage_errors() {
+    for (start, end) in [("100", "10"), ("5", "5")] {
+        let output = run(&["-g", start, end]);
+        assert_eq!(output.status.code(), Some(2), "{}", stderr(&output));
+        assert!(stderr(&output).contains("START must be less than END"), "{}", stderr(&output));
+        assert!(stdout(&output).is_empty());
+    }
+}
+
+#[test]
+fn unparsable_generate_bounds_name_the_argument() {
+    for args in [["-g", "abc", "10"], ["-g", "1", "1e6"]] {
+        let output = run(&args);
+        assert_eq!(output.status.code(), Some(2), "{}", stderr(&output));
+        assert!(stderr(&output).contains("--generate <START> <END>"), "{}", stderr(&output));
+        assert!(!stderr(&output).contains("panicked"), "{}", stderr(&output));
+    }
+}
+
+#[test]
+fn verbose_prp_names_the_witness_or_the_rounds() {
+    let output = run(&["-p", "-v", "-q", "2047", "91", "97", "4"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        stdout(&output),
+        "2047: Probably prime (1 round passed, error at most 2.5e-1)\n91: Probably not prime (witness 2)\n97: Probably prime (1 round passed, error at most 2.5e-1)\n4: Probably not prime (no witness needed)\n"
+    );
+}
+
+#[test]
+fn numbers_below_two_are_refused_with_a_reason() {
+    let output = run(&["-l", "-q", "0", "1"]);
+    assert!(stderr(&output).contains("Lucas-Lehmer exponents must be at least 2, got 0."), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Lucas-Lehmer exponents must be at least 2, got 1."), "{}", stderr(&output));
+    assert!(stdout(&output).is_empty(), "{}", stdout(&output));
+
+    let output = run(&["-p", "-q", "1", "-7", "7"]);
+    assert!(stderr(&output).contains("PRP numbers must be at least 2, got 1."), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Negative numbers are not supported: -7"), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "7: Probably prime\n");
+}
+
+#[test]
+fn csv_output_numbers_the_primes_under_a_header() {
+    let dir = scratch_dir();
+    let output = run_in(&dir, &["-g", "1", "20", "-o", "primes.csv", "--output-format", "csv"], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let csv = std::fs::read_to_string(dir.join("primes.csv")).unwrap();
+    assert_eq!(csv, "index,prime\n1,2\n2,3\n3,5\n4,7\n5,11\n6,13\n7,17\n8,19\n");
+}
+
+#[test]
+fn columns_lay_every_prime_out_in_aligned_rows() {
+    let output = run(&["-g", "1", "1000", "--columns", "7"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let table = stdout(&output);
+    let rows: Vec<&str> = table.lines().collect();
+    // 168 primes below 1000, the widest of them 997
+    assert_eq!(rows.len(), 24, "{}", table);
+    assert!(rows.iter().all(|row| row.len() == 7 * 3 + 6), "{}", table);
+    assert!(rows.iter().all(|row| row.split_whitespace().count() == 7), "{}", table);
+    assert_eq!(rows[0], "  2   3   5   7  11  13  17");
+    let primes: Vec<u128> = table.split_whitespace().map(|prime| prime.parse().unwrap()).collect();
+    let lines: Vec<u128> = stdout(&run(&["-g", "1", "1000"])).lines().map(|prime| prime.parse().unwrap()).collect();
+    assert_eq!(primes, lines);
+
+    let output = run(&["-g", "1", "30", "--columns", "4"]);
+    assert_eq!(stdout(&output), " 2  3  5  7\n11 13 17 19\n23 29\n");
+    assert_eq!(run(&["-g", "1", "30", "--columns", "0"]).status.code(), Some(2));
+    assert_eq!(run(&["-g", "1", "30", "--columns", "4", "--output-format", "csv"]).status.code(), Some(2));
+}
+
+#[test]
+fn json_reports_hold_the_configuration_and_every_result() {
+    let dir = scratch_dir();
+    let output = run_in(&dir, &["-p", "-q", "97", "100", "--save-results-json", "report.json"], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(!dir.join("report.json.tmp").exists());
+    let report: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(dir.join("report.json")).unwrap()).unwrap();
+    assert_eq!((&report["test"], &report["format"]), (&"prp".into(), &"plain".into()));
+    assert!(report["device"].is_null() || report["device"].is_string(), "{}", report);
+    let results = report["results"].as_array().unwrap();
+    assert_eq!(results.len(), 2, "{}", report);
+    for (result, (number, verdict, prime)) in results.iter().zip([(97, "probable prime", true), (100, "composite", false)]) {
+        assert_eq!((&result["number"], &result["verdict"], &result["prime"]), (&number.into(), &verdict.into(), &prime.into()));
+        assert!(result["seconds"].as_f64().unwrap() >= 0.0, "{}", result);
+    }
+}
+
+#[test]
+fn binary_prime_files_feed_back_into_the_tests() {
+    let dir = scratch_dir();
+    let output = run_in(&dir, &["-g", "1", "100", "-o", "primes.bin", "--output-format", "bin"], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let output = run_in(&dir, &["-p", "-q", "-f", "primes.bin", "--read-binary"], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let text = stdout(&output);
+    let verdicts: Vec<&str> = text.lines().skip(1).collect();
+    assert_eq!(verdicts.len(), 25, "{}", text);
+    assert!(verdicts.iter().all(|line| line.ends_with(": Probably prime")), "{}", text);
+
+    std::fs::write(dir.join("primes.txt"), "2\n3\n").unwrap();
+    let output = run_in(&dir, &["-p", "-f", "primes.txt", "--read-binary"], &[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("is not a binary prime file"), "{}", stderr(&output));
+}
+
+#[test]
+fn gzipped_lists_feed_back_into_the_tests() {
+    let dir = scratch_dir();
+    let output = run_in(&dir, &["-g", "1", "10000", "-o", "primes.txt.gz"], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let output = run_in(&dir, &["-g", "1", "10000", "-o", "primes.packed", "--compress"], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    for list in ["primes.txt.gz", "primes.packed"] {
+        assert!(std::fs::read(dir.join(list)).unwrap().starts_with(&[0x1f, 0x8b]), "{}", list);
+        let output = run_in(&dir, &["-p", "-q", "-f", list], &[]);
+        assert!(output.status.success(), "{}", stderr(&output));
+        let text = stdout(&output);
+        let verdicts: Vec<&str> = text.lines().skip(1).collect();
+        assert_eq!(verdicts.len(), 1229, "{}", list);
+        assert_eq!(verdicts[0], "2: Probably prime");
+        assert!(verdicts.iter().all(|line| line.ends_with(": Probably prime")), "{}", list);
+    }
+}
+
+#[test]
+fn llr_reports_riesel_numbers() {
+    let output = run(&["--llr", "3", "18"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "3*2^18-1 is prime.\n");
+    assert_eq!(stdout(&run(&["--llr", "3", "19"])), "3*2^19-1 is not prime.\n");
+
+    let output = run(&["--llr", "6", "10"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("LLR needs an odd k, got 6."), "{}", stderr(&output));
+}
+
+#[test]
+fn sqlite_databases_collect_primes_and_tests() {
+    let dir = scratch_dir();
+    let output = run_in(&dir, &["-g", "1", "100000", "--sqlite", "results.db"], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).is_empty());
+    let output = run_in(&dir, &["-p", "-q", "97", "100", "--sqlite", "results.db"], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let connection = rusqlite::Connection::open(dir.join("results.db")).unwrap();
+    let count = |sql: &str| connection.query_row(sql, [], |row| row.get::<_, i64>(0)).unwrap();
+    assert_eq!(count("SELECT COUNT(*) FROM primes"), 9592);
+    assert_eq!(count("SELECT MAX(value) FROM primes"), 99991);
+    assert_eq!(count("SELECT value FROM primes ORDER BY value LIMIT 1 OFFSET 999"), 7919);
+    assert_eq!(count("SELECT COUNT(*) FROM tests WHERE kind = 'prp'"), 2);
+    let verdict: String =
+        connection.query_row("SELECT verdict FROM tests WHERE exponent = 97", [], |row| row.get(0)).unwrap();
+    assert_eq!(verdict, "probable prime");
+}
+
+#[test]
+fn skip_done_passes_over_exponents_the_results_record() {
+    let dir = scratch_dir();
+    let output = run_in(&dir, &["-l", "-q", "7", "11", "-o", "results.txt", "--sqlite", "results.db"], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    // The second pass tests only 13, from either record of the first
+    for results in [["-o", "results.txt"], ["--sqlite", "results.db"]] {
+        let output = run_in(&dir, &[&["-l", "-q", "7", "11", "13", "--skip-done"][..], &results].concat(), &[]);
+        assert!(output.status.success(), "{}", stderr(&output));
+        assert_eq!(stdout(&output), "8191 is a Mersenne prime.\n");
+        assert_eq!(
+            stderr(&output),
+            "M7: skipped (already done, Res64 0000000000000000)\nM11: skipped (already done, Res64 00000000000006c8)\n"
+        );
+    }
+    let text = std::fs::read_to_string(dir.join("results.txt")).unwrap();
+    assert_eq!(text.lines().count(), 3, "{}", text);
+
+    let output = run_in(&dir, &["-l", "-q", "7", "--skip-done", "--redo", "-o", "results.txt"], &[]);
+    assert_eq!(stdout(&output), "127 is a Mersenne prime.\n");
+    let output = run_in(&dir, &["-l", "-q", "7", "11"], &[("MP_SKIP_DONE", "true"), ("MP_RESULT_FILE", "results.txt")]);
+    assert!(stdout(&output).is_empty(), "{}", stdout(&output));
+
+    // PRP tests are looked up by their own kind, so M7's Lucas-Lehmer test doesn't skip -p 7
+    // or --prp-mersenne 7
+    let output = run_in(&dir, &["-p", "-q", "7", "--skip-done", "-o", "results.txt"], &[]);
+    assert_eq!(stdout(&output), "7: Probably prime\n");
+    let output = run_in(&dir, &["prp", "-q", "7", "11", "--skip-done", "-o", "results.txt"], &[]);
+    assert_eq!((stdout(&output), stderr(&output)), ("11: Probably prime\n".to_string(), "7: skipped (already done)\n".to_string()));
+    for expected in ["2^7-1 is probably prime.\n", ""] {
+        let output = run_in(&dir, &["--prp-mersenne", "7", "--skip-done", "-o", "results.txt"], &[]);
+        assert!(output.status.success(), "{}", stderr(&output));
+        assert_eq!(stdout(&output), expected);
+    }
+    assert_eq!(run(&["-g", "1", "10", "--skip-done"]).status.code(), Some(2));
+}
+
+#[test]
+fn resumed_generation_matches_an_uninterrupted_run() {
+    let dir = scratch_dir();
+    let args = ["-g", "1", "40000000", "-o", "primes.txt", "--resume"];
+    let mut child = Command::new(env!("CARGO_BIN_EXE_mersenne-prime"))
+        .args(args)
+        .current_dir(&dir)
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    while !dir.join("primes.txt.progress").exists() {
+        assert!(child.try_wait().unwrap().is_none(), "the run finished before it could be interrupted");
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    child.kill().unwrap();
+    child.wait().unwrap();
+    // Whatever the kill left half-written is cut off again
+    std::fs::OpenOptions::new().append(true).open(dir.join("primes.txt")).unwrap().write_all(b"12").unwrap();
+
+    let output = run_in(&dir, &["-g", "1", "40000001", "-o", "primes.txt", "--resume"], &[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("different arguments"), "{}", stderr(&output));
+
+    let output = run_in(&dir, &args, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Resuming primes.txt from "), "{}", stderr(&output));
+    assert!(!dir.join("primes.txt.progress").exists());
+    let output = run_in(&dir, &["-g", "1", "40000000", "-o", "clean.txt"], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    // The headers differ only in when the runs started
+    let resumed = std::fs::read_to_string(dir.join("primes.txt")).unwrap();
+    let clean = std::fs::read_to_string(dir.join("clean.txt")).unwrap();
+    assert!(resumed.starts_with("# generator: mersenne-prime "));
+    assert_eq!(resumed.matches("# generated: ").count(), 1);
+    assert!(without_comments(&resumed) == without_comments(&clean));
+}
+
+#[test]
+fn base_files_add_witnesses_to_prp() {
+    let dir = scratch_dir();
+    // 2047 = 23 * 89 is the smallest strong pseudoprime to base 2
+    let output = run_in(&dir, &["-p", "-q", "2047"], &[]);
+    assert_eq!(stdout(&output), "2047: Probably prime\n");
+
+    std::fs::write(dir.join("bases.txt"), "# first primes\n2\n3\n\n3\n5\n").unwrap();
+    let output = run_in(&dir, &["-p", "-q", "-v", "2047", "2053", "--base-file", "bases.txt"], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "2047: Probably not prime (witness 3)\n2053: Probably prime (3 rounds passed, error at most 1.6e-2)\n");
+    let output = run_in(&dir, &["-p", "-q", "5", "--bases", "5", "--base-file", "bases.txt"], &[]);
+    assert_eq!(stdout(&output), "5: Probably prime\n");
+
+    std::fs::write(dir.join("bad.txt"), "2\n1\n").unwrap();
+    let output = run_in(&dir, &["-p", "2047", "--base-file", "bad.txt"], &[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("Invalid base in bad.txt: 1"), "{}", stderr(&output));
+    assert_eq!(run_in(&dir, &["-g", "1", "100", "--base-file", "bases.txt"], &[]).status.code(), Some(2));
+}
+
+#[test]
+fn progress_log_separates_sieving_from_writing() {
+    // One rayon thread hands over primes every four segments, so the range takes several chunks
+    let args = ["-g", "1", "8000000", "-o", "primes.txt"];
+    let output = run_env(&args, &[("RAYON_NUM_THREADS", "1")]);
+    assert!(stderr(&output).is_empty(), "{}", stderr(&output));
+
+    let output = run_env(&[&args[..], &["--progress-log"]].concat(), &[("RAYON_NUM_THREADS", "1")]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let log = stderr(&output);
+    let lines: Vec<&str> = log.lines().collect();
+    assert!(lines[0].starts_with("Sieving Segments [") && lines[0].contains("0/7,999,998"), "{}", log);
+    assert!(lines.iter().any(|line| line.starts_with("Writing Primes [")), "{}", log);
+    let last = lines.last().unwrap();
+    assert!(last.starts_with("Sieving Completed [") && last.contains("7,999,998/7,999,998 (100%"), "{}", log);
+    assert!(last.contains("/s, ETA "), "{}", log);
+}
+
+#[test]
+fn color_decides_whether_progress_lines_carry_ansi_codes() {
+    let args = ["-g", "1", "100000", "-o", "primes.txt", "--progress-log"];
+    // An explicit --color wins over NO_COLOR, which auto respects even on a terminal
+    for (color, no_color) in [("never", ""), ("always", "1"), ("auto", "")] {
+        let output = run_env(&[&args[..], &["--color", color]].concat(), &[("NO_COLOR", no_color)]);
+        let log = stderr(&output);
+        assert!(log.contains("Sieving Segments [") && (color == "always") == log.contains('\x1b'), "{}: {}", color, log);
+    }
+    assert_eq!(run(&[&args[..], &["--color", "sometimes"]].concat()).status.code(), Some(2));
+}
+
+#[test]
+fn progress_json_streams_iterations_up_to_a_done_event() {
+    let output = run(&["-l", "9689", "-q", "--progress-json", "50ms"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let log = stderr(&output);
+    let events: Vec<serde_json::Value> = log.lines().map(|line| serde_json::from_str(line).expect(line)).collect();
+    assert!(events.len() >= 2, "{}", log);
+    assert!(events.iter().all(|event| event["kind"] == "ll" && event["exponent"] == 9689 && event["total"] == 9687), "{}", log);
+    let iterations: Vec<u64> = events.iter().map(|event| event["iteration"].as_u64().unwrap()).collect();
+    assert!(iterations.windows(2).all(|pair| pair[0] < pair[1]), "{}", log);
+    let (last, progress) = events.split_last().unwrap();
+    assert!(progress.iter().all(|event| event["event"] == "progress"), "{}", log);
+    assert_eq!(last["event"], "done", "{}", log);
+    assert_eq!(last["iteration"], 9687, "{}", log);
+
+    let output = run_env(&["-g", "1", "8000000", "-o", "primes.txt", "--progress-json"], &[("RAYON_NUM_THREADS", "1")]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let log = stderr(&output);
+    let last: serde_json::Value = serde_json::from_str(log.lines().last().unwrap()).unwrap();
+    assert_eq!((&last["kind"], &last["event"], &last["position"]), (&"generate".into(), &"done".into(), &7_999_998.into()), "{}", log);
+    assert_eq!(run(&["-l", "127", "--progress-json", "--progress-log"]).status.code(), Some(2));
+}
+
+#[test]
+fn dedup_tests_each_repeated_exponent_once() {
+    let dir = scratch_dir();
+    // Exponent 2 is settled without a kernel, which keeps the runs fast without a device
+    std::fs::write(dir.join("exponents.txt"), "2\n2\n\n2\n").unwrap();
+    let args = ["-l", "--format", "json", "-f", "exponents.txt"];
+    let output = run_in(&dir, &args, &[]);
+    assert!(stderr(&output).contains("Warning: repeated numbers will be tested again: 2 "), "{}", stderr(&output));
+    assert_eq!(stdout(&output).matches("{\"exponent\":2,").count(), 3);
+    assert!(stdout(&output).contains("\"tested\":3,"), "{}", stdout(&output));
+
+    let output = run_in(&dir, &[&args[..], &["--dedup"]].concat(), &[]);
+    assert!(stderr(&output).contains("Skipping repeated numbers: 2\n"), "{}", stderr(&output));
+    assert_eq!(stdout(&output).matches("{\"exponent\":2,").count(), 1);
+    assert!(stdout(&output).contains("\"tested\":1,"), "{}", stdout(&output));
+}
+
+#[test]
+fn worktodo_files_run_their_test_assignments() {
+    let dir = scratch_dir();
+    std::fs::write(dir.join("worktodo.txt"), "Test=127\nFactor=N/A,1277,1,80\n").unwrap();
+    let output = run_in(&dir, &["ll", "--worktodo", "worktodo.txt", "--format", "json", "-q"], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "{\"exponent\":127,\"mersenne_prime\":true}\n");
+    assert!(stderr(&output).contains("worktodo.txt: ignoring line 2, which is not a Test= or DoubleCheck= assignment: Factor=N/A,1277,1,80"), "{}", stderr(&output));
+
+    // Real assignments are far past the 64-bit kernel and run on the CPU without an OpenCL device
+    std::fs::write(dir.join("worktodo.txt"), "Test=0123456789ABCDEF0123456789ABCDEF,4423,64,1\nDoubleCheck=4421,64,1\n").unwrap();
+    let output = run_in(&dir, &["ll", "--worktodo", "worktodo.txt", "--format", "json", "-q"], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "{\"exponent\":4423,\"mersenne_prime\":true}\n{\"exponent\":4421,\"mersenne_prime\":false}\n");
+
+    let output = run_in(&dir, &["-l", "--worktodo", "missing.txt"], &[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("Cannot read the worktodo file missing.txt"), "{}", stderr(&output));
+}
+
+#[test]
+fn dash_output_writes_to_standard_output() {
+    let dir = scratch_dir();
+    let output = run_in(&dir, &["-g", "1", "100000", "-o", "primes.txt"], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).is_empty());
+    let file = without_comments(&std::fs::read_to_string(dir.join("primes.txt")).unwrap());
+    assert_eq!(file.lines().count(), 9592);
+
+    let dash = run_in(&dir, &["-g", "1", "100000", "-o", "-"], &[]);
+    assert!(dash.status.success(), "{}", stderr(&dash));
+    assert_eq!(stdout(&dash), file);
+    assert!(stderr(&dash).is_empty(), "{}", stderr(&dash));
+    assert!(!dir.join("-").exists());
+    assert_eq!(stdout(&run_in(&dir, &["-g", "1", "100000"], &[])), file);
+    let csv = run_in(&dir, &["-g", "1", "10", "-o", "-", "--output-format", "csv"], &[]);
+    assert_eq!(stdout(&csv), "index,prime\n1,2\n2,3\n3,5\n4,7\n");
+
+    let output = run_in(&dir, &["-g", "1", "100", "-o"], &[]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("a value is required for '--output <FILE>'"), "{}", stderr(&output));
+    let output = run_in(&dir, &["-g", "1", "100", "-o", "-", "--compress"], &[]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("--compress needs -o to name a file"), "{}", stderr(&output));
+}
+
+#[test]
+fn file_headers_describe_the_run_and_feed_back_into_prp() {
+    let dir = scratch_dir();
+    let output = run_in(&dir, &["-g", "10", "100", "-o", "primes.txt", "--mod", "4", "--residue", "3"], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let text = std::fs::read_to_string(dir.join("primes.txt")).unwrap();
+    let header: Vec<&str> = text.lines().take_while(|line| line.starts_with('#')).collect();
+    assert_eq!(header[0], format!("# generator: mersenne-prime {}", env!("CARGO_PKG_VERSION")));
+    assert_eq!(header[1..4], ["# range: [10, 100)", "# method: segmented sieve (CPU)", "# filter: p mod 4 in {3}"]);
+    assert!(header[4].starts_with("# generated: 20") && header[4].ends_with('Z'), "{}", header[4]);
+    assert_eq!(without_comments(&text), "11\n19\n23\n31\n43\n47\n59\n67\n71\n79\n83\n");
+
+    let output = run_in(&dir, &["-p", "-q", "-f", "primes.txt"], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).is_empty(), "{}", stderr(&output));
+    let text = stdout(&output);
+    let verdicts: Vec<&str> = text.lines().skip(1).collect();
+    assert_eq!(verdicts.len(), 11);
+    assert!(verdicts.iter().all(|line| line.ends_with(": Probably prime")), "{}", text);
+
+    let output = run_in(&dir, &["-g", "10", "100", "-o", "bare.txt", "--no-header"], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(std::fs::read_to_string(dir.join("bare.txt")).unwrap().starts_with("11\n13\n"));
+}
+
+#[test]
+fn devices_must_be_indices_or_all() {
+    let output = run(&["-g", "1", "100", "--devices", "0,first"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("--devices takes indices like 0,1 or all, not 0,first"), "{}", stderr(&output));
+    assert_eq!(run(&["-g", "1", "100", "--devices", "0", "--fermat"]).status.code(), Some(2));
+}
+
+#[test]
+fn prp_mersenne_tests_the_mersenne_number_itself() {
+    // 2047 = 23 * 89 fools base 2, as every 2^p-1 does, but not the default base 3
+    let output = run(&["--prp-mersenne", "11"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "2^11-1 is not prime.\n");
+    assert_eq!(stdout(&run(&["--prp-mersenne", "13"])), "2^13-1 is probably prime.\n");
+    assert_eq!(stdout(&run(&["--prp-mersenne", "127"])), "2^127-1 is probably prime.\n");
+    assert_eq!(stdout(&run(&["--prp-mersenne", "11", "--bases", "2"])), "2^11-1 is probably prime.\n");
+    assert_eq!(stdout(&run(&["--prp-mersenne", "67", "-v"])), "2^67-1 is not prime (witness 3).\n");
+    assert_eq!(
+        stdout(&run(&["--prp-mersenne", "127", "-v"])),
+        "2^127-1 is probably prime (error at most 2.5e-1, 4.2e-2 for a random 127-bit number).\n"
+    );
+    assert_eq!(run(&["--prp-mersenne", "1"]).status.code(), Some(2));
+}
+
+#[test]
+fn gerbicz_checks_leave_prp_verdicts_as_they_were() {
+    for block in ["0", "1", "3", "1000"] {
+        assert_eq!(stdout(&run(&["--prp-mersenne", "127", "--gerbicz-block", block])), "2^127-1 is probably prime.\n");
+        assert_eq!(stdout(&run(&["--prp-mersenne", "67", "--gerbicz-block", block])), "2^67-1 is not prime.\n");
+    }
+    let output = run(&["--cross-check", "89", "--gerbicz-block", "5"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).ends_with("(res64 0000000000000001): the tests agree.\n"), "{}", stdout(&output));
+    assert_eq!(run(&["-l", "7", "--gerbicz-block", "3"]).status.code(), Some(2));
+}
+
+#[test]
+fn cross_check_runs_both_tests_and_reports_both_residues() {
+    let output = run(&["--cross-check", "13"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        stdout(&output),
+        "M13: Lucas-Lehmer says prime (res64 0000000000000000), PRP to base 3 says probably prime (res64 0000000000000001): the tests agree.\n"
+    );
+    let output = run(&["--cross-check", "11", "--format", "json"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let record: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+    assert_eq!(record["lucas_lehmer"]["prime"], false);
+    assert_eq!(record["lucas_lehmer"]["res64"], "00000000000006c8");
+    assert_eq!(record["prp"]["probable_prime"], false);
+    assert_eq!(record["prp"]["res64"], "00000000000003f5");
+    assert_eq!(record["agree"], true);
+    // 3 divides 2^p-1 for every even p, which leaves the PRP test nothing to square
+    assert_eq!(run(&["--cross-check", "12", "--format", "json"]).status.code(), Some(0));
+    assert_eq!(run(&["--cross-check", "13", "--expected-residue", "0"]).status.code(), Some(2));
+
+    // Exponents past the 64-bit kernel need no OpenCL device
+    let output = run(&["--cross-check", "9941"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).starts_with("M9941: Lucas-Lehmer says prime (res64 0000000000000000)"), "{}", stdout(&output));
+    assert!(stdout(&output).ends_with("the tests agree.\n"), "{}", stdout(&output));
+
+    // A directory where M11's checkpoint goes fails that check alone, and the batch goes on
+    let dir = scratch_dir();
+    std::fs::create_dir(dir.join("lucas_lehmer_residue_11.bin")).unwrap();
+    let output = run_in(&dir, &["--cross-check", "7", "11", "13", "-m", "--format", "json"], &[]);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+    let records: Vec<serde_json::Value> = stdout(&output).lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    let exponents: Vec<u64> = records.iter().map(|record| record["exponent"].as_u64().unwrap()).collect();
+    assert_eq!(exponents, [7, 11, 13]);
+    assert!(records[1]["error"].is_string() && records[1].get("agree").is_none(), "{}", records[1]);
+    assert_eq!((&records[0]["agree"], &records[2]["agree"]), (&true.into(), &true.into()));
+    assert!(stderr(&output).contains("Cross-check of 2^11-1 failed: "), "{}", stderr(&output));
+    assert!(stderr(&output).contains("The cross-checks of M11 failed"), "{}", stderr(&output));
+}
+
+#[test]
+fn compare_tests_agree_on_several_exponents() {
+    let output = run(&["--compare-tests", "3", "5", "7", "11", "13", "--format", "json"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let records: Vec<serde_json::Value> = stdout(&output).lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    let exponents: Vec<u64> = records.iter().map(|record| record["exponent"].as_u64().unwrap()).collect();
+    assert_eq!(exponents, [3, 5, 7, 11, 13]);
+    for record in &records {
+        assert_eq!(record["agree"], true, "{}", record);
+        assert_eq!(record["lucas_lehmer"]["prime"], record["prp"]["probable_prime"], "{}", record);
+    }
+    assert_eq!(records.iter().filter(|record| record["lucas_lehmer"]["prime"] == true).count(), 4);
+}
+
+#[test]
+fn subcommands_run_as_the_top_level_flags_they_replace() {
+    for (new, legacy) in [
+        (&["ll", "7", "11", "-q"][..], &["-l", "7", "11", "-q"][..]),
+        (&["prp", "-q", "2", "9", "97"], &["-p", "2", "9", "97", "-q"]),
+        (&["gen", "--twins", "1", "30"], &["-g", "1", "30", "--twins"]),
+        (&["-v", "gen", "--mod=4", "--residue", "1", "1", "60"], &["--generate", "1", "60", "--mod", "4", "--residue", "1"]),
+        (&["factor", "360"], &["--factor", "360"]),
+    ] {
+        let output = run(new);
+        assert!(output.status.success(), "{:?}: {}", new, stderr(&output));
+        assert!(!stdout(&output).is_empty(), "{:?}", new);
+        assert_eq!(stdout(&output), stdout(&run(legacy)), "{:?}", new);
+    }
+
+    let dir = scratch_dir();
+    let output = run_in(&dir, &["gen", "-o", "primes.txt", "10", "30", "--no-header"], &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(std::fs::read_to_string(dir.join("primes.txt")).unwrap(), "11\n13\n17\n19\n23\n29\n");
+    // Options keep their checks under a subcommand
+    assert_eq!(run(&["gen", "1", "100", "--twins", "--count"]).status.code(), Some(2));
+    assert_eq!(run(&["gen", "--compress", "1", "100"]).status.code(), Some(2));
+    assert_eq!(run(&["ll", "7", "-j", "2", "--chunked-progress", "status.txt"]).status.code(), Some(2));
+    assert_eq!(run(&["ll"]).status.code(), Some(2));
+    // Errors speak of the subcommand, not of the options it replaces
+    let output = run(&["gen", "100", "1"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("START must be less than END") && !stderr(&output).contains("--generate"), "{}", stderr(&output));
+    let output = run(&["gen", "1", "20", "--bases", "3"]);
+    assert!(!stderr(&output).contains("-g"), "{}", stderr(&output));
+    // Options of the subcommand go after it, where it sees them
+    let output = run(&["-m", "ll", "7"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("--memory must come after the subcommand"), "{}", stderr(&output));
+    assert_eq!(stdout(&run(&["gen", "1", "12", "--columns", "5"])), " 2  3  5  7 11\n");
+    assert_eq!(stdout(&run(&["--color", "never", "prp", "-q", "97"])), "97: Probably prime\n");
+}
+
+/// Whether `help` has a line for `option`.
+fn lists_option(help: &str, option: &str) -> bool {
+    help.lines().any(|line| line.split_whitespace().take(2).any(|word| word.trim_end_matches([',', '.']) == option))
+}
+
+#[test]
+fn each_subcommand_has_its_own_help() {
+    let help = stdout(&run(&["--help"]));
+    for command in ["ll", "prp", "gen", "factor"] {
+        assert!(help.lines().any(|line| line.trim_start().starts_with(command)), "{}", help);
+    }
+    // The top-level spellings still work, but only the options of the remaining modes are listed
+    assert!(lists_option(&help, "--nth") && lists_option(&help, "--verbose"), "{}", help);
+    for legacy in ["--ll", "--prp", "--generate", "--twins", "--jobs"] {
+        assert!(!lists_option(&help, legacy), "{} in {}", legacy, help);
+    }
+
+    let help = stdout(&run(&["ll", "--help"]));
+    assert!(help.contains("Usage: mersenne-prime ll [OPTIONS] [EXPONENT]..."), "{}", help);
+    assert!(lists_option(&help, "--jobs") && lists_option(&help, "--verbose") && !lists_option(&help, "--twins"), "{}", help);
+    let help = stdout(&run(&["gen", "--help"]));
+    assert!(help.contains("<START> <END>") && lists_option(&help, "--twins") && !lists_option(&help, "--jobs"), "{}", help);
+    let help = stdout(&run(&["help", "factor"]));
+    assert!(help.contains("Usage: mersenne-prime factor [OPTIONS] <N>"), "{}", help);
+}
+
+#[test]
+fn factor_prints_prime_powers() {
+    assert_eq!(stdout(&run(&["factor", "360"])), "360 = 2^3 * 3^2 * 5\n");
+    assert_eq!(stdout(&run(&["factor", "97"])), "97 = 97\n");
+    assert_eq!(
+        stdout(&run(&["factor", "18446744073709551615"])),
+        "18446744073709551615 = 3 * 5 * 17 * 257 * 641 * 65537 * 6700417\n"
+    );
+    assert_eq!(run(&["factor", "1"]).status.code(), Some(2));
+    assert_eq!(run(&["factor", "18446744073709551616"]).status.code(), Some(2));
+}
+
+#[test]
+fn trial_division_gives_exact_verdicts() {
+    // 341 = 11 * 31 and the Carmichael number 561 both pass the base-2 PRP test
+    let output = run(&["prp", "--trial", "-q", "2", "341", "561", "97"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "2: Prime\n341: Not prime\n561: Not prime\n97: Prime\n");
+    let output = run(&["-p", "--trial", "--format", "csv", "-q", "341", "7"]);
+    assert_eq!(stdout(&output), "number,prime\n341,false\n7,true\n");
+    assert_eq!(run(&["--trial", "7"]).status.code(), Some(2));
+    // 2^64 + 13 is prime, but would take years to prove so by trial division
+    let output = run(&["prp", "--trial", "-q", "7", "18446744073709551629"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stdout(&output).is_empty(), "{}", stdout(&output));
+    assert!(stderr(&output).contains("test it with -p alone"), "{}", stderr(&output));
+    let output = run(&["prp", "--trial", "-q", "18446744073709551615"]);
+    assert_eq!(stdout(&output), "18446744073709551615: Not prime\n");
+}
+
+#[test]
+fn completion_scripts_cover_the_subcommands() {
+    for shell in ["bash", "zsh", "fish"] {
+        let output = run(&["completions", shell]);
+        assert!(output.status.success(), "{}: {}", shell, stderr(&output));
+        let script = stdout(&output);
+        for command in ["ll", "prp", "gen", "factor", "print-config"] {
+            assert!(script.contains(command), "{} in the {} script", command, shell);
+        }
+    }
+    assert_eq!(run(&["completions", "tcsh"]).status.code(), Some(2));
+}
+
+#[test]
+fn capabilities_are_valid_json() {
+    let output = run(&["--capabilities"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let capabilities: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+    let opencl = capabilities["opencl"].as_bool().unwrap();
+    let devices = capabilities["devices"].as_array().unwrap();
+    assert_eq!(opencl, !devices.is_empty());
+    assert_eq!(capabilities["backends"]["cpu"], true);
+    assert_eq!(capabilities["backends"]["ntt"], opencl);
+    assert_eq!(capabilities["version"], env!("CARGO_PKG_VERSION"));
+}
+
+/// The seconds of each row of a `--profile` breakdown in `text`, by the row's name.
+fn profile_rows(text: &str) -> Vec<(String, f64)> {
+    let start = text.find("Phase ").expect("a profile breakdown");
+    text[start..]
+        .lines()
+        .skip(1)
+        .map(|line| {
+            let (name, rest) = line.split_at(14);
+            let seconds = rest.split_whitespace().next().unwrap().trim_end_matches('s');
+            (name.trim().to_string(), seconds.parse().unwrap())
+        })
+        .collect()
+}
+
+#[test]
+fn profiles_account_for_the_whole_run() {
+    for args in [&["ll", "9689", "--profile", "-q"][..], &["-g", "1", "20000000", "--count", "--profile"]] {
+        let output = run(args);
+        assert!(output.status.success(), "{}", stderr(&output));
+        let rows = profile_rows(&stderr(&output));
+        let names: Vec<&str> = rows.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["OpenCL init", "compilation", "buffer setup", "execution", "readback", "other", "total"]);
+        let total = rows[6].1;
+        let phases: f64 = rows[..5].iter().map(|(_, seconds)| seconds).sum();
+        // On the CPU the run is all execution, short of parsing and printing
+        assert!(phases <= total + 0.001 && phases >= 0.8 * total, "{:?}", rows);
+        assert!((phases + rows[5].1 - total).abs() <= 0.005, "{:?}", rows);
+    }
+    assert!(!stderr(&run(&["gen", "1", "100"])).contains("Phase"));
+}